@@ -39,7 +39,7 @@ async fn main() {
     //
     // when calling storage::open, it load whole storage from disk
     //
-    let s = Arc::new(Storage::<Pid, User>::open(ops).await.unwrap());
+    let s = Arc::new(Storage::<Pid, User>::open(ops).await.unwrap().0);
     
         
         
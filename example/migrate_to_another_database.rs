@@ -32,7 +32,7 @@ async fn main() {
     // when calling storage::open, it load whole storage from disk
     //
     
-    let s = Arc::new(Storage::<Pid, User>::open(ops).await.unwrap());
+    let s = Arc::new(Storage::<Pid, User>::open(ops).await.unwrap().0);
     
     let pw = Persistent::connect(DatabaseName::Postgres, "host=localhost user=postgres").await.unwrap();
     
@@ -24,7 +24,7 @@ async fn main() {
     let ops = Options::new(path, storage_name, total_page_size, StorageType::RamCopies, true);
 
 
-    let s = Storage::<Pid, User>::open(ops).await.unwrap();
+    let (s, _) = Storage::<Pid, User>::open(ops).await.unwrap();
 
 
     // ----------------------------------------------------------------
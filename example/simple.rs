@@ -33,7 +33,7 @@ async fn main() {
     // when calling storage::open, it load whole storage from disk
     //
 
-    let s1 = Storage::<Pid, User>::open(ops).await.unwrap();
+    let (s1, _) = Storage::<Pid, User>::open(ops).await.unwrap();
 
     // insert to memory and send to (disk_log) and (reporter)
     s1.insert("+98 9370156893".to_owned(), User::new("DanyalMh"))
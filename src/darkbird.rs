@@ -1,17 +1,37 @@
 use serde::{Deserialize, Serialize};
 use simple_wal::LogError;
-use std::{io::Error, time::Duration};
+use std::{io::Error, sync::Arc, time::Duration};
 
+pub mod cluster;
 pub mod database;
 pub mod document;
+pub mod migration;
 mod index;
 pub mod persistent_worker;
+pub mod replica;
+pub mod replication;
 mod router;
 pub mod schema;
 pub mod storage;
 pub mod storage_redis;
 pub mod storage_vector;
+pub mod transaction;
+#[cfg(test)]
 mod storage_vector_test;
+#[cfg(test)]
+mod storage_test;
+#[cfg(test)]
+mod migration_test;
+#[cfg(test)]
+mod replication_test;
+#[cfg(test)]
+mod replica_test;
+#[cfg(test)]
+mod cluster_test;
+#[cfg(test)]
+mod transaction_test;
+#[cfg(test)]
+mod test_fixture;
 pub mod vector;
 pub mod wal;
 
@@ -20,6 +40,7 @@ pub use async_trait::async_trait;
 pub static TIMEOUT: Duration = Duration::from_secs(5);
 
 pub use storage::{Event, RQuery};
+pub use wal::codec::{Compression, Format, KeyProvider};
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -36,6 +57,9 @@ pub enum StatusResult {
     ReporterIsOff,
     Err(String),
     Duplicate,
+
+    // version of document is not expected version
+    Conflict { expected: u64, actual: u64 },
 }
 
 impl ToString for StatusResult {
@@ -47,6 +71,7 @@ impl ToString for StatusResult {
             StatusResult::ReporterIsOff => "ReporterIsOff".to_string(),
             StatusResult::Err(e) => e.to_string(),
             StatusResult::Duplicate => "Duplicate".to_string(),
+            StatusResult::Conflict { expected, actual } => format!("Conflict: expected version {}, actual {}", expected, actual),
         }
     }
 }
@@ -59,6 +84,12 @@ pub enum SessionResult {
     NoResponse,
     DataStoreNotFound,
     UnImplement,
+
+    // key is not loaded yet, storage is still loading in background
+    Loading,
+
+    // storage is replication follower
+    ReadOnly,
     Err(StatusResult),
 }
 
@@ -71,6 +102,8 @@ impl ToString for SessionResult {
             SessionResult::NoResponse => "NoResponse".to_string(),
             SessionResult::DataStoreNotFound => "DataStoreNotFound".to_string(),
             SessionResult::UnImplement => "UnImplement".to_string(),
+            SessionResult::Loading => "Loading".to_string(),
+            SessionResult::ReadOnly => "ReadOnly".to_string(),
             SessionResult::Err(e) => e.to_string(),
         }
     }
@@ -90,6 +123,58 @@ pub enum StorageType {
 
     // Store to memory and persist to disk
     DiskCopies,
+
+    // Store to memory and persist to disk, every node of list hold a copy
+    // and write is acknowledged when quorum of them applied it. write that
+    // does not reach quorum fail but is not undone, nodes that missed it get it by resync
+    ReplicatedCopies(Vec<String>),
+}
+
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Durability {
+    // insert return as soon as record queued to disk_log
+    Enqueued,
+
+    // insert return when batch containing record written to page
+    Flushed,
+
+    // insert return when batch containing record written to page and fsynced
+    Fsynced,
+}
+
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum RecoveryPolicy {
+    // open fail if any page is corrupt
+    Strict,
+
+    // cut last page from first corrupt record, corrupt record in other pages fail open
+    TruncateTail,
+
+    // remove every corrupt record from pages
+    SkipCorruptRecords,
+}
+
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    // take snapshot automatically after this many records logged
+    pub every: usize,
+
+    // move pages older than snapshot to archive folder instead of removing them
+    pub archive: bool,
+}
+
+
+/// progress of loading DiskCopies storage on open
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoadProgress {
+    pub pages_loaded: usize,
+    pub pages_total: usize,
+
+    // records decoded from snapshot and pages so far
+    pub records: usize,
 }
 
 
@@ -100,6 +185,30 @@ pub struct Options<'a> {
     pub total_page_size: usize,
     pub stype: StorageType,
     pub off_reporter: bool,
+    pub durability: Durability,
+    pub checkpoint: Option<Checkpoint>,
+    pub recovery: RecoveryPolicy,
+    pub format: Format,
+    pub compression: Compression,
+
+    // keys can not serialized, so they must set again after deserialize
+    #[serde(skip)]
+    pub keys: Option<Arc<dyn KeyProvider>>,
+
+    // threads that decode pages on open, 0 is available parallelism
+    pub load_threads: usize,
+
+    #[serde(skip)]
+    pub load_progress: Option<Arc<dyn Fn(LoadProgress) + Send + Sync>>,
+
+    // Schema register storage before it is loaded
+    pub lazy_load: bool,
+
+    // nodes of ReplicatedCopies that must apply write, None is majority of them
+    pub quorum: Option<usize>,
+
+    // address of this node, set by Schema::with_node
+    pub node: Option<String>,
 }
 
 impl<'a> Options<'a> {
@@ -116,8 +225,85 @@ impl<'a> Options<'a> {
             total_page_size,
             stype,
             off_reporter,
+            durability: Durability::Enqueued,
+            checkpoint: None,
+            recovery: RecoveryPolicy::Strict,
+            format: Format::Bincode,
+            compression: Compression::None,
+            keys: None,
+            load_threads: 0,
+            load_progress: None,
+            lazy_load: false,
+            quorum: None,
+            node: None,
         }
     }
+
+    /// set acknowledgement level of DiskCopies writes (default is `Durability::Enqueued`)
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// take a snapshot of DiskCopies storage after every `every` records logged,
+    /// so open load latest snapshot and replay just pages written after it
+    pub fn with_checkpoint(mut self, every: usize, archive: bool) -> Self {
+        self.checkpoint = Some(Checkpoint { every, archive });
+        self
+    }
+
+    /// set how open treat corrupt records of pages (default is `RecoveryPolicy::Strict`),
+    /// dropped records are moved to quarantine file of datastore
+    pub fn with_recovery(mut self, recovery: RecoveryPolicy) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// set format of records written to pages (default is `Format::Bincode`),
+    /// pages written with other formats are still readable
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// compress records written to pages (default is `Compression::None`),
+    /// compression is recorded in header of page so uncompressed pages still load
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// encrypt records written to pages with current key of provider,
+    /// pages encrypted with older keys are decrypted by their key id
+    pub fn with_keys(mut self, keys: Arc<dyn KeyProvider>) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    /// decode pages on open with this many threads (default is available parallelism)
+    pub fn with_load_threads(mut self, threads: usize) -> Self {
+        self.load_threads = threads;
+        self
+    }
+
+    /// call progress while pages are loaded on open
+    pub fn with_load_progress(mut self, progress: Arc<dyn Fn(LoadProgress) + Send + Sync>) -> Self {
+        self.load_progress = Some(progress);
+        self
+    }
+
+    /// Schema register storage at once and load it in background (see `Storage::open_lazy`)
+    pub fn with_lazy_load(mut self, lazy_load: bool) -> Self {
+        self.lazy_load = lazy_load;
+        self
+    }
+
+    /// nodes of ReplicatedCopies that must apply a write before it is acknowledged,
+    /// local node is counted (default is majority of nodes)
+    pub fn with_quorum(mut self, quorum: usize) -> Self {
+        self.quorum = Some(quorum);
+        self
+    }
 }
 
 impl<'a> Into<Config> for Options<'a> {
@@ -128,6 +314,17 @@ impl<'a> Into<Config> for Options<'a> {
             total_page_size: self.total_page_size.to_owned(),
             stype: self.stype.to_owned(),
             off_reporter: self.off_reporter.to_owned(),
+            durability: self.durability,
+            checkpoint: self.checkpoint,
+            recovery: self.recovery,
+            format: self.format,
+            compression: self.compression,
+            keys: self.keys,
+            load_threads: self.load_threads,
+            load_progress: self.load_progress,
+            lazy_load: self.lazy_load,
+            quorum: self.quorum,
+            node: self.node,
         }
     }
 }
@@ -140,6 +337,30 @@ pub struct Config {
     pub total_page_size: usize,
     pub stype: StorageType,
     pub off_reporter: bool,
+    pub durability: Durability,
+    pub checkpoint: Option<Checkpoint>,
+    pub recovery: RecoveryPolicy,
+    pub format: Format,
+    pub compression: Compression,
+
+    // keys can not serialized, so they must set again after deserialize
+    #[serde(skip)]
+    pub keys: Option<Arc<dyn KeyProvider>>,
+
+    // threads that decode pages on open, 0 is available parallelism
+    pub load_threads: usize,
+
+    #[serde(skip)]
+    pub load_progress: Option<Arc<dyn Fn(LoadProgress) + Send + Sync>>,
+
+    // Schema register storage before it is loaded
+    pub lazy_load: bool,
+
+    // nodes of ReplicatedCopies that must apply write, None is majority of them
+    pub quorum: Option<usize>,
+
+    // address of this node, set by Schema::with_node
+    pub node: Option<String>,
 }
 
 impl Config {
@@ -156,6 +377,17 @@ impl Config {
             total_page_size,
             stype,
             off_reporter,
+            durability: Durability::Enqueued,
+            checkpoint: None,
+            recovery: RecoveryPolicy::Strict,
+            format: Format::Bincode,
+            compression: Compression::None,
+            keys: None,
+            load_threads: 0,
+            load_progress: None,
+            lazy_load: false,
+            quorum: None,
+            node: None,
         }
     }
 
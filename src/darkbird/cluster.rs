@@ -0,0 +1,505 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+use crate::{document::{Document, RangeQuery, RangeValue}, RQuery};
+
+use super::{replica::{self, Message, Query}, SessionResult, StatusResult};
+
+
+
+/// datastore partitioned across nodes by consistent hashing of key,
+/// every node serve its shard by `Schema::with_node` and a datastore of same name.
+///
+/// ring and state of rebalancing are kept in memory of ClusterDatabase, so just one
+/// of it route a datastore, nodes refuse another one while it has open connections.
+/// if it stop while rebalancing, it is created again with nodes before added node
+/// and add_node is called again, documents that are moved already are skipped
+pub struct ClusterDatabase<K, Doc> {
+    cluster: Arc<Cluster>,
+    _marker: PhantomData<(K, Doc)>,
+}
+
+
+/// result of rebalancing after a node is added
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RebalanceReport {
+    pub node: String,
+
+    // documents moved to new owner
+    pub moved: usize,
+}
+
+
+struct Cluster {
+    datastore: String,
+
+    // id that nodes know router by
+    router: String,
+    rings: RwLock<Rings>,
+    conns: Mutex<HashMap<String, Conn>>,
+    rebalance: tokio::sync::Mutex<Option<JoinHandle<Result<RebalanceReport, String>>>>,
+}
+
+type Conn = Arc<tokio::sync::Mutex<Option<TcpStream>>>;
+
+
+struct Rings {
+    ring: Ring,
+
+    // ring before latest added node, kept until rebalancing is done
+    previous: Option<Ring>,
+    adding: Option<String>,
+}
+
+
+#[derive(Clone)]
+struct Ring {
+    points: BTreeMap<u64, String>,
+    nodes: Vec<String>,
+    vnodes: usize,
+}
+
+impl Ring {
+    fn new(nodes: &[String], vnodes: usize) -> Self {
+        let mut ring = Ring { points: BTreeMap::new(), nodes: Vec::new(), vnodes };
+        for node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    fn add(&mut self, node: &str) {
+        for i in 0..self.vnodes {
+            self.points.insert(hash(format!("{}#{}", node, i).as_bytes()), node.to_owned());
+        }
+        self.nodes.push(node.to_owned());
+    }
+
+    // first point clockwise from hash of key
+    fn owner(&self, key: &[u8]) -> Option<&str> {
+        let hash = hash(key);
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+}
+
+
+// fnv-1a mixed by finalizer of murmur3, it must be same on every process of cluster.
+// high bits of fnv-1a of short keys are close together, so without mixing keys may all fall on one node
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3));
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+
+
+impl<K, Doc> ClusterDatabase<K, Doc>
+where
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+    K: Serialize + DeserializeOwned + Hash + Eq + Clone + Send + Sync + 'static,
+{
+    /// route datastore to nodes, every node has vnodes points on ring (default is 64)
+    pub fn new(datastore: &str, nodes: Vec<String>) -> Self {
+        ClusterDatabase {
+            cluster: Arc::new(Cluster {
+                datastore: datastore.to_owned(),
+                router: uuid::Uuid::new_v4().to_string(),
+                rings: RwLock::new(Rings { ring: Ring::new(&nodes, DEFAULT_VNODES), previous: None, adding: None }),
+                conns: Mutex::new(HashMap::new()),
+                rebalance: tokio::sync::Mutex::new(None),
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// points of every node on ring, more points spread keys more evenly
+    pub fn with_vnodes(self, vnodes: usize) -> Self {
+        {
+            let mut rings = self.cluster.rings.write().unwrap();
+            rings.ring = Ring::new(&rings.ring.nodes, vnodes.max(1));
+        }
+        self
+    }
+
+    pub fn nodes(&self) -> Vec<String> {
+        self.cluster.rings.read().unwrap().ring.nodes.clone()
+    }
+
+    /// node that own key
+    pub fn owner(&self, key: &K) -> Result<String, SessionResult> {
+        let (owner, _) = self.cluster.owners(&encode(key)?)?;
+        Ok(owner)
+    }
+
+
+    /// insert to node that own key
+    pub async fn insert(&self, key: K, doc: Doc) -> Result<(), SessionResult> {
+        let (owner, previous) = self.cluster.owners(&encode(&key)?)?;
+        let remove = encode(&RQuery::<K, Doc>::Remove(key.clone()))?;
+
+        self.cluster.write(&owner, encode(&RQuery::Insert(key, doc))?, None).await?;
+
+        // moving copy on previous owner must not overwrite it
+        if let Some(previous) = previous {
+            self.cluster.write(&previous, remove, None).await?;
+        }
+
+        Ok(())
+    }
+
+    /// remove from node that own key
+    pub async fn remove(&self, key: K) -> Result<(), SessionResult> {
+        let (owner, previous) = self.cluster.owners(&encode(&key)?)?;
+        let query = encode(&RQuery::<K, Doc>::Remove(key))?;
+
+        // copy on previous owner is removed first, so copy that is moving meanwhile
+        // conflict with it and is undone, otherwise it is moved after owner is removed
+        if let Some(previous) = previous {
+            self.cluster.write(&previous, query.clone(), None).await?;
+        }
+        self.cluster.write(&owner, query, None).await?;
+
+        Ok(())
+    }
+
+    /// lookup from node that own key, while rebalancing key may be still on previous owner
+    pub async fn lookup(&self, key: &K) -> Result<Option<Doc>, SessionResult> {
+        let key = encode(key)?;
+        let (owner, previous) = self.cluster.owners(&key)?;
+
+        for node in std::iter::once(owner).chain(previous) {
+            let entries = self.cluster.query(&node, Query::Lookup(key.clone())).await?;
+            if let Some((_, doc)) = decode::<(K, Doc)>(entries)?.pop() {
+                return Ok(Some(doc))
+            }
+        }
+
+        Ok(None)
+    }
+
+
+    /// lookup by value of tag on every shard
+    pub async fn lookup_by_tag(&self, tag_name: &str, value: &str) -> Result<Vec<(K, Doc)>, SessionResult> {
+        self.scatter(Query::Tag(tag_name.to_owned(), value.to_owned())).await
+    }
+
+    /// range on every shard, merged by value of field, from is included and to is excluded
+    pub async fn range(&self, field_name: &str, from: impl Into<RangeValue>, to: impl Into<RangeValue>) -> Result<Vec<(K, Doc)>, SessionResult> {
+        let query = RangeQuery::new(field_name)
+            .with_from(Bound::Included(from))
+            .with_to(Bound::Excluded(to));
+
+        self.range_query(&query).await
+    }
+
+    /// range query on every shard, merged by value of field, every shard return
+    /// offset + limit documents and offset and limit are applied to merged result
+    pub async fn range_query(&self, query: &RangeQuery) -> Result<Vec<(K, Doc)>, SessionResult> {
+        let mut shard_query = query.clone().with_offset(0);
+        shard_query.limit = query.limit.map(|limit| query.offset.saturating_add(limit));
+
+        let mut entries = self.scatter(Query::Range(shard_query)).await?;
+
+        // least value of field in range, or greatest one for reverse order
+        let value = |doc: &Doc| {
+            let values = doc.get_fields()
+                .into_iter()
+                .filter(|f| f.name == query.field && (query.from.as_ref(), query.to.as_ref()).contains(&f.value))
+                .map(|f| f.value);
+
+            match query.reverse {
+                true => values.max(),
+                false => values.min(),
+            }
+        };
+        entries.sort_by_cached_key(|(_, doc)| value(doc));
+        if query.reverse {
+            entries.reverse();
+        }
+
+        Ok(entries.into_iter().skip(query.offset).take(query.limit.unwrap_or(usize::MAX)).collect())
+    }
+
+    /// full text search on every shard
+    pub async fn search(&self, text: String) -> Result<Vec<(K, Doc)>, SessionResult> {
+        self.scatter(Query::Search(text)).await
+    }
+
+
+    /// add node to ring, documents it own are moved to it in background,
+    /// meanwhile reads fall back to previous owner and writes remove previous copy
+    pub async fn add_node(&self, node: &str) -> Result<(), SessionResult> {
+        let mut rebalance = self.cluster.rebalance.lock().await;
+
+        {
+            let mut rings = self.cluster.rings.write().unwrap();
+            if rings.previous.is_some() {
+                return Err(SessionResult::Err(StatusResult::Err("cluster is rebalancing".to_owned())))
+            }
+
+            if rings.ring.nodes.iter().any(|n| n == node) {
+                return Err(SessionResult::Err(StatusResult::Err(format!("node {} is already in cluster", node))))
+            }
+
+            let previous = rings.ring.clone();
+            rings.ring.add(node);
+            rings.previous = Some(previous);
+            rings.adding = Some(node.to_owned());
+        }
+
+        *rebalance = Some(tokio::spawn(rebalance_to::<K, Doc>(self.cluster.clone(), node.to_owned())));
+        Ok(())
+    }
+
+    /// start moving documents again after rebalancing failed
+    pub async fn resume_rebalance(&self) -> Result<(), SessionResult> {
+        let mut rebalance = self.cluster.rebalance.lock().await;
+        if let Some(task) = rebalance.take() {
+            let _ = task.await;
+        }
+
+        let adding = self.cluster.rings.read().unwrap().adding.clone();
+        if let Some(node) = adding {
+            *rebalance = Some(tokio::spawn(rebalance_to::<K, Doc>(self.cluster.clone(), node)));
+        }
+        Ok(())
+    }
+
+    /// false while documents are moving to added node
+    pub fn is_rebalanced(&self) -> bool {
+        self.cluster.rings.read().unwrap().previous.is_none()
+    }
+
+    /// wait for rebalancing started by latest add_node
+    pub async fn rebalanced(&self) -> Result<Option<RebalanceReport>, SessionResult> {
+        let task = self.cluster.rebalance.lock().await.take();
+        match task {
+            None => Ok(None),
+            Some(task) => match task.await {
+                Ok(Ok(report)) => Ok(Some(report)),
+                Ok(Err(e)) => Err(SessionResult::Err(StatusResult::Err(e))),
+                Err(e) => Err(SessionResult::Err(StatusResult::Err(e.to_string())))
+            }
+        }
+    }
+
+
+    // query every node, document that is on two nodes while moving is taken from its owner
+    async fn scatter(&self, query: Query) -> Result<Vec<(K, Doc)>, SessionResult> {
+        let tasks = self.nodes()
+            .into_iter()
+            .map(|node| {
+                let (cluster, query) = (self.cluster.clone(), query.clone());
+                tokio::spawn(async move {
+                    let entries = cluster.query(&node, query).await?;
+                    Ok::<_, SessionResult>((node, entries))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut merged: HashMap<K, (bool, Doc)> = HashMap::new();
+        let mut order = Vec::new();
+
+        for task in tasks {
+            let (node, entries) = task.await.map_err(|e| SessionResult::Err(StatusResult::Err(e.to_string())))??;
+
+            for (key, doc) in decode::<(K, Doc)>(entries)? {
+                let owned = self.cluster.owners(&encode(&key)?)?.0 == node;
+                match merged.get(&key) {
+                    Some((true, _)) => {}
+                    Some((false, _)) if !owned => {}
+                    Some(_) => { merged.insert(key, (owned, doc)); }
+                    None => {
+                        order.push(key.clone());
+                        merged.insert(key, (owned, doc));
+                    }
+                }
+            }
+        }
+
+        Ok(order
+            .into_iter()
+            .filter_map(|key| merged.remove(&key).map(|(_, doc)| (key, doc)))
+            .collect())
+    }
+}
+
+
+
+impl Cluster {
+    // owner of key and previous owner of it if key is moving
+    fn owners(&self, key: &[u8]) -> Result<(String, Option<String>), SessionResult> {
+        let rings = self.rings.read().unwrap();
+        let owner = rings.ring.owner(key).ok_or(SessionResult::DataStoreNotFound)?.to_owned();
+        let previous = rings.previous
+            .as_ref()
+            .and_then(|ring| ring.owner(key))
+            .filter(|previous| *previous != owner)
+            .map(|previous| previous.to_owned());
+
+        Ok((owner, previous))
+    }
+
+    // new version of key if version is expected
+    async fn write(&self, node: &str, query: Vec<u8>, expected: Option<u64>) -> Result<Option<u64>, SessionResult> {
+        let message = Message::Write { datastore: self.datastore.clone(), query, expected };
+        match self.request(node, &message).await? {
+            Message::Ack => Ok(None),
+            Message::Written(version) => Ok(Some(version)),
+            Message::Conflict { expected, actual } => Err(SessionResult::Err(StatusResult::Conflict { expected, actual })),
+            Message::Error(e) => Err(SessionResult::Err(StatusResult::Err(e))),
+            _ => Err(SessionResult::NoResponse)
+        }
+    }
+
+    async fn query(&self, node: &str, query: Query) -> Result<Vec<Vec<u8>>, SessionResult> {
+        let message = Message::Query { datastore: self.datastore.clone(), query };
+        match self.request(node, &message).await? {
+            Message::Entries(entries) => Ok(entries),
+            Message::Error(e) => Err(SessionResult::Err(StatusResult::Err(e))),
+            _ => Err(SessionResult::NoResponse)
+        }
+    }
+
+    // connection that node accept as router of datastore
+    async fn connect(&self, node: &str) -> Result<TcpStream, String> {
+        let mut stream = replica::connect(node).await?;
+        let message = Message::Route { datastore: self.datastore.clone(), router: self.router.clone() };
+        replica::write_message(&mut stream, &message).await?;
+
+        match tokio::time::timeout(replica::TIMEOUT, replica::read_message(&mut stream)).await {
+            Ok(Ok(Message::Ack)) => Ok(stream),
+            Ok(Ok(Message::Error(e))) => Err(e),
+            Ok(Ok(_)) => Err(format!("node {} answered route by unexpected message", node)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(format!("node {} did not answer", node))
+        }
+    }
+
+    // one request at a time on connection of node, connection is opened again after error
+    async fn request(&self, node: &str, message: &Message) -> Result<Message, SessionResult> {
+        let conn = self.conns
+            .lock()
+            .unwrap()
+            .entry(node.to_owned())
+            .or_default()
+            .clone();
+
+        let mut conn = conn.lock().await;
+        if conn.is_none() {
+            *conn = Some(self.connect(node).await.map_err(|e| SessionResult::Err(StatusResult::Err(e)))?);
+        }
+        let stream = conn.as_mut().unwrap();
+
+        let res = match replica::write_message(stream, message).await {
+            Ok(()) => match tokio::time::timeout(replica::TIMEOUT, replica::read_message(stream)).await {
+                Ok(res) => res,
+                Err(_) => Err(format!("node {} did not answer", node))
+            },
+            Err(e) => Err(e)
+        };
+
+        res.map_err(|e| {
+            *conn = None;
+            SessionResult::Err(StatusResult::Err(e))
+        })
+    }
+}
+
+
+
+// move documents of old nodes that added node own now, every key is copied to owner just if owner
+// has not it and removed from source just if it is not written after it is read, otherwise copy is
+// undone and key is read again, so a write or remove on source while moving is not lost
+async fn rebalance_to<K, Doc>(cluster: Arc<Cluster>, node: String) -> Result<RebalanceReport, String>
+where
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+    K: Serialize + DeserializeOwned + Hash + Eq + Clone + Send + Sync + 'static,
+{
+    let mut report = RebalanceReport { node: node.clone(), moved: 0 };
+    let sources = cluster.rings.read().unwrap().ring.nodes.clone();
+
+    for source in sources.iter().filter(|source| **source != node) {
+        let entries = cluster.query(source, Query::Scan).await.map_err(|e| e.to_string())?;
+
+        for (key, doc, version) in decode::<(K, Doc, u64)>(entries).map_err(|e| e.to_string())? {
+            let encoded = encode(&key).map_err(|e| e.to_string())?;
+            let (owner, _) = cluster.owners(&encoded).map_err(|e| e.to_string())?;
+            if owner == *source {
+                continue
+            }
+
+            let remove = encode(&RQuery::<K, Doc>::Remove(key.clone())).map_err(|e| e.to_string())?;
+            let mut current = Some((doc, version));
+
+            while let Some((doc, version)) = current.take() {
+                let insert = encode(&RQuery::Insert(key.clone(), doc)).map_err(|e| e.to_string())?;
+
+                let copied = match cluster.write(&owner, insert, Some(0)).await {
+                    Ok(copied) => copied.unwrap_or_default(),
+
+                    // document written to owner meanwhile is newer
+                    Err(SessionResult::Err(StatusResult::Conflict { .. })) => {
+                        cluster.write(source, remove.clone(), None).await.map_err(|e| e.to_string())?;
+                        break
+                    }
+                    Err(e) => return Err(e.to_string())
+                };
+
+                match cluster.write(source, remove.clone(), Some(version)).await {
+                    Ok(_) => report.moved += 1,
+
+                    // written or removed on source after it is read
+                    Err(SessionResult::Err(StatusResult::Conflict { .. })) => {
+                        match cluster.write(&owner, remove.clone(), Some(copied)).await {
+                            Ok(_) | Err(SessionResult::Err(StatusResult::Conflict { .. })) => {}
+                            Err(e) => return Err(e.to_string())
+                        }
+
+                        let entries = cluster.query(source, Query::LookupVersion(encoded.clone())).await.map_err(|e| e.to_string())?;
+                        current = decode::<(K, Doc, u64)>(entries)
+                            .map_err(|e| e.to_string())?
+                            .pop()
+                            .map(|(_, doc, version)| (doc, version));
+                    }
+                    Err(e) => return Err(e.to_string())
+                }
+            }
+        }
+    }
+
+    let mut rings = cluster.rings.write().unwrap();
+    rings.previous = None;
+    rings.adding = None;
+    Ok(report)
+}
+
+
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, SessionResult> {
+    bincode::serialize(value).map_err(|e| SessionResult::Err(StatusResult::Err(e.to_string())))
+}
+
+fn decode<T: DeserializeOwned>(entries: Vec<Vec<u8>>) -> Result<Vec<T>, SessionResult> {
+    entries
+        .iter()
+        .map(|entry| bincode::deserialize(entry).map_err(|e| SessionResult::Err(StatusResult::Err(e.to_string()))))
+        .collect()
+}
+
+
+const DEFAULT_VNODES: usize = 64;
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use crate::{Options, Schema, StorageType, ClusterDatabase};
+use crate::darkbird::test_fixture::{Member, TempDir};
+
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cluster_route_keys_scatter_queries_and_rebalance_added_node() {
+    let path = TempDir::new();
+    let mut shards = Vec::new();
+    for _ in 0..3 {
+        let ops = Options::new(&path, "members", 1000, StorageType::RamCopies, true);
+        let db = Schema::new()
+            .with_node("127.0.0.1:0").await.unwrap()
+            .with_datastore::<u32, Member>(ops).await.unwrap()
+            .build();
+        shards.push(db);
+    }
+    let addr = |db: &crate::Database| db.node().unwrap().local_addr().to_string();
+
+    let cluster = ClusterDatabase::<u32, Member>::new("members", shards[..2].iter().map(addr).collect());
+    for i in 0..200 {
+        let team = if i % 2 == 0 { "red" } else { "blue" };
+        cluster.insert(i, Member { age: i as i32, bio: format!("member{} player", i), ..Member::new(&format!("{}@x", i), team) }).await.unwrap();
+    }
+
+    // keys are partitioned between shards
+    let sizes = shards.iter().map(|db| db.iter::<u32, Member>().unwrap().count()).collect::<Vec<_>>();
+    assert_eq!(sizes[0] + sizes[1], 200);
+    assert!(sizes[0] > 0 && sizes[1] > 0 && sizes[2] == 0);
+
+    assert_eq!(cluster.lookup(&7).await.unwrap().unwrap().age, 7);
+    cluster.remove(7).await.unwrap();
+    assert!(cluster.lookup(&7).await.unwrap().is_none());
+
+    assert_eq!(cluster.lookup_by_tag("team", "red").await.unwrap().len(), 100);
+    let ages = cluster.range("age", 10, 20).await.unwrap()
+        .into_iter()
+        .map(|(_, member)| member.age)
+        .collect::<Vec<_>>();
+    assert_eq!(ages, (10..20).collect::<Vec<_>>());
+    assert_eq!(cluster.search("member42".to_owned()).await.unwrap()[0].0, 42);
+
+    // added node take its keys in background
+    cluster.add_node(&addr(&shards[2])).await.unwrap();
+    let report = cluster.rebalanced().await.unwrap().unwrap();
+    assert!(report.moved > 0);
+    assert!(cluster.is_rebalanced());
+
+    assert_eq!(shards[2].iter::<u32, Member>().unwrap().count(), report.moved);
+    assert_eq!(shards.iter().map(|db| db.iter::<u32, Member>().unwrap().count()).sum::<usize>(), 199);
+    for i in (0..200).filter(|i| *i != 7) {
+        let owner = cluster.owner(&i).unwrap();
+        let db = shards.iter().find(|db| addr(db) == owner).unwrap();
+        assert_eq!(db.lookup::<u32, Member>(&i).unwrap().unwrap().age, i as i32);
+    }
+    assert_eq!(cluster.lookup_by_tag("team", "blue").await.unwrap().len(), 99);
+
+    // ring is kept by one router, another one is refused until it is dropped
+    let other = ClusterDatabase::<u32, Member>::new("members", shards.iter().map(addr).collect());
+    assert!(other.lookup(&1).await.is_err());
+    drop(cluster);
+    for _ in 0..100 {
+        if other.lookup(&1).await.is_ok() {
+            return
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("router is refused after previous one is dropped");
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn cluster_writes_while_rebalancing_are_not_undone() {
+    let path = TempDir::new();
+    let mut shards = Vec::new();
+    for _ in 0..3 {
+        let ops = Options::new(&path, "accounts", 1000, StorageType::RamCopies, true);
+        let db = Schema::new()
+            .with_node("127.0.0.1:0").await.unwrap()
+            .with_datastore::<u32, Member>(ops).await.unwrap()
+            .build();
+        shards.push(db);
+    }
+    let addr = |db: &crate::Database| db.node().unwrap().local_addr().to_string();
+
+    let cluster = Arc::new(ClusterDatabase::<u32, Member>::new("accounts", shards[..2].iter().map(addr).collect()));
+    for i in 0..600 {
+        cluster.insert(i, Member::account(&format!("{}@x", i), 0)).await.unwrap();
+    }
+
+    // even keys are removed and odd keys are written while they are moving
+    cluster.add_node(&addr(&shards[2])).await.unwrap();
+    let writer = {
+        let cluster = cluster.clone();
+        tokio::spawn(async move {
+            for i in 0..600 {
+                match i % 2 {
+                    0 => cluster.remove(i).await.unwrap(),
+                    _ => cluster.insert(i, Member::account(&format!("{}@x", i), 1)).await.unwrap(),
+                }
+            }
+        })
+    };
+    writer.await.unwrap();
+    cluster.rebalanced().await.unwrap().unwrap();
+
+    for i in 0..600 {
+        let copies = shards
+            .iter()
+            .filter_map(|db| db.lookup::<u32, Member>(&i).unwrap().map(|account| (addr(db), account.value().balance)))
+            .collect::<Vec<_>>();
+
+        match i % 2 {
+            0 => assert!(copies.is_empty(), "removed key {} is back", i),
+            _ => assert_eq!(copies, vec![(cluster.owner(&i).unwrap(), 1)]),
+        }
+    }
+}
@@ -1,24 +1,108 @@
 use anymap::AnyMap;
-use dashmap::{mapref::one::Ref, iter::Iter, DashSet};
+use dashmap::{mapref::one::Ref, iter::Iter, DashMap, DashSet};
 use tokio::sync::mpsc::Sender;
 use std::{hash::Hash, sync::Arc, time::Duration};
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::{Storage, document::Document, Event, VecStorage, Vector};
+use crate::{Storage, document::{Document, RangeQuery, RangeValue}, Event, VecStorage, Vector};
 
-use super::{SessionResult, storage_redis::RedisStorage, vector::VectorId};
+use super::{SessionResult, replica::Node, replication::{Follower, Leader}, transaction::{DatabaseTransaction, TransactionLog}, storage_redis::RedisStorage, vector::VectorId, wal::{backup::{self, Backup, SetManifest, Source}, compaction::CompactionStats, recovery::RecoveryReport}, StatusResult};
 
 
 
 pub struct Database {
-    datastores: AnyMap
+    datastores: AnyMap,
+
+    // DiskCopies datastores of schema
+    sources: Vec<Source>,
+
+    // serve ReplicatedCopies datastores to other nodes while database is open
+    node: Option<Node>,
+
+    // commit records of transactions over several DiskCopies datastores
+    transaction_log: Option<Arc<TransactionLog>>,
 }
 
 impl Database {
     
 
     pub fn open(datastores: AnyMap) -> Database {
-        Database { datastores }
+        Database { datastores, sources: Vec::new(), node: None, transaction_log: None }
+    }
+
+    pub(crate) fn with_sources(datastores: AnyMap, sources: Vec<Source>, node: Option<Node>, transaction_log: Option<Arc<TransactionLog>>) -> Database {
+        Database { datastores, sources, node, transaction_log }
+    }
+
+    /// node of schema that serve ReplicatedCopies datastores
+    pub fn node(&self) -> Option<&Node> {
+        self.node.as_ref()
+    }
+
+
+    pub(crate) fn storage<K, Doc>(&self) -> Result<&Storage<K, Doc>, SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K: Serialize + DeserializeOwned + PartialOrd + Ord + PartialEq + Eq + Hash + Clone + Send + Sync + 'static
+    {
+        self.datastores.get::<Storage<K, Doc>>().ok_or(SessionResult::DataStoreNotFound)
+    }
+
+
+    /// run f on a transaction over datastores of database and commit writes of all of them
+    /// together, writes are validated by every datastore before any of them is applied.
+    /// writes of several DiskCopies datastores need transaction log of schema, they are
+    /// committed by one record of it and datastores complete them on open after a crash
+    pub async fn transaction<F, T>(&self, f: F) -> Result<T, SessionResult>
+    where
+        F: FnOnce(&mut DatabaseTransaction<'_>) -> Result<T, SessionResult>,
+    {
+        let mut tx = DatabaseTransaction::new(self);
+        let res = f(&mut tx)?;
+
+        tx.commit(self.transaction_log.as_deref()).await?;
+        Ok(res)
+    }
+
+
+    /// backup every DiskCopies datastore of schema to dest as one backup set,
+    /// writers are blocked just while pinning position of datastores, so
+    /// backup set restore all of them to same point in time by `backup::restore_set`
+    pub async fn backup(&self, dest: &str) -> Result<SetManifest, SessionResult> {
+        backup::backup_set(&self.sources, dest)
+            .await
+            .map_err(|e| SessionResult::Err(StatusResult::Err(e)))
+    }
+
+
+    /// serve DiskCopies datastores of schema to replication followers on addr,
+    /// serving stop when returned leader is dropped
+    pub async fn serve_replication(&self, addr: &str) -> Result<Leader, SessionResult> {
+        Leader::bind(addr, self.sources.clone())
+            .await
+            .map_err(|e| SessionResult::Err(StatusResult::Err(e)))
+    }
+
+    /// follow datastore of leader, datastore is read-only until follower is promoted
+    pub fn follow<K, Doc>(&self, leader: &str) -> Result<Follower, SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K:  Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static
+    {
+        match self.datastores.get::<Storage<K, Doc>>() {
+            None => Err(SessionResult::DataStoreNotFound),
+            Some(datastore) => Ok(Follower::start(leader, datastore))
+        }
     }
 
 
@@ -149,7 +233,7 @@ impl Database {
     #[inline]        
     pub async fn subscribe<K, Doc>(&self, sender: Sender<Event<K, Doc>>) -> Result<(), SessionResult> 
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -173,7 +257,7 @@ impl Database {
     #[inline]        
     pub async fn insert<K, Doc>(&self, key: K, doc: Doc) -> Result<(), SessionResult>
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -197,7 +281,7 @@ impl Database {
     #[inline]        
     pub async fn remove<K, Doc>(&self, key: K) -> Result<(), SessionResult>
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -219,11 +303,117 @@ impl Database {
     }
 
 
+
+    /// backup just records of datastore logged after its latest backup
+    #[inline]        
+    pub async fn backup_incremental<K, Doc>(&self) -> Result<Backup, SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K:  Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static
+    {
+        match self.datastores.get::<Storage<K, Doc>>() {
+            None => Err(SessionResult::DataStoreNotFound),
+            Some(datastore) => {
+                datastore.backup_incremental().await
+            }
+        }
+    }
+
+
+
+    /// take snapshot of datastore and release pages older than it
+    #[inline]        
+    pub async fn checkpoint<K, Doc>(&self) -> Result<(), SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K:  Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static
+    {
+        match self.datastores.get::<Storage<K, Doc>>() {
+            None => Err(SessionResult::DataStoreNotFound),
+            Some(datastore) => {
+                datastore.checkpoint().await
+            }
+        }
+    }
+
     
+    /// records dropped from corrupt pages of datastore on open
+    #[inline]        
+    pub fn recovery_report<K, Doc>(&self) -> Result<RecoveryReport, SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K:  Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static
+    {
+        match self.datastores.get::<Storage<K, Doc>>() {
+            None => Err(SessionResult::DataStoreNotFound),
+            Some(datastore) => {
+                Ok(datastore.recovery_report().clone())
+            }
+        }
+    }
+
+
+    /// compact sealed pages of datastore while it is open
+    #[inline]        
+    pub async fn compact<K, Doc>(&self) -> Result<CompactionStats, SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K:  Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static
+    {
+        match self.datastores.get::<Storage<K, Doc>>() {
+            None => Err(SessionResult::DataStoreNotFound),
+            Some(datastore) => {
+                datastore.compact().await
+            }
+        }
+    }
+
+    
+
     #[inline]        
     pub fn gets<'a, K, Doc>(&self, list: Vec<&K>) -> Result<Vec<Ref<K, Doc>>, SessionResult>
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -247,9 +437,9 @@ impl Database {
 
 
     #[inline]        
-    pub fn range<K, Doc>(&self, field_name: &str, from: String, to: String) -> Result<Vec<Ref<K, Doc>>, SessionResult>
+    pub fn range<K, Doc>(&self, field_name: &str, from: impl Into<RangeValue>, to: impl Into<RangeValue>) -> Result<Vec<Ref<'_, K, Doc>>, SessionResult>
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -273,10 +463,37 @@ impl Database {
 
 
 
+    #[inline]        
+    pub fn range_query<K, Doc>(&self, query: &RangeQuery) -> Result<Vec<Ref<'_, K, Doc>>, SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K:  Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static
+    {
+        match self.datastores.get::<Storage<K, Doc>>() {
+            None => Err(SessionResult::DataStoreNotFound),
+            Some(datastore) => {
+                let res = datastore.range_query(query);
+                Ok(res)
+            }
+        }
+    }
+
+
+
     #[inline]        
     pub fn lookup<K, Doc>(&self, key: &K) -> Result<Option<Ref<K, Doc>>, SessionResult> 
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -299,10 +516,82 @@ impl Database {
     }
 
 
+    /// lookup by key, `SessionResult::Loading` if key is not loaded yet
+    #[inline]        
+    pub fn try_lookup<K, Doc>(&self, key: &K) -> Result<Option<Ref<'_, K, Doc>>, SessionResult> 
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K:  Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static
+    {
+        match self.datastores.get::<Storage<K, Doc>>() {
+            None => Err(SessionResult::DataStoreNotFound),
+            Some(datastore) => datastore.try_lookup(key)
+        }
+    }
+
+
+    /// lookup by key, wait while key is not loaded yet
+    #[inline]        
+    pub async fn lookup_wait<K, Doc>(&self, key: &K) -> Result<Option<Ref<'_, K, Doc>>, SessionResult> 
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K:  Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static
+    {
+        match self.datastores.get::<Storage<K, Doc>>() {
+            None => Err(SessionResult::DataStoreNotFound),
+            Some(datastore) => datastore.lookup_wait(key).await
+        }
+    }
+
+
+    /// wait until datastore is loaded
     #[inline]        
-    pub fn lookup_by_index<K, Doc>(&self, index_key: &str) -> Result<Option<Ref<K, Doc>>, SessionResult>
+    pub async fn ready<K, Doc>(&self) -> Result<(), SessionResult> 
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K:  Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static
+    {
+        match self.datastores.get::<Storage<K, Doc>>() {
+            None => Err(SessionResult::DataStoreNotFound),
+            Some(datastore) => datastore.ready().await
+        }
+    }
+
+
+    #[inline]        
+    pub fn lookup_by_index<K, Doc>(&self, index_name: &str, value: &str) -> Result<Option<Ref<'_, K, Doc>>, SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -318,7 +607,7 @@ impl Database {
         match self.datastores.get::<Storage<K, Doc>>() {
             None => Err(SessionResult::DataStoreNotFound),
             Some(datastore) => {
-                let res = datastore.lookup_by_index(index_key);
+                let res = datastore.lookup_by_index(index_name, value);
                 Ok(res)
             }
         }
@@ -327,9 +616,33 @@ impl Database {
 
 
     #[inline]        
-    pub fn lookup_by_tag<K, Doc>(&self, tag: &str) -> Result<Vec<Ref<K, Doc>>, SessionResult>
+    pub fn lookup_all_by_index<K, Doc>(&self, index_name: &str, value: &str) -> Result<Vec<Ref<'_, K, Doc>>, SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K:  Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static
+    {
+        match self.datastores.get::<Storage<K, Doc>>() {
+            None => Err(SessionResult::DataStoreNotFound),
+            Some(datastore) => Ok(datastore.lookup_all_by_index(index_name, value))
+        }
+    }
+
+
+
+    #[inline]        
+    pub fn lookup_by_tag<K, Doc>(&self, tag_name: &str, value: &str) -> Result<Vec<Ref<'_, K, Doc>>, SessionResult>
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -345,7 +658,7 @@ impl Database {
         match self.datastores.get::<Storage<K, Doc>>() {
             None => Err(SessionResult::DataStoreNotFound),
             Some(datastore) => {
-                let res = datastore.lookup_by_tag(tag);
+                let res = datastore.lookup_by_tag(tag_name, value);
                 Ok(res)
             }
         }
@@ -356,7 +669,7 @@ impl Database {
     #[inline]        
     pub fn fetch_view<K, Doc>(&self, view_name: &str) -> Result<Vec<Ref<K, Doc>>, SessionResult>
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -383,7 +696,7 @@ impl Database {
     #[inline]        
     pub fn search<K, Doc>(&self, text: String) -> Result<Vec<Ref<K, Doc>>, SessionResult>
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -410,7 +723,7 @@ impl Database {
     #[inline]        
     pub fn iter<K, Doc>(&self) -> Result<Iter<'_, K, Doc>, SessionResult>
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -434,9 +747,9 @@ impl Database {
 
 
     #[inline]        
-    pub fn iter_index<K, Doc>(&self) -> Result<Iter<String, K>, SessionResult>
+    pub fn iter_index<K, Doc>(&self) -> Result<Iter<'_, String, DashMap<String, K>>, SessionResult>
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
@@ -460,9 +773,9 @@ impl Database {
 
 
     #[inline]        
-    pub fn iter_tags<K, Doc>(&self) -> Result<Iter<String, DashSet<K>>, SessionResult>
+    pub fn iter_tags<K, Doc>(&self) -> Result<Iter<'_, String, DashMap<String, DashSet<K>>>, SessionResult>
     where
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K:  Serialize
             + DeserializeOwned
             + PartialOrd
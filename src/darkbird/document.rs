@@ -1,16 +1,27 @@
+use std::{cmp::Ordering, ops::Bound};
+
+use chrono::{DateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+
 
 pub trait Document: Indexer + Tags + Range + MaterializedView + FullText {}
 
 
-// used for exracting fields for hash index
+// used for exracting fields for hash index, as (index_name, value)
 pub trait Indexer {
-    fn extract(&self) -> Vec<String>;
+    // unique index keys, every one of them belong to just one document
+    fn extract(&self) -> Vec<(String, String)>;
+
+    // non-unique index keys, many documents can have same one (e.g. country)
+    fn extract_multi(&self) -> Vec<(String, String)> {
+        vec![]
+    }
 }
 
 
-// use for indexing group of documents by tag
+// use for indexing group of documents by tag, as (tag_name, value)
 pub trait Tags {
-    fn get_tags(&self) -> Vec<String>;
+    fn get_tags(&self) -> Vec<(String, String)>;
 }
 
 
@@ -34,5 +45,212 @@ pub trait FullText {
 
 pub struct RangeField {
     pub name: String,
-    pub value: String
+    pub value: RangeValue
+}
+
+impl RangeField {
+    pub fn new(name: &str, value: impl Into<RangeValue>) -> Self {
+        RangeField { name: name.to_owned(), value: value.into() }
+    }
+}
+
+
+/// typed value of range field, numbers are ordered numerically whatever type they have,
+/// other values are ordered by their type, values of different kinds are ordered
+/// as numbers, timestamps, strings and bytes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RangeValue {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+
+    // milliseconds since unix epoch
+    Timestamp(i64),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl RangeValue {
+    fn kind(&self) -> u8 {
+        match self {
+            RangeValue::I64(_) | RangeValue::U64(_) | RangeValue::F64(_) => 0,
+            RangeValue::Timestamp(_) => 1,
+            RangeValue::String(_) => 2,
+            RangeValue::Bytes(_) => 3,
+        }
+    }
+}
+
+impl Ord for RangeValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (RangeValue::F64(a), RangeValue::F64(b)) => cmp_float(*a, *b),
+            (RangeValue::F64(a), b) if b.kind() == 0 => cmp_int_float(int(b), *a).reverse(),
+            (a, RangeValue::F64(b)) if a.kind() == 0 => cmp_int_float(int(a), *b),
+            (a, b) if a.kind() == 0 && b.kind() == 0 => int(a).cmp(&int(b)),
+            (RangeValue::Timestamp(a), RangeValue::Timestamp(b)) => a.cmp(b),
+            (RangeValue::String(a), RangeValue::String(b)) => a.cmp(b),
+            (RangeValue::Bytes(a), RangeValue::Bytes(b)) => a.cmp(b),
+            (a, b) => a.kind().cmp(&b.kind()),
+        }
+    }
+}
+
+impl PartialOrd for RangeValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RangeValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RangeValue {}
+
+
+// integer of I64 or U64
+fn int(value: &RangeValue) -> i128 {
+    match value {
+        RangeValue::I64(v) => *v as i128,
+        RangeValue::U64(v) => *v as i128,
+        _ => unreachable!(),
+    }
+}
+
+// NaN is greater than every number
+fn cmp_float(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.partial_cmp(&b).unwrap(),
+    }
+}
+
+// exact, integer is not rounded to float
+fn cmp_int_float(a: i128, b: f64) -> Ordering {
+    if b.is_nan() {
+        return Ordering::Less
+    }
+
+    // cast of integral part saturate, so infinity is greater than every integer
+    let integral = b.trunc();
+    match a.cmp(&(integral as i128)) {
+        Ordering::Equal => cmp_float(integral, b),
+        ord => ord,
+    }
+}
+
+impl From<i64> for RangeValue {
+    fn from(value: i64) -> Self { RangeValue::I64(value) }
+}
+
+impl From<i32> for RangeValue {
+    fn from(value: i32) -> Self { RangeValue::I64(value as i64) }
+}
+
+impl From<u64> for RangeValue {
+    fn from(value: u64) -> Self { RangeValue::U64(value) }
+}
+
+impl From<u32> for RangeValue {
+    fn from(value: u32) -> Self { RangeValue::U64(value as u64) }
+}
+
+impl From<usize> for RangeValue {
+    fn from(value: usize) -> Self { RangeValue::U64(value as u64) }
+}
+
+impl From<f64> for RangeValue {
+    fn from(value: f64) -> Self { RangeValue::F64(value) }
+}
+
+impl From<f32> for RangeValue {
+    fn from(value: f32) -> Self { RangeValue::F64(value as f64) }
+}
+
+impl<Tz: TimeZone> From<DateTime<Tz>> for RangeValue {
+    fn from(value: DateTime<Tz>) -> Self { RangeValue::Timestamp(value.timestamp_millis()) }
+}
+
+impl From<String> for RangeValue {
+    fn from(value: String) -> Self { RangeValue::String(value) }
+}
+
+impl From<&str> for RangeValue {
+    fn from(value: &str) -> Self { RangeValue::String(value.to_owned()) }
+}
+
+impl From<Vec<u8>> for RangeValue {
+    fn from(value: Vec<u8>) -> Self { RangeValue::Bytes(value) }
+}
+
+
+/// range query on a field, ends are unbounded by default. documents are returned
+/// once in order of value, or reverse order, after offset and at most limit of them
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RangeQuery {
+    pub field: String,
+    pub from: Bound<RangeValue>,
+    pub to: Bound<RangeValue>,
+    pub reverse: bool,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl RangeQuery {
+    pub fn new(field: &str) -> Self {
+        RangeQuery {
+            field: field.to_owned(),
+            from: Bound::Unbounded,
+            to: Bound::Unbounded,
+            reverse: false,
+            limit: None,
+            offset: 0,
+        }
+    }
+
+    /// lower end, e.g. `Bound::Included(18)`
+    pub fn with_from(mut self, from: Bound<impl Into<RangeValue>>) -> Self {
+        self.from = from.map(Into::into);
+        self
+    }
+
+    /// upper end, e.g. `Bound::Excluded(65)`
+    pub fn with_to(mut self, to: Bound<impl Into<RangeValue>>) -> Self {
+        self.to = to.map(Into::into);
+        self
+    }
+
+    pub fn with_reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// false if no value can be in range
+    pub fn is_valid(&self) -> bool {
+        let (from, to) = match (&self.from, &self.to) {
+            (Bound::Included(from) | Bound::Excluded(from), Bound::Included(to) | Bound::Excluded(to)) => (from, to),
+            _ => return true
+        };
+
+        match from.cmp(to) {
+            Ordering::Less => true,
+            Ordering::Equal => matches!((&self.from, &self.to), (Bound::Included(_), Bound::Included(_))),
+            Ordering::Greater => false,
+        }
+    }
 }
\ No newline at end of file
@@ -1,12 +1,19 @@
-use dashmap::{iter::Iter, mapref::one::Ref, DashMap};
+use dashmap::{iter::Iter, mapref::entry::Entry, DashMap, DashSet};
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{document::Document, darkbird::StatusResult};
 use std::hash::Hash;
 
 
+// every index has its own map by index name, so same value of two indexes don't collide
 pub struct HashIndex<K> {
-    hash: DashMap<String, K>,
+    hash: DashMap<String, DashMap<String, K>>,
+
+    // non-unique indexes
+    multi: DashMap<String, DashMap<String, DashSet<K>>>,
+
+    // unique index keys of documents that are logging, by (index name, value)
+    claims: DashMap<(String, String), K>,
 }
 
 impl<K> HashIndex<K>
@@ -25,6 +32,8 @@ where
     pub fn new() -> Self {
         HashIndex {
             hash: DashMap::new(),
+            multi: DashMap::new(),
+            claims: DashMap::new(),
         }
     }
 
@@ -35,39 +44,154 @@ where
         Doc: Document,
     {
         let index_keys = doc.extract();
-        for ik in index_keys.iter() {
-            if let Some(_) = self.hash.get(ik) {
+        for (name, value) in index_keys.iter() {
+            if self.lookup(name, value).is_some() {
                 return Err(StatusResult::Duplicate)
             }
         }
 
-        doc.extract().into_iter().for_each(|index_key| {
-            self.hash.insert(index_key, key.clone());
-        });
+        index_keys.into_iter().for_each(|(name, value)| self.insert_unique(key, name, value));
+
+        doc.extract_multi().into_iter().for_each(|(name, value)| self.insert_multi(key, name, value));
 
         Ok(())
     }
 
-    /// remove entry
+    /// move entry from index keys of old document to index keys of new document,
+    /// nothing is changed if an index key of new document belong to other key
+    /// and it is not claimed by key
+    #[inline]
+    pub fn update<Doc>(&self, key: &K, old: Option<&Doc>, new: &Doc) -> Result<(), StatusResult>
+    where
+        Doc: Document,
+    {
+        let index_keys = new.extract();
+        for index_key in index_keys.iter() {
+            let claimed = self.claims.get(index_key).is_some_and(|owner| owner.value() == key);
+            if !claimed && self.lookup(&index_key.0, &index_key.1).is_some_and(|owner| &owner != key) {
+                return Err(StatusResult::Duplicate)
+            }
+        }
+
+        if let Some(old) = old {
+            old.extract().iter().filter(|ik| !index_keys.contains(ik)).for_each(|(name, value)| {
+                if let Some(index) = self.hash.get(name) {
+                    index.remove_if(value, |_, owner| owner == key);
+                }
+            });
+        }
+
+        index_keys.into_iter().for_each(|(name, value)| self.insert_unique(key, name, value));
+
+        let index_keys = new.extract_multi();
+        if let Some(old) = old {
+            old.extract_multi().iter().filter(|ik| !index_keys.contains(ik)).for_each(|(name, value)| self.remove_multi(key, name, value));
+        }
+        index_keys.into_iter().for_each(|(name, value)| self.insert_multi(key, name, value));
+
+        Ok(())
+    }
+
+    /// claim unique index keys of document for key until they are released, so no other key
+    /// can claim or insert them meanwhile. nothing is claimed if an index key belong to other key
+    pub fn claim<Doc>(&self, key: &K, doc: &Doc) -> Result<Vec<(String, String)>, StatusResult>
+    where
+        Doc: Document,
+    {
+        self.claim_from(key, doc, |_| false)
+    }
+
+    /// claim, index keys of a key that released(key) is true can be claimed too,
+    /// (e.g. key that same transaction write) they are moved to key when it is written
+    pub fn claim_from<Doc, F>(&self, key: &K, doc: &Doc, released: F) -> Result<Vec<(String, String)>, StatusResult>
+    where
+        Doc: Document,
+        F: Fn(&K) -> bool,
+    {
+        let mut claimed = Vec::new();
+
+        for index_key in doc.extract() {
+            let taken = match self.claims.entry(index_key.clone()) {
+                Entry::Occupied(owner) => owner.get() != key,
+                Entry::Vacant(entry) => {
+                    entry.insert(key.clone());
+                    claimed.push(index_key.clone());
+                    false
+                }
+            };
+
+            // owner is checked after claim, so a key that is inserted before claim is seen
+            if taken || self.lookup(&index_key.0, &index_key.1).is_some_and(|owner| &owner != key && !released(&owner)) {
+                self.release(&claimed);
+                return Err(StatusResult::Duplicate)
+            }
+        }
+
+        Ok(claimed)
+    }
+
+    /// release index keys returned by claim
+    pub fn release(&self, claimed: &[(String, String)]) {
+        claimed.iter().for_each(|index_key| { self.claims.remove(index_key); });
+    }
+
+    /// remove entry, index key that is moved to other key is kept
     #[inline]
-    pub fn remove<Doc>(&self, doc: &Doc)
+    pub fn remove<Doc>(&self, key: &K, doc: &Doc)
     where
         Doc: Document,
     {
-        doc.extract().iter().for_each(|index_key| {
-            self.hash.remove(index_key);
+        doc.extract().iter().for_each(|(name, value)| {
+            if let Some(index) = self.hash.get(name) {
+                index.remove_if(value, |_, owner| owner == key);
+            }
         });
+
+        doc.extract_multi().iter().for_each(|(name, value)| self.remove_multi(key, name, value));
     }
 
-    /// lookup by index_key
+    fn insert_unique(&self, key: &K, name: String, value: String) {
+        self.hash.entry(name).or_default().insert(value, key.clone());
+    }
+
+    fn insert_multi(&self, key: &K, name: String, value: String) {
+        self.multi.entry(name).or_default().entry(value).or_default().insert(key.clone());
+    }
+
+    // value without any document is dropped
+    fn remove_multi(&self, key: &K, name: &str, value: &str) {
+        if let Some(index) = self.multi.get(name) {
+            index.remove_if(value, |_, set| {
+                set.remove(key);
+                set.is_empty()
+            });
+        }
+    }
+
+    /// lookup by value of index
     #[inline]
-    pub fn lookup(&self, index_key: &str) -> Option<Ref<String, K>>{
-        self.hash.get(index_key)
+    pub fn lookup(&self, name: &str, value: &str) -> Option<K> {
+        self.hash.get(name)?.get(value).map(|owner| owner.value().clone())
+    }
+
+    /// keys of every document with value of index, unique or non-unique
+    #[inline]
+    pub fn lookup_all(&self, name: &str, value: &str) -> Vec<K> {
+        let mut keys = self.lookup(name, value).into_iter().collect::<Vec<K>>();
+
+        if let Some(index) = self.multi.get(name) {
+            if let Some(set) = index.get(value) {
+                keys.extend(set.iter().map(|k| k.key().clone()).filter(|k| !keys.contains(k)).collect::<Vec<K>>());
+            }
+        }
+
+        keys.sort();
+        keys
     }
 
-    /// get iter
+    /// get iter of unique indexes by name
     #[inline]
-    pub fn iter(&self) -> Iter<String, K>{
+    pub fn iter(&self) -> Iter<'_, String, DashMap<String, K>> {
         self.hash.iter()
     }
 }
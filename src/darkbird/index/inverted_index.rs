@@ -29,38 +29,45 @@ where
     }
 
 
+    /// insert without spawning a task, used when loading storage
     #[inline]
-    pub fn insert(&self, key: K, content: String) -> JoinHandle<()> {
+    pub fn bulk_insert(&self, key: &K, content: &str) {
+        insert_words(&self.index, key, content)
+    }
+    #[inline]
+    pub fn remove(&self, key: K, content: String) -> JoinHandle<()> {
         let index = self.index.clone();
         spawn(async move {
             for word in content.split_whitespace() {
                 let word = word.to_lowercase() ;
-                let key = &key;
-                match index.get_mut(&word) {
-                    Some(list) => {
-                        if let None = list.value().get(&key) {
-                            list.value().insert(key.to_owned());
-                        }
-                    }
-                    None => {
-                        let list = DashSet::new();
-                        list.insert(key.to_owned());
-                        index.insert(word, list);
-                    }
+                if let Some(list) = index.get_mut(&word) {
+                    list.value().remove(&key);
                 }
             }
         })
     }
+   
+   
+    /// move key from words of old content to words of new content in one task,
+    /// so a word of both of them is never missing
     #[inline]
-    pub fn remove(&self, key: K, content: String) -> JoinHandle<()> {
+    pub fn update(&self, key: K, old: Option<String>, new: Option<String>) -> JoinHandle<()> {
         let index = self.index.clone();
         spawn(async move {
-            for word in content.split_whitespace() {
-                let word = word.to_lowercase() ;
+            let new = new.unwrap_or_default();
+            let words = new.split_whitespace().map(|word| word.to_lowercase()).collect::<HashSet<String>>();
+
+            for word in old.iter().flat_map(|old| old.split_whitespace()) {
+                let word = word.to_lowercase();
+                if words.contains(&word) {
+                    continue
+                }
                 if let Some(list) = index.get_mut(&word) {
                     list.value().remove(&key);
                 }
             }
+
+            insert_words(&index, &key, &new)
         })
     }
    
@@ -107,3 +114,22 @@ where
 
 }
 
+
+
+fn insert_words<K: Hash + Eq + Clone>(index: &DashMap<String, DashSet<K>>, key: &K, content: &str) {
+    for word in content.split_whitespace() {
+        let word = word.to_lowercase() ;
+        match index.get_mut(&word) {
+            Some(list) => {
+                if list.value().get(key).is_none() {
+                    list.value().insert(key.to_owned());
+                }
+            }
+            None => {
+                let list = DashSet::new();
+                list.insert(key.to_owned());
+                index.insert(word, list);
+            }
+        }
+    }
+}
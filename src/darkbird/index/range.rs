@@ -1,12 +1,12 @@
-use std::{collections::{BTreeMap, BTreeSet}, ops::Bound};
+use std::collections::{BTreeMap, HashSet};
 
-use crate::document::Document;
+use crate::document::{Document, RangeQuery, RangeValue};
 use dashmap::{DashMap, DashSet};
 use serde::{de::DeserializeOwned, Serialize};
 use std::hash::Hash;
 
 pub struct RangeIndex<K> {
-    multi_btree: DashMap<String, BTreeMap<String, DashSet<K>>>,
+    multi_btree: DashMap<String, BTreeMap<RangeValue, DashSet<K>>>,
 }
 
 impl<K> RangeIndex<K>
@@ -66,13 +66,40 @@ where
     where
         Doc: Document,
     {
-        doc.get_fields().into_iter().for_each(|rf| {
-            if let Some(mut tree) = self.multi_btree.get_mut(&rf.name) {
-                if let Some(set) = tree.value_mut().get_mut(&rf.value) {
-                    set.insert(key.clone());
+        doc.get_fields().into_iter().for_each(|rf| self.remove_value(key, &rf.name, &rf.value));
+    }
+
+    // value without key is dropped from tree
+    fn remove_value(&self, key: &K, field_name: &str, value: &RangeValue) {
+        if let Some(mut tree) = self.multi_btree.get_mut(field_name) {
+            let empty = match tree.value_mut().get_mut(value) {
+                Some(set) => {
+                    set.remove(key);
+                    set.is_empty()
                 }
+                None => false
+            };
+
+            if empty {
+                tree.value_mut().remove(value);
             }
-        });
+        }
+    }
+
+    /// move entry from fields of old document to fields of new document
+    #[inline]
+    pub fn update<Doc>(&self, key: &K, old: Option<&Doc>, new: &Doc)
+    where
+        Doc: Document,
+    {
+        if let Some(old) = old {
+            let fields = new.get_fields().into_iter().map(|rf| (rf.name, rf.value)).collect::<Vec<_>>();
+            old.get_fields().into_iter().filter(|rf| !fields.contains(&(rf.name.clone(), rf.value.clone()))).for_each(|rf| {
+                self.remove_value(key, &rf.name, &rf.value)
+            });
+        }
+
+        self.insert(key, new)
     }
 
     /// remove tree from multi-tree
@@ -82,28 +109,47 @@ where
     }
 
 
-    /// fetch document by range hash_index
+    /// keys of documents with a value of field in range of query, in order of value,
+    /// keys of same value are in order of key, every key is returned once
     #[inline]
-    pub fn range(&self, field_name: &str, from: String, to: String) -> Vec<K> {
-        let set = match self.multi_btree.get(field_name) {
-            Some(tree) => {
-                let mut set_result = BTreeSet::new();
-
-                // collect and distinct keys
-                for (_, set) in tree.range((Bound::Included(from), Bound::Excluded(to))) {
-                    for k in set.iter() {
-                        set_result.insert(k.key().clone());
-                    }
-                }
+    pub fn range(&self, query: &RangeQuery) -> Vec<K> {
+        let tree = match self.multi_btree.get(&query.field) {
+            Some(tree) if query.is_valid() => tree,
+            _ => return vec![]
+        };
 
-                set_result
-            }
-            None => BTreeSet::new()
+        let sets = tree.range((query.from.clone(), query.to.clone())).map(|(_, set)| set);
+        let sets: Box<dyn Iterator<Item = &DashSet<K>>> = match query.reverse {
+            true => Box::new(sets.rev()),
+            false => Box::new(sets),
         };
 
-        set
-        .into_iter()
-        .collect::<Vec<K>>()
+        let wanted = query.limit.map(|limit| query.offset.saturating_add(limit)).unwrap_or(usize::MAX);
+        let mut seen = HashSet::new();
+        let mut result = Vec::new();
+
+        for set in sets {
+            if result.len() == wanted {
+                break
+            }
+
+            let mut keys = set.iter().map(|k| k.key().clone()).collect::<Vec<K>>();
+            keys.sort();
+            if query.reverse {
+                keys.reverse();
+            }
+
+            for key in keys {
+                if result.len() == wanted {
+                    break
+                }
+                if seen.insert(key.clone()) {
+                    result.push(key);
+                }
+            }
+        }
+
+        result.into_iter().skip(query.offset).collect()
     }
 
     
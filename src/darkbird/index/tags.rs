@@ -1,11 +1,14 @@
-use dashmap::{iter::Iter, mapref::one::Ref, DashMap, DashSet};
+use dashmap::{iter::Iter, DashMap, DashSet};
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::document::Document;
 use std::hash::Hash;
 
 pub struct TagIndex<K> {
-    pub tags: DashMap<String, DashSet<K>>,
+    // every tag has its own map of values by tag name
+    pub tags: DashMap<String, DashMap<String, DashSet<K>>>,
+
+    pub views: DashMap<String, DashSet<K>>,
 }
 
 impl<K> TagIndex<K>
@@ -24,6 +27,7 @@ where
     pub fn new() -> Self {
         TagIndex {
             tags: DashMap::new(),
+            views: DashMap::new(),
         }
     }
 
@@ -35,99 +39,110 @@ where
     {
         doc.get_tags()
             .into_iter()
-            .for_each(|index_key| match self.tags.get_mut(&index_key) {
-                Some(set) => {
-                    set.value().insert(key.clone());
-                }
-                None => {
-                    let set = DashSet::new();
-                    set.insert(key.clone());
-                    self.tags.insert(index_key, set);
-                }
+            .for_each(|(name, value)| {
+                self.tags.entry(name).or_default().entry(value).or_default().insert(key.clone());
             });
     }
 
     /// insert entry with tags
     #[inline]
     pub fn insert_view(&self, view_name: &str, key: &K) {
-        let view_key = self.view_key_maker(view_name);
-
-        match self.tags.get_mut(&view_key) {
+        match self.views.get_mut(view_name) {
             Some(set) => {
                 set.value().insert(key.clone());
             }
             None => {
                 let set = DashSet::new();
                 set.insert(key.clone());
-                self.tags.insert(view_key, set);
+                self.views.insert(view_name.to_owned(), set);
             }
         }
     }
 
-    /// remove entry from tags
+    /// move entry from tags of old document to tags of new document
     #[inline]
-    pub fn remove<Doc>(&self, key: &K, doc: &Doc)
+    pub fn update<Doc>(&self, key: &K, old: Option<&Doc>, new: &Doc)
     where
         Doc: Document,
     {
-        doc.get_tags().into_iter().for_each(|index_key| {
-            if let Some(set) = self.tags.get_mut(&index_key) {
-                set.value().remove(&key);
-            }
-        });
+        if let Some(old) = old {
+            let tags = new.get_tags();
+            old.get_tags().into_iter().filter(|tag| !tags.contains(tag)).for_each(|(name, value)| {
+                self.remove_from_tag(&name, &value, key)
+            });
+        }
+
+        self.insert(key, new)
     }
 
-    /// remove entry from view
+    /// move entry from view of old document to view of new document
     #[inline]
-    pub fn remove_from_view(&self, view_name: &str, key: &K) {
-        let view_key = &self.view_key_maker(view_name);
-        if let Some(set) = self.tags.get_mut(view_key) {
-            set.value().remove(&key);
+    pub fn update_view(&self, key: &K, old: Option<String>, new: Option<String>) {
+        if let Some(old) = old.filter(|old| new.as_ref() != Some(old)) {
+            self.remove_from_view(&old, key)
         }
-    }
 
+        if let Some(new) = new {
+            self.insert_view(&new, key)
+        }
+    }
 
-    /// remove tag
+    /// remove entry from tags
     #[inline]
-    pub fn remove_tag(&self, tag: &str) {
-        self.tags.remove(tag);
+    pub fn remove<Doc>(&self, key: &K, doc: &Doc)
+    where
+        Doc: Document,
+    {
+        doc.get_tags().into_iter().for_each(|(name, value)| {
+            self.remove_from_tag(&name, &value, key)
+        });
     }
 
-
-    /// remove view
     #[inline]
-    pub fn remove_view(&self, view_name: &str) {
-        let view_key = self.view_key_maker(view_name);
-        self.tags.remove(&view_key);
+    fn remove_from_tag(&self, name: &str, value: &str, key: &K) {
+        if let Some(tag) = self.tags.get(name) {
+            if let Some(set) = tag.get(value) {
+                set.value().remove(key);
+            }
+        }
     }
 
+    /// remove entry from view
+    #[inline]
+    pub fn remove_from_view(&self, view_name: &str, key: &K) {
+        if let Some(set) = self.views.get_mut(view_name) {
+            set.value().remove(key);
+        }
+    }
 
 
-    /// lookup by tag
+    /// lookup by value of tag, keys are cloned because set is owned by map of tag
     #[inline]
-    pub fn lookup(&self, tag: &str) -> Option<Ref<String, DashSet<K>>> {
-        self.tags.get(tag)
+    pub fn lookup(&self, name: &str, value: &str) -> Vec<K> {
+        match self.tags.get(name) {
+            Some(tag) => match tag.get(value) {
+                Some(set) => set.iter().map(|k| k.key().clone()).collect(),
+                None => vec![]
+            },
+            None => vec![]
+        }
     }
 
     
-    /// lookup by tag
+    /// lookup by view, keys are cloned so view is not locked while documents of them are read
     #[inline]
-    pub fn lookup_view(&self, view_name: &str) -> Option<Ref<String, DashSet<K>>> {
-        self.tags.get(&self.view_key_maker(view_name))
+    pub fn lookup_view(&self, view_name: &str) -> Vec<K> {
+        match self.views.get(view_name) {
+            Some(set) => set.iter().map(|k| k.key().clone()).collect(),
+            None => vec![]
+        }
     }
     
     
-    /// get iter
+    /// get iter of tags by name
     #[inline]
-    pub fn iter(&self) -> Iter<String, DashSet<K>> {
+    pub fn iter(&self) -> Iter<'_, String, DashMap<String, DashSet<K>>> {
         self.tags.iter()
     }
 
-
-    #[inline]
-    fn view_key_maker(&self, name: &str) -> String {
-        format!("__View__{}", name)
-    }
-        
-
 }
@@ -0,0 +1,351 @@
+use std::fs::{self, File};
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Serialize};
+use simple_wal::LogFile;
+use std::hash::Hash;
+
+use crate::RQuery;
+
+use super::{
+    wal::{codec::{self, Keyring}, compaction, disk_log::{self, filename_factory, list_pages}, page_processor::{PageProcessor, Sync}, patch::Resolver, snapshot::{self, sync_dir}},
+    Options,
+};
+
+
+
+type Step<K, Doc> = Box<dyn FnMut(RQuery<K, Doc>) -> Result<Option<RQuery<K, Doc>>, String>>;
+
+// (version, step) in order of version
+type Steps<K, Doc> = [(u32, Step<K, Doc>)];
+
+
+/// ordered chain of versioned migration steps of a datastore,
+/// version of datastore is kept in SCHEMA_VERSION file of its directory
+/// and just steps with higher version run on it
+pub struct Migrations<K, Doc> {
+    steps: Vec<(u32, Step<K, Doc>)>
+}
+
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StepReport {
+    pub version: u32,
+
+    // records that reached step
+    pub records: usize,
+    pub changed: usize,
+    pub dropped: usize,
+}
+
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MigrationReport {
+    pub from_version: u32,
+    pub to_version: u32,
+
+    // true if nothing is written
+    pub dry_run: bool,
+    pub steps: Vec<StepReport>,
+}
+
+
+impl<K, Doc> Migrations<K, Doc>
+where
+    K: Serialize + DeserializeOwned + Hash + Eq + 'static,
+    Doc: Serialize + DeserializeOwned + 'static,
+{
+    pub fn new() -> Self {
+        Migrations { steps: Vec::new() }
+    }
+
+    /// add step that migrate datastore to version, step can transform RQuery,
+    /// drop it by returning None or fail migration by error
+    pub fn step<F, E>(mut self, version: u32, mut step: F) -> Self
+    where
+        F: FnMut(RQuery<K, Doc>) -> Result<Option<RQuery<K, Doc>>, E> + 'static,
+        E: ToString,
+    {
+        self.steps.push((version, Box::new(move |rq| {
+            step(rq).map_err(|e| format!("migration {}: {}", version, e.to_string()))
+        })));
+        self
+    }
+
+    /// version of datastore after all steps
+    pub fn latest_version(&self) -> u32 {
+        self.steps.iter().map(|(version, _)| *version).max().unwrap_or(0)
+    }
+
+
+    /// run pending steps on datastore of options, storage must be closed while migrating,
+    /// result is written beside datastore and swapped in, so failed migration change nothing
+    pub fn run(&mut self, ops: &Options) -> Result<MigrationReport, String> {
+        let path = format!("{}/{}", ops.path, ops.storage_name);
+        let total_page_size = disk_log::page_size(ops.total_page_size);
+        recover(ops.path, ops.storage_name)?;
+
+        let (from_version, pending) = self.pending(ops)?;
+        let mut report = MigrationReport {
+            from_version,
+            to_version: pending.last().map(|(version, _)| *version).unwrap_or(from_version),
+            dry_run: false,
+            steps: pending.iter().map(|(version, _)| StepReport { version: *version, ..Default::default() }).collect()
+        };
+
+        if pending.is_empty() {
+            return Ok(report)
+        }
+
+        // new datastore is created on latest version
+        if !Path::new(&path).is_dir() {
+            fs::create_dir_all(&path).map_err(|e| e.to_string())?;
+            return write_version(&path, report.to_version).map(|_| report)
+        }
+
+        compaction::recover(&path, total_page_size)?;
+
+        let sync_name = format!("{}.migrate", ops.storage_name);
+        let sync_path = format!("{}/{}", ops.path, sync_name);
+        if Path::new(&sync_path).is_dir() {
+            fs::remove_dir_all(&sync_path).map_err(|e| e.to_string())?;
+        }
+
+        {
+            let (steps, reports) = (pending, &mut report.steps);
+            let mut pp = PageProcessor::<K, Doc, K, Doc>::with_handler(
+                ops.path,
+                ops.storage_name,
+                ops.total_page_size,
+                Sync::New(&sync_name),
+                false,
+                |rq| apply(steps, reports, rq))
+                .with_format(ops.format)
+                .with_compression(ops.compression);
+
+            if let Some(keys) = ops.keys.clone() {
+                pp = pp.with_keys(keys);
+            }
+
+            if let Err(e) = pp.start() {
+                let _ = fs::remove_dir_all(&sync_path);
+                return Err(e)
+            }
+        }
+
+        // quarantine, archive and other files of datastore are kept
+        for entry in fs::read_dir(&path).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let dst = Path::new(&sync_path).join(&name);
+
+            if name.starts_with("page-") || name.starts_with("snapshot-") || name == VERSION_FILE || dst.exists() {
+                continue
+            }
+
+            fs::rename(entry.path(), dst).map_err(|e| e.to_string())?;
+        }
+
+        // migrated datastore is complete when it has version file, after that recover roll it forward
+        write_version(&sync_path, report.to_version)?;
+        swap(ops.path, ops.storage_name)?;
+
+        Ok(report)
+    }
+
+
+    /// run pending steps on records of datastore and report what would change, nothing is written
+    pub fn dry_run(&mut self, ops: &Options) -> Result<MigrationReport, String> {
+        let path = format!("{}/{}", ops.path, ops.storage_name);
+        let total_page_size = disk_log::page_size(ops.total_page_size);
+        let keys = Keyring::new(ops.keys.clone());
+        recover(ops.path, ops.storage_name)?;
+
+        let (from_version, pending) = self.pending(ops)?;
+        let mut report = MigrationReport {
+            from_version,
+            to_version: pending.last().map(|(version, _)| *version).unwrap_or(from_version),
+            dry_run: true,
+            steps: pending.iter().map(|(version, _)| StepReport { version: *version, ..Default::default() }).collect()
+        };
+
+        if pending.is_empty() || !Path::new(&path).is_dir() {
+            return Ok(report)
+        }
+
+        // Update is given to steps as document it made
+        let mut resolver = Resolver::new();
+        resolver.watch_pages(&path, total_page_size, &keys)?;
+
+        // snapshot is migrated as list of Insert
+        let first_page = match snapshot::latest(&path)? {
+            Some((page_index, filename)) => {
+                for (key, doc) in snapshot::read::<K, Doc>(&filename, &keys)? {
+                    resolver.insert(&key, &doc)?;
+                    apply(pending, &mut report.steps, RQuery::Insert(key, doc))?;
+                }
+                page_index
+            }
+            None => 0
+        };
+
+        for page_index in list_pages(&path, total_page_size).map_err(|e| e.to_string())? {
+            if page_index < first_page {
+                continue
+            }
+
+            let mut page = LogFile::open(filename_factory(&path, total_page_size * page_index)).map_err(|e| e.to_string())?;
+            for rq in codec::read_page::<RQuery<K, Doc>>(&mut page, &keys)? {
+                apply(pending, &mut report.steps, resolver.resolve(rq)?)?;
+            }
+        }
+
+        Ok(report)
+    }
+
+
+    // version of datastore and steps after it, in order of version
+    fn pending(&mut self, ops: &Options) -> Result<(u32, &mut Steps<K, Doc>), String> {
+        self.steps.sort_by_key(|(version, _)| *version);
+        if self.steps.windows(2).any(|w| w[0].0 == w[1].0) {
+            return Err("version of migration steps must be unique".to_owned())
+        }
+
+        let version = schema_version(ops.path, ops.storage_name)?;
+        let first = self.steps.iter().position(|(v, _)| *v > version).unwrap_or(self.steps.len());
+
+        Ok((version, &mut self.steps[first..]))
+    }
+}
+
+
+impl<K, Doc> Default for Migrations<K, Doc>
+where
+    K: Serialize + DeserializeOwned + Hash + Eq + 'static,
+    Doc: Serialize + DeserializeOwned + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+
+/// schema version of datastore, 0 if it has not version file
+pub fn schema_version(root: &str, name: &str) -> Result<u32, String> {
+    match fs::read_to_string(format!("{}/{}/{}", root, name, VERSION_FILE)) {
+        Ok(version) => version.trim().parse().map_err(|_| format!("invalid schema version {:?}", version)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+
+
+// pass RQuery through steps, stop on first step that drop it
+fn apply<K, Doc>(steps: &mut Steps<K, Doc>, reports: &mut [StepReport], rq: RQuery<K, Doc>) -> Result<Option<RQuery<K, Doc>>, String>
+where
+    K: Serialize,
+    Doc: Serialize,
+{
+    let mut rq = rq;
+
+    for ((_, step), report) in steps.iter_mut().zip(reports.iter_mut()) {
+        report.records += 1;
+        let before = bincode::serialize(&rq).map_err(|e| e.to_string())?;
+
+        // step see Insert and Remove, version and batch of them are kept
+        match rq.map_writes(step)? {
+            None => {
+                report.dropped += 1;
+                return Ok(None)
+            }
+            Some(new_rq) => {
+                if bincode::serialize(&new_rq).map_err(|e| e.to_string())? != before {
+                    report.changed += 1;
+                }
+                rq = new_rq;
+            }
+        }
+    }
+
+    Ok(Some(rq))
+}
+
+
+/// finish or undo migration of datastore that is stopped by a crash, migrated datastore that has
+/// version file replace datastore, otherwise it is removed and files moved to it are moved back
+pub(crate) fn recover(root: &str, name: &str) -> Result<(), String> {
+    let path = format!("{}/{}", root, name);
+    let sync_path = format!("{}/{}.migrate", root, name);
+    let old_path = format!("{}/{}.old", root, name);
+
+    if Path::new(&sync_path).is_dir() {
+        if Path::new(&sync_path).join(VERSION_FILE).is_file() {
+            return swap(root, name)
+        }
+
+        // quarantine, archive and other files of datastore are moved before version file is written
+        for entry in fs::read_dir(&sync_path).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let dst = Path::new(&path).join(&name);
+
+            if name.starts_with("page-") || name.starts_with("snapshot-") || name.starts_with(VERSION_FILE) || dst.exists() {
+                continue
+            }
+
+            fs::rename(entry.path(), dst).map_err(|e| e.to_string())?;
+        }
+
+        fs::remove_dir_all(&sync_path).map_err(|e| e.to_string())?;
+        return sync_dir(root)
+    }
+
+    if Path::new(&old_path).is_dir() {
+        match Path::new(&path).is_dir() {
+            true => fs::remove_dir_all(&old_path).map_err(|e| e.to_string())?,
+            false => fs::rename(&old_path, &path).map_err(|e| e.to_string())?,
+        }
+        return sync_dir(root)
+    }
+
+    Ok(())
+}
+
+
+// put complete migrated datastore in place of datastore, every step can be done again after a crash
+fn swap(root: &str, name: &str) -> Result<(), String> {
+    let path = format!("{}/{}", root, name);
+    let sync_path = format!("{}/{}.migrate", root, name);
+    let old_path = format!("{}/{}.old", root, name);
+
+    if Path::new(&path).is_dir() {
+        if Path::new(&old_path).is_dir() {
+            fs::remove_dir_all(&old_path).map_err(|e| e.to_string())?;
+        }
+        fs::rename(&path, &old_path).map_err(|e| e.to_string())?;
+    }
+
+    fs::rename(&sync_path, &path).map_err(|e| e.to_string())?;
+    sync_dir(root)?;
+
+    if Path::new(&old_path).is_dir() {
+        fs::remove_dir_all(&old_path).map_err(|e| e.to_string())?;
+    }
+    sync_dir(root)
+}
+
+
+// version file is written by rename, so it exist whole or not at all
+fn write_version(path: &str, version: u32) -> Result<(), String> {
+    let filename = format!("{}/{}", path, VERSION_FILE);
+    let tmp_filename = format!("{}.TMP", filename);
+    fs::write(&tmp_filename, version.to_string()).map_err(|e| e.to_string())?;
+    File::open(&tmp_filename).and_then(|f| f.sync_all()).map_err(|e| e.to_string())?;
+    fs::rename(&tmp_filename, &filename).map_err(|e| e.to_string())?;
+    sync_dir(path)
+}
+
+
+const VERSION_FILE: &str = "SCHEMA_VERSION";
@@ -0,0 +1,120 @@
+use crate::{Options, Schema, Storage, StorageType, RQuery, Migrations, schema_version, Durability};
+use crate::darkbird::test_fixture::{Profile, TempDir};
+
+
+#[tokio::test]
+async fn versioned_migrations_run_from_schema() {
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        for i in 0..10 {
+            storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+        }
+    }
+
+    let migrations = || {
+        let offset = 100;
+        Migrations::<String, Profile>::new()
+            .step(1, move |rq| match rq {
+                RQuery::Insert(key, mut doc) => {
+                    doc.age += offset;
+                    Ok::<_, String>(Some(RQuery::Insert(key, doc)))
+                }
+                rq => Ok(Some(rq))
+            })
+            .step(2, |rq| match rq {
+                RQuery::Insert(key, _) if key == "pid-0" => Ok::<_, String>(None),
+                rq => Ok(Some(rq))
+            })
+    };
+
+    let ops = || Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+
+    // dry run report and write nothing
+    let report = migrations().dry_run(&ops()).unwrap();
+    assert!(report.dry_run);
+    assert_eq!((report.from_version, report.to_version), (0, 2));
+    assert_eq!((report.steps[0].records, report.steps[0].changed), (10, 10));
+    assert_eq!((report.steps[1].records, report.steps[1].dropped), (10, 1));
+    assert_eq!(schema_version(&path, "profiles").unwrap(), 0);
+
+    let db = Schema::new()
+        .with_migrations(migrations())
+        .with_datastore::<String, Profile>(ops()).await.unwrap()
+        .build();
+
+    assert_eq!(schema_version(&path, "profiles").unwrap(), 2);
+    assert!(db.lookup::<String, Profile>(&"pid-0".to_owned()).unwrap().is_none());
+    assert_eq!(db.lookup::<String, Profile>(&"pid-1".to_owned()).unwrap().unwrap().age, 101);
+    drop(db);
+
+    // applied steps do not run again
+    let report = migrations().run(&ops()).unwrap();
+    assert_eq!((report.from_version, report.to_version), (2, 2));
+    assert!(report.steps.is_empty());
+
+    // failed step change nothing
+    let failing = migrations().step(3, |_| Err("boom"));
+    let res = Schema::new()
+        .with_migrations(failing)
+        .with_datastore::<String, Profile>(ops()).await;
+    assert!(res.is_err());
+    assert_eq!(schema_version(&path, "profiles").unwrap(), 2);
+
+    let storage = Storage::<String, Profile>::open(ops()).await.unwrap().0;
+    assert_eq!(storage.collection_len(), 9);
+}
+
+
+#[tokio::test]
+async fn migration_stopped_by_crash_is_finished_or_undone() {
+    let path = TempDir::new();
+    let ops = || Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+    let dir = |suffix: &str| format!("{}/profiles{}", path, suffix);
+    let copy_dir = |from: &str, to: &str| {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), std::path::Path::new(to).join(entry.file_name())).unwrap();
+        }
+    };
+
+    {
+        let ops = ops().with_durability(Durability::Flushed);
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        for i in 0..10 {
+            storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+        }
+    }
+
+    // crash before version file of migrated datastore is written, files moved to it are moved back
+    copy_dir(&dir(""), &dir(".migrate"));
+    std::fs::write(format!("{}/notes", dir("")), b"kept").unwrap();
+    std::fs::rename(format!("{}/notes", dir("")), format!("{}/notes", dir(".migrate"))).unwrap();
+
+    let report = Migrations::<String, Profile>::new().dry_run(&ops()).unwrap();
+    assert_eq!(report.from_version, 0);
+    assert!(!std::path::Path::new(&dir(".migrate")).exists());
+    assert_eq!(std::fs::read(format!("{}/notes", dir(""))).unwrap(), b"kept");
+
+    // crash after datastore is moved away and before migrated one take its place
+    copy_dir(&dir(""), &dir(".migrate"));
+    std::fs::write(format!("{}/SCHEMA_VERSION", dir(".migrate")), b"1").unwrap();
+    std::fs::rename(dir(""), dir(".old")).unwrap();
+
+    let storage = Storage::<String, Profile>::open(ops()).await.unwrap().0;
+    assert_eq!(storage.collection_len(), 10);
+    assert_eq!(schema_version(&path, "profiles").unwrap(), 1);
+    assert!(!std::path::Path::new(&dir(".migrate")).exists());
+    assert!(!std::path::Path::new(&dir(".old")).exists());
+    drop(storage);
+
+    // crash before previous datastore is removed
+    copy_dir(&dir(""), &dir(".old"));
+    let report = Migrations::<String, Profile>::new().step(1, |rq| Ok::<_, String>(Some(rq))).run(&ops()).unwrap();
+    assert_eq!(report.from_version, 1);
+    assert!(!std::path::Path::new(&dir(".old")).exists());
+}
@@ -108,7 +108,7 @@ impl Persistent {
         handler: &THandler,
     ) where
         THandler: Setter<K, Doc>,
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K: Serialize
             + DeserializeOwned
             + PartialOrd
@@ -139,7 +139,7 @@ impl Persistent {
     ) -> Result<(), SessionResult>
     where
         THandler: Getter<K, Doc>,
-        Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
         K: Serialize
             + DeserializeOwned
             + PartialOrd
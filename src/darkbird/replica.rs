@@ -0,0 +1,696 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+
+use crate::{document::{Document, RangeQuery}, RQuery, Storage};
+
+use super::{wal::codec::PageHeader, SessionResult, StatusResult};
+
+
+
+/// messages between nodes of ReplicatedCopies datastores,
+/// each one is bincode of message prefixed by its u32 length
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Message {
+    // apply record written on another node
+    Apply { datastore: String, header: Vec<u8>, record: Vec<u8> },
+    Ack,
+    Error(String),
+
+    // ask for copy of datastore, answered by Copy chunks and CopyDone
+    Sync { datastore: String },
+    Copy { header: Vec<u8>, records: Vec<Vec<u8>> },
+    CopyDone,
+
+    // write of ClusterDatabase, query is bincode of RQuery. with expected version it is
+    // written just if key has that version, answered by Written or Conflict
+    Write { datastore: String, query: Vec<u8>, expected: Option<u64> },
+    Written(u64),
+    Conflict { expected: u64, actual: u64 },
+
+    // read of ClusterDatabase, answered by Entries of bincode (key, document)
+    Query { datastore: String, query: Query },
+    Entries(Vec<Vec<u8>>),
+
+    // node that could not deliver records ask peer to copy datastore, from node first.
+    // answered by Ack when copy is applied
+    Resync { datastore: String, from: Option<String> },
+
+    // first message of ClusterDatabase on a connection, answered by Ack or by Error
+    // if another router of datastore has open connection to node
+    Route { datastore: String, router: String },
+}
+
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum Query {
+    // bincode of key
+    Lookup(Vec<u8>),
+    // bincode of key, answered by (key, document, version)
+    LookupVersion(Vec<u8>),
+    // tag name and value
+    Tag(String, String),
+    Range(RangeQuery),
+    Search(String),
+
+    // every document of datastore, as (key, document, version)
+    Scan,
+}
+
+
+/// node of schema, it apply writes of other nodes to ReplicatedCopies datastores,
+/// send copy of them to rejoining nodes and serve shards of ClusterDatabase,
+/// node stop when it is dropped
+pub struct Node {
+    addr: String,
+    local_addr: SocketAddr,
+    replicas: Arc<RwLock<HashMap<String, Arc<dyn Replica>>>>,
+    _stop: watch::Sender<bool>,
+}
+
+// router of datastore and count of its open connections
+type Routers = Mutex<HashMap<String, (String, usize)>>;
+
+impl Node {
+    pub(crate) async fn bind(addr: &str) -> Result<Node, String> {
+        let listener = TcpListener::bind(addr).await.map_err(|e| e.to_string())?;
+        let local_addr = listener.local_addr().map_err(|e| e.to_string())?;
+        let replicas: Arc<RwLock<HashMap<String, Arc<dyn Replica>>>> = Arc::new(RwLock::new(HashMap::new()));
+        let (stop, mut stopped) = watch::channel(false);
+
+        let registry = replicas.clone();
+        let routers = Arc::new(Routers::default());
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(_) => continue
+                    },
+                    _ = stopped.changed() => return
+                };
+
+                let _ = stream.set_nodelay(true);
+                tokio::spawn(serve(stream, registry.clone(), routers.clone(), stopped.clone()));
+            }
+        });
+
+        Ok(Node { addr: addr.to_owned(), local_addr, replicas, _stop: stop })
+    }
+
+    /// address node is known by in ReplicatedCopies nodes
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// address node is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+
+    // serve datastore to other nodes, ReplicatedCopies datastore is resynced from
+    // first reachable peer, writes that are newer than copy win over it
+    pub(crate) async fn join<K, Doc>(&self, storage: &Storage<K, Doc>) -> Result<(), String>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K: Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
+        let replica = Arc::new(ReplicaSet { storage: storage.share() });
+
+        self.replicas
+            .write()
+            .unwrap()
+            .insert(storage.name().to_owned(), replica.clone());
+
+        match storage.peers() {
+            Some(peers) => replica.copy_from(peers.addrs()).await,
+            None => Ok(())
+        }
+    }
+}
+
+
+
+// datastore that is served to other nodes
+#[async_trait]
+trait Replica: Send + Sync {
+    async fn apply(&self, header: &PageHeader, record: &[u8]) -> Result<(), String>;
+
+    // every document as Insert record
+    fn copy(&self) -> Result<(PageHeader, Vec<Vec<u8>>), String>;
+
+    // write of ClusterDatabase, it is replicated like local writes.
+    // return new version of key if version is expected
+    async fn write(&self, query: &[u8], expected: Option<u64>) -> Result<Option<u64>, StatusResult>;
+
+    fn query(&self, query: Query) -> Result<Vec<Vec<u8>>, String>;
+
+    // copy datastore from peers, from node first
+    async fn resync(&self, from: Option<String>) -> Result<(), String>;
+}
+
+
+struct ReplicaSet<K, Doc: Document> {
+    storage: Storage<K, Doc>,
+}
+
+
+#[async_trait]
+impl<K, Doc> Replica for ReplicaSet<K, Doc>
+where
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+    K: Serialize
+        + DeserializeOwned
+        + PartialOrd
+        + Ord
+        + PartialEq
+        + Eq
+        + Hash
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    async fn apply(&self, header: &PageHeader, record: &[u8]) -> Result<(), String> {
+        let (_, rq) = self.storage.decode_replicated(header, record)?;
+        self.storage.apply_peer(rq).await.map_err(|e| e.to_string())
+    }
+
+    fn copy(&self) -> Result<(PageHeader, Vec<Vec<u8>>), String> {
+        let records = self.storage
+            .iter()
+            .map(|entry| {
+                let version = self.storage.version(entry.key()).unwrap_or_default();
+                self.storage.seal_replicated(&RQuery::Versioned(entry.key().clone(), entry.value().clone(), version))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok((self.storage.replicated_header(), records))
+    }
+
+    async fn write(&self, query: &[u8], expected: Option<u64>) -> Result<Option<u64>, StatusResult> {
+        let query = bincode::deserialize::<RQuery<K, Doc>>(query).map_err(|e| StatusResult::Err(e.to_string()))?;
+
+        let res = match (query, expected) {
+            (RQuery::Insert(key, doc), Some(expected)) => self.storage.compare_and_swap(key, expected, Some(doc)).await.map(Some),
+            (RQuery::Remove(key), Some(expected)) => self.storage.compare_and_swap(key, expected, None).await.map(Some),
+            (_, Some(_)) => Err(SessionResult::Err(StatusResult::Err("version of batch can not be expected".to_owned()))),
+            (RQuery::Insert(key, doc), None) => self.storage.insert(key, doc).await.map(|_| None),
+            (RQuery::Remove(key), None) => self.storage.remove(key).await.map(|_| None),
+            (batch, None) => self.storage.transaction(|tx| {
+                tx.stage(batch);
+                Ok(None)
+            }).await,
+        };
+
+        res.map_err(|e| match e {
+            SessionResult::Err(status) => status,
+            e => StatusResult::Err(e.to_string())
+        })
+    }
+
+    fn query(&self, query: Query) -> Result<Vec<Vec<u8>>, String> {
+        let encode = |key: &K, doc: &Doc| bincode::serialize(&(key, doc)).map_err(|e| e.to_string());
+
+        // version is read while document is held, so writer of key can not change it between them
+        let versioned = |key: &K, doc: &Doc| {
+            let version = self.storage.version(key).unwrap_or_default();
+            bincode::serialize(&(key, doc, version)).map_err(|e| e.to_string())
+        };
+
+        let entries = match query {
+            Query::Lookup(key) => {
+                let key = bincode::deserialize::<K>(&key).map_err(|e| e.to_string())?;
+                self.storage.lookup(&key).into_iter().collect()
+            }
+            Query::Tag(name, value) => self.storage.lookup_by_tag(&name, &value),
+            Query::Range(query) => self.storage.range_query(&query),
+            Query::Search(text) => self.storage.search(text),
+            Query::LookupVersion(key) => {
+                let key = bincode::deserialize::<K>(&key).map_err(|e| e.to_string())?;
+                return self.storage.lookup(&key).into_iter().map(|entry| versioned(entry.key(), entry.value())).collect()
+            }
+            Query::Scan => return self.storage.iter().map(|entry| versioned(entry.key(), entry.value())).collect(),
+        };
+
+        entries.iter().map(|entry| encode(entry.key(), entry.value())).collect()
+    }
+
+    async fn resync(&self, from: Option<String>) -> Result<(), String> {
+        let mut peers = self.storage.peers().map(Peers::addrs).unwrap_or_default();
+
+        // node that ask for resync has every record it could not deliver
+        if let Some(from) = from {
+            peers.retain(|peer| *peer != from);
+            peers.insert(0, from);
+        }
+
+        self.copy_from(peers).await
+    }
+}
+
+
+impl<K, Doc> ReplicaSet<K, Doc>
+where
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+    K: Serialize
+        + DeserializeOwned
+        + PartialOrd
+        + Ord
+        + PartialEq
+        + Eq
+        + Hash
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    // copy datastore from first peer that answer, first node of cluster has no peer to answer.
+    // copied documents are written like records of peers, so newer writes are not undone by copy
+    async fn copy_from(&self, peers: Vec<String>) -> Result<(), String> {
+        'peers: for peer in peers {
+            let mut stream = match connect(&peer).await {
+                Ok(stream) => stream,
+                Err(_) => continue
+            };
+
+            // version of keys before copy, key that is not copied is removed
+            // just if it is not written after that
+            let before = self.storage
+                .iter()
+                .map(|entry| (entry.key().clone(), self.storage.version(entry.key()).unwrap_or_default()))
+                .collect::<HashMap<K, u64>>();
+
+            let datastore = self.storage.name().to_owned();
+            write_message(&mut stream, &Message::Sync { datastore }).await?;
+
+            let mut copied = HashSet::new();
+            loop {
+                match read_message(&mut stream).await? {
+                    Message::Copy { header, records } => {
+                        let header = PageHeader::parse(&header).ok_or_else(|| "invalid page header".to_owned())?;
+                        for record in records {
+                            if let (_, RQuery::Versioned(key, doc, version)) = self.storage.decode_replicated(&header, &record)? {
+                                copied.insert(key.clone());
+                                self.storage.apply_peer(RQuery::Versioned(key, doc, version)).await.map_err(|e| e.to_string())?;
+                            }
+                        }
+                    }
+                    Message::CopyDone => break,
+
+                    // peer has not opened datastore yet
+                    Message::Error(_) if copied.is_empty() => continue 'peers,
+                    Message::Error(e) => return Err(e),
+                    _ => return Err("unexpected message from peer".to_owned())
+                }
+            }
+
+            // removed on peer while this node missed its records
+            for (key, version) in before {
+                if !copied.contains(&key) {
+                    self.storage.apply_peer(RQuery::Removed(key, version)).await.map_err(|e| e.to_string())?;
+                }
+            }
+
+            return Ok(())
+        }
+
+        Ok(())
+    }
+}
+
+
+
+/// other nodes of ReplicatedCopies datastore, every node has a queue
+/// so records are delivered to it in order of writes
+pub(crate) struct Peers {
+    queues: Vec<(String, mpsc::Sender<Delivery>)>,
+    quorum: usize,
+}
+
+struct Delivery {
+    message: Vec<u8>,
+    ack: mpsc::Sender<bool>,
+}
+
+/// record sent to peers, acks of them are counted until quorum is reached
+pub(crate) struct Pending {
+    acks: mpsc::Receiver<bool>,
+    peers: usize,
+    quorum: usize,
+}
+
+impl Peers {
+    // node is removed from nodes, quorum count local node too
+    pub(crate) fn start(nodes: &[String], node: Option<&str>, datastore: &str, quorum: Option<usize>) -> Result<Arc<Peers>, String> {
+        let peers = nodes
+            .iter()
+            .filter(|addr| Some(addr.as_str()) != node)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let total = peers.len() + 1;
+        let quorum = quorum.unwrap_or(total / 2 + 1);
+        if quorum == 0 || quorum > total {
+            return Err(format!("quorum {} is not possible with {} nodes", quorum, total))
+        }
+
+        let resync = Message::Resync { datastore: datastore.to_owned(), from: node.map(str::to_owned) };
+        let resync = bincode::serialize(&resync).map_err(|e| e.to_string())?;
+
+        let queues = peers
+            .into_iter()
+            .map(|addr| {
+                let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+                tokio::spawn(deliver(addr.clone(), resync.clone(), receiver));
+                (addr, sender)
+            })
+            .collect();
+
+        Ok(Arc::new(Peers { queues, quorum }))
+    }
+
+    pub(crate) fn addrs(&self) -> Vec<String> {
+        self.queues.iter().map(|(addr, _)| addr.clone()).collect()
+    }
+
+
+    // send record applied on local node to peers, every peer get records in order they are enqueued
+    pub(crate) async fn enqueue(&self, datastore: &str, header: &PageHeader, record: Vec<u8>) -> Result<Pending, SessionResult> {
+        let message = Message::Apply { datastore: datastore.to_owned(), header: header.to_bytes(), record };
+        let message = bincode::serialize(&message).map_err(|e| SessionResult::Err(StatusResult::Err(e.to_string())))?;
+
+        let (ack, acks) = mpsc::channel(self.queues.len().max(1));
+        for (_, queue) in &self.queues {
+            let delivery = Delivery { message: message.clone(), ack: ack.clone() };
+            if queue.send(delivery).await.is_err() {
+                return Err(SessionResult::Closed)
+            }
+        }
+
+        Ok(Pending { acks, peers: self.queues.len(), quorum: self.quorum })
+    }
+}
+
+impl Pending {
+    // wait until quorum applied record, record is still delivered to peers that answer after quorum
+    pub(crate) async fn wait(mut self) -> Result<(), SessionResult> {
+
+        // local node applied it
+        let mut applied = 1;
+        let mut answered = 0;
+        while applied < self.quorum {
+            match self.acks.recv().await {
+                Some(ok) => {
+                    answered += 1;
+                    if ok {
+                        applied += 1;
+                    }
+                }
+                None => break
+            }
+
+            if applied + (self.peers - answered) < self.quorum {
+                break
+            }
+        }
+
+        match applied >= self.quorum {
+            true => Ok(()),
+            false => Err(SessionResult::Err(StatusResult::Err(format!(
+                "quorum {} is not reached, {} of {} nodes applied write", self.quorum, applied, self.peers + 1))))
+        }
+    }
+}
+
+
+// deliver records to peer in order, while peer is down deliveries fail fast. peer that
+// missed a record is behind, it is asked to resync before next record is sent to it and
+// every RETRY_INTERVAL while no record is sent, so missed records reach it by copy
+async fn deliver(addr: String, resync: Vec<u8>, mut queue: mpsc::Receiver<Delivery>) {
+    let mut stream: Option<TcpStream> = None;
+
+    // time of latest failure while peer is behind
+    let mut behind: Option<Instant> = None;
+
+    loop {
+        // None when it is time to retry resync
+        let delivery = match behind {
+            None => Some(queue.recv().await),
+            Some(_) => tokio::select! {
+                delivery = queue.recv() => Some(delivery),
+                _ = tokio::time::sleep(RETRY_INTERVAL) => None
+            }
+        };
+
+        if let Some(failed_at) = behind {
+            if failed_at.elapsed() >= RETRY_INTERVAL {
+                behind = match send(&mut stream, &addr, &resync, RESYNC_TIMEOUT).await {
+                    Ok(()) => None,
+                    Err(_) => {
+                        stream = None;
+                        Some(Instant::now())
+                    }
+                };
+            }
+        }
+
+        // queue is closed when datastore is dropped
+        let delivery = match delivery {
+            Some(Some(delivery)) => delivery,
+            Some(None) => return,
+            None => continue
+        };
+
+        let ok = match behind {
+            Some(_) => false,
+            None => match send(&mut stream, &addr, &delivery.message, TIMEOUT).await {
+                Ok(()) => true,
+                Err(_) => {
+                    stream = None;
+                    behind = Some(Instant::now());
+                    false
+                }
+            }
+        };
+
+        let _ = delivery.ack.send(ok).await;
+    }
+}
+
+
+async fn send(stream: &mut Option<TcpStream>, addr: &str, message: &[u8], timeout: Duration) -> Result<(), String> {
+    if stream.is_none() {
+        *stream = Some(connect(addr).await?);
+    }
+    let conn = stream.as_mut().unwrap();
+
+    conn.write_u32_le(message.len() as u32).await.map_err(|e| e.to_string())?;
+    conn.write_all(message).await.map_err(|e| e.to_string())?;
+
+    match tokio::time::timeout(timeout, read_message(conn)).await {
+        Ok(Ok(Message::Ack)) => Ok(()),
+        Ok(Ok(Message::Error(e))) => Err(e),
+        Ok(Ok(_)) => Err("unexpected message from peer".to_owned()),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err("peer did not answer".to_owned())
+    }
+}
+
+
+pub(crate) async fn connect(addr: &str) -> Result<TcpStream, String> {
+    match tokio::time::timeout(TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => {
+            let _ = stream.set_nodelay(true);
+            Ok(stream)
+        }
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("connect to {} timed out", addr))
+    }
+}
+
+
+
+// answer messages of a peer until it disconnect
+async fn serve(mut stream: TcpStream, replicas: Arc<RwLock<HashMap<String, Arc<dyn Replica>>>>, routers: Arc<Routers>, mut stopped: watch::Receiver<bool>) {
+    // datastore is routed by router of connection until it is closed
+    let mut _routed = None;
+
+    loop {
+        let message = tokio::select! {
+            message = read_message(&mut stream) => match message {
+                Ok(message) => message,
+                Err(_) => return
+            },
+            _ = stopped.changed() => return
+        };
+
+        let answer = match message {
+            Message::Apply { datastore, header, record } => {
+                match (replica(&replicas, &datastore), PageHeader::parse(&header)) {
+                    (Err(e), _) => Message::Error(e),
+                    (_, None) => Message::Error("invalid page header".to_owned()),
+                    (Ok(replica), Some(header)) => match replica.apply(&header, &record).await {
+                        Ok(()) => Message::Ack,
+                        Err(e) => Message::Error(e)
+                    }
+                }
+            }
+
+            Message::Sync { datastore } => {
+                let copy = replica(&replicas, &datastore).and_then(|replica| replica.copy());
+                match copy {
+                    Err(e) => Message::Error(e),
+                    Ok((header, records)) => {
+                        for chunk in records.chunks(COPY_CHUNK) {
+                            let message = Message::Copy { header: header.to_bytes(), records: chunk.to_vec() };
+                            if write_message(&mut stream, &message).await.is_err() {
+                                return
+                            }
+                        }
+                        Message::CopyDone
+                    }
+                }
+            }
+
+            Message::Write { datastore, query, expected } => {
+                match replica(&replicas, &datastore) {
+                    Err(e) => Message::Error(e),
+                    Ok(replica) => match replica.write(&query, expected).await {
+                        Ok(None) => Message::Ack,
+                        Ok(Some(version)) => Message::Written(version),
+                        Err(StatusResult::Conflict { expected, actual }) => Message::Conflict { expected, actual },
+                        Err(e) => Message::Error(e.to_string())
+                    }
+                }
+            }
+
+            Message::Query { datastore, query } => {
+                match replica(&replicas, &datastore).and_then(|replica| replica.query(query)) {
+                    Ok(entries) => Message::Entries(entries),
+                    Err(e) => Message::Error(e)
+                }
+            }
+
+            Message::Resync { datastore, from } => {
+                match replica(&replicas, &datastore) {
+                    Err(e) => Message::Error(e),
+                    Ok(replica) => match replica.resync(from).await {
+                        Ok(()) => Message::Ack,
+                        Err(e) => Message::Error(e)
+                    }
+                }
+            }
+
+            Message::Route { datastore, router } => {
+                match Routed::claim(&routers, datastore, router) {
+                    Ok(routed) => {
+                        _routed = Some(routed);
+                        Message::Ack
+                    }
+                    Err(e) => Message::Error(e)
+                }
+            }
+
+            _ => Message::Error("unexpected message".to_owned())
+        };
+
+        if write_message(&mut stream, &answer).await.is_err() {
+            return
+        }
+    }
+}
+
+
+// ring of ClusterDatabase is kept by its router, so one router at a time route a datastore
+struct Routed {
+    routers: Arc<Routers>,
+    datastore: String,
+}
+
+impl Routed {
+    fn claim(routers: &Arc<Routers>, datastore: String, router: String) -> Result<Self, String> {
+        let mut claims = routers.lock().unwrap();
+        let (owner, connections) = claims.entry(datastore.clone()).or_insert_with(|| (router.clone(), 0));
+        if *owner != router {
+            return Err(format!("datastore {} is routed by another ClusterDatabase", datastore))
+        }
+
+        *connections += 1;
+        Ok(Routed { routers: routers.clone(), datastore })
+    }
+}
+
+impl Drop for Routed {
+    fn drop(&mut self) {
+        let mut claims = self.routers.lock().unwrap();
+        if let Some((_, connections)) = claims.get_mut(&self.datastore) {
+            *connections -= 1;
+            if *connections == 0 {
+                claims.remove(&self.datastore);
+            }
+        }
+    }
+}
+
+
+fn replica(replicas: &RwLock<HashMap<String, Arc<dyn Replica>>>, datastore: &str) -> Result<Arc<dyn Replica>, String> {
+    replicas
+        .read()
+        .unwrap()
+        .get(datastore)
+        .cloned()
+        .ok_or_else(|| format!("datastore {} is not replicated on this node", datastore))
+}
+
+
+
+pub(crate) async fn write_message<W: AsyncWrite + Unpin>(write: &mut W, message: &Message) -> Result<(), String> {
+    let bytes = bincode::serialize(message).map_err(|e| e.to_string())?;
+    write.write_u32_le(bytes.len() as u32).await.map_err(|e| e.to_string())?;
+    write.write_all(&bytes).await.map_err(|e| e.to_string())
+}
+
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(read: &mut R) -> Result<Message, String> {
+    let len = read.read_u32_le().await.map_err(|e| e.to_string())?;
+    if len > MAX_MESSAGE {
+        return Err(format!("message of {} bytes is too large", len))
+    }
+
+    let mut bytes = vec![0; len as usize];
+    read.read_exact(&mut bytes).await.map_err(|e| e.to_string())?;
+    bincode::deserialize(&bytes).map_err(|e| e.to_string())
+}
+
+
+
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(5);
+
+// deliveries to a down peer fail without connecting until this passed
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// peer answer resync when it applied copy of datastore
+const RESYNC_TIMEOUT: Duration = Duration::from_secs(60);
+
+const QUEUE_SIZE: usize = 1024;
+const COPY_CHUNK: usize = 1000;
+const MAX_MESSAGE: u32 = 64 * 1024 * 1024;
@@ -0,0 +1,90 @@
+use crate::{Options, Schema, Storage, StorageType, RQuery};
+use crate::darkbird::test_fixture::{Profile, TempDir};
+
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replicated_copies_apply_quorum_writes_and_resync_rejoined_node() {
+    let paths = [TempDir::new(), TempDir::new(), TempDir::new()];
+    let nodes = (0..3)
+        .map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string())
+        .collect::<Vec<_>>();
+
+    async fn open_node(path: &str, node: &str, nodes: &[String]) -> crate::Database {
+        let ops = Options::new(path, "profiles", 1000, StorageType::ReplicatedCopies(nodes.to_vec()), true);
+        Schema::new()
+            .with_node(node).await.unwrap()
+            .with_datastore::<String, Profile>(ops).await.unwrap()
+            .build()
+    }
+
+    async fn eventually(db: &crate::Database, key: &str, age: Option<i32>) {
+        for _ in 0..500 {
+            if db.lookup::<String, Profile>(&key.to_owned()).unwrap().map(|doc| doc.age) == age {
+                return
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("{} is not replicated", key)
+    }
+
+    let a = open_node(&paths[0], &nodes[0], &nodes).await;
+    let b = open_node(&paths[1], &nodes[1], &nodes).await;
+    let c = open_node(&paths[2], &nodes[2], &nodes).await;
+
+    a.insert::<String, Profile>("pid-1".to_owned(), Profile::new("DanyalMh", 1)).await.unwrap();
+    b.insert::<String, Profile>("pid-2".to_owned(), Profile::new("DanyalMh", 2)).await.unwrap();
+    for db in [&a, &b, &c] {
+        eventually(db, "pid-1", Some(1)).await;
+        eventually(db, "pid-2", Some(2)).await;
+    }
+
+    // majority is still available while node is down
+    drop(c);
+    a.insert::<String, Profile>("pid-3".to_owned(), Profile::new("DanyalMh", 3)).await.unwrap();
+    a.remove::<String, Profile>("pid-1".to_owned()).await.unwrap();
+    eventually(&b, "pid-3", Some(3)).await;
+
+    // rejoined node resync from a peer
+    let c = open_node(&paths[2], &nodes[2], &nodes).await;
+    assert_eq!(c.lookup::<String, Profile>(&"pid-3".to_owned()).unwrap().unwrap().age, 3);
+    assert!(c.lookup::<String, Profile>(&"pid-1".to_owned()).unwrap().is_none());
+
+    c.insert::<String, Profile>("pid-4".to_owned(), Profile::new("DanyalMh", 4)).await.unwrap();
+    eventually(&a, "pid-4", Some(4)).await;
+    eventually(&b, "pid-4", Some(4)).await;
+
+    // quorum that can not be reached fail write
+    let (path, node) = (TempDir::new(), std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string());
+    let ops = Options::new(&path, "profiles", 1000, StorageType::ReplicatedCopies(vec![node.clone(), "127.0.0.1:1".to_owned()]), true)
+        .with_quorum(2);
+    let alone = Schema::new()
+        .with_node(&node).await.unwrap()
+        .with_datastore::<String, Profile>(ops).await.unwrap()
+        .build();
+    assert!(alone.insert::<String, Profile>("pid-1".to_owned(), Profile::new("DanyalMh", 1)).await.is_err());
+}
+
+
+#[tokio::test]
+async fn stale_records_of_peers_are_skipped() {
+    let path = TempDir::new();
+    let ops = Options::new(&path, "profiles", 1000, StorageType::RamCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+    let key = "pid-1".to_owned();
+
+    storage.apply_peer(RQuery::Versioned(key.clone(), Profile::new("DanyalMh", 3), 3)).await.unwrap();
+
+    // older write and remove of older version are delivered late
+    storage.apply_peer(RQuery::Versioned(key.clone(), Profile::new("DanyalMh", 2), 2)).await.unwrap();
+    storage.apply_peer(RQuery::Removed(key.clone(), 2)).await.unwrap();
+    assert_eq!(storage.lookup_with_version(&key).map(|(doc, version)| (doc.value().age, version)), Some((3, 3)));
+
+    // nodes that wrote same version keep greater document
+    storage.apply_peer(RQuery::Versioned(key.clone(), Profile::new("DanyalMh", 1), 3)).await.unwrap();
+    storage.apply_peer(RQuery::Versioned(key.clone(), Profile::new("DanyalMh", 5), 3)).await.unwrap();
+    storage.apply_peer(RQuery::Versioned(key.clone(), Profile::new("DanyalMh", 4), 3)).await.unwrap();
+    assert_eq!(storage.lookup(&key).unwrap().value().age, 5);
+
+    storage.apply_peer(RQuery::Removed(key.clone(), 3)).await.unwrap();
+    assert!(storage.lookup(&key).is_none());
+}
@@ -0,0 +1,461 @@
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+use crate::{document::Document, Storage};
+
+use super::wal::{backup::Source, codec::{self, PageHeader, Stamp}, reader::{StartAt, Tail}, snapshot::sync_dir};
+
+
+
+/// frames of replication, each one is bincode of frame prefixed by its u32 length
+#[derive(Serialize, Deserialize)]
+enum Frame {
+    // first frame of follower, records after from_seq are wanted
+    Follow { datastore: String, from_seq: u64 },
+
+    // record as it is on page of leader with header of that page,
+    // so leader never decrypt records and follower need same keys
+    Record { header: Vec<u8>, record: Vec<u8> },
+
+    // sent while follower is caught up
+    Heartbeat { last_seq: u64 },
+
+    Error(String),
+}
+
+
+/// leader side of replication, it serve records of DiskCopies datastores
+/// of database to followers, replication stop when leader is dropped
+pub struct Leader {
+    local_addr: SocketAddr,
+    stop: watch::Sender<bool>,
+}
+
+impl Leader {
+    pub(crate) async fn bind(addr: &str, sources: Vec<Source>) -> Result<Leader, String> {
+        let listener = TcpListener::bind(addr).await.map_err(|e| e.to_string())?;
+        let local_addr = listener.local_addr().map_err(|e| e.to_string())?;
+        let (stop, mut stopped) = watch::channel(false);
+        let sources = Arc::new(sources);
+
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(_) => continue
+                    },
+                    _ = stopped.changed() => return
+                };
+
+                let _ = stream.set_nodelay(true);
+                tokio::spawn(serve(stream, sources.clone(), stopped.clone()));
+            }
+        });
+
+        Ok(Leader { local_addr, stop })
+    }
+
+    /// address leader is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// stop accepting followers and close connection of them
+    pub fn stop(&self) {
+        let _ = self.stop.send(true);
+    }
+}
+
+
+/// state of follower, lag is how far follower is behind leader
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplicationStatus {
+    pub connected: bool,
+
+    // latest sequence leader reported
+    pub leader_seq: u64,
+    pub applied_seq: u64,
+
+    // time of latest applied record on leader, micros since epoch
+    pub applied_at: u64,
+
+    // latest error of connection, follower reconnect after it
+    pub last_error: Option<String>,
+}
+
+impl ReplicationStatus {
+    /// records logged by leader and not applied yet
+    pub fn lag_records(&self) -> u64 {
+        self.leader_seq.saturating_sub(self.applied_seq)
+    }
+
+    /// time since latest applied record was logged by leader, zero when caught up
+    pub fn lag(&self) -> Duration {
+        if self.lag_records() == 0 || self.applied_at == 0 {
+            return Duration::ZERO
+        }
+        Duration::from_micros(Stamp::now(0).timestamp.saturating_sub(self.applied_at))
+    }
+}
+
+
+/// follower side of replication, it apply records of leader to its storage
+/// and catch up from latest applied sequence after reconnect or restart,
+/// storage is read-only until follower is promoted
+pub struct Follower {
+    status: Arc<Mutex<ReplicationStatus>>,
+    stop: watch::Sender<bool>,
+    task: tokio::task::JoinHandle<()>,
+    writable: Box<dyn FnOnce() + Send>,
+}
+
+impl Follower {
+    /// follow datastore with same name of storage on leader
+    pub fn start<K, Doc>(leader: &str, storage: &Storage<K, Doc>) -> Follower
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K: Serialize
+            + DeserializeOwned
+            + PartialOrd
+            + Ord
+            + PartialEq
+            + Eq
+            + Hash
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
+        storage.set_read_only(true);
+
+        let seq_file = storage.disk_path().map(|path| format!("{}/{}", path, SEQ_FILE));
+        let status = Arc::new(Mutex::new(ReplicationStatus {
+            applied_seq: read_seq(seq_file.as_deref()),
+            ..Default::default()
+        }));
+
+        let (stop, stopped) = watch::channel(false);
+        let task = tokio::spawn(follow(leader.to_owned(), storage.share(), seq_file, status.clone(), stopped));
+
+        let storage = storage.share();
+        Follower {
+            status,
+            stop,
+            task,
+            writable: Box::new(move || storage.set_read_only(false)),
+        }
+    }
+
+    pub fn status(&self) -> ReplicationStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// stop replication, storage stay read-only
+    pub async fn stop(self) -> ReplicationStatus {
+        let _ = self.stop.send(true);
+        let _ = self.task.await;
+        self.status.lock().unwrap().clone()
+    }
+
+    /// stop replication and make storage writable, so it can be leader
+    pub async fn promote(self) -> ReplicationStatus {
+        let writable = self.writable;
+        let _ = self.stop.send(true);
+        let _ = self.task.await;
+
+        writable();
+
+        let mut status = self.status.lock().unwrap().clone();
+        status.connected = false;
+        status
+    }
+}
+
+
+
+// ship records of datastore to follower until it disconnect or leader stop
+async fn serve(stream: TcpStream, sources: Arc<Vec<Source>>, mut stopped: watch::Receiver<bool>) {
+    let (mut read, write) = stream.into_split();
+    let mut write = BufWriter::new(write);
+
+    let (datastore, from_seq) = match read_frame(&mut read).await {
+        Ok(Frame::Follow { datastore, from_seq }) => (datastore, from_seq),
+        _ => return
+    };
+
+    let source = match sources.iter().find(|source| source.name == datastore) {
+        Some(source) => source,
+        None => {
+            let _ = write_frame(&mut write, &Frame::Error(format!("datastore {} not found", datastore))).await;
+            let _ = write.flush().await;
+            return
+        }
+    };
+
+    if let Err(e) = ship(&mut write, source, from_seq, &mut stopped).await {
+        let _ = write_frame(&mut write, &Frame::Error(e)).await;
+        let _ = write.flush().await;
+    }
+}
+
+
+async fn ship<W>(write: &mut W, source: &Source, from_seq: u64, stopped: &mut watch::Receiver<bool>) -> Result<(), String>
+where
+    W: AsyncWrite + Unpin
+{
+    let last_seq = codec::last_seq(&source.path, source.total_page_size)?;
+    let mut tail = Tail::open(source.path.clone(), source.total_page_size, StartAt::Seq(from_seq + 1))?;
+
+    // first shipped record must be next of follower, older pages may be released by checkpoint
+    let mut expected = Some(from_seq + 1);
+    let mut sent = from_seq;
+
+    write_frame(write, &Frame::Heartbeat { last_seq }).await?;
+
+    let mut idle = 0;
+    loop {
+        if *stopped.borrow() {
+            return Ok(())
+        }
+
+        match tail.try_next()? {
+            Some((header, record)) => {
+                if header.stamped {
+                    let seq = header.stamp(&record)?.seq;
+                    if let Some(expected) = expected.take() {
+                        if seq > expected {
+                            return Err(released(expected, seq))
+                        }
+                    }
+                    sent = seq;
+                }
+
+                write_frame(write, &Frame::Record { header: header.to_bytes(), record }).await?;
+                idle = 0;
+            }
+            None => {
+                if let Some(expected) = expected {
+                    if last_seq >= expected {
+                        return Err(released(expected, last_seq + 1))
+                    }
+                }
+
+                if idle % HEARTBEAT_EVERY == 0 {
+                    write_frame(write, &Frame::Heartbeat { last_seq: sent.max(last_seq) }).await?;
+                }
+                write.flush().await.map_err(|e| e.to_string())?;
+                idle += 1;
+
+                tokio::select! {
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                    _ = stopped.changed() => return Ok(())
+                }
+            }
+        }
+    }
+}
+
+
+fn released(from: u64, to: u64) -> String {
+    format!("records {}..{} are released from leader, follower need a copy of leader datastore", from, to)
+}
+
+
+
+// connect to leader and apply records until stopped, reconnect on error
+async fn follow<K, Doc>(
+    leader: String,
+    storage: Storage<K, Doc>,
+    seq_file: Option<String>,
+    status: Arc<Mutex<ReplicationStatus>>,
+    mut stopped: watch::Receiver<bool>)
+
+where
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+    K: Serialize
+        + DeserializeOwned
+        + PartialOrd
+        + Ord
+        + PartialEq
+        + Eq
+        + Hash
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    loop {
+        let res = replicate(&leader, &storage, seq_file.as_deref(), &status, &mut stopped).await;
+
+        {
+            let mut status = status.lock().unwrap();
+            status.connected = false;
+            if let Err(e) = res {
+                status.last_error = Some(e);
+            }
+        }
+
+        if *stopped.borrow() {
+            return
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_INTERVAL) => {}
+            _ = stopped.changed() => return
+        }
+    }
+}
+
+
+// Ok when stopped
+async fn replicate<K, Doc>(
+    leader: &str,
+    storage: &Storage<K, Doc>,
+    seq_file: Option<&str>,
+    status: &Mutex<ReplicationStatus>,
+    stopped: &mut watch::Receiver<bool>) -> Result<(), String>
+
+where
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+    K: Serialize
+        + DeserializeOwned
+        + PartialOrd
+        + Ord
+        + PartialEq
+        + Eq
+        + Hash
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    let stream = tokio::select! {
+        stream = TcpStream::connect(leader) => stream.map_err(|e| e.to_string())?,
+        _ = stopped.changed() => return Ok(())
+    };
+    let _ = stream.set_nodelay(true);
+    let (mut read, mut write) = stream.into_split();
+
+    let from_seq = status.lock().unwrap().applied_seq;
+    write_frame(&mut write, &Frame::Follow { datastore: storage.name().to_owned(), from_seq }).await?;
+
+    {
+        let mut status = status.lock().unwrap();
+        status.connected = true;
+        status.last_error = None;
+    }
+
+    // applied sequence is saved in batches, records applied again after crash are idempotent
+    let mut unsaved = 0;
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(&mut read) => frame?,
+            _ = stopped.changed() => {
+                save_seq(seq_file, status.lock().unwrap().applied_seq)?;
+                return Ok(())
+            }
+        };
+
+        match frame {
+            Frame::Record { header, record } => {
+                let header = PageHeader::parse(&header).ok_or_else(|| "invalid page header".to_owned())?;
+                let stamp = storage.apply_replicated(&header, &record).await.map_err(|e| e.to_string())?;
+
+                let mut status = status.lock().unwrap();
+                if header.stamped {
+                    status.applied_seq = stamp.seq;
+                    status.applied_at = stamp.timestamp;
+                    status.leader_seq = status.leader_seq.max(stamp.seq);
+                }
+
+                unsaved += 1;
+                if unsaved >= SAVE_EVERY {
+                    save_seq(seq_file, status.applied_seq)?;
+                    unsaved = 0;
+                }
+            }
+
+            Frame::Heartbeat { last_seq } => {
+                let mut status = status.lock().unwrap();
+                status.leader_seq = last_seq.max(status.applied_seq);
+
+                if unsaved > 0 {
+                    save_seq(seq_file, status.applied_seq)?;
+                    unsaved = 0;
+                }
+            }
+
+            Frame::Error(e) => return Err(e),
+            Frame::Follow { .. } => return Err("unexpected frame from leader".to_owned())
+        }
+    }
+}
+
+
+
+async fn write_frame<W: AsyncWrite + Unpin>(write: &mut W, frame: &Frame) -> Result<(), String> {
+    let bytes = bincode::serialize(frame).map_err(|e| e.to_string())?;
+    write.write_u32_le(bytes.len() as u32).await.map_err(|e| e.to_string())?;
+    write.write_all(&bytes).await.map_err(|e| e.to_string())
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(read: &mut R) -> Result<Frame, String> {
+    let len = read.read_u32_le().await.map_err(|e| e.to_string())?;
+    if len > MAX_FRAME {
+        return Err(format!("frame of {} bytes is too large", len))
+    }
+
+    let mut bytes = vec![0; len as usize];
+    read.read_exact(&mut bytes).await.map_err(|e| e.to_string())?;
+    bincode::deserialize(&bytes).map_err(|e| e.to_string())
+}
+
+
+
+// applied sequence of follower, 0 for RamCopies or a new follower
+fn read_seq(seq_file: Option<&str>) -> u64 {
+    seq_file
+        .and_then(|filename| fs::read_to_string(filename).ok())
+        .and_then(|seq| seq.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// written to temporary file and renamed, so crash never leave a half-written sequence
+fn save_seq(seq_file: Option<&str>, seq: u64) -> Result<(), String> {
+    let filename = match seq_file {
+        Some(filename) => filename,
+        None => return Ok(())
+    };
+
+    let tmp_filename = format!("{}.TMP", filename);
+    fs::write(&tmp_filename, seq.to_string()).map_err(|e| e.to_string())?;
+    File::open(&tmp_filename).and_then(|f| f.sync_all()).map_err(|e| e.to_string())?;
+    fs::rename(&tmp_filename, filename).map_err(|e| e.to_string())?;
+
+    match Path::new(filename).parent().and_then(|path| path.to_str()) {
+        Some(path) => sync_dir(path),
+        None => Ok(())
+    }
+}
+
+
+
+const SEQ_FILE: &str = "REPLICA_SEQ";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+// heartbeat is sent every HEARTBEAT_EVERY polls while follower is caught up
+const HEARTBEAT_EVERY: usize = 20;
+const SAVE_EVERY: usize = 1000;
+const MAX_FRAME: u32 = 64 * 1024 * 1024;
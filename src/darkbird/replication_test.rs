@@ -0,0 +1,73 @@
+use crate::{Options, Schema, Storage, StorageType, Follower, ReplicationStatus, Durability};
+use crate::darkbird::test_fixture::{Profile, TempDir};
+
+
+#[tokio::test(flavor = "multi_thread")]
+async fn follower_catch_up_from_leader_and_promote() {
+    let (leader_path, follower_path) = (TempDir::new(), TempDir::new());
+
+    let ops = Options::new(&leader_path, "profiles", 1000, StorageType::DiskCopies, true)
+        .with_durability(Durability::Flushed);
+    let leader = Schema::new()
+        .with_datastore::<String, Profile>(ops).await.unwrap()
+        .build();
+
+    for i in 0..1500 {
+        leader.insert::<String, Profile>(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+    }
+
+    let serving = leader.serve_replication("127.0.0.1:0").await.unwrap();
+    let addr = serving.local_addr().to_string();
+
+    async fn caught_up(follower: &Follower, seq: u64) -> ReplicationStatus {
+        for _ in 0..500 {
+            let status = follower.status();
+            if status.applied_seq >= seq && status.lag_records() == 0 {
+                return status
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("follower is not caught up: {:?}", follower.status())
+    }
+
+    {
+        let ops = Options::new(&follower_path, "profiles", 1000, StorageType::DiskCopies, true);
+        let replica = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        let follower = Follower::start(&addr, &replica);
+
+        caught_up(&follower, 1500).await;
+        assert_eq!(replica.collection_len(), 1500);
+        assert!(matches!(replica.insert("pid-x".to_owned(), Profile::new("Jack", 1)).await, Err(crate::SessionResult::ReadOnly)));
+
+        // live records are streamed
+        leader.remove::<String, Profile>("pid-0".to_owned()).await.unwrap();
+        leader.insert::<String, Profile>("pid-1".to_owned(), Profile::new("Jack", 10)).await.unwrap();
+        let status = caught_up(&follower, 1502).await;
+        assert!(status.connected);
+        assert!(replica.lookup(&"pid-0".to_owned()).is_none());
+        assert_eq!(replica.lookup(&"pid-1".to_owned()).unwrap().age, 10);
+
+        follower.stop().await;
+    }
+
+    // restarted follower catch up from its applied sequence
+    for i in 1500..1600 {
+        leader.insert::<String, Profile>(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+    }
+
+    let ops = Options::new(&follower_path, "profiles", 1000, StorageType::DiskCopies, true);
+    let replica = Schema::new()
+        .with_datastore::<String, Profile>(ops).await.unwrap()
+        .build();
+    let follower = replica.follow::<String, Profile>(&addr).unwrap();
+
+    let status = caught_up(&follower, 1602).await;
+    assert_eq!(status.leader_seq, 1602);
+    assert_eq!(replica.iter::<String, Profile>().unwrap().count(), 1599);
+
+    // promoted follower is writable
+    follower.promote().await;
+    replica.insert::<String, Profile>("pid-x".to_owned(), Profile::new("Jack", 1)).await.unwrap();
+
+    serving.stop();
+}
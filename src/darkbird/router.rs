@@ -241,6 +241,12 @@ pub struct Session<Msg> {
     sender: mpsc::Sender<Request<Msg>>
 }
 
+impl<Msg> Clone for Session<Msg> {
+    fn clone(&self) -> Self {
+        Session { sender: self.sender.clone() }
+    }
+}
+
 impl<Msg> Session<Msg> 
 where
     Msg: Send + 'static
@@ -1,16 +1,28 @@
 use anymap::AnyMap;
-use std::{hash::Hash, collections::HashSet};
+use std::{hash::Hash, collections::HashSet, sync::Arc};
 use serde::{Serialize, de::DeserializeOwned};
 
-use crate::{Options, document::Document, Storage, VecStorage};
+use crate::{Options, StorageType, document::Document, Storage, VecStorage, Migrations};
 
-use super::{database::Database, storage_redis::RedisStorage};
+use super::{database::Database, replica::Node, storage_redis::RedisStorage, transaction::TransactionLog, wal::backup::Source};
 
 
 
 pub struct Schema {
     datastores: AnyMap,
-    names: HashSet<String>
+    names: HashSet<String>,
+
+    // DiskCopies datastores, backed up together by Database::backup
+    sources: Vec<Source>,
+
+    // Migrations<K, Doc> that run before datastore of K, Doc opened
+    migrations: AnyMap,
+
+    // serve ReplicatedCopies datastores to other nodes
+    node: Option<Node>,
+
+    // commit records of Database::transaction
+    transaction_log: Option<Arc<TransactionLog>>,
 }
 
 impl Schema {
@@ -18,12 +30,54 @@ impl Schema {
     pub fn new() -> Schema {
         Schema { 
             datastores: AnyMap::new(),
-            names: HashSet::new()
+            names: HashSet::new(),
+            sources: Vec::new(),
+            migrations: AnyMap::new(),
+            node: None,
+            transaction_log: None,
+        }
+    }
+
+
+    /// listen on addr for other nodes of ReplicatedCopies datastores,
+    /// addr is how this node is named in nodes of them
+    pub async fn with_node(mut self, addr: &str) -> Result<Schema, SchemaError> {
+        match Node::bind(addr).await {
+            Err(e) => Err(SchemaError::Err(e)),
+            Ok(node) => {
+                self.node = Some(node);
+                Ok(self)
+            }
         }
     }
 
 
-    pub async fn with_datastore<'a, K, Doc>(mut self, opts: Options<'a>) -> Result<Schema, SchemaError> 
+    /// keep commit records of transactions over several DiskCopies datastores in path,
+    /// it must be set before datastores, so they complete committed transactions on open,
+    /// lazy opened datastores complete them after loading and are read-only until then
+    pub fn with_transaction_log(mut self, path: &str) -> Result<Schema, SchemaError> {
+        match TransactionLog::open(path) {
+            Err(e) => Err(SchemaError::Err(e)),
+            Ok(log) => {
+                self.transaction_log = Some(Arc::new(log));
+                Ok(self)
+            }
+        }
+    }
+
+
+    /// register migrations of datastore, pending steps of them run by with_datastore before opening it
+    pub fn with_migrations<K, Doc>(mut self, migrations: Migrations<K, Doc>) -> Schema
+    where
+        Doc: Serialize + DeserializeOwned + 'static,
+        K: Serialize + DeserializeOwned + Hash + Eq + 'static
+    {
+        self.migrations.insert(migrations);
+        self
+    }
+
+
+    pub async fn with_datastore<'a, K, Doc>(mut self, mut opts: Options<'a>) -> Result<Schema, SchemaError> 
     where
         Doc: Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Document,
         K:  Serialize
@@ -47,9 +101,42 @@ impl Schema {
             return Err(SchemaError::DatastoreAlreadyExist(opts.storage_name.to_owned()))
         }
 
-        match Storage::<K, Doc>::open(opts).await {
+        let replicated = matches!(opts.stype, StorageType::ReplicatedCopies(_));
+        if replicated {
+            match &self.node {
+                Some(node) => opts.node = Some(node.addr().to_owned()),
+                None => return Err(SchemaError::Err("ReplicatedCopies datastore need node of schema".to_owned()))
+            }
+        }
+
+        if let Some(mut migrations) = self.migrations.remove::<Migrations<K, Doc>>() {
+            if let Err(e) = migrations.run(&opts) {
+                return Err(SchemaError::Err(e))
+            }
+        }
+
+        let opened = match opts.lazy_load {
+            true => Storage::<K, Doc>::open_lazy(opts).await,
+            false => Storage::<K, Doc>::open(opts).await,
+        };
+
+        // recovery report is kept by datastore, see Database::recovery_report
+        match opened {
             Err(e) => Err(SchemaError::Err(e)),
-            Ok(ds) => {
+            Ok((ds, _)) => {
+                if let Some(node) = &self.node {
+                    if let Err(e) = node.join(&ds).await {
+                        return Err(SchemaError::Err(e))
+                    }
+                }
+
+                if let Some(log) = &self.transaction_log {
+                    if let Err(e) = recover(log, &ds).await {
+                        return Err(SchemaError::Err(e))
+                    }
+                }
+
+                self.sources.extend(ds.backup_source());
                 self.datastores.insert(ds);
                 Ok(self)
             }
@@ -70,7 +157,8 @@ impl Schema {
 
         match VecStorage::open(opts).await {
             Err(e) => Err(SchemaError::Err(e)),
-            Ok(ds) => {
+            Ok((ds, _)) => {
+                self.sources.extend(ds.backup_source());
                 self.datastores.insert(ds);
                 Ok(self)
             }
@@ -112,9 +200,40 @@ impl Schema {
 
 
     pub fn build(self) -> Database {
-        Database::open(self.datastores)
+        Database::with_sources(self.datastores, self.sources, self.node, self.transaction_log)
+    }
+
+}
+
+
+
+// complete committed transactions of datastore, lazy opened datastore is read-only
+// until it is loaded and completed them, so no write of it is applied before them
+async fn recover<K, Doc>(log: &Arc<TransactionLog>, ds: &Storage<K, Doc>) -> Result<(), String>
+where
+    Doc: Serialize + DeserializeOwned + Clone + Sync + Send + 'static + Document,
+    K: Serialize + DeserializeOwned + PartialOrd + Ord + PartialEq + Eq + Hash + Clone + Send + Sync + 'static
+{
+    if ds.is_loaded() {
+        return log.recover(ds).await
     }
 
+    match ds.disk_path() {
+        Some(path) if log.pending(path)? => {}
+        _ => return Ok(())
+    }
+
+    ds.set_read_only(true);
+
+    // datastore that failed to complete them stay read-only
+    let (log, ds) = (log.clone(), ds.share());
+    tokio::spawn(async move {
+        if ds.ready().await.is_ok() && log.recover(&ds).await.is_ok() {
+            ds.set_read_only(false);
+        }
+    });
+
+    Ok(())
 }
 
 
@@ -1,51 +1,108 @@
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use std::cmp::Ordering as CmpOrdering;
 use std::hash::Hash;
-use tokio::sync::mpsc::Sender;
+use std::ops::Bound;
+use std::collections::{HashMap, HashSet};
+use std::sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex};
+use simple_wal::LogFile;
+use tokio::sync::{mpsc::Sender, Mutex as KeyMutex, Notify, OwnedMutexGuard, RwLock, RwLockReadGuard};
 
 use dashmap::{iter::Iter, mapref::one::Ref, DashMap, DashSet};
 
 
 use super::{
-    wal::disk_log::{DiskLog, Session},
+    wal::{disk_log::{self, DiskLog, Session}, codec::{self, Keyring, PageHeader, Stamp}, reader, snapshot, patch, compaction::{self, CompactionStats}, recovery::{self, RecoveryReport}, backup::{self, Backup, Source}},
     index::{hash::HashIndex, range::RangeIndex, tags::TagIndex, inverted_index::InvertedIndex},
     router::{self, Router},
-    Options, StatusResult, StorageType,
+    replica::{Peers, Pending},
+    migration,
+    Options, StatusResult, StorageType, Checkpoint, LoadProgress,
 };
 
-use crate::{darkbird::SessionResult, document::Document};
+use crate::{darkbird::SessionResult, document::{Document, RangeQuery, RangeValue}};
 
 
 
 pub struct Storage<K, Doc: Document> {
+    // collection and indexes are shared with background loader of lazy open
+
     // DashMap
-    collection: DashMap<K, Doc>,
+    collection: Arc<DashMap<K, Doc>>,
+
+    // version of every key, insert increment it and remove drop it
+    versions: Arc<DashMap<K, u64>>,
+
+    // writer of a key hold its lock from reading version of key until its record
+    // is logged and applied, so every write of key get its own version
+    key_locks: Arc<DashMap<K, Arc<KeyMutex<()>>>>,
 
     // HashIndex
-    hash_index: HashIndex<K>,
+    hash_index: Arc<HashIndex<K>>,
 
     // TagIndex
-    tag_index: TagIndex<K>,
+    tag_index: Arc<TagIndex<K>>,
 
     // RangeIndex
-    range_index: RangeIndex<K>,
+    range_index: Arc<RangeIndex<K>>,
 
     // InvertedIndex
-    inverted_index: InvertedIndex<K>,
+    inverted_index: Arc<InvertedIndex<K>>,
 
     // Wal session
     wal_session: Session,
 
+    // held by every handle of storage, None for RamCopies
+    flush_on_drop: Option<Arc<FlushOnDrop>>,
+
     // Reporter session
     reporter_session: router::Session<Event<K, Doc>>,
 
     off_reporter: bool,
 
-    off_disk: bool
+    off_disk: bool,
+
+    // datastore directory
+    path: String,
+
+    total_page_size: usize,
+
+    // records are encoded by format of it
+    header: PageHeader,
+
+    // keys of encrypted pages
+    keys: Keyring,
+
+    checkpoint: Option<Checkpoint>,
+
+    // writers hold it shared while logging and applying a record,
+    // checkpoint hold it exclusive while sealing page
+    gate: Arc<RwLock<()>>,
+
+    // count of records logged after latest checkpoint
+    logged: Arc<AtomicUsize>,
+
+    // true while checkpoint or compaction is running
+    maintenance: Arc<AtomicBool>,
+
+    // error of latest checkpoint that is run by writes, None after it succeed
+    checkpoint_error: Arc<Mutex<Option<String>>>,
+
+    // what dropped from pages on open
+    recovery_report: RecoveryReport,
+
+    // done when pages are loaded
+    loading: Arc<Loading>,
+
+    // true while storage is replication follower, just replicated records are written
+    read_only: Arc<AtomicBool>,
+
+    // other nodes of ReplicatedCopies datastore
+    peers: Option<Arc<Peers>>,
 }
 
 impl<K, Doc> Storage<K, Doc>
 where
-    Doc: Serialize + DeserializeOwned + Clone + Send + 'static + Document,
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
     K: Serialize
         + DeserializeOwned
         + PartialOrd
@@ -58,9 +115,54 @@ where
         + Sync
         + 'static,
 {
-    pub async fn open<'a>(ops: Options<'a>) -> Result<Self, String> {
+    /// open storage and load it, recovery report is what open dropped from corrupt pages
+    /// according to `RecoveryPolicy`, it is also kept by `recovery_report`
+    pub async fn open<'a>(ops: Options<'a>) -> Result<(Self, RecoveryReport), String> {
+        let (load_threads, load_progress) = (ops.load_threads, ops.load_progress.clone());
+        let (mut st, off_disk) = Self::prepare(ops)?;
+
+        // load from disk
+        if let Err(x) = st.loader(load_threads, load_progress).await {
+            if x != "End" {
+                return Err(x);
+            } 
+        }
+
+        // because we want loader dont write to disk_log
+        st.off_disk = off_disk;
+
+        let report = st.recovery_report.clone();
+        Ok((st, report))
+    }
+
+
+    // open disk_log and create storage that is not loaded, off_disk of it is true
+    fn prepare(ops: Options) -> Result<(Self, bool), String> {
+
+        let path = format!("{}/{}", ops.path, ops.storage_name);
+
+        // restore and migration stopped by a crash are finished or undone before datastore is opened,
+        // restore first because old datastore of it is taken back by migration recover
+        backup::recover(ops.path, ops.storage_name)?;
+        migration::recover(ops.path, ops.storage_name)?;
+        let total_page_size = disk_log::page_size(ops.total_page_size);
+        let keys = Keyring::new(ops.keys.clone());
+        let header = PageHeader::new(ops.format, ops.compression, keys.encryption());
+
+        let peers = match &ops.stype {
+            StorageType::ReplicatedCopies(nodes) => Some(Peers::start(nodes, ops.node.as_deref(), ops.storage_name, ops.quorum)?),
+            _ => None
+        };
+
+        // missing key must fail open, not treated as corrupt records
+        codec::check_keys(&path, total_page_size, &keys)?;
+
+        // repair corrupt pages before disk_log open tail page
+        let recovery_report = recovery::repair(&path, total_page_size, ops.recovery, |page_header, bytes| {
+            page_header.decode::<RQuery<K, Doc>>(bytes, &keys).is_ok()
+        })?;
         
-        match DiskLog::open(ops.path, ops.storage_name, ops.total_page_size) {
+        match DiskLog::open(ops.path, ops.storage_name, ops.total_page_size, ops.durability, header, keys.clone()) {
             Err(e) => return Err(e.to_string()),
             Ok(disklog) => {
                 // Run DiskLog
@@ -74,31 +176,39 @@ where
 
 
                 // Create Storage
-                let mut st = Storage {
-                    collection: DashMap::new(),
-                    hash_index: HashIndex::new(),
-                    tag_index: TagIndex::new(),
-                    range_index: RangeIndex::new(),
-                    inverted_index: InvertedIndex::new(),
+                let st = Storage {
+                    collection: Arc::new(DashMap::new()),
+                    versions: Arc::new(DashMap::new()),
+                    key_locks: Arc::new(DashMap::new()),
+                    hash_index: Arc::new(HashIndex::new()),
+                    tag_index: Arc::new(TagIndex::new()),
+                    range_index: Arc::new(RangeIndex::new()),
+                    inverted_index: Arc::new(InvertedIndex::new()),
+                    flush_on_drop: (!off_disk).then(|| Arc::new(FlushOnDrop(wal_session.clone()))),
                     wal_session: wal_session,
                     reporter_session: reporter,
                     off_reporter: ops.off_reporter,
-                    off_disk: true
+                    off_disk: true,
+                    path,
+                    total_page_size,
+                    header,
+                    keys,
+                    recovery_report,
+                    checkpoint: ops.checkpoint,
+                    gate: Arc::new(RwLock::new(())),
+                    logged: Arc::new(AtomicUsize::new(0)),
+                    maintenance: Arc::new(AtomicBool::new(false)),
+                    checkpoint_error: Arc::new(Mutex::new(None)),
+                    loading: Arc::new(Loading::new()),
+                    read_only: Arc::new(AtomicBool::new(false)),
+                    peers,
                 };
 
 
-                // load from disk
-                if let Err(x) = st.loader().await {
-                    if x != "End" {
-                        return Err(x);
-                    } 
-                }
-
+                // complete or discard interrupted compaction
+                compaction::recover(&st.path, st.total_page_size)?;
 
-                // because we want loader dont write to disk_log
-                st.off_disk = off_disk;
-
-                return Ok(st);
+                return Ok((st, off_disk));
             }
         }
 
@@ -120,112 +230,357 @@ where
         self.reporter_session.register(sender).await
     }
 
-    /// insert to storage and persist to disk
+    /// insert to storage and persist to disk, ReplicatedCopies wait until quorum of nodes applied it
+    /// (write stay applied on this node if quorum is not reached)
     #[inline]
     pub async fn insert(&self, key: K, doc: Doc) -> Result<(), SessionResult> {
+        self.writable()?;
+        self.insert_entry(key, doc, None, None, Origin::Local).await
+    }
+
+    // version is next version of key, if it is not written with record.
+    // write fail with conflict if version of key is not expected
+    async fn insert_entry(&self, key: K, doc: Doc, version: Option<u64>, expected: Option<u64>, origin: Origin) -> Result<(), SessionResult> {
+        self.ready().await?;
+
+        let gate = self.gate.read().await;
+        let key_lock = self.lock_key(&key).await;
+        self.check_version(&key, expected)?;
+
+        if origin == Origin::Peer && self.is_stale(&key, Some(&doc), version.unwrap_or_default()) {
+            return Ok(())
+        }
+        let version = version.unwrap_or_else(|| self.version(&key).unwrap_or(0) + 1);
+
+        // document that index reject is not logged, its index keys are held until it is applied
+        let claimed = self.hash_index.claim(&key, &doc).map_err(SessionResult::Err)?;
+
+        if !self.off_disk {
+            let res = match self.header.encode(&RQuery::Versioned(key.clone(), doc.clone(), version)) {
+                Ok(bytes) => self.wal_session.log(bytes).await,
+                Err(e) => Err(SessionResult::Err(StatusResult::Err(e)))
+            };
+
+            if let Err(e) = res {
+                self.hash_index.release(&claimed);
+                return Err(e)
+            }
+        }
+
+        if !self.off_reporter {
+            let _ = self.reporter_session.dispatch(Event::Query(RQuery::Insert(key.clone(), doc.clone()))).await;
+        }
+
+        let pending = self.replicate(origin, || RQuery::Versioned(key.clone(), doc.clone(), version)).await;
+
+        let res = self.index(key, doc, version).await;
+        self.hash_index.release(&claimed);
+        res.map_err(SessionResult::Err)?;
+
+        drop(key_lock);
+        drop(gate);
+        self.auto_checkpoint().await;
+
+        quorum(pending).await
+    }
+
+    // caller hold lock of key, so version is not changed until its write is applied
+    fn check_version(&self, key: &K, expected: Option<u64>) -> Result<(), SessionResult> {
+        let actual = self.version(key).unwrap_or(0);
+        match expected {
+            Some(expected) if expected != actual => Err(SessionResult::Err(StatusResult::Conflict { expected, actual })),
+            _ => Ok(())
+        }
+    }
+
+    // record of other node is stale if key has a newer version, of same version greater document
+    // win, so nodes that wrote key at same time keep same document. remove of missing key is stale
+    fn is_stale(&self, key: &K, doc: Option<&Doc>, version: u64) -> bool {
+        let local = match self.version(key) {
+            Some(local) => local,
+            None => return doc.is_none()
+        };
+
+        match (local.cmp(&version), doc) {
+            (CmpOrdering::Greater, _) => true,
+            (CmpOrdering::Less, _) | (CmpOrdering::Equal, None) => false,
+            (CmpOrdering::Equal, Some(doc)) => match self.collection.get(key) {
+                Some(local) => bincode::serialize(local.value()).ok() >= bincode::serialize(doc).ok(),
+                None => false
+            }
+        }
+    }
+
+    // send record of local write to peers while its keys are locked, so every peer get writes
+    // of a key in order this node applied them. None if record is not sent to any peer
+    async fn replicate<F>(&self, origin: Origin, rq: F) -> Result<Option<Pending>, SessionResult>
+    where
+        F: FnOnce() -> RQuery<K, Doc>,
+    {
+        let peers = match (&self.peers, origin) {
+            (Some(peers), Origin::Local) => peers,
+            _ => return Ok(None)
+        };
+
+        let record = self.seal_replicated(&rq()).map_err(|e| SessionResult::Err(StatusResult::Err(e)))?;
+        peers.enqueue(self.name(), &self.replicated_header(), record).await.map(Some)
+    }
+
+    // lock of key for writers of it, in order they asked for it
+    async fn lock_key(&self, key: &K) -> KeyLock<'_, K> {
+        let lock = self.key_locks.entry(key.clone()).or_default().clone();
+        let guard = lock.lock_owned().await;
+        KeyLock { locks: &self.key_locks, key: key.clone(), guard: Some(guard) }
+    }
+
+    // insert to indexes and memory, indexes of previous document of key are moved to new one.
+    // caller hold lock of key, so previous document is copied and no entry of collection is
+    // held while indexes are locked. nothing is written if key of hash_index is duplicate
+    async fn index(&self, key: K, doc: Doc, version: u64) -> Result<(), StatusResult> {
+        let old = self.collection.get(&key).map(|old| old.value().clone());
+        self.reindex(&key, old.as_ref(), &doc)?;
+
+        let (old_content, new_content) = (old.and_then(|old| old.get_content()), doc.get_content());
+        self.versions.insert(key.clone(), version);
+        self.collection.insert(key.clone(), doc);
+
+        // Insert to InvertedIndex
+        if old_content != new_content {
+            let _ = self.inverted_index.update(key, old_content, new_content).await;
+        }
+
+        Ok(())
+    }
+
+    /// remove from storage and persist to disk, ReplicatedCopies wait until quorum of nodes applied it
+    /// (write stay applied on this node if quorum is not reached)
+    #[inline]
+    pub async fn remove(&self, key: K) -> Result<(), SessionResult> {
+        self.writable()?;
+        self.remove_entry(key, None, None, Origin::Local).await
+    }
+
+    // version is version of removed document, for record of other node.
+    // remove fail with conflict if version of key is not expected
+    async fn remove_entry(&self, key: K, version: Option<u64>, expected: Option<u64>, origin: Origin) -> Result<(), SessionResult> {
+        self.ready().await?;
+
+        let gate = self.gate.read().await;
+        let key_lock = self.lock_key(&key).await;
+        self.check_version(&key, expected)?;
+
+        let stale = match origin {
+            Origin::Peer => self.is_stale(&key, None, version.unwrap_or_default()),
+            _ => !self.collection.contains_key(&key)
+        };
+        if stale {
+            return Ok(())
+        }
 
         if !self.off_disk || !self.off_reporter {
-            let query = RQuery::Insert(key.clone(), doc.clone());
+            let query = RQuery::<K, Doc>::Remove(key.clone());
 
             if !self.off_disk {
-                if let Err(e) = self.wal_session.log(bincode::serialize(&query).unwrap()).await {
-                    return Err(e);
-                }
+                let bytes = self.header.encode(&query).map_err(|e| SessionResult::Err(StatusResult::Err(e)))?;
+                self.wal_session.log(bytes).await?;
             }
 
             if !self.off_reporter {
                 let _ = self.reporter_session.dispatch(Event::Query(query)).await;
             }
-
+            
         }
 
-    
-        // Insert to indexes
-        if let Err(e) = self.hash_index.insert(&key, &doc) {
-            return Err(SessionResult::Err(e))
-        }
-        
+        // peers remove document just if they have not a newer one
+        let pending = self.replicate(origin, || RQuery::Removed(key.clone(), self.version(&key).unwrap_or_default())).await;
+
+        self.unindex(&key).await;
 
-        // Insert to view
+        drop(key_lock);
+        drop(gate);
+        self.auto_checkpoint().await;
+
+        quorum(pending).await
+    }
+
+    // remove from indexes and memory, removed document is returned with its version
+    async fn unindex(&self, key: &K) -> Option<(Doc, u64)> {
+        let (key, doc) = self.collection.remove(key)?;
+        let version = self.versions.remove(&key).map(|(_, version)| version).unwrap_or_default();
+
+        // remove from hash_index
+        self.hash_index.remove(&key, &doc);
+
+        // remove from view
         if let Some(view_name) = doc.filter() {
-            self.tag_index.insert_view(&view_name, &key)
+            self.tag_index.remove_from_view(&view_name, &key)
         }
 
-
-        // Insert to InvertedIndex
+        // remove from invertedIndex
         if let Some(content) = doc.get_content() {
-            let _ = self.inverted_index.insert(key.clone(), content).await;
+            let _ = self.inverted_index.remove(key.clone(), content).await;
         }
 
+        // remove from tag_index
+        self.tag_index.remove(&key, &doc);
 
-        // Insert to tag_index
-        self.tag_index.insert(&key, &doc);
+        // remove to range
+        self.range_index.remove(&key, &doc);
 
+        Some((doc, version))
+    }
 
-        // Insert to range
-        self.range_index.insert(&key, &doc);
+    // move key from index entries of old document to entries of new document, entries that
+    // both of them have are kept. nothing is changed if key of hash_index of new document is duplicate,
+    // inverted index is updated by a task, so it is left to caller
+    fn reindex(&self, key: &K, old: Option<&Doc>, new: &Doc) -> Result<(), StatusResult> {
+        self.hash_index.update(key, old, new)?;
+        self.tag_index.update_view(key, old.and_then(|old| old.filter()), new.filter());
+        self.tag_index.update(key, old, new);
+        self.range_index.update(key, old, new);
+        Ok(())
+    }
 
+    /// run f on a transaction and commit its writes together, writes are validated
+    /// against every index before any of them is applied and logged to disk as one record,
+    /// so after crash all of them or none of them is loaded. error of f or of commit discard all writes,
+    /// but writes that are sent to other nodes of ReplicatedCopies stay applied if quorum is not reached
+    pub async fn transaction<F, T>(&self, f: F) -> Result<T, SessionResult>
+    where
+        F: FnOnce(&mut Transaction<'_, K, Doc>) -> Result<T, SessionResult>,
+    {
+        self.writable()?;
+
+        let mut tx = Transaction { storage: self, staged: Staged::new() };
+        let res = f(&mut tx)?;
+
+        let (batch, expected) = match tx.staged.into_batch() {
+            Some(batch) => batch,
+            None => return Ok(res)
+        };
+
+        self.commit_entry(batch, &expected, Origin::Local).await?;
+        Ok(res)
+    }
 
-        // Insert to memory
-        self.collection.insert(key, doc);
+    // commit Batch, or Transaction of database that its id is kept in wal
+    async fn commit_entry(&self, batch: RQuery<K, Doc>, expected: &[(K, u64)], origin: Origin) -> Result<(), SessionResult> {
+        let txid = match &batch {
+            RQuery::Transaction(txid, _) => Some(*txid),
+            _ => None
+        };
+
+        let prepared = match self.prepare_batch(batch, expected, origin).await? {
+            Some(prepared) => prepared,
+            None => return Ok(())
+        };
+
+        if let Err(e) = prepared.log(txid).await {
+            prepared.rollback();
+            return Err(e)
+        }
 
-        Ok(())
+        prepared.finish().await
+    }
 
+    /// validate writes of batch and versions of keys, keys of batch are locked until returned
+    /// Prepared is finished or rolled back, nothing is applied before it is finished. None if batch change nothing
+    pub(crate) async fn prepare_batch(&self, batch: RQuery<K, Doc>, expected: &[(K, u64)], origin: Origin) -> Result<Option<Prepared<'_, K, Doc>>, SessionResult> {
+        self.ready().await?;
+
+        // latest write of every key, in order keys are written first,
+        // version is kept for writes that are written with their version
+        let mut order = Vec::new();
+        let mut staged = HashMap::new();
+        for rq in batch.flatten() {
+            let (key, write) = match rq {
+                RQuery::Insert(key, doc) => (key, (Some(doc), None)),
+                RQuery::Versioned(key, doc, version) => (key, (Some(doc), Some(version))),
+                RQuery::Remove(key) => (key, (None, None)),
+                RQuery::Removed(key, version) => (key, (None, Some(version))),
+                _ => unreachable!(),
+            };
 
-    }
+            if staged.insert(key.clone(), write).is_none() {
+                order.push(key);
+            }
+        }
 
-    /// remove from storage and persist to disk
-    #[inline]
-    pub async fn remove(&self, key: K) -> Result<(), SessionResult> {
-        match self.collection.get(&key) {
-            Some(doc) => {
+        // writers of other keys are not blocked, keys are locked in order so batches don't deadlock
+        let gate = self.gate.read().await;
+        let mut keys = order.iter().chain(expected.iter().map(|(key, _)| key)).cloned().collect::<Vec<K>>();
+        keys.sort();
+        keys.dedup();
 
-                if !self.off_disk || !self.off_reporter {
-                    let query = RQuery::<K, Doc>::Remove(key.clone());
-        
-                    if !self.off_disk {
-                        if let Err(e) = self.wal_session.log(bincode::serialize(&query).unwrap()).await {
-                            return Err(e);
-                        }
-                    }
-        
-                    if !self.off_reporter {
-                        let _ = self.reporter_session.dispatch(Event::Query(query)).await;
-                    }
-                    
-                }
+        let mut locks = Vec::with_capacity(keys.len());
+        for key in keys.iter() {
+            locks.push(self.lock_key(key).await);
+        }
+
+        for (key, expected) in expected {
+            self.check_version(key, Some(*expected))?;
+        }
 
-                // remove from hash_index
-                self.hash_index.remove(doc.value());
+        // remove of missing key and stale write of other node are not written
+        staged.retain(|key, (doc, version)| match origin {
+            Origin::Peer => !self.is_stale(key, doc.as_ref(), version.unwrap_or_default()),
+            _ => doc.is_some() || self.collection.contains_key(key)
+        });
+        order.retain(|key| staged.contains_key(key));
+        if order.is_empty() {
+            return Ok(None)
+        }
 
-                // remove from view
-                if let Some(view_name) = doc.filter() {
-                    self.tag_index.remove_from_view(&view_name, &key)
+        // final documents must not take key of hash_index from each other or from a key that
+        // batch does not write, index keys are held until writes are applied
+        let mut claimed = Vec::new();
+        for key in order.iter() {
+            if let Some((Some(doc), _)) = staged.get(key) {
+                match self.hash_index.claim_from(key, doc, |owner| staged.contains_key(owner)) {
+                    Ok(index_keys) => claimed.extend(index_keys),
+                    Err(e) => {
+                        self.hash_index.release(&claimed);
+                        return Err(SessionResult::Err(e))
+                    }
                 }
+            }
+        }
 
-                // remove from invertedIndex
-                if let Some(content) = doc.value().get_content() {
-                    let _ = self.inverted_index.remove(key.clone(), content).await;
+        let writes = order
+            .into_iter()
+            .map(|key| match staged.remove(&key) {
+                Some((Some(doc), version)) => {
+                    let version = version.unwrap_or_else(|| self.version(&key).unwrap_or(0) + 1);
+                    RQuery::Versioned(key, doc, version)
                 }
+                _ => {
+                    let version = self.version(&key).unwrap_or_default();
+                    RQuery::Removed(key, version)
+                }
+            })
+            .collect();
 
-                // remove from tag_index
-                self.tag_index.remove(&key, doc.value());
-
-                // remove to range
-                self.range_index.remove(&key, doc.value());
+        Ok(Some(Prepared { storage: self, gate, locks, claimed, writes, origin }))
+    }
 
+    // true if wal has Transaction of txid, every page exist is searched, so it is found
+    // even if datastore logged other records after it
+    pub(crate) fn logged_transaction(&self, txid: u64) -> Result<bool, String> {
+        let mut tail = reader::Tail::open(self.path.clone(), self.total_page_size, reader::StartAt::Beginning)?;
+        while let Some((header, record)) = tail.try_next()? {
+            if let RQuery::Transaction(logged, _) = header.decode::<RQuery<K, Doc>>(&record, &self.keys)? {
+                if logged == txid {
+                    return Ok(true)
+                }
             }
-            None => return Ok(()),
         }
 
-        self.collection.remove(&key);
-
-        Ok(())
+        Ok(false)
     }
 
     /// gets documents  
     #[inline]
-    pub fn gets(&self, list: Vec<&K>) -> Vec<Ref<K, Doc>> {
+    pub fn gets(&self, list: Vec<&K>) -> Vec<Ref<'_, K, Doc>> {
         let mut result = Vec::with_capacity(list.len());
 
         list.iter().for_each(|key| {
@@ -239,7 +594,7 @@ where
 
         /// gets documents  
         #[inline]
-        pub fn gets_by_value(&self, list: Vec<K>) -> Vec<Ref<K, Doc>> {
+        pub fn gets_by_value(&self, list: Vec<K>) -> Vec<Ref<'_, K, Doc>> {
             let mut result = Vec::with_capacity(list.len());
     
             list.iter().for_each(|key| {
@@ -251,13 +606,23 @@ where
             result
         }
 
-    /// fetch document by range hash_index
+    /// fetch document by range index, from is included and to is excluded
     #[inline]
-    pub fn range(&self, field_name: &str, from: String, to: String) -> Vec<Ref<K, Doc>> {
+    pub fn range(&self, field_name: &str, from: impl Into<RangeValue>, to: impl Into<RangeValue>) -> Vec<Ref<'_, K, Doc>> {
+        let query = RangeQuery::new(field_name)
+            .with_from(Bound::Included(from))
+            .with_to(Bound::Excluded(to));
+
+        self.range_query(&query)
+    }
+
+    /// fetch document by range index in order of value
+    #[inline]
+    pub fn range_query(&self, query: &RangeQuery) -> Vec<Ref<'_, K, Doc>> {
         let mut result = Vec::new();
 
         // collect and distinct keys
-        for k in self.range_index.range(field_name, from, to) {
+        for k in self.range_index.range(query) {
             if let Some(r) = self.collection.get(&k) {
                 result.push(r);
             }
@@ -268,59 +633,154 @@ where
 
     /// lookup by key
     #[inline]
-    pub fn lookup(&self, key: &K) -> Option<Ref<K, Doc>> {
-        return self.collection.get(key);
+    pub fn lookup(&self, key: &K) -> Option<Ref<'_, K, Doc>> {
+        self.collection.get(key)
+    }
+
+    /// lookup by key with version of document, version can be passed to
+    /// insert_if_version or compare_and_swap for writing it just if it is not changed
+    #[inline]
+    pub fn lookup_with_version(&self, key: &K) -> Option<(Ref<'_, K, Doc>, u64)> {
+        let doc = self.collection.get(key)?;
+        let version = self.version(key).unwrap_or_default();
+        Some((doc, version))
     }
 
-    /// lookup by hash_index
+    /// version of key, first insert of key is version 1 and every insert increment it
     #[inline]
-    pub fn lookup_by_index(&self, index_key: &str) -> Option<Ref<K, Doc>> {
-        match self.hash_index.lookup(index_key) {
-            Some(rf) => {
-                self.collection.get(rf.value())
+    pub fn version(&self, key: &K) -> Option<u64> {
+        self.versions.get(key).map(|version| *version)
+    }
+
+    /// insert if version of key is expected version, expected 0 insert just if key not exist.
+    /// return new version or `StatusResult::Conflict` if key is changed
+    pub async fn insert_if_version(&self, key: K, doc: Doc, expected: u64) -> Result<u64, SessionResult> {
+        self.compare_and_swap(key, expected, Some(doc)).await
+    }
+
+    /// replace document of key by doc, or remove key if doc is None, if version of
+    /// key is expected version. return new version, 0 if key is removed
+    pub async fn compare_and_swap(&self, key: K, expected: u64, doc: Option<Doc>) -> Result<u64, SessionResult> {
+        self.writable()?;
+
+        // version is checked and changed under lock of key, so other keys are not blocked
+        match doc {
+            Some(doc) => self.insert_entry(key, doc, None, Some(expected), Origin::Local).await.map(|_| expected + 1),
+            None => self.remove_entry(key, None, Some(expected), Origin::Local).await.map(|_| 0)
+        }
+    }
+
+    /// change document of key by f, indexes are updated from old and new document
+    /// and just patch of it is logged to disk. return new version, None if key not exist.
+    /// nothing is changed if new document has a duplicate key of hash_index
+    pub async fn update<F>(&self, key: K, f: F) -> Result<Option<u64>, SessionResult>
+    where
+        F: FnOnce(&mut Doc),
+    {
+        self.writable()?;
+        self.ready().await?;
+
+        // patch is made from current document, so no other writer of key change it until new one is published
+        let gate = self.gate.read().await;
+        let key_lock = self.lock_key(&key).await;
+
+        let (old, version) = match self.lookup_with_version(&key) {
+            Some((old, version)) => (old.value().clone(), version),
+            None => return Ok(None)
+        };
+
+        let mut new = old.clone();
+        f(&mut new);
+        if bincode::serialize(&old).ok() == bincode::serialize(&new).ok() {
+            return Ok(Some(version))
+        }
+        let version = version + 1;
+
+        // readers see old document until new one is logged
+        let claimed = self.hash_index.claim(&key, &new).map_err(SessionResult::Err)?;
+
+        if !self.off_disk {
+            // document that can not be patched is logged as a whole
+            let query = match patch::diff(&old, &new) {
+                Some(patch) => RQuery::Update(key.clone(), patch, version),
+                None => RQuery::Versioned(key.clone(), new.clone(), version),
+            };
+
+            let res = match self.header.encode(&query) {
+                Ok(bytes) => self.wal_session.log(bytes).await,
+                Err(e) => Err(SessionResult::Err(StatusResult::Err(e)))
+            };
+
+            if let Err(e) = res {
+                self.hash_index.release(&claimed);
+                return Err(e)
             }
-            None => None
         }
+
+        if !self.off_reporter {
+            let _ = self.reporter_session.dispatch(Event::Query(RQuery::Insert(key.clone(), new.clone()))).await;
+        }
+
+        // peers get whole document, because their copy may not be same as old one
+        let pending = self.replicate(Origin::Local, || RQuery::Versioned(key.clone(), new.clone(), version)).await;
+
+        let res = self.index(key, new, version).await;
+        self.hash_index.release(&claimed);
+        res.map_err(SessionResult::Err)?;
+
+        drop(key_lock);
+        drop(gate);
+        self.auto_checkpoint().await;
+
+        quorum(pending).await?;
+        Ok(Some(version))
     }
 
-    /// lookup by tag
+    /// lookup by value of hash_index, (e.g. lookup_by_index("email", "foo@x"))
     #[inline]
-    pub fn lookup_by_tag(&self, tag: &str) -> Vec<Ref<K, Doc>> {
-        match self.tag_index.lookup(tag) {
-            Some(rf) => {
-                let mut result = Vec::with_capacity(rf.value().len());
-                for k in rf.value().iter() {
-                    if let Some(kd) = self.collection.get(&k) {
-                        result.push(kd);
-                    }  
-                }
-                result
+    pub fn lookup_by_index(&self, index_name: &str, value: &str) -> Option<Ref<'_, K, Doc>> {
+        match self.hash_index.lookup(index_name, value) {
+            Some(key) => {
+                self.collection.get(&key)
             }
-            None => vec![]
+            None => None
         }
     }
 
+    /// lookup every document with value of index, for non-unique index
+    #[inline]
+    pub fn lookup_all_by_index(&self, index_name: &str, value: &str) -> Vec<Ref<'_, K, Doc>> {
+        self.hash_index
+            .lookup_all(index_name, value)
+            .iter()
+            .filter_map(|key| self.collection.get(key))
+            .collect()
+    }
+
+    /// lookup by value of tag, (e.g. lookup_by_tag("role", "admin"))
+    #[inline]
+    pub fn lookup_by_tag(&self, tag_name: &str, value: &str) -> Vec<Ref<'_, K, Doc>> {
+        self.tag_index
+            .lookup(tag_name, value)
+            .iter()
+            .filter_map(|key| self.collection.get(key))
+            .collect()
+    }
+
     /// fetch view
     #[inline]
-    pub fn fetch_view(&self, view_name: &str) -> Vec<Ref<K, Doc>> {
-        match self.tag_index.lookup_view(view_name) {
-            Some(rf) => {
-                let mut result = Vec::with_capacity(rf.value().len());
-                for k in rf.value().iter() {
-                    if let Some(kd) = self.collection.get(&k) {
-                        result.push(kd);
-                    }  
-                }
-                result
-            }
-            None => vec![]
-        }
+    pub fn fetch_view(&self, view_name: &str) -> Vec<Ref<'_, K, Doc>> {
+        self.tag_index
+            .lookup_view(view_name)
+            .iter()
+            .filter_map(|key| self.collection.get(key))
+            .collect()
     }
 
 
     /// search by text
     #[inline]
-    pub fn search(&self, text: String) -> Vec<Ref<K, Doc>> {
+    pub fn search(&self, text: String) -> Vec<Ref<'_, K, Doc>> {
         let words: Vec<&str> = text.split_whitespace().collect();
         let keys = self.inverted_index.search(words);
         let mut result = Vec::with_capacity(keys.len());
@@ -340,15 +800,15 @@ where
         self.collection.iter()
     }
 
-    /// return Iter (Safe for mutation)
+    /// return Iter of indexes by name (Safe for mutation)
     #[inline]
-    pub fn iter_index(&self) -> Iter<'_, String, K> {
+    pub fn iter_index(&self) -> Iter<'_, String, DashMap<String, K>> {
         self.hash_index.iter()
     }
 
-    /// return Iter (Safe for mutation)
+    /// return Iter of tags by name (Safe for mutation)
     #[inline]
-    pub fn iter_tags(&self) -> Iter<String, DashSet<K>> {
+    pub fn iter_tags(&self) -> Iter<'_, String, DashMap<String, DashSet<K>>> {
         self.tag_index.iter()
     }
 
@@ -358,72 +818,915 @@ where
         self.collection.len()
     }
 
+    /// records dropped from corrupt pages on open
+    #[inline]
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
 
+    /// error of latest finished checkpoint run by Checkpoint.every, None if it succeeded
+    pub fn checkpoint_error(&self) -> Option<String> {
+        self.checkpoint_error.lock().unwrap().clone()
+    }
 
+    /// read-only storage reject insert and remove with `SessionResult::ReadOnly`,
+    /// replication follower is read-only until it is promoted
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Release)
+    }
 
-    /// load storage from disk
     #[inline]
-    async fn loader(&self) -> Result<(), String> {
-        // when storage just open with Disc Copies option it call loader, else it don't call
-        let wal = &self.wal_session;
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
 
-        let mut page_index = 1;
+    fn writable(&self) -> Result<(), SessionResult> {
+        match self.is_read_only() {
+            true => Err(SessionResult::ReadOnly),
+            false => Ok(())
+        }
+    }
 
-        loop {
-            // Get Page
-            let mut logfile = match wal.get_page(page_index).await {
-                Ok(lf) => lf,
-                Err(sess_res) => {
-                    if let SessionResult::Err(e) = sess_res {
-                        return Err(e.to_string())
-                    }
+    /// apply record replicated from leader, it is written even if storage is read-only
+    pub(crate) async fn apply_replicated(&self, header: &PageHeader, record: &[u8]) -> Result<Stamp, SessionResult> {
+        let (stamp, rq) = self.decode_replicated(header, record).map_err(|e| SessionResult::Err(StatusResult::Err(e)))?;
+        self.apply_query(rq).await?;
+        Ok(stamp)
+    }
+
+    // write RQuery of leader or of commit record as it is, it is not sent to peers
+    pub(crate) async fn apply_query(&self, rq: RQuery<K, Doc>) -> Result<(), SessionResult> {
+        match rq {
+            RQuery::Insert(key, doc) => self.insert_entry(key, doc, None, None, Origin::Replay).await,
+            RQuery::Versioned(key, doc, version) => self.insert_entry(key, doc, Some(version), None, Origin::Replay).await,
+            RQuery::Update(key, patch, version) => {
+                // records applied again after crash of follower may patch a document
+                // that a later record removed, that document is not written again
+                let doc = match self.lookup(&key) {
+                    Some(doc) => patch::apply(doc.value(), &patch).map_err(|e| SessionResult::Err(StatusResult::Err(e)))?,
+                    None => return Ok(())
+                };
+                self.insert_entry(key, doc, Some(version), None, Origin::Replay).await
+            }
+            RQuery::Remove(key) | RQuery::Removed(key, _) => self.remove_entry(key, None, None, Origin::Replay).await,
+            batch => self.commit_entry(batch, &[], Origin::Replay).await,
+        }
+    }
+
+    // write record of other node of ReplicatedCopies, write is skipped if key has a newer version
+    pub(crate) async fn apply_peer(&self, rq: RQuery<K, Doc>) -> Result<(), SessionResult> {
+        match rq {
+            RQuery::Versioned(key, doc, version) => self.insert_entry(key, doc, Some(version), None, Origin::Peer).await,
+            RQuery::Removed(key, version) => self.remove_entry(key, Some(version), None, Origin::Peer).await,
+            batch @ (RQuery::Batch(_) | RQuery::Transaction(_, _)) => self.commit_entry(batch, &[], Origin::Peer).await,
+            _ => Err(SessionResult::Err(StatusResult::Err("record of peer has not version".to_owned())))
+        }
+    }
+
+    pub(crate) fn decode_replicated(&self, header: &PageHeader, record: &[u8]) -> Result<(Stamp, RQuery<K, Doc>), String> {
+        Ok((header.stamp(record)?, header.decode(record, &self.keys)?))
+    }
+
+    // records sent to other nodes are encoded like page records without stamp
+    pub(crate) fn replicated_header(&self) -> PageHeader {
+        self.header.unstamped()
+    }
+
+    pub(crate) fn seal_replicated(&self, rq: &RQuery<K, Doc>) -> Result<Vec<u8>, String> {
+        self.replicated_header().seal(rq, Stamp::default(), &self.keys)
+    }
 
-                    return Err("disk_log closed".to_string())
+    pub(crate) fn peers(&self) -> Option<&Peers> {
+        self.peers.as_deref()
+    }
+
+    // handle to same datastore, for tasks that outlive borrow of storage
+    pub(crate) fn share(&self) -> Self {
+        Storage {
+            collection: self.collection.clone(),
+            versions: self.versions.clone(),
+            key_locks: self.key_locks.clone(),
+            hash_index: self.hash_index.clone(),
+            tag_index: self.tag_index.clone(),
+            range_index: self.range_index.clone(),
+            inverted_index: self.inverted_index.clone(),
+            wal_session: self.wal_session.clone(),
+            flush_on_drop: self.flush_on_drop.clone(),
+            reporter_session: self.reporter_session.clone(),
+            off_reporter: self.off_reporter,
+            off_disk: self.off_disk,
+            path: self.path.clone(),
+            total_page_size: self.total_page_size,
+            header: self.header,
+            keys: self.keys.clone(),
+            recovery_report: self.recovery_report.clone(),
+            checkpoint: self.checkpoint.clone(),
+            gate: self.gate.clone(),
+            logged: self.logged.clone(),
+            maintenance: self.maintenance.clone(),
+            checkpoint_error: self.checkpoint_error.clone(),
+            loading: self.loading.clone(),
+            read_only: self.read_only.clone(),
+            peers: self.peers.clone(),
+        }
+    }
+
+    // name of datastore
+    pub(crate) fn name(&self) -> &str {
+        std::path::Path::new(&self.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+    }
+
+    // datastore directory, RamCopies datastore has not any file in it
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    // datastore directory, None for RamCopies
+    pub(crate) fn disk_path(&self) -> Option<&str> {
+        match self.off_disk {
+            true => None,
+            false => Some(&self.path)
+        }
+    }
+
+    // RamCopies datastore has nothing to backup
+    pub(crate) fn backup_source(&self) -> Option<Source> {
+        if self.off_disk {
+            return None
+        }
+
+        Some(Source {
+            name: std::path::Path::new(&self.path).file_name()?.to_str()?.to_owned(),
+            path: self.path.clone(),
+            total_page_size: self.total_page_size,
+            session: self.wal_session.clone(),
+            gate: self.gate.clone(),
+            maintenance: self.maintenance.clone(),
+        })
+    }
+
+
+
+
+    /// backup just records logged after latest backup of storage (see `backup::backup_incremental`),
+    /// checkpoint and compaction wait until it is taken
+    pub async fn backup_incremental(&self) -> Result<Backup, SessionResult> {
+        let source = match self.backup_source() {
+            Some(source) => source,
+            None => return Err(SessionResult::Err(StatusResult::Err("RamCopies storage has not backup".to_owned())))
+        };
+
+        backup::backup_incremental(&source)
+            .await
+            .map_err(|e| SessionResult::Err(StatusResult::Err(e)))
+    }
+
+
+    /// take snapshot of collection and remove (or archive) pages older than it,
+    /// so next open load snapshot and replay just pages written after it
+    pub async fn checkpoint(&self) -> Result<(), SessionResult> {
+        if self.off_disk {
+            return Ok(())
+        }
+
+        // snapshot of partly loaded collection lose records
+        self.ready().await?;
+
+        // just one checkpoint or compaction at a time
+        if self.maintenance.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return Ok(())
+        }
+
+        let res = self.take_checkpoint().await;
+
+        self.maintenance.store(false, Ordering::Release);
+        res
+    }
+
+    async fn take_checkpoint(&self) -> Result<(), SessionResult> {
+        
+        // writers are blocked just while sealing page, after that
+        // every record of pages lower than page_index is applied to collection
+        let page_index = {
+            let _gate = self.gate.write().await;
+            self.wal_session.rotate().await?
+        };
+
+        self.logged.store(0, Ordering::Release);
+
+        let archive = match &self.checkpoint {
+            Some(cp) => cp.archive,
+            None => false
+        };
+
+        // snapshot is written from collection while writers go on
+        let storage = self.share();
+        let task = tokio::task::spawn_blocking(move || {
+            storage.write_snapshot(page_index)
+                .and_then(|_| snapshot::release_older(&storage.path, storage.total_page_size, page_index, archive))
+        });
+
+        match task.await {
+            Ok(res) => res.map_err(|e| SessionResult::Err(StatusResult::Err(e))),
+            Err(e) => Err(SessionResult::Err(StatusResult::Err(e.to_string()))),
+        }
+    }
+
+    fn write_snapshot(&self, page_index: usize) -> Result<(), String> {
+        let mut writer = snapshot::Writer::create(&self.path, page_index, self.header, self.keys.clone())?;
+        for entry in self.collection.iter() {
+            writer.append(entry.key(), entry.value(), self.version(entry.key()).unwrap_or_default())?;
+        }
+        writer.commit()
+    }
+
+    /// rewrite sealed pages in background and keep just latest RQuery per key,
+    /// writers are not blocked because disk_log continue on a new page
+    pub async fn compact(&self) -> Result<CompactionStats, SessionResult> {
+        if self.off_disk {
+            return Ok(CompactionStats::default())
+        }
+
+        // background loader may be reading pages that compaction rewrite
+        self.ready().await?;
+
+        // just one checkpoint or compaction at a time
+        if self.maintenance.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return Err(SessionResult::Err(StatusResult::Err("maintenance is running".to_owned())))
+        }
+
+        let res = match self.wal_session.rotate().await {
+            Ok(until_page) => {
+                let path = self.path.clone();
+                let total_page_size = self.total_page_size;
+                let header = self.header;
+                let keys = self.keys.clone();
+
+                let task = tokio::task::spawn_blocking(move || {
+                    compaction::compact::<K, Doc>(&path, total_page_size, until_page, header, &keys)
+                });
+
+                match task.await {
+                    Ok(Ok(stats)) => Ok(stats),
+                    Ok(Err(e)) => Err(SessionResult::Err(StatusResult::Err(e))),
+                    Err(e) => Err(SessionResult::Err(StatusResult::Err(e.to_string()))),
                 }
-            };
+            }
+            Err(e) => Err(e)
+        };
+
+        self.maintenance.store(false, Ordering::Release);
+        res
+    }
+
+    /// checkpoint in background if records logged after latest checkpoint reached to Checkpoint.every,
+    /// writer does not wait for it. if checkpoint or compaction is running, next write try again
+    #[inline]
+    async fn auto_checkpoint(&self) {
+        if self.off_disk {
+            return
+        }
+
+        let every = match &self.checkpoint {
+            Some(cp) => cp.every,
+            None => return
+        };
+
+        if self.logged.fetch_add(1, Ordering::AcqRel) + 1 < every {
+            return
+        }
+
+        if self.maintenance.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return
+        }
+
+        // write is logged anyway, failed checkpoint is tried again after Checkpoint.every records
+        let storage = self.share();
+        tokio::spawn(async move {
+            let res = storage.take_checkpoint().await;
+            storage.maintenance.store(false, Ordering::Release);
+            *storage.checkpoint_error.lock().unwrap() = res.err().map(|e| e.to_string());
+        });
+    }
+
+
+
+
+    /// load storage from disk, pages are decoded in parallel and loaded newest first,
+    /// same as background loader of lazy open, so both open to same state
+    async fn loader(&self, threads: usize, progress: Option<Arc<dyn Fn(LoadProgress) + Send + Sync>>) -> Result<(), String> {
+        load_newest_first(&self.tables(), &self.path, self.total_page_size, &self.keys, threads, progress, &self.loading)?;
+
+        self.loading.finish(Ok(()));
+        Ok(())
+    }
+
+
+    #[inline]
+    fn tables(&self) -> Tables<K, Doc> {
+        Tables {
+            collection: self.collection.clone(),
+            versions: self.versions.clone(),
+            hash_index: self.hash_index.clone(),
+            tag_index: self.tag_index.clone(),
+            range_index: self.range_index.clone(),
+            inverted_index: self.inverted_index.clone(),
+        }
+    }
 
-            page_index += 1;
 
-            // Must Call Recover if return Err, remove unwrap()
-            let iter = match logfile.iter(..) {
-                Ok(iter) => iter,
-                Err(e) => {
-                    eprintln!("==> {:?}", e);
-                    return Err(e.to_string());
+    /// false while lazy opened storage is loading in background
+    #[inline]
+    pub fn is_loaded(&self) -> bool {
+        self.loading.done.load(Ordering::Acquire)
+    }
+
+    /// wait until storage is loaded, error if background loading failed
+    pub async fn ready(&self) -> Result<(), SessionResult> {
+        loop {
+            let notified = self.loading.notify.notified();
+            if self.is_loaded() {
+                return match self.loading.error.lock().unwrap().clone() {
+                    Some(e) => Err(SessionResult::Err(StatusResult::Err(e))),
+                    None => Ok(())
                 }
-            };
+            }
+
+            notified.await;
+        }
+    }
+
+    /// lookup by key, `SessionResult::Loading` if key is not loaded yet
+    #[inline]
+    pub fn try_lookup(&self, key: &K) -> Result<Option<Ref<'_, K, Doc>>, SessionResult> {
+        match self.collection.get(key) {
+            Some(doc) => Ok(Some(doc)),
+            None if !self.is_loaded() => Err(SessionResult::Loading),
+            None => Ok(None)
+        }
+    }
+
+    /// lookup by key, wait while key is not loaded yet
+    pub async fn lookup_wait(&self, key: &K) -> Result<Option<Ref<'_, K, Doc>>, SessionResult> {
+        loop {
+            let notified = self.loading.notify.notified();
+
+            let res = self.try_lookup(key);
+            if !matches!(res, Err(SessionResult::Loading)) {
+                return res
+            }
+            drop(res);
+
+            notified.await;
+        }
+    }
+
+    /// open storage and return before it is loaded, pages are loaded in background newest first,
+    /// so a key is loaded with its latest value. writes, checkpoint and compaction wait until loading is done,
+    /// other reads see documents loaded so far, recovery report is returned same as `open`
+    pub async fn open_lazy<'a>(ops: Options<'a>) -> Result<(Self, RecoveryReport), String> {
+        let (threads, progress) = (ops.load_threads, ops.load_progress.clone());
+        let (mut st, off_disk) = Self::prepare(ops)?;
+
+        // background loader does not write to disk_log
+        st.off_disk = off_disk;
+
+        let tables = st.tables();
+        let loading = st.loading.clone();
+        let (path, total_page_size, keys) = (st.path.clone(), st.total_page_size, st.keys.clone());
+
+        tokio::task::spawn_blocking(move || {
+            let res = load_newest_first(&tables, &path, total_page_size, &keys, threads, progress, &loading);
+            loading.finish(res);
+        });
+
+        let report = st.recovery_report.clone();
+        Ok((st, report))
+    }
+}
+
+
+
+// records logged with Durability::Enqueued are flushed when last handle of storage is dropped,
+// so storage opened again after it see every write
+struct FlushOnDrop(Session);
+
+impl Drop for FlushOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.flush_blocking();
+    }
+}
+
+
+
+// writer of a record
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Origin {
+    // write of this node, it is sent to peers of ReplicatedCopies
+    Local,
+
+    // record of leader or of commit record, written as it is
+    Replay,
+
+    // record of other node of ReplicatedCopies, it is skipped if key has a newer version
+    Peer,
+}
+
+// wait until quorum of nodes applied write, write stay applied on this node if it is not reached
+async fn quorum(pending: Result<Option<Pending>, SessionResult>) -> Result<(), SessionResult> {
+    match pending? {
+        Some(pending) => pending.wait().await,
+        None => Ok(())
+    }
+}
+
+
+
+// lock of a key held by its writer, lock is dropped from map when no other writer wait for it
+struct KeyLock<'a, K: Hash + Eq> {
+    locks: &'a DashMap<K, Arc<KeyMutex<()>>>,
+    key: K,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl<'a, K: Hash + Eq> Drop for KeyLock<'a, K> {
+    fn drop(&mut self) {
+        self.guard.take();
+        self.locks.remove_if(&self.key, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
+
+
+
+// state of loading pages of storage
+struct Loading {
+    done: AtomicBool,
+    error: Mutex<Option<String>>,
+
+    // notified when loaded keys change and when loading is done
+    notify: Notify,
+}
+
+impl Loading {
+    fn new() -> Self {
+        Loading { done: AtomicBool::new(false), error: Mutex::new(None), notify: Notify::new() }
+    }
+
+    fn finish(&self, res: Result<(), String>) {
+        if let Err(e) = res {
+            *self.error.lock().unwrap() = Some(e);
+        }
+
+        self.done.store(true, Ordering::Release);
+        self.notify.notify_waiters();
+    }
+}
+
+
+
+// collection and indexes of storage
+struct Tables<K, Doc> {
+    collection: Arc<DashMap<K, Doc>>,
+    versions: Arc<DashMap<K, u64>>,
+    hash_index: Arc<HashIndex<K>>,
+    tag_index: Arc<TagIndex<K>>,
+    range_index: Arc<RangeIndex<K>>,
+    inverted_index: Arc<InvertedIndex<K>>,
+}
+
+impl<K, Doc> Tables<K, Doc>
+where
+    Doc: Document,
+    K: Serialize + DeserializeOwned + PartialOrd + Ord + Hash + Clone + Send + Sync + 'static,
+{
+    // insert loaded document to collection and every index, wal never has two documents
+    // with same index key, so a duplicate index key fail loading
+    fn publish(&self, key: K, doc: Doc, version: u64) -> Result<(), String> {
+        if self.hash_index.insert(&key, &doc).is_err() {
+            return Err("two loaded documents have same key of hash index".to_owned())
+        }
+
+        if let Some(view_name) = doc.filter() {
+            self.tag_index.insert_view(&view_name, &key)
+        }
+
+        if let Some(content) = doc.get_content() {
+            self.inverted_index.bulk_insert(&key, &content);
+        }
+
+        self.tag_index.insert(&key, &doc);
+        self.range_index.insert(&key, &doc);
+        self.versions.insert(key.clone(), version);
+        self.collection.insert(key, doc);
+        Ok(())
+    }
+}
 
-            for qline in iter {
-                let bytes = match qline {
-                    Ok(ql)  => ql,
-                    Err(e) => return Err(e.to_string()),
-                };
 
-                let query: RQuery<K, Doc> = match bincode::deserialize(&bytes) {
-                    Ok(rq) => rq,
-                    Err(e) => {
-                        return Err(e.to_string());
-                    }
-                };
 
+// resolve 0 to available parallelism
+fn load_threads(threads: usize) -> usize {
+    match threads {
+        0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        threads => threads
+    }
+}
+
+
+// load pages newest first, first RQuery seen of a key is the latest one, so it is published at once
+// and older RQuery of it are skipped, snapshot is oldest and loaded last. eager and lazy open both use it
+fn load_newest_first<K, Doc>(
+    tables: &Tables<K, Doc>,
+    path: &str,
+    total_page_size: usize,
+    keys: &Keyring,
+    threads: usize,
+    progress: Option<Arc<dyn Fn(LoadProgress) + Send + Sync>>,
+    loading: &Loading) -> Result<(), String>
+where
+    Doc: Serialize + DeserializeOwned + Send + Document,
+    K: Serialize + DeserializeOwned + PartialOrd + Ord + Hash + Clone + Send + Sync + 'static,
+{
+    let threads = load_threads(threads);
+    let snapshot = snapshot::latest(path)?;
+    let first_page = snapshot.as_ref().map(|(page_index, _)| *page_index).unwrap_or(1);
+
+    let mut pages = disk_log::list_pages(path, total_page_size)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter(|index| *index >= first_page)
+        .collect::<Vec<usize>>();
+    pages.reverse();
+
+    let mut seen = HashSet::new();
+    let mut status = LoadProgress { pages_loaded: 0, pages_total: pages.len(), records: 0 };
+    if let Some(progress) = &progress {
+        progress(status);
+    }
+
+    // latest Update of key is seen before document it patch, so its patches are kept
+    // newest first, with version of latest one, until that document is found
+    let mut patched: HashMap<K, (u64, Vec<Vec<u8>>)> = HashMap::new();
+
+    // record written before versions is next version of key, so version of key that its
+    // latest record is Insert is number of Inserts after its latest versioned record or Remove
+    let mut counting: HashMap<K, u64> = HashMap::new();
+
+    for batch in pages.chunks(threads * 2) {
+        for queries in decode_pages::<K, Doc>(path, total_page_size, keys, batch, threads)? {
+            status.records += queries.len();
+
+            for query in queries.into_iter().flat_map(RQuery::flatten).rev() {
                 match query {
-                    RQuery::Insert(key, doc) => {                        
-                        let _ = self.insert(key, doc).await;
+                    RQuery::Insert(key, doc) => {
+                        if let Some((version, patches)) = patched.remove(&key) {
+                            tables.publish(key, apply_patches(doc, &patches)?, version)?;
+                        } else if let Some(count) = counting.get_mut(&key) {
+                            *count += 1;
+                            tables.versions.insert(key, *count);
+                        } else if seen.insert(key.clone()) {
+                            tables.publish(key.clone(), doc, 1)?;
+                            counting.insert(key, 1);
+                        }
+                    }
+                    RQuery::Versioned(key, doc, version) => {
+                        if let Some((version, patches)) = patched.remove(&key) {
+                            tables.publish(key, apply_patches(doc, &patches)?, version)?;
+                        } else if let Some(count) = counting.remove(&key) {
+                            tables.versions.insert(key, version + count);
+                        } else if seen.insert(key.clone()) {
+                            tables.publish(key, doc, version)?;
+                        }
+                    }
+                    RQuery::Update(key, patch, version) => {
+                        if let Some((_, patches)) = patched.get_mut(&key) {
+                            patches.push(patch);
+                        } else if let Some(count) = counting.remove(&key) {
+                            tables.versions.insert(key, version + count);
+                        } else if seen.insert(key.clone()) {
+                            patched.insert(key, (version, vec![patch]));
+                        }
                     }
                     RQuery::Remove(key) => {
-                        let _ = self.remove(key).await;
+                        if patched.remove(&key).is_some() {
+                            return Err("update of missing document".to_owned())
+                        }
+                        counting.remove(&key);
+                        seen.insert(key);
                     }
+                    _ => unreachable!(),
                 }
             }
         }
+
+        status.pages_loaded += batch.len();
+        if let Some(progress) = &progress {
+            progress(status);
+        }
+        loading.notify.notify_waiters();
+    }
+
+    if let Some((_, filename)) = snapshot {
+        for (key, doc, version) in snapshot::read_versioned::<K, Doc>(&filename, keys)? {
+            status.records += 1;
+            if let Some((version, patches)) = patched.remove(&key) {
+                tables.publish(key, apply_patches(doc, &patches)?, version)?;
+            } else if let Some(count) = counting.remove(&key) {
+                tables.versions.insert(key, version + count);
+            } else if !seen.contains(&key) {
+                tables.publish(key, doc, version)?;
+            }
+        }
+
+        if let Some(progress) = &progress {
+            progress(status);
+        }
+    }
+
+    if !patched.is_empty() {
+        return Err("update of missing document".to_owned())
     }
+
+    Ok(())
 }
 
+
+
+// apply patches of newest first loader in order they written
+fn apply_patches<Doc>(doc: Doc, patches: &[Vec<u8>]) -> Result<Doc, String>
+where
+    Doc: Serialize + DeserializeOwned,
+{
+    patches.iter().rev().try_fold(doc, |doc, patch| patch::apply(&doc, patch))
+}
+
+
+
+// decode pages on threads, result is in order of pages
+fn decode_pages<K, Doc>(path: &str, total_page_size: usize, keys: &Keyring, pages: &[usize], threads: usize) -> Result<Vec<Vec<RQuery<K, Doc>>>, String>
+where
+    K: DeserializeOwned + Send,
+    Doc: DeserializeOwned + Send,
+{
+    let next = AtomicUsize::new(0);
+    let decoded = Mutex::new((0..pages.len()).map(|_| None).collect::<Vec<_>>());
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(pages.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index >= pages.len() {
+                    break
+                }
+
+                let filename = disk_log::filename_factory(path, total_page_size * pages[index]);
+                let res = LogFile::open(filename)
+                    .map_err(|e| e.to_string())
+                    .and_then(|mut page| codec::read_page::<RQuery<K, Doc>>(&mut page, keys));
+
+                decoded.lock().unwrap()[index] = Some(res);
+            });
+        }
+    });
+
+    decoded
+        .into_inner()
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|res| res.unwrap_or_else(|| Err("page is not decoded".to_owned())))
+        .collect()
+}
+
+/// writes staged by `Storage::transaction`, storage is not changed until commit
+pub struct Transaction<'a, K, Doc: Document> {
+    storage: &'a Storage<K, Doc>,
+    staged: Staged<K, Doc>,
+}
+
+impl<'a, K, Doc> Transaction<'a, K, Doc>
+where
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+    K: Serialize
+        + DeserializeOwned
+        + PartialOrd
+        + Ord
+        + PartialEq
+        + Eq
+        + Hash
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    pub fn insert(&mut self, key: K, doc: Doc) {
+        self.staged.stage(RQuery::Insert(key, doc))
+    }
+
+    pub fn remove(&mut self, key: K) {
+        self.staged.stage(RQuery::Remove(key))
+    }
+
+    /// transaction fail with `StatusResult::Conflict` if version of key is not
+    /// version on commit, 0 is expected version of key that not exist
+    pub fn expect_version(&mut self, key: K, version: u64) {
+        self.staged.expect(key, version)
+    }
+
+    /// lookup by key, writes staged before are seen
+    pub fn lookup(&self, key: &K) -> Option<Doc> {
+        match self.staged.get(key) {
+            Some(doc) => doc.clone(),
+            None => self.storage.lookup(key).map(|doc| doc.value().clone())
+        }
+    }
+
+    pub(crate) fn stage(&mut self, rq: RQuery<K, Doc>) {
+        self.staged.stage(rq)
+    }
+}
+
+
+// writes of a transaction and latest staged document of keys, None is removed
+pub(crate) struct Staged<K, Doc> {
+    writes: Vec<RQuery<K, Doc>>,
+    docs: HashMap<K, Option<Doc>>,
+
+    expected: Expected<K>,
+}
+
+// versions that keys must have on commit
+pub(crate) type Expected<K> = Vec<(K, u64)>;
+
+impl<K, Doc> Staged<K, Doc>
+where
+    K: Hash + Eq + Clone,
+    Doc: Clone,
+{
+    pub(crate) fn new() -> Self {
+        Staged { writes: Vec::new(), docs: HashMap::new(), expected: Vec::new() }
+    }
+
+    pub(crate) fn stage(&mut self, rq: RQuery<K, Doc>) {
+        for rq in rq.flatten() {
+            match &rq {
+                RQuery::Insert(key, doc) | RQuery::Versioned(key, doc, _) => { self.docs.insert(key.clone(), Some(doc.clone())); }
+                RQuery::Remove(key) => { self.docs.insert(key.clone(), None); }
+                _ => unreachable!(),
+            }
+            self.writes.push(rq);
+        }
+    }
+
+    pub(crate) fn expect(&mut self, key: K, version: u64) {
+        self.expected.push((key, version))
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Option<&Option<Doc>> {
+        self.docs.get(key)
+    }
+
+    // writes with expected versions, None if nothing is staged
+    pub(crate) fn into_batch(self) -> Option<(RQuery<K, Doc>, Expected<K>)> {
+        match self.writes.is_empty() {
+            true => None,
+            false => Some((RQuery::Batch(self.writes), self.expected))
+        }
+    }
+}
+
+
+/// validated writes of transaction, their keys are locked until
+/// they are logged and applied by finish or dropped by rollback
+pub(crate) struct Prepared<'a, K: Hash + Eq, Doc: Document> {
+    storage: &'a Storage<K, Doc>,
+    gate: RwLockReadGuard<'a, ()>,
+    locks: Vec<KeyLock<'a, K>>,
+
+    // unique index keys of written documents
+    claimed: Vec<(String, String)>,
+
+    // Versioned and Removed, in order keys are written first
+    writes: Vec<RQuery<K, Doc>>,
+
+    origin: Origin,
+}
+
+impl<'a, K, Doc> Prepared<'a, K, Doc>
+where
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+    K: Serialize
+        + DeserializeOwned
+        + PartialOrd
+        + Ord
+        + PartialEq
+        + Eq
+        + Hash
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+{
+    pub(crate) fn storage(&self) -> &'a Storage<K, Doc> {
+        self.storage
+    }
+
+    // writes as one record encoded like records of wal, for commit record
+    pub(crate) fn seal(&self) -> Result<Vec<u8>, String> {
+        self.storage.seal_replicated(&RQuery::Batch(self.logged()))
+    }
+
+    // log writes as one record, Transaction of database keep its id
+    pub(crate) async fn log(&self, txid: Option<u64>) -> Result<(), SessionResult> {
+        if self.storage.off_disk {
+            return Ok(())
+        }
+
+        let query = match txid {
+            Some(txid) => RQuery::Transaction(txid, self.logged()),
+            None => RQuery::Batch(self.logged()),
+        };
+
+        let bytes = self.storage.header.encode(&query).map_err(|e| SessionResult::Err(StatusResult::Err(e)))?;
+        self.storage.wal_session.log(bytes).await
+    }
+
+    // wal has not version of removed document
+    fn logged(&self) -> Vec<RQuery<K, Doc>> {
+        self.writes
+            .iter()
+            .map(|query| match query {
+                RQuery::Removed(key, _) => RQuery::Remove(key.clone()),
+                query => query.clone()
+            })
+            .collect()
+    }
+
+    // nothing is applied yet, so just claims and locks are dropped
+    pub(crate) fn rollback(self) {
+        self.storage.hash_index.release(&self.claimed);
+    }
+
+    // apply writes, send them to peers and unlock keys, then wait until quorum of peers applied them
+    pub(crate) async fn finish(self) -> Result<(), SessionResult> {
+        let Prepared { storage, gate, locks, claimed, writes, origin } = self;
+
+        // index keys are claimed for written documents, so index of them does not fail
+        for query in writes.iter() {
+            match query {
+                RQuery::Versioned(key, doc, version) => { let _ = storage.index(key.clone(), doc.clone(), *version).await; }
+                RQuery::Removed(key, _) => { storage.unindex(key).await; }
+                _ => unreachable!(),
+            }
+        }
+
+        let pending = storage.replicate(origin, || RQuery::Batch(writes.clone())).await;
+
+        storage.hash_index.release(&claimed);
+        drop(locks);
+        drop(gate);
+
+        if !storage.off_reporter {
+            for query in writes {
+                let query = match query {
+                    RQuery::Versioned(key, doc, _) => RQuery::Insert(key, doc),
+                    RQuery::Removed(key, _) => RQuery::Remove(key),
+                    query => query
+                };
+                let _ = storage.reporter_session.dispatch(Event::Query(query)).await;
+            }
+        }
+
+        storage.auto_checkpoint().await;
+        quorum(pending).await
+    }
+}
+
+
+
 // used for log to disk
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum RQuery<K, Doc> {
     Insert(K, Doc),
     Remove(K),
+
+    // insert with version of document after it
+    Versioned(K, Doc, u64),
+
+    // patch of document made by update, with version of document after it
+    Update(K, Vec<u8>, u64),
+
+    // writes of a transaction, replayed all or nothing
+    Batch(Vec<RQuery<K, Doc>>),
+
+    // writes of a database transaction in one datastore, with id of its commit record
+    Transaction(u64, Vec<RQuery<K, Doc>>),
+
+    // remove of document of version, it is sent to other nodes so they don't apply
+    // a stale remove, wal has Remove
+    Removed(K, u64),
 }
 
 impl<K, Doc> RQuery<K, Doc> {
@@ -438,8 +1741,50 @@ impl<K, Doc> RQuery<K, Doc> {
 
     pub fn into_raw(self) -> (&'static str, K, Option<Doc>) {
         match self {
-            RQuery::Insert(k, d) => (RQUERY_INSERT_TYPE, k, Some(d)),
+            RQuery::Insert(k, d) | RQuery::Versioned(k, d, _) => (RQUERY_INSERT_TYPE, k, Some(d)),
             RQuery::Remove(k) => (RQUERY_REMOVE_TYPE, k, None),
+            _ => panic!("batch and update have not raw form, flatten and resolve them first"),
+        }
+    }
+
+    /// Insert and Remove of query, writes of Batch are in order
+    pub fn flatten(self) -> Vec<RQuery<K, Doc>> {
+        match self {
+            RQuery::Batch(writes) | RQuery::Transaction(_, writes) => writes.into_iter().flat_map(RQuery::flatten).collect(),
+            rq => vec![rq],
+        }
+    }
+
+    /// pass every Insert and Remove of query to f, version of document and
+    /// batch of writes are kept around what f return, Update is passed as it is
+    pub fn map_writes<NewKey, NewDoc, E, F>(self, f: &mut F) -> Result<Option<RQuery<NewKey, NewDoc>>, E>
+    where
+        F: FnMut(RQuery<K, Doc>) -> Result<Option<RQuery<NewKey, NewDoc>>, E>,
+    {
+        let map_all = |writes: Vec<RQuery<K, Doc>>, f: &mut F| {
+            let mut mapped = Vec::with_capacity(writes.len());
+            for rq in writes {
+                mapped.extend(rq.map_writes(f)?);
+            }
+            Ok(mapped)
+        };
+
+        match self {
+            RQuery::Versioned(key, doc, version) => Ok(f(RQuery::Insert(key, doc))?.map(|rq| match rq {
+                RQuery::Insert(key, doc) => RQuery::Versioned(key, doc, version),
+                rq => rq
+            })),
+            RQuery::Batch(writes) => Ok(Some(RQuery::Batch(map_all(writes, f)?))),
+            RQuery::Transaction(txid, writes) => Ok(Some(RQuery::Transaction(txid, map_all(writes, f)?))),
+            rq => f(rq),
+        }
+    }
+
+    /// keys written by query
+    pub fn keys(&self) -> Vec<&K> {
+        match self {
+            RQuery::Insert(key, _) | RQuery::Versioned(key, _, _) | RQuery::Update(key, _, _) | RQuery::Remove(key) | RQuery::Removed(key, _) => vec![key],
+            RQuery::Batch(writes) | RQuery::Transaction(_, writes) => writes.iter().flat_map(RQuery::keys).collect(),
         }
     }
 
@@ -0,0 +1,711 @@
+use std::sync::Arc;
+use std::ops::Bound;
+
+use crate::{Options, Schema, Storage, StorageType, RQuery, WalReader, StartAt, Durability};
+use crate::document::{RangeQuery, RangeValue};
+use crate::darkbird::test_fixture::{Profile, Member, TempDir, wait_until};
+
+
+#[tokio::test]
+async fn fsynced_insert_survive_reopen() {
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Fsynced);
+
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        for i in 0..100 {
+            storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+        }
+    }
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+    assert_eq!(storage.collection_len(), 100);
+    assert_eq!(storage.lookup(&"pid-42".to_owned()).unwrap().value(), &Profile::new("DanyalMh", 42));
+}
+
+
+#[tokio::test]
+async fn checkpoint_release_old_pages_and_reload() {
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed)
+            .with_checkpoint(40, false);
+
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        for i in 0..100 {
+            storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+        }
+        storage.remove("pid-7".to_owned()).await.unwrap();
+
+        // first page is released by checkpoint in background
+        wait_until(|| !std::path::Path::new(&format!("{}/profiles/page-5000.LOG", path)).exists()).await;
+    }
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+    assert_eq!(storage.collection_len(), 99);
+    assert!(storage.lookup(&"pid-7".to_owned()).is_none());
+    assert_eq!(storage.lookup(&"pid-99".to_owned()).unwrap().value(), &Profile::new("DanyalMh", 99));
+}
+
+
+#[tokio::test]
+async fn failed_checkpoint_is_recorded_until_one_succeed() {
+    let path = TempDir::new();
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+        .with_durability(Durability::Flushed)
+        .with_checkpoint(5, false);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+    // snapshot can not be created where a directory has its name
+    let blocked = (1..10).map(|index| format!("{}/profiles/snapshot-{}.TMP", path, index)).collect::<Vec<_>>();
+    for dir in blocked.iter() {
+        std::fs::create_dir_all(dir).unwrap();
+    }
+
+    for i in 0..5 {
+        storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+    }
+    wait_until(|| storage.checkpoint_error().is_some()).await;
+
+    for dir in blocked.iter() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    for i in 5..10 {
+        storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+    }
+    wait_until(|| storage.checkpoint_error().is_none()).await;
+    assert_eq!(storage.collection_len(), 10);
+}
+
+
+#[tokio::test]
+async fn enqueued_writes_are_flushed_on_drop() {
+    let path = TempDir::new();
+
+    // default durability does not wait for disk, drop of storage wait for every logged record
+    for round in 0..3 {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        assert_eq!(storage.collection_len(), round * 1000);
+
+        for i in 0..1000 {
+            storage.insert(format!("pid-{}-{}", round, i), Profile::new("DanyalMh", i)).await.unwrap();
+        }
+    }
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+    assert_eq!(storage.collection_len(), 3000);
+}
+
+
+#[tokio::test]
+async fn parallel_replay_keep_last_writer_and_report_progress() {
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+        for i in 0..6000 {
+            storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+        }
+        for i in (0..6000).step_by(2) {
+            storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i + 1)).await.unwrap();
+        }
+        for i in (0..6000).step_by(3) {
+            storage.remove(format!("pid-{}", i)).await.unwrap();
+        }
+    }
+
+    let last = Arc::new(std::sync::Mutex::new(crate::LoadProgress::default()));
+    let progress = last.clone();
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+        .with_load_threads(4)
+        .with_load_progress(Arc::new(move |status| *progress.lock().unwrap() = status));
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+    assert_eq!(storage.collection_len(), 4000);
+    assert_eq!(storage.lookup(&"pid-1".to_owned()).unwrap().age, 1);
+    assert_eq!(storage.lookup(&"pid-2".to_owned()).unwrap().age, 3);
+    assert!(storage.lookup(&"pid-3".to_owned()).is_none());
+
+    let last = *last.lock().unwrap();
+    assert!(last.pages_total >= 3);
+    assert_eq!(last.pages_loaded, last.pages_total);
+    assert_eq!(last.records, 11000);
+}
+
+
+#[tokio::test]
+async fn rejected_insert_is_not_logged() {
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+        let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+
+        storage.insert(1, Member::account("a@x", 10)).await.unwrap();
+        storage.insert(2, Member::account("b@x", 20)).await.unwrap();
+        assert!(storage.insert(2, Member::account("a@x", 30)).await.is_err());
+        assert!(storage.insert(3, Member::account("a@x", 40)).await.is_err());
+    }
+
+    let mut reader = WalReader::<u32, Member>::open(&path, "accounts", 1000, StartAt::Beginning).unwrap();
+    let mut records = 0;
+    while reader.try_next().unwrap().is_some() {
+        records += 1;
+    }
+    assert_eq!(records, 2);
+
+    for lazy in [false, true] {
+        let ops = Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true);
+        let storage = match lazy {
+            false => Storage::<u32, Member>::open(ops).await.unwrap().0,
+            true => Storage::<u32, Member>::open_lazy(ops).await.unwrap().0,
+        };
+        storage.ready().await.unwrap();
+        assert_eq!(storage.collection_len(), 2);
+        assert_eq!(storage.lookup(&2).unwrap().value(), &Member::account("b@x", 20));
+        assert_eq!(storage.lookup_by_index("email", "a@x").unwrap().key(), &1);
+    }
+}
+
+
+#[tokio::test]
+async fn eager_and_lazy_open_load_same_state() {
+    let path = TempDir::new();
+
+    // page written before versions, records are not stamped and Insert has not version
+    {
+        std::fs::create_dir_all(format!("{}/accounts", path)).unwrap();
+        let mut page = simple_wal::LogFile::open(format!("{}/accounts/page-5000.LOG", path)).unwrap();
+        let records: Vec<RQuery<u32, Member>> = vec![
+            RQuery::Insert(1, Member::account("a@x", 1)),
+            RQuery::Insert(1, Member::account("a@x", 2)),
+            RQuery::Versioned(2, Member::account("b@x", 1), 5),
+            RQuery::Insert(2, Member::account("b@x", 2)),
+            RQuery::Insert(3, Member::account("c@x", 1)),
+            RQuery::Remove(3),
+            RQuery::Insert(3, Member::account("c@x", 2)),
+            RQuery::Insert(4, Member::account("d@x", 1)),
+        ];
+        for record in records {
+            page.write(&mut bincode::serialize(&record).unwrap()).unwrap();
+        }
+        page.flush().unwrap();
+    }
+
+    {
+        let ops = Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+        let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+        storage.insert(4, Member::account("d@x", 2)).await.unwrap();
+        storage.insert(5, Member::account("e@x", 1)).await.unwrap();
+    }
+
+    let mut states = Vec::new();
+    for lazy in [false, true] {
+        let ops = Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true);
+        let storage = match lazy {
+            false => Storage::<u32, Member>::open(ops).await.unwrap().0,
+            true => Storage::<u32, Member>::open_lazy(ops).await.unwrap().0,
+        };
+        storage.ready().await.unwrap();
+
+        let mut state = (1..=5)
+            .filter_map(|key| storage.lookup_with_version(&key).map(|(doc, version)| (key, doc.value().clone(), version)))
+            .collect::<Vec<_>>();
+        state.sort_by_key(|(key, _, _)| *key);
+        states.push(state);
+    }
+
+    assert_eq!(states[0], states[1]);
+    assert_eq!(
+        states[0].iter().map(|(key, _, version)| (*key, *version)).collect::<Vec<_>>(),
+        vec![(1, 2), (2, 6), (3, 1), (4, 2), (5, 1)]
+    );
+
+    // compaction write Insert with version that loading give it
+    {
+        let ops = Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true);
+        let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+        assert_eq!(storage.compact().await.unwrap().records_before, 10);
+    }
+
+    let ops = Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+    let state = (1..=5)
+        .filter_map(|key| storage.lookup_with_version(&key).map(|(doc, version)| (key, doc.value().clone(), version)))
+        .collect::<Vec<_>>();
+    assert_eq!(state, states[0]);
+}
+
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lazy_open_serve_reads_while_loading() {
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+        for i in 0..12000 {
+            storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+        }
+        storage.insert("pid-1".to_owned(), Profile::new("DanyalMh", 100)).await.unwrap();
+        storage.remove("pid-2".to_owned()).await.unwrap();
+    }
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+        .with_lazy_load(true)
+        .with_load_threads(1);
+    let db = Schema::new()
+        .with_datastore::<String, Profile>(ops).await.unwrap()
+        .build();
+
+    // missing key is unknown until loading is done
+    match db.try_lookup::<String, Profile>(&"pid-missing".to_owned()) {
+        Ok(None) | Err(crate::SessionResult::Loading) => {}
+        _ => panic!("missing key found")
+    }
+
+    // loaded key has its latest value
+    assert_eq!(db.lookup_wait::<String, Profile>(&"pid-1".to_owned()).await.unwrap().unwrap().age, 100);
+    assert_eq!(db.lookup_wait::<String, Profile>(&"pid-0".to_owned()).await.unwrap().unwrap().age, 0);
+
+    // write wait for loading
+    db.insert::<String, Profile>("pid-0".to_owned(), Profile::new("DanyalMh", 200)).await.unwrap();
+
+    db.ready::<String, Profile>().await.unwrap();
+    assert!(db.try_lookup::<String, Profile>(&"pid-2".to_owned()).unwrap().is_none());
+    assert!(db.try_lookup::<String, Profile>(&"pid-missing".to_owned()).unwrap().is_none());
+    assert_eq!(db.lookup::<String, Profile>(&"pid-0".to_owned()).unwrap().unwrap().age, 200);
+    assert_eq!(db.iter::<String, Profile>().unwrap().count(), 11999);
+}
+
+
+#[tokio::test]
+async fn versions_detect_conflicting_writes_and_survive_restart() {
+    let path = TempDir::new();
+    let key = "pid-1".to_owned();
+
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+        assert_eq!(storage.insert_if_version(key.clone(), Profile::new("DanyalMh", 1), 0).await.unwrap(), 1);
+        storage.insert(key.clone(), Profile::new("DanyalMh", 2)).await.unwrap();
+
+        let (doc, version) = storage.lookup_with_version(&key).unwrap();
+        assert_eq!((doc.value().age, version), (2, 2));
+        drop(doc);
+
+        // two writers read version 2, just first of them write
+        assert_eq!(storage.compare_and_swap(key.clone(), 2, Some(Profile::new("First", 3))).await.unwrap(), 3);
+        match storage.insert_if_version(key.clone(), Profile::new("Second", 3), 2).await {
+            Err(crate::SessionResult::Err(crate::StatusResult::Conflict { expected: 2, actual: 3 })) => {}
+            _ => panic!("expected conflict"),
+        }
+        assert!(storage.insert_if_version(key.clone(), Profile::new("Again", 3), 0).await.is_err());
+        assert_eq!(storage.lookup(&key).unwrap().value().fullname, "First");
+
+        // version is kept by snapshot and by records after it
+        storage.checkpoint().await.unwrap();
+        storage.insert(key.clone(), Profile::new("DanyalMh", 4)).await.unwrap();
+        storage.insert("pid-2".to_owned(), Profile::new("Jack", 1)).await.unwrap();
+        assert_eq!(storage.compare_and_swap("pid-2".to_owned(), 1, None).await.unwrap(), 0);
+        assert_eq!(storage.version(&"pid-2".to_owned()), None);
+    }
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+    assert_eq!(storage.version(&key), Some(4));
+    assert!(storage.lookup(&"pid-2".to_owned()).is_none());
+
+    storage.compact().await.unwrap();
+    drop(storage);
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+    assert_eq!(storage.version(&key), Some(4));
+    assert_eq!(storage.insert_if_version(key.clone(), Profile::new("DanyalMh", 5), 4).await.unwrap(), 5);
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_writes_of_key_get_every_version() {
+    let path = TempDir::new();
+    let key = "pid-1".to_owned();
+
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+        let storage = Arc::new(Storage::<String, Profile>::open(ops).await.unwrap().0);
+
+        let writers = (0..500)
+            .map(|i| {
+                let (storage, key) = (storage.clone(), key.clone());
+                tokio::spawn(async move { storage.insert(key, Profile::new("DanyalMh", i)).await.unwrap() })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        assert_eq!(storage.version(&key), Some(500));
+    }
+
+    // every record is logged with version it is applied
+    let mut reader = WalReader::<String, Profile>::open(&path, "profiles", 1000, StartAt::Beginning).unwrap();
+    let mut versions = Vec::new();
+    while let Some((_, rq)) = reader.try_next().unwrap() {
+        if let RQuery::Versioned(_, _, version) = rq {
+            versions.push(version);
+        }
+    }
+    assert_eq!(versions, (1..=500).collect::<Vec<_>>());
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+    assert_eq!(storage.version(&key), Some(500));
+}
+
+
+#[tokio::test]
+async fn update_log_patch_and_reindex_document() {
+    let member = |email: &str, team: &str| Member { bio: "x".repeat(1000), ..Member::new(email, team) };
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "members", 1000, StorageType::DiskCopies, true);
+        let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+        storage.insert(1, member("a@x", "red")).await.unwrap();
+        storage.insert(2, member("b@x", "red")).await.unwrap();
+
+        assert_eq!(storage.update(1, |m| m.team = "blue".to_owned()).await.unwrap(), Some(2));
+        assert_eq!(storage.lookup_by_tag("team", "red").len(), 1);
+        assert_eq!(storage.lookup_by_tag("team", "blue")[0].key(), &1);
+
+        // duplicate index key change nothing, missing key is not updated
+        assert!(storage.update(1, |m| m.email = "b@x".to_owned()).await.is_err());
+        assert_eq!(storage.lookup(&1).unwrap().value(), &member("a@x", "blue"));
+        assert_eq!(storage.lookup_by_index("email", "a@x").unwrap().key(), &1);
+        assert_eq!(storage.update(3, |m| m.team = "blue".to_owned()).await.unwrap(), None);
+
+        // base of later patch is in snapshot
+        storage.checkpoint().await.unwrap();
+        assert_eq!(storage.update(1, |m| m.email = "c@x".to_owned()).await.unwrap(), Some(3));
+        assert!(storage.lookup_by_index("email", "a@x").is_none());
+    }
+
+    // patch is logged in place of whole document
+    let mut reader = WalReader::<u32, Member>::open(&path, "members", 1000, StartAt::Beginning).unwrap();
+    let mut records = Vec::new();
+    while let Some((_, rq)) = reader.try_next().unwrap() {
+        records.push(rq);
+    }
+    match records.last().unwrap() {
+        RQuery::Update(1, patch, 3) => assert!(patch.len() < 100),
+        rq => panic!("unexpected record {:?}", rq),
+    }
+
+    let expected = Member { email: "c@x".to_owned(), ..member("a@x", "blue") };
+    for lazy in [false, true] {
+        let ops = Options::new(&path, "members", 1000, StorageType::DiskCopies, true).with_lazy_load(lazy);
+        let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+        storage.ready().await.unwrap();
+        assert_eq!(storage.lookup_with_version(&1).map(|(m, v)| (m.value().clone(), v)), Some((expected.clone(), 3)));
+        assert_eq!(storage.lookup_by_index("email", "c@x").unwrap().key(), &1);
+    }
+
+    let ops = Options::new(&path, "members", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+    storage.compact().await.unwrap();
+    drop(storage);
+
+    let ops = Options::new(&path, "members", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+    assert_eq!(storage.lookup(&1).unwrap().value(), &expected);
+    assert_eq!(storage.version(&1), Some(3));
+
+    // follower that apply records again after crash skip patch of removed document
+    let patch = match records.last().unwrap() {
+        RQuery::Update(_, patch, _) => patch.clone(),
+        _ => unreachable!(),
+    };
+    storage.remove(1).await.unwrap();
+    storage.apply_query(RQuery::Update(1, patch, 3)).await.unwrap();
+    assert!(storage.lookup(&1).is_none());
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_updates_of_key_apply_every_change() {
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+        let storage = Arc::new(Storage::<u32, Member>::open(ops).await.unwrap().0);
+        storage.insert(1, Member::account("a@x", 0)).await.unwrap();
+
+        let writers = (0..200)
+            .map(|_| {
+                let storage = storage.clone();
+                tokio::spawn(async move { storage.update(1, |account| account.balance += 1).await.unwrap() })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        assert_eq!(storage.lookup_with_version(&1).map(|(a, v)| (a.balance, v)), Some((200, 201)));
+    }
+
+    let ops = Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+    assert_eq!(storage.lookup_with_version(&1).map(|(a, v)| (a.balance, v)), Some((200, 201)));
+}
+
+
+#[tokio::test]
+async fn insert_overwrite_move_every_index_to_new_document() {
+    let person = |email: &str, team: &str, age: i32, bio: &str| Member { age, bio: bio.to_owned(), ..Member::new(email, team) };
+    let path = TempDir::new();
+
+    let ops = Options::new(&path, "persons", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+    storage.insert(1, person("a@x", "red", 30, "rust developer")).await.unwrap();
+    storage.insert(2, person("b@x", "red", 10, "young developer")).await.unwrap();
+
+    // same document again is not duplicate of itself
+    storage.insert(1, person("a@x", "red", 30, "rust developer")).await.unwrap();
+    assert_eq!(storage.lookup_by_index("email", "a@x").unwrap().key(), &1);
+
+    storage.insert(1, person("c@x", "blue", 12, "go developer")).await.unwrap();
+    let keys = |refs: Vec<dashmap::mapref::one::Ref<u32, Member>>| {
+        let mut keys = refs.iter().map(|r| *r.key()).collect::<Vec<_>>();
+        keys.sort();
+        keys
+    };
+
+    // hash
+    assert!(storage.lookup_by_index("email", "a@x").is_none());
+    assert_eq!(storage.lookup_by_index("email", "c@x").unwrap().key(), &1);
+    storage.insert(3, person("a@x", "green", 40, "free")).await.unwrap();
+    assert!(storage.insert(4, person("c@x", "green", 40, "taken")).await.is_err());
+
+    // tags
+    assert_eq!(keys(storage.lookup_by_tag("team", "red")), vec![2]);
+    assert_eq!(keys(storage.lookup_by_tag("team", "blue")), vec![1]);
+
+    // range
+    assert!(keys(storage.range("age", 18, 35)).is_empty());
+    assert_eq!(keys(storage.range("age", 0, 18)), vec![1, 2]);
+
+    // view
+    assert_eq!(keys(storage.fetch_view("adults")), vec![3]);
+
+    // inverted, word of both documents is kept
+    assert!(storage.search("rust".to_owned()).is_empty());
+    assert_eq!(keys(storage.search("go".to_owned())), vec![1]);
+    assert_eq!(keys(storage.search("developer".to_owned())), vec![1, 2]);
+
+    // update move indexes same as insert
+    storage.update(1, |p| { p.team = "red".to_owned(); p.age = 20; p.bio = "rust".to_owned(); }).await.unwrap();
+    assert_eq!(keys(storage.lookup_by_tag("team", "red")), vec![1, 2]);
+    assert!(storage.lookup_by_tag("team", "blue").is_empty());
+    assert_eq!(keys(storage.range("age", 18, 35)), vec![1]);
+    assert_eq!(keys(storage.fetch_view("adults")), vec![1, 3]);
+    assert_eq!(keys(storage.search("developer".to_owned())), vec![2]);
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn view_reads_while_inserting_do_not_deadlock() {
+    let path = TempDir::new();
+    let ops = Options::new(&path, "persons", 1000, StorageType::RamCopies, true);
+    let storage = Arc::new(Storage::<u32, Member>::open(ops).await.unwrap().0);
+
+    let readers = (0..3)
+        .map(|_| {
+            let storage = storage.clone();
+            tokio::task::spawn_blocking(move || {
+                for _ in 0..2000 {
+                    storage.fetch_view("adults");
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let writer = {
+        let storage = storage.clone();
+        tokio::spawn(async move {
+            for i in 0..2000 {
+                // documents move in and out of view every round
+                storage.insert(i % 50, Member { age: 10 + (i as i32 / 50 % 2) * 20, ..Member::new(&format!("{}@x", i), "red") }).await.unwrap();
+            }
+        })
+    };
+
+    let done = async {
+        writer.await.unwrap();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(30), done).await.unwrap();
+    assert_eq!(storage.fetch_view("adults").len(), 50);
+}
+
+
+#[tokio::test]
+async fn range_values_are_ordered_by_type_and_queried_by_bounds() {
+    // numbers of every type are compared by value
+    assert!(RangeValue::from(9) < RangeValue::from(10u64));
+    assert!(RangeValue::from(-1) < RangeValue::from(0.5));
+    assert!(RangeValue::from(2.5) < RangeValue::from(3u32));
+    assert_eq!(RangeValue::from(2), RangeValue::from(2.0));
+    assert!(RangeValue::from(f64::INFINITY) > RangeValue::from(u64::MAX));
+    assert!(RangeValue::from(u64::MAX) < RangeValue::from("0"));
+    assert!(RangeValue::from("9") > RangeValue::from("10"));
+
+    let path = TempDir::new();
+    let ops = Options::new(&path, "members", 1000, StorageType::RamCopies, true);
+    let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+
+    let values = [-20.5, -3.0, 0.0, 2.5, 9.0, 10.0, 100.0];
+    for (i, value) in values.iter().enumerate() {
+        let member = Member {
+            username: format!("s{}", i), score: *value, balance: *value as i64, joined: 1_700_000_000_000 + i as i64 * 1000, ..Member::new("", "")
+        };
+        storage.insert(i as u32, member).await.unwrap();
+    }
+
+    let keys = |query: RangeQuery| storage.range_query(&query).iter().map(|r| *r.key()).collect::<Vec<_>>();
+
+    // 9 is before 10 and negatives are first
+    assert_eq!(storage.range("score", -5, 10).iter().map(|r| *r.key()).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+    assert_eq!(keys(RangeQuery::new("score")), vec![0, 1, 2, 3, 4, 5, 6]);
+
+    // inclusive, exclusive and unbounded ends
+    assert_eq!(keys(RangeQuery::new("balance").with_from(Bound::Excluded(0)).with_to(Bound::Included(10))), vec![3, 4, 5]);
+    assert_eq!(keys(RangeQuery::new("balance").with_from(Bound::Included(10))), vec![5, 6]);
+    assert_eq!(keys(RangeQuery::new("balance").with_to(Bound::Excluded(-3))), vec![0]);
+    assert!(keys(RangeQuery::new("balance").with_from(Bound::Included(10)).with_to(Bound::Excluded(10))).is_empty());
+    assert!(keys(RangeQuery::new("balance").with_from(Bound::Included(50)).with_to(Bound::Included(10))).is_empty());
+
+    // reverse, offset and limit
+    assert_eq!(keys(RangeQuery::new("score").with_reverse(true).with_limit(3)), vec![6, 5, 4]);
+    assert_eq!(keys(RangeQuery::new("score").with_offset(2).with_limit(2)), vec![2, 3]);
+    assert_eq!(keys(RangeQuery::new("score").with_reverse(true).with_offset(5)), vec![1, 0]);
+
+    // timestamps and strings
+    let at = |i: i64| RangeValue::Timestamp(1_700_000_000_000 + i * 1000);
+    assert_eq!(keys(RangeQuery::new("joined").with_from(Bound::Included(at(2))).with_to(Bound::Excluded(at(4)))), vec![2, 3]);
+    assert_eq!(keys(RangeQuery::new("username").with_from(Bound::Included("s5"))), vec![5, 6]);
+
+    // value of overwritten document is not in range anymore
+    storage.update(6, |r| r.score = 1.0).await.unwrap();
+    assert_eq!(keys(RangeQuery::new("score").with_from(Bound::Included(1)).with_to(Bound::Included(2.5))), vec![6, 3]);
+    assert!(keys(RangeQuery::new("score").with_from(Bound::Included(50))).is_empty());
+}
+
+
+#[tokio::test]
+async fn non_unique_index_return_every_document_with_value() {
+    let user = |email: &str, country: &str| Member { country: country.to_owned(), ..Member::new(email, "") };
+    let keys = |refs: Vec<dashmap::mapref::one::Ref<u32, Member>>| {
+        let mut keys = refs.iter().map(|r| *r.key()).collect::<Vec<_>>();
+        keys.sort();
+        keys
+    };
+    let path = TempDir::new();
+
+    let ops = Options::new(&path, "users", 1000, StorageType::DiskCopies, true)
+        .with_durability(Durability::Flushed);
+    let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+
+    // same country is not duplicate
+    storage.insert(1, user("a@x", "iran")).await.unwrap();
+    storage.insert(2, user("b@x", "iran")).await.unwrap();
+    storage.insert(3, user("c@x", "spain")).await.unwrap();
+    assert_eq!(keys(storage.lookup_all_by_index("country", "iran")), vec![1, 2]);
+    assert_eq!(keys(storage.lookup_all_by_index("country", "spain")), vec![3]);
+
+    // overwrite and update move document between values
+    storage.insert(2, user("b@x", "spain")).await.unwrap();
+    assert_eq!(keys(storage.lookup_all_by_index("country", "iran")), vec![1]);
+    assert_eq!(keys(storage.lookup_all_by_index("country", "spain")), vec![2, 3]);
+
+    storage.update(1, |u| u.country = "spain".to_owned()).await.unwrap();
+    assert!(storage.lookup_all_by_index("country", "iran").is_empty());
+    assert_eq!(keys(storage.lookup_all_by_index("country", "spain")), vec![1, 2, 3]);
+
+    storage.remove(3).await.unwrap();
+    assert_eq!(keys(storage.lookup_all_by_index("country", "spain")), vec![1, 2]);
+
+    // rebuilt on reopen
+    drop(storage);
+    let ops = Options::new(&path, "users", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+    assert_eq!(keys(storage.lookup_all_by_index("country", "spain")), vec![1, 2]);
+
+    // unique index still enforced, and found by lookup_all_by_index too
+    assert!(storage.insert(4, user("a@x", "spain")).await.is_err());
+    assert_eq!(keys(storage.lookup_all_by_index("email", "a@x")), vec![1]);
+    assert!(storage.lookup_by_index("country", "spain").is_none());
+}
+
+
+#[tokio::test]
+async fn named_indexes_keep_same_value_of_each_index_apart() {
+    let member = |email: &str, username: &str, role: &str, team: &str| Member {
+        username: username.to_owned(), role: role.to_owned(), ..Member::new(email, team)
+    };
+    let path = TempDir::new();
+
+    let ops = Options::new(&path, "members", 1000, StorageType::DiskCopies, true)
+        .with_durability(Durability::Flushed);
+    let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+
+    // email of one and username of other are same value of different indexes
+    storage.insert(1, Member { age: 30, ..member("foo", "bar", "admin", "staff") }).await.unwrap();
+    storage.insert(2, member("baz", "foo", "staff", "admin")).await.unwrap();
+    assert_eq!(storage.lookup_by_index("email", "foo").unwrap().key(), &1);
+    assert_eq!(storage.lookup_by_index("username", "foo").unwrap().key(), &2);
+    assert!(storage.lookup_by_index("email", "bar").is_none());
+
+    // still unique in its own index
+    assert!(storage.insert(3, member("qux", "bar", "admin", "staff")).await.is_err());
+
+    assert_eq!(storage.lookup_by_tag("role", "admin")[0].key(), &1);
+    assert_eq!(storage.lookup_by_tag("team", "admin")[0].key(), &2);
+    assert_eq!(storage.fetch_view("adults").len(), 1);
+    assert_eq!(storage.iter_index().count(), 2);
+    assert_eq!(storage.iter_tags().count(), 2);
+
+    // moved and removed in own index
+    storage.update(1, |m| m.username = "baz".to_owned()).await.unwrap();
+    assert_eq!(storage.lookup_by_index("username", "baz").unwrap().key(), &1);
+    assert_eq!(storage.lookup_by_index("email", "baz").unwrap().key(), &2);
+
+    storage.remove(2).await.unwrap();
+    assert!(storage.lookup_by_index("username", "foo").is_none());
+    assert!(storage.lookup_by_tag("team", "admin").is_empty());
+    assert_eq!(storage.lookup_by_tag("role", "admin")[0].key(), &1);
+}
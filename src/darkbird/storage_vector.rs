@@ -1,11 +1,12 @@
-use tokio::sync::mpsc::Sender;
+use std::sync::{atomic::AtomicBool, Arc};
+use tokio::sync::{mpsc::Sender, RwLock};
 
 use dashmap::{iter::Iter, DashMap};
 use uuid::Uuid;
 
 
 use super::{
-    wal::disk_log::{DiskLog, Session},
+    wal::{disk_log::{self, DiskLog, Session}, codec::{self, Keyring, PageHeader}, recovery::{self, RecoveryReport}, backup::{self, Source}},
     router::{self, Router},
     Options, StatusResult, StorageType, vector::{VectorId, Vector},
 };
@@ -26,14 +27,52 @@ pub struct VecStorage {
 
     off_reporter: bool,
 
-    off_disk: bool
+    off_disk: bool,
+
+    // datastore directory
+    path: String,
+
+    total_page_size: usize,
+
+    // records are encoded by format of it
+    header: PageHeader,
+
+    // keys of encrypted pages
+    keys: Keyring,
+
+    // writers hold it shared while logging and applying a record,
+    // backup hold it exclusive while pinning page
+    gate: Arc<RwLock<()>>,
+
+    // true while backup is copying pages
+    maintenance: Arc<AtomicBool>,
+
+    // what dropped from pages on open
+    recovery_report: RecoveryReport
 }
 
 impl VecStorage
 {
-    pub async fn open<'a>(ops: Options<'a>) -> Result<Self, String> {
+    /// open storage and load it, recovery report is what open dropped from corrupt pages
+    pub async fn open<'a>(ops: Options<'a>) -> Result<(Self, RecoveryReport), String> {
+
+        let path = format!("{}/{}", ops.path, ops.storage_name);
+
+        // restore stopped by a crash is finished or undone before datastore is opened
+        backup::recover(ops.path, ops.storage_name)?;
+        let total_page_size = disk_log::page_size(ops.total_page_size);
+        let keys = Keyring::new(ops.keys.clone());
+        let header = PageHeader::new(ops.format, ops.compression, keys.encryption());
+
+        // missing key must fail open, not treated as corrupt records
+        codec::check_keys(&path, total_page_size, &keys)?;
+
+        // repair corrupt pages before disk_log open tail page
+        let recovery_report = recovery::repair(&path, total_page_size, ops.recovery, |page_header, bytes| {
+            page_header.decode::<RQuery<VectorId, Vector>>(bytes, &keys).is_ok()
+        })?;
         
-        match DiskLog::open(ops.path, ops.storage_name, ops.total_page_size) {
+        match DiskLog::open(ops.path, ops.storage_name, ops.total_page_size, ops.durability, header, keys.clone()) {
             Err(e) => return Err(e.to_string()),
             Ok(disklog) => {
                 // Run DiskLog
@@ -52,7 +91,14 @@ impl VecStorage
                     wal_session: wal_session,
                     reporter_session: reporter,
                     off_reporter: ops.off_reporter,
-                    off_disk: true
+                    off_disk: true,
+                    path,
+                    total_page_size,
+                    header,
+                    keys,
+                    gate: Arc::new(RwLock::new(())),
+                    maintenance: Arc::new(AtomicBool::new(false)),
+                    recovery_report
                 };
 
 
@@ -67,7 +113,8 @@ impl VecStorage
                 // because we want loader dont write to disk_log
                 st.off_disk = off_disk;
 
-                return Ok(st);
+                let report = st.recovery_report.clone();
+                return Ok((st, report));
             }
         }
 
@@ -92,14 +139,15 @@ impl VecStorage
     /// insert to storage and persist to disk
     #[inline]
     pub async fn insert(&self, vid: VectorId, vec: Vec<f32>) -> Result<(), SessionResult> {
+        let gate = self.gate.read().await;
+
         let v = Vector(vec);
         if !self.off_disk || !self.off_reporter {
             let query = RQuery::Insert(vid.clone(), v.clone());
 
             if !self.off_disk {
-                if let Err(e) = self.wal_session.log(bincode::serialize(&query).unwrap()).await {
-                    return Err(e);
-                }
+                let bytes = self.header.encode(&query).map_err(|e| SessionResult::Err(StatusResult::Err(e)))?;
+                self.wal_session.log(bytes).await?;
             }
 
             if !self.off_reporter {
@@ -112,6 +160,7 @@ impl VecStorage
         // Insert to memory
         self.vcache.insert(vid, v);
 
+        drop(gate);
 
         Ok(())
 
@@ -130,6 +179,8 @@ impl VecStorage
     /// remove from storage and persist to disk
     #[inline]
     pub async fn remove(&self, vid: VectorId) -> Result<(), SessionResult> {
+        let _gate = self.gate.read().await;
+
         match self.vcache.get(&vid) {
             Some(_) => {
 
@@ -137,9 +188,8 @@ impl VecStorage
                     let query = RQuery::<VectorId, Vector>::Remove(vid.clone());
         
                     if !self.off_disk {
-                        if let Err(e) = self.wal_session.log(bincode::serialize(&query).unwrap()).await {
-                            return Err(e);
-                        }
+                        let bytes = self.header.encode(&query).map_err(|e| SessionResult::Err(StatusResult::Err(e)))?;
+                        self.wal_session.log(bytes).await?;
                     }
         
                     if !self.off_reporter {
@@ -207,14 +257,15 @@ impl VecStorage
     ///
     /// // Create a new storage instance
     /// 
-    /// let path = ".";
+    /// let dir = std::env::temp_dir().join("darkbird-vectors");
+    /// let path = dir.to_str().unwrap();
     /// let storage_name = "vectors";
     /// let total_page_size = 500;
     /// let stype = crate::StorageType::RamCopies;
     /// let off_reporter = true;
     /// 
     /// let ops = Options::new(path, storage_name, total_page_size, stype, off_reporter);
-    /// let db = VecStorage::open(ops).await.unwrap();
+    /// let (db, _) = VecStorage::open(ops).await.unwrap();
     ///
     /// // Insert vectors into the storage
     /// db.insert_with_key("vector1".to_string(), vec![1.0, 2.0, 3.0]);
@@ -269,14 +320,15 @@ impl VecStorage
     ///
     /// // Create a new storage instance
     /// 
-    /// let path = ".";
+    /// let dir = std::env::temp_dir().join("darkbird-vectors");
+    /// let path = dir.to_str().unwrap();
     /// let storage_name = "vectors";
     /// let total_page_size = 500;
     /// let stype = crate::StorageType::RamCopies;
     /// let off_reporter = true;
     /// 
     /// let ops = Options::new(path, storage_name, total_page_size, stype, off_reporter);
-    /// let db = VecStorage::open(ops).await.unwrap();
+    /// let (db, _) = VecStorage::open(ops).await.unwrap();
     ///
     /// // Insert vectors into the storage
     /// db.insert_with_key("vector1".to_string(), vec![1.0, 2.0, 3.0]);
@@ -321,14 +373,15 @@ impl VecStorage
     ///
     /// // Create a new storage instance
     /// 
-    /// let path = ".";
+    /// let dir = std::env::temp_dir().join("darkbird-vectors");
+    /// let path = dir.to_str().unwrap();
     /// let storage_name = "vectors";
     /// let total_page_size = 500;
     /// let stype = crate::StorageType::RamCopies;
     /// let off_reporter = true;
     /// 
     /// let ops = Options::new(path, storage_name, total_page_size, stype, off_reporter);
-    /// let db = VecStorage::open(ops).await.unwrap();
+    /// let (db, _) = VecStorage::open(ops).await.unwrap();
     ///
     /// // Insert vectors into the storage
     /// db.insert_with_key("vector1".to_string(), vec![1.0, 2.0, 3.0]);
@@ -371,14 +424,15 @@ impl VecStorage
     ///
     /// // Create a new storage instance
     /// 
-    /// let path = ".";
+    /// let dir = std::env::temp_dir().join("darkbird-vectors");
+    /// let path = dir.to_str().unwrap();
     /// let storage_name = "vectors";
     /// let total_page_size = 500;
     /// let stype = crate::StorageType::RamCopies;
     /// let off_reporter = true;
     /// 
     /// let ops = Options::new(path, storage_name, total_page_size, stype, off_reporter);
-    /// let db = VecStorage::open(ops).await.unwrap();
+    /// let (db, _) = VecStorage::open(ops).await.unwrap();
     ///
     /// // Insert a vector into the storage
     /// db.insert_with_key("vector1".to_string(), vec![1.0, 2.0, 3.0]);
@@ -402,6 +456,29 @@ impl VecStorage
     }
 
 
+    /// records dropped from corrupt pages on open
+    #[inline]
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery_report
+    }
+
+    // RamCopies datastore has nothing to backup
+    pub(crate) fn backup_source(&self) -> Option<Source> {
+        if self.off_disk {
+            return None
+        }
+
+        Some(Source {
+            name: std::path::Path::new(&self.path).file_name()?.to_str()?.to_owned(),
+            path: self.path.clone(),
+            total_page_size: self.total_page_size,
+            session: self.wal_session.clone(),
+            gate: self.gate.clone(),
+            maintenance: self.maintenance.clone(),
+        })
+    }
+
+
     
 
     /// load storage from disk
@@ -427,28 +504,8 @@ impl VecStorage
 
             page_index += 1;
 
-            // Must Call Recover if return Err, remove unwrap()
-            let iter = match logfile.iter(..) {
-                Ok(iter) => iter,
-                Err(e) => {
-                    eprintln!("==> {:?}", e);
-                    return Err(e.to_string());
-                }
-            };
-
-            for qline in iter {
-                let bytes = match qline {
-                    Ok(ql)  => ql,
-                    Err(e) => return Err(e.to_string()),
-                };
-
-                let query: RQuery<VectorId, Vector> = match bincode::deserialize(&bytes) {
-                    Ok(rq) => rq,
-                    Err(e) => {
-                        return Err(e.to_string());
-                    }
-                };
-
+            // every page decoded according to its header
+            for query in codec::read_page::<RQuery<VectorId, Vector>>(&mut logfile, &self.keys)?.into_iter().flat_map(RQuery::flatten) {
                 match query {
                     RQuery::Insert(vid, v) => {                        
                         let _ = self.insert(vid, v.0).await;
@@ -456,6 +513,7 @@ impl VecStorage
                     RQuery::Remove(vid) => {
                         let _ = self.remove(vid).await;
                     }
+                    _ => {}
                 }
             }
         }
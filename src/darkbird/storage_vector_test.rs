@@ -1,20 +1,21 @@
 use crate::{VecStorage, Options, Vector, Schema, StorageType};
+use crate::darkbird::test_fixture::TempDir;
 
-fn factory_option(name: &str) -> Options {
-    Options::new(".", name, 1000, StorageType::RamCopies, true)
+fn factory_option<'a>(path: &'a str, name: &'a str) -> Options<'a> {
+    Options::new(path, name, 1000, StorageType::RamCopies, true)
 }
 
 
 #[tokio::test]
 async fn vector() {
-    let path = ".";
+    let path = TempDir::new();
     let storage_name = "vectors";
     let total_page_size = 500;
     let stype = crate::StorageType::RamCopies;
     let off_reporter = true;
 
-    let ops = Options::new(path, storage_name, total_page_size, stype, off_reporter);
-    let vstorage = VecStorage::open(ops).await.unwrap();
+    let ops = Options::new(&path, storage_name, total_page_size, stype, off_reporter);
+    let vstorage = VecStorage::open(ops).await.unwrap().0;
 
 
     let vid = String::from("vector_id");
@@ -36,8 +37,9 @@ async fn vector() {
 
 #[tokio::test]
 async fn k_nearest_neighbors() {
+    let path = TempDir::new();
     let db = Schema::new()
-        .with_vecstore(factory_option("LLM"))
+        .with_vecstore(factory_option(&path, "LLM"))
         .await
         .unwrap()
         .build();
@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::KeyProvider;
+use crate::document::{Document, Indexer, Tags, Range, MaterializedView, FullText, RangeField, RangeValue};
+
+
+// document without any index
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Profile {
+    pub fullname: String,
+    pub age: i32
+}
+
+impl Profile {
+    pub fn new(fullname: &str, age: i32) -> Self {
+        Profile { fullname: fullname.to_owned(), age }
+    }
+}
+
+impl Document for Profile {}
+
+impl Indexer for Profile {
+    fn extract(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+}
+
+impl Tags for Profile {
+    fn get_tags(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+}
+
+impl Range for Profile {
+    fn get_fields(&self) -> Vec<RangeField> {
+        vec![]
+    }
+}
+
+impl MaterializedView for Profile {
+    fn filter(&self) -> Option<String> {
+        None
+    }
+}
+
+impl FullText for Profile {
+    fn get_content(&self) -> Option<String> {
+        None
+    }
+}
+
+
+// document of every index, empty fields are not indexed:
+// email and username are unique indexes, country is non unique index,
+// team and role are tags, adults are in view, bio is searched and
+// username, age, balance, score and joined (timestamp) are range
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Member {
+    pub email: String,
+    pub username: String,
+    pub country: String,
+    pub team: String,
+    pub role: String,
+    pub age: i32,
+    pub balance: i64,
+    pub score: f64,
+    pub joined: i64,
+    pub bio: String,
+}
+
+impl Member {
+    pub fn new(email: &str, team: &str) -> Self {
+        Member {
+            email: email.to_owned(),
+            username: email.to_owned(),
+            country: String::new(),
+            team: team.to_owned(),
+            role: "member".to_owned(),
+            age: 0,
+            balance: 0,
+            score: 0.0,
+            joined: 0,
+            bio: String::new(),
+        }
+    }
+
+    pub fn account(email: &str, balance: i64) -> Self {
+        Member { balance, ..Member::new(email, "") }
+    }
+}
+
+fn not_empty(fields: Vec<(&str, &String)>) -> Vec<(String, String)> {
+    fields
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(name, value)| (name.to_owned(), value.clone()))
+        .collect()
+}
+
+impl Document for Member {}
+
+impl Indexer for Member {
+    fn extract(&self) -> Vec<(String, String)> {
+        not_empty(vec![("email", &self.email), ("username", &self.username)])
+    }
+
+    fn extract_multi(&self) -> Vec<(String, String)> {
+        not_empty(vec![("country", &self.country)])
+    }
+}
+
+impl Tags for Member {
+    fn get_tags(&self) -> Vec<(String, String)> {
+        not_empty(vec![("team", &self.team), ("role", &self.role)])
+    }
+}
+
+impl Range for Member {
+    fn get_fields(&self) -> Vec<RangeField> {
+        vec![
+            RangeField::new("username", self.username.as_str()),
+            RangeField::new("age", self.age),
+            RangeField::new("balance", self.balance),
+            RangeField::new("score", self.score),
+            RangeField { name: "joined".to_owned(), value: RangeValue::Timestamp(self.joined) },
+        ]
+    }
+}
+
+impl MaterializedView for Member {
+    fn filter(&self) -> Option<String> {
+        if self.age >= 18 { Some("adults".to_owned()) } else { None }
+    }
+}
+
+impl FullText for Member {
+    fn get_content(&self) -> Option<String> {
+        Some(self.bio.clone())
+    }
+}
+
+
+pub struct TestKeys {
+    pub current: u32,
+    pub keys: Vec<(u32, [u8; 32])>
+}
+
+impl KeyProvider for TestKeys {
+    fn current_key_id(&self) -> u32 {
+        self.current
+    }
+
+    fn key(&self, key_id: u32) -> Option<[u8; 32]> {
+        self.keys.iter().find(|(id, _)| *id == key_id).map(|(_, key)| *key)
+    }
+}
+
+
+// unique directory under temp_dir for DiskCopies tests, it is removed when dropped
+pub struct TempDir(String);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("darkbird-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path.to_str().unwrap().to_owned())
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for TempDir {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// background work of storage is done when f is true
+pub async fn wait_until<F: Fn() -> bool>(f: F) {
+    for _ in 0..500 {
+        if f() {
+            return
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("background work is not done")
+}
@@ -0,0 +1,460 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{document::Document, RQuery, Storage};
+
+use super::{
+    database::Database,
+    storage::{Origin, Prepared, Staged},
+    wal::{codec::PageHeader, snapshot::sync_dir},
+    SessionResult, StatusResult,
+};
+
+
+
+/// commit records of database transactions, every record is a file written by
+/// fsync and rename, so it exist whole or not at all. record is removed when
+/// every datastore of it logged its writes, so records exist on open are
+/// committed transactions that some datastores must complete
+pub struct TransactionLog {
+    path: String,
+
+    // latest id given to a transaction
+    last: AtomicU64,
+
+    // records are rewritten by one datastore at a time
+    recovering: tokio::sync::Mutex<()>,
+}
+
+
+// writes of a transaction per DiskCopies datastore
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CommitRecord {
+    pub(crate) txid: u64,
+
+    // datastore directory, page header and writes of datastore sealed as one record
+    pub(crate) parts: Vec<(String, Vec<u8>, Vec<u8>)>,
+}
+
+
+impl TransactionLog {
+    pub fn open(path: &str) -> Result<Self, String> {
+        fs::create_dir_all(path).map_err(|e| e.to_string())?;
+
+        // record that is not renamed is not committed
+        for entry in fs::read_dir(path).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            if entry.file_name().to_string_lossy().ends_with(".TMP") {
+                fs::remove_file(entry.path()).map_err(|e| e.to_string())?;
+            }
+        }
+
+        Ok(TransactionLog { path: path.to_owned(), last: AtomicU64::new(0), recovering: tokio::sync::Mutex::new(()) })
+    }
+
+    fn filename(&self, txid: u64) -> String {
+        format!("{}/tx-{}.TXN", self.path, txid)
+    }
+
+    // ids increase with time, so id of a removed record is not given again after restart
+    fn next_txid(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+
+        let last = self.last
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| Some(now.max(last + 1)))
+            .unwrap_or_default();
+
+        now.max(last + 1)
+    }
+
+    pub(crate) fn write(&self, record: &CommitRecord) -> Result<(), String> {
+        let tmp_filename = format!("{}/tx-{}.TMP", self.path, record.txid);
+        let bytes = bincode::serialize(record).map_err(|e| e.to_string())?;
+
+        let mut file = File::create(&tmp_filename).map_err(|e| e.to_string())?;
+        file.write_all(&bytes).map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+
+        fs::rename(&tmp_filename, self.filename(record.txid)).map_err(|e| e.to_string())?;
+        sync_dir(&self.path)
+    }
+
+    fn remove(&self, txid: u64) -> Result<(), String> {
+        fs::remove_file(self.filename(txid)).map_err(|e| e.to_string())?;
+        sync_dir(&self.path)
+    }
+
+    // records in order they are committed
+    fn records(&self) -> Result<Vec<CommitRecord>, String> {
+        let mut records = Vec::new();
+
+        for entry in fs::read_dir(&self.path).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            if entry.file_name().to_string_lossy().ends_with(".TXN") {
+                let bytes = fs::read(entry.path()).map_err(|e| e.to_string())?;
+                records.push(bincode::deserialize::<CommitRecord>(&bytes).map_err(|e| e.to_string())?);
+            }
+        }
+
+        records.sort_by_key(|record| record.txid);
+        Ok(records)
+    }
+
+    // true if a record has writes of datastore at path
+    pub(crate) fn pending(&self, path: &str) -> Result<bool, String> {
+        Ok(self.records()?.iter().any(|record| record.parts.iter().any(|(part, _, _)| part == path)))
+    }
+
+
+    /// complete writes of committed transactions that datastore has not logged,
+    /// datastore is removed from their records after that
+    pub(crate) async fn recover<K, Doc>(&self, storage: &Storage<K, Doc>) -> Result<(), String>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K: Serialize + DeserializeOwned + PartialOrd + Ord + PartialEq + Eq + Hash + Clone + Send + Sync + 'static,
+    {
+        let path = match storage.disk_path() {
+            Some(path) => path.to_owned(),
+            None => return Ok(())
+        };
+
+        let _recovering = self.recovering.lock().await;
+        for mut record in self.records()? {
+            let part = match record.parts.iter().position(|(part, _, _)| *part == path) {
+                Some(part) => record.parts.remove(part),
+                None => continue
+            };
+
+            // datastore that logged writes of transaction has it in its wal
+            if !storage.logged_transaction(record.txid)? {
+                let (_, header, bytes) = part;
+                let header = PageHeader::parse(&header).ok_or("bad page header in commit record")?;
+                let (_, batch) = storage.decode_replicated(&header, &bytes)?;
+
+                storage.apply_query(RQuery::Transaction(record.txid, batch.flatten()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+
+            match record.parts.is_empty() {
+                true => self.remove(record.txid)?,
+                false => self.write(&record)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+
+/// writes staged by `Database::transaction`, database is not changed until commit
+pub struct DatabaseTransaction<'a> {
+    db: &'a Database,
+
+    // Staged<K, Doc> per datastore
+    parts: HashMap<TypeId, Box<dyn Part>>,
+}
+
+
+impl<'a> DatabaseTransaction<'a> {
+    pub(crate) fn new(db: &'a Database) -> Self {
+        DatabaseTransaction { db, parts: HashMap::new() }
+    }
+
+    pub fn insert<K, Doc>(&mut self, key: K, doc: Doc) -> Result<(), SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K: Serialize + DeserializeOwned + PartialOrd + Ord + PartialEq + Eq + Hash + Clone + Send + Sync + 'static,
+    {
+        self.staged::<K, Doc>()?.stage(RQuery::Insert(key, doc));
+        Ok(())
+    }
+
+    pub fn remove<K, Doc>(&mut self, key: K) -> Result<(), SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K: Serialize + DeserializeOwned + PartialOrd + Ord + PartialEq + Eq + Hash + Clone + Send + Sync + 'static,
+    {
+        self.staged::<K, Doc>()?.stage(RQuery::Remove(key));
+        Ok(())
+    }
+
+    /// transaction fail with `StatusResult::Conflict` if version of key is not version on commit
+    pub fn expect_version<K, Doc>(&mut self, key: K, version: u64) -> Result<(), SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K: Serialize + DeserializeOwned + PartialOrd + Ord + PartialEq + Eq + Hash + Clone + Send + Sync + 'static,
+    {
+        self.staged::<K, Doc>()?.expect(key, version);
+        Ok(())
+    }
+
+    /// lookup by key, writes staged before are seen
+    pub fn lookup<K, Doc>(&self, key: &K) -> Result<Option<Doc>, SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K: Serialize + DeserializeOwned + PartialOrd + Ord + PartialEq + Eq + Hash + Clone + Send + Sync + 'static,
+    {
+        let storage = self.db.storage::<K, Doc>()?;
+
+        let staged = self.parts
+            .get(&TypeId::of::<Staged<K, Doc>>())
+            .and_then(|part| part.as_any().downcast_ref::<Staged<K, Doc>>())
+            .and_then(|staged| staged.get(key));
+
+        match staged {
+            Some(doc) => Ok(doc.clone()),
+            None => Ok(storage.lookup(key).map(|doc| doc.value().clone()))
+        }
+    }
+
+    fn staged<K, Doc>(&mut self) -> Result<&mut Staged<K, Doc>, SessionResult>
+    where
+        Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+        K: Serialize + DeserializeOwned + PartialOrd + Ord + PartialEq + Eq + Hash + Clone + Send + Sync + 'static,
+    {
+        self.db.storage::<K, Doc>()?;
+
+        let part = self.parts
+            .entry(TypeId::of::<Staged<K, Doc>>())
+            .or_insert_with(|| Box::new(Staged::<K, Doc>::new()));
+
+        Ok(part.as_any_mut().downcast_mut::<Staged<K, Doc>>().unwrap())
+    }
+
+
+    /// validate writes of every datastore, make them durable by one commit record and
+    /// then apply them, transaction is rolled back if any datastore reject its writes
+    pub(crate) async fn commit(self, log: Option<&TransactionLog>) -> Result<(), SessionResult> {
+        let db = self.db;
+
+        // datastores are locked in order of their path, so transactions don't deadlock
+        let mut parts = self.parts
+            .into_values()
+            .map(|part| Ok((part.path(db)?, part)))
+            .collect::<Result<Vec<_>, SessionResult>>()?;
+        parts.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut prepared = Vec::with_capacity(parts.len());
+        for (_, part) in parts {
+            match part.prepare(db).await {
+                Ok(Some(participant)) => prepared.push(participant),
+                Ok(None) => {}
+                Err(e) => {
+                    rollback(prepared).await;
+                    return Err(e)
+                }
+            }
+        }
+
+        // one record is all or nothing by itself, commit record is needed for more
+        let durable = prepared.iter().filter(|participant| participant.on_disk()).count();
+        let txid = match (durable, log) {
+            (0 | 1, _) => None,
+            (_, None) => {
+                rollback(prepared).await;
+                return Err(SessionResult::Err(StatusResult::Err("database has not transaction log".to_owned())))
+            }
+            (_, Some(log)) => {
+                let txid = log.next_txid();
+                if let Err(e) = commit_record(txid, &prepared).and_then(|record| log.write(&record)) {
+                    rollback(prepared).await;
+                    return Err(SessionResult::Err(StatusResult::Err(e)))
+                }
+                Some(txid)
+            }
+        };
+
+        // without commit record nothing is committed until its only durable datastore log it
+        let mut res = Ok(());
+        let mut failed = Vec::new();
+        for participant in prepared.iter() {
+            if let Err(e) = participant.log(txid).await {
+                failed.push(participant.path());
+                res = res.and(Err(e));
+
+                if txid.is_none() {
+                    break
+                }
+            }
+        }
+
+        match (txid, log) {
+            (None, _) if res.is_err() => {
+                rollback(prepared).await;
+                return res
+            }
+            (Some(txid), Some(log)) if failed.is_empty() => {
+                res = log.remove(txid).map_err(|e| SessionResult::Err(StatusResult::Err(e)));
+            }
+
+            // committed, datastores that failed are read-only until they complete it
+            // from commit record on next open, so no write of them is logged after it
+            (Some(txid), Some(log)) => {
+                for participant in prepared.iter().filter(|participant| failed.contains(&participant.path())) {
+                    participant.set_read_only();
+                }
+
+                // if record is not rewritten, datastores that logged it find it in their wal
+                let rewritten = commit_record(txid, &prepared)
+                    .map(|mut record| {
+                        record.parts.retain(|(path, _, _)| failed.contains(path));
+                        record
+                    })
+                    .and_then(|record| log.write(&record));
+
+                if let Err(e) = rewritten {
+                    res = res.and(Err(SessionResult::Err(StatusResult::Err(e))));
+                }
+            }
+            _ => {}
+        }
+
+        for participant in prepared {
+            if let Err(e) = participant.finish().await {
+                res = res.and(Err(e));
+            }
+        }
+
+        res
+    }
+}
+
+
+fn commit_record(txid: u64, prepared: &[Box<dyn Participant + '_>]) -> Result<CommitRecord, String> {
+    let mut parts = Vec::new();
+    for participant in prepared.iter().filter(|participant| participant.on_disk()) {
+        parts.push(participant.seal()?);
+    }
+
+    Ok(CommitRecord { txid, parts })
+}
+
+async fn rollback(prepared: Vec<Box<dyn Participant + '_>>) {
+    for participant in prepared.into_iter().rev() {
+        participant.rollback().await;
+    }
+}
+
+
+
+// staged writes of one datastore
+#[async_trait(?Send)]
+trait Part {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    // directory of datastore
+    fn path(&self, db: &Database) -> Result<String, SessionResult>;
+
+    async fn prepare<'s>(self: Box<Self>, db: &'s Database) -> Result<Option<Box<dyn Participant + 's>>, SessionResult>;
+}
+
+
+#[async_trait(?Send)]
+impl<K, Doc> Part for Staged<K, Doc>
+where
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+    K: Serialize + DeserializeOwned + PartialOrd + Ord + PartialEq + Eq + Hash + Clone + Send + Sync + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn path(&self, db: &Database) -> Result<String, SessionResult> {
+        Ok(db.storage::<K, Doc>()?.path().to_owned())
+    }
+
+    async fn prepare<'s>(self: Box<Self>, db: &'s Database) -> Result<Option<Box<dyn Participant + 's>>, SessionResult> {
+        let storage = db.storage::<K, Doc>()?;
+        if storage.is_read_only() {
+            return Err(SessionResult::ReadOnly)
+        }
+
+        let (batch, expected) = match self.into_batch() {
+            Some(batch) => batch,
+            None => return Ok(None)
+        };
+
+        match storage.prepare_batch(batch, &expected, Origin::Local).await? {
+            Some(prepared) => Ok(Some(Box::new(prepared))),
+            None => Ok(None)
+        }
+    }
+}
+
+
+
+// validated writes of one datastore, its keys are locked until writes are applied
+#[async_trait(?Send)]
+trait Participant {
+    fn on_disk(&self) -> bool;
+
+    // directory, page header and writes as one record, for commit record
+    fn seal(&self) -> Result<(String, Vec<u8>, Vec<u8>), String>;
+
+    // directory of datastore
+    fn path(&self) -> String;
+
+    async fn log(&self, txid: Option<u64>) -> Result<(), SessionResult>;
+    async fn rollback(self: Box<Self>);
+
+    // datastore that failed to log a committed transaction reject writes until it is reopened
+    fn set_read_only(&self);
+
+    // apply writes, unlock keys and send writes to other nodes of ReplicatedCopies
+    async fn finish(self: Box<Self>) -> Result<(), SessionResult>;
+}
+
+
+#[async_trait(?Send)]
+impl<'a, K, Doc> Participant for Prepared<'a, K, Doc>
+where
+    Doc: Serialize + DeserializeOwned + Clone + Send + Sync + 'static + Document,
+    K: Serialize + DeserializeOwned + PartialOrd + Ord + PartialEq + Eq + Hash + Clone + Send + Sync + 'static,
+{
+    fn on_disk(&self) -> bool {
+        self.storage().disk_path().is_some()
+    }
+
+    fn seal(&self) -> Result<(String, Vec<u8>, Vec<u8>), String> {
+        let storage = self.storage();
+        Ok((storage.path().to_owned(), storage.replicated_header().to_bytes(), Prepared::seal(self)?))
+    }
+
+    fn path(&self) -> String {
+        self.storage().path().to_owned()
+    }
+
+    async fn log(&self, txid: Option<u64>) -> Result<(), SessionResult> {
+        Prepared::log(self, txid).await
+    }
+
+    fn set_read_only(&self) {
+        self.storage().set_read_only(true)
+    }
+
+    async fn rollback(self: Box<Self>) {
+        Prepared::rollback(*self)
+    }
+
+    async fn finish(self: Box<Self>) -> Result<(), SessionResult> {
+        Prepared::finish(*self).await
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{Options, Schema, Storage, StorageType, RQuery, WalReader, StartAt};
+use crate::darkbird::test_fixture::{Profile, Member, TempDir, wait_until};
+
+
+#[tokio::test]
+async fn transaction_commit_all_writes_or_none() {
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true);
+        let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+        storage.insert(1, Member::account("a@x", 100)).await.unwrap();
+        storage.insert(2, Member::account("b@x", 0)).await.unwrap();
+
+        // duplicate index key fail whole transaction before anything is applied
+        let res = storage.transaction(|tx| {
+            tx.insert(1, Member::account("a@x", 50));
+            tx.insert(2, Member::account("b@x", 50));
+            tx.insert(3, Member::account("a@x", 0));
+            Ok(())
+        }).await;
+        assert!(res.is_err());
+
+        // error of closure discard staged writes
+        let res = storage.transaction(|tx| {
+            tx.remove(1);
+            assert!(tx.lookup(&1).is_none());
+            Err::<(), _>(crate::SessionResult::Err(crate::StatusResult::Err("abort".to_owned())))
+        }).await;
+        assert!(res.is_err());
+
+        assert_eq!(storage.lookup(&1).unwrap().value(), &Member::account("a@x", 100));
+        assert_eq!(storage.lookup(&2).unwrap().value(), &Member::account("b@x", 0));
+        assert!(storage.lookup(&3).is_none());
+
+        // index keys can move between documents of same transaction
+        let moved = storage.transaction(|tx| {
+            let a = tx.lookup(&1).unwrap();
+            let b = tx.lookup(&2).unwrap();
+            tx.insert(1, Member::account(&b.email, a.balance - 30));
+            tx.insert(2, Member::account(&a.email, b.balance + 30));
+            tx.remove(4);
+            Ok(30)
+        }).await.unwrap();
+        assert_eq!(moved, 30);
+        assert_eq!(storage.lookup_by_index("email", "b@x").unwrap().key(), &1);
+    }
+
+    // two inserts and one record for the transaction
+    let mut reader = WalReader::<u32, Member>::open(&path, "accounts", 1000, StartAt::Beginning).unwrap();
+    let mut records = Vec::new();
+    while let Some((_, rq)) = reader.try_next().unwrap() {
+        records.push(rq);
+    }
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[2], RQuery::Batch(vec![RQuery::Versioned(1, Member::account("b@x", 70), 2), RQuery::Versioned(2, Member::account("a@x", 30), 2)]));
+
+    let ops = Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<u32, Member>::open(ops).await.unwrap().0;
+    assert_eq!(storage.collection_len(), 2);
+    assert_eq!(storage.lookup_by_index("email", "a@x").unwrap().value(), &Member::account("a@x", 30));
+    assert_eq!(storage.lookup_by_index("email", "b@x").unwrap().value(), &Member::account("b@x", 70));
+
+    // compaction keep writes of transaction in one record
+    let stats = storage.compact().await.unwrap();
+    assert_eq!((stats.records_before, stats.records_after), (3, 1));
+    drop(storage);
+
+    let mut reader = WalReader::<u32, Member>::open(&path, "accounts", 1000, StartAt::Beginning).unwrap();
+    assert_eq!(reader.try_next().unwrap().unwrap().1, records[2]);
+    assert!(reader.try_next().unwrap().is_none());
+}
+
+
+#[tokio::test]
+async fn database_transaction_commit_across_datastores_and_recover() {
+    let path = TempDir::new();
+    let log_path = format!("{}/transactions", path);
+    let open = || async {
+        Schema::new()
+            .with_transaction_log(&log_path).unwrap()
+            .with_datastore::<String, Profile>(Options::new(&path, "users", 1000, StorageType::DiskCopies, true)).await.unwrap()
+            .with_datastore::<u32, Member>(Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true)).await.unwrap()
+            .build()
+    };
+    let records = || std::fs::read_dir(&log_path).unwrap().count();
+
+    {
+        let db = open().await;
+        db.transaction(|tx| {
+            tx.insert("u1".to_owned(), Profile::new("DanyalMh", 30))?;
+            tx.insert(1u32, Member::account("a@x", 100))
+        }).await.unwrap();
+        assert_eq!(records(), 0);
+
+        // account reject duplicate email, so user is not inserted too
+        let res = db.transaction(|tx| {
+            tx.insert("u2".to_owned(), Profile::new("Other", 20))?;
+            tx.insert(2u32, Member::account("a@x", 0))
+        }).await;
+        assert!(res.is_err());
+        assert!(db.lookup::<String, Profile>(&"u2".to_owned()).unwrap().is_none());
+        assert!(db.lookup::<u32, Member>(&2).unwrap().is_none());
+
+        // crash after commit record is written and just accounts logged its writes
+        let users = db.storage::<String, Profile>().unwrap();
+        let accounts = db.storage::<u32, Member>().unwrap();
+        let part = |path: &str, header: crate::darkbird::wal::codec::PageHeader, record| (path.to_owned(), header.to_bytes(), record);
+        let record = crate::darkbird::transaction::CommitRecord {
+            txid: 7,
+            parts: vec![
+                part(users.path(), users.replicated_header(), users.seal_replicated(&RQuery::Batch(vec![RQuery::Insert("u3".to_owned(), Profile::new("Late", 40))])).unwrap()),
+                part(accounts.path(), accounts.replicated_header(), accounts.seal_replicated(&RQuery::Batch(vec![RQuery::Insert(3, Member::account("c@x", 5))])).unwrap()),
+            ],
+        };
+        crate::TransactionLog::open(&log_path).unwrap().write(&record).unwrap();
+        accounts.apply_query(RQuery::Transaction(7, vec![RQuery::Insert(3, Member::account("c@x", 5))])).await.unwrap();
+        accounts.insert(4, Member::account("d@x", 1)).await.unwrap();
+
+        // commit record that is not renamed is not committed
+        std::fs::write(format!("{}/tx-8.TMP", log_path), b"torn").unwrap();
+    }
+
+    let db = open().await;
+    assert_eq!(records(), 0);
+    assert_eq!(db.lookup::<String, Profile>(&"u1".to_owned()).unwrap().unwrap().value(), &Profile::new("DanyalMh", 30));
+    assert_eq!(db.lookup::<String, Profile>(&"u3".to_owned()).unwrap().unwrap().value(), &Profile::new("Late", 40));
+    assert_eq!(db.lookup::<u32, Member>(&3).unwrap().unwrap().value(), &Member::account("c@x", 5));
+
+    // accounts had logged its writes before other writes, so they are not written again
+    let mut reader = WalReader::<u32, Member>::open(&path, "accounts", 1000, StartAt::Beginning).unwrap();
+    let mut transactions = 0;
+    while let Some((_, rq)) = reader.try_next().unwrap() {
+        transactions += matches!(rq, RQuery::Transaction(..)) as usize;
+    }
+    assert_eq!(transactions, 2);
+
+    // lazy opened datastore complete it after loading and is read-only until then
+    let users = db.storage::<String, Profile>().unwrap();
+    let record = crate::darkbird::transaction::CommitRecord {
+        txid: 9,
+        parts: vec![(users.path().to_owned(), users.replicated_header().to_bytes(), users.seal_replicated(&RQuery::Batch(vec![RQuery::Insert("u5".to_owned(), Profile::new("Lazy", 50))])).unwrap())],
+    };
+    crate::TransactionLog::open(&log_path).unwrap().write(&record).unwrap();
+    drop(db);
+
+    let db = Schema::new()
+        .with_transaction_log(&log_path).unwrap()
+        .with_datastore::<String, Profile>(Options::new(&path, "users", 1000, StorageType::DiskCopies, true).with_lazy_load(true)).await.unwrap()
+        .build();
+    let users = db.storage::<String, Profile>().unwrap();
+    wait_until(|| !users.is_read_only()).await;
+    assert_eq!(users.lookup(&"u5".to_owned()).unwrap().value(), &Profile::new("Lazy", 50));
+    assert_eq!(records(), 0);
+}
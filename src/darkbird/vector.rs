@@ -30,14 +30,15 @@ impl Vector {
     /// ```
     /// // Create a new storage instance
     /// 
-    /// let path = ".";
+    /// let dir = std::env::temp_dir().join("darkbird-vectors");
+    /// let path = dir.to_str().unwrap();
     /// let storage_name = "vectors";
     /// let total_page_size = 500;
     /// let stype = crate::StorageType::RamCopies;
     /// let off_reporter = true;
     /// 
     /// let ops = Options::new(path, storage_name, total_page_size, stype, off_reporter);
-    /// let db = VecStorage::open(ops).await.unwrap();
+    /// let (db, _) = VecStorage::open(ops).await.unwrap();
     ///
     /// // Define two vectors
     /// let vector1 = vec![1.0, 2.0, 3.0];
@@ -0,0 +1,690 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use simple_wal::LogFile;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use super::codec::{self, PageHeader};
+use super::disk_log::{filename_factory, list_pages, page_size, Session};
+use super::layout;
+use super::recovery::{self, Entry};
+use super::snapshot::{self, sync_dir};
+
+
+
+/// written alongside every backup
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    // name of datastore that backup taken from
+    pub source: String,
+
+    // rfc3339
+    pub created_at: String,
+
+    pub total_page_size: usize,
+    pub pages: usize,
+    pub records: usize,
+
+    // sequence of last record logged before backup, 0 if datastore has not stamped records
+    #[serde(default)]
+    pub seq: u64,
+
+    // backup that incremental backup contain records after it, None for full backup
+    #[serde(default)]
+    pub base: Option<String>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backup {
+    // name of backup directory under root
+    pub name: String,
+    pub manifest: Manifest,
+}
+
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VerifyReport {
+    pub pages: usize,
+    pub records: usize,
+    pub errors: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+
+/// written in root of backup set taken by Database::backup, after every datastore of it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SetManifest {
+    // rfc3339
+    pub created_at: String,
+
+    // name of datastores, every one is a backup with own manifest
+    pub datastores: Vec<String>,
+}
+
+
+/// DiskCopies datastore that take part in backup set
+#[derive(Clone)]
+pub struct Source {
+    pub(crate) name: String,
+    pub(crate) path: String,
+    pub(crate) total_page_size: usize,
+    pub(crate) session: Session,
+
+    // writers of datastore hold it shared
+    pub(crate) gate: Arc<RwLock<()>>,
+
+    // true while checkpoint or compaction is rewriting pages
+    pub(crate) maintenance: Arc<AtomicBool>,
+}
+
+
+#[derive(Clone, Copy)]
+pub enum RestoreMode {
+    // remove target datastore and put backup in place of it
+    Replace,
+
+    // create target datastore from backup, fail if target exist
+    Clone,
+}
+
+
+
+/// return every backup of datastore, oldest first
+pub fn list(root: &str, name: &str) -> Result<Vec<Backup>, String> {
+    let prefix = format!("{}_backup_", name);
+    let mut backups = Vec::new();
+
+    for entry in fs::read_dir(root).map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        let backup_name = match entry.file_name().to_str() {
+            Some(backup_name) if backup_name.starts_with(&prefix) => backup_name.to_owned(),
+            _ => continue
+        };
+
+        // backup without manifest is incomplete
+        if let Ok(manifest) = read_manifest(root, &backup_name) {
+            backups.push(Backup { name: backup_name, manifest });
+        }
+    }
+
+    backups.sort_by(|a, b| a.manifest.created_at.cmp(&b.manifest.created_at));
+    Ok(backups)
+}
+
+
+/// checksum every record of backup and compare with manifest
+pub fn verify(root: &str, backup_name: &str) -> Result<VerifyReport, String> {
+    let manifest = read_manifest(root, backup_name)?;
+    let path = format!("{}/{}", root, backup_name);
+
+    let mut report = count(&path, manifest.total_page_size)?;
+
+    if report.pages != manifest.pages {
+        report.errors.push(format!("manifest has {} pages, found {}", manifest.pages, report.pages));
+    }
+
+    if report.records != manifest.records {
+        report.errors.push(format!("manifest has {} records, found {}", manifest.records, report.records));
+    }
+
+    if let Some((_, filename)) = snapshot::latest(&path)? {
+        if let Err(e) = snapshot::header(&filename) {
+            report.errors.push(e);
+        }
+    }
+
+    Ok(report)
+}
+
+
+/// restore datastore target from backup, storage of target must be closed while restoring
+pub fn restore(root: &str, backup_name: &str, target: &str, mode: RestoreMode) -> Result<(), String> {
+    let report = verify(root, backup_name)?;
+    if !report.is_valid() {
+        return Err(format!("backup {} is corrupt: {}", backup_name, report.errors.join(", ")))
+    }
+
+    if read_manifest(root, backup_name)?.base.is_some() {
+        return Err(format!("backup {} is incremental, restore it by restore_to", backup_name))
+    }
+
+    restore_dir(&format!("{}/{}", root, backup_name), root, target, mode)
+}
+
+
+
+/// backup just records logged after latest backup of datastore, latest backup can be full or incremental,
+/// so incremental backups make a chain to a full backup. backup is written next to datastore and
+/// source is pinned same as `backup_set`, so records logged while reading pages are left to next backup
+pub async fn backup_incremental(source: &Source) -> Result<Backup, String> {
+    let sources = std::slice::from_ref(source);
+
+    hold_maintenance(sources).await;
+    let res = take_backup_incremental(source).await;
+    release_maintenance(sources);
+
+    res
+}
+
+async fn take_backup_incremental(source: &Source) -> Result<Backup, String> {
+    let (page_index, len) = pin(std::slice::from_ref(source)).await?[0];
+
+    let root = Path::new(&source.path)
+        .parent()
+        .and_then(|root| root.to_str())
+        .ok_or_else(|| format!("{} has not parent", source.path))?
+        .to_owned();
+
+    let source = source.clone();
+    let task = tokio::task::spawn_blocking(move || {
+        incremental(&root, &source.name, source.total_page_size, page_index, len)
+    });
+
+    task.await.map_err(|e| e.to_string())?
+}
+
+// records of pages up to pinned position that are logged after previous backup
+fn incremental(root: &str, name: &str, total_page_size: usize, pinned_page: usize, pinned_len: u64) -> Result<Backup, String> {
+    let total_page_size = page_size(total_page_size);
+
+    let previous = match list(root, name)?.pop() {
+        Some(previous) if previous.manifest.seq > 0 => previous,
+        Some(previous) => return Err(format!("backup {} has not stamped records, take a full backup", previous.name)),
+        None => return Err(format!("datastore {} has not any backup, take a full backup", name)),
+    };
+
+    let dt = Utc::now();
+    let backup_name = format!("{}_backup_{}-{}", name, dt.date_naive(), dt.time());
+    let source_path = format!("{}/{}", root, name);
+    let backup_path = format!("{}/{}", root, backup_name);
+
+    // page index -> header record and records logged after previous backup
+    let mut pages = BTreeMap::new();
+    let mut seq = previous.manifest.seq;
+    let mut first_seq = None;
+
+    for page_index in list_pages(&source_path, total_page_size).map_err(|e| e.to_string())? {
+        if page_index > pinned_page {
+            break
+        }
+
+        // pinned page is still appended, so just pinned len of it is read
+        let limit = if page_index == pinned_page { pinned_len } else { u64::MAX };
+        let (header, (header_record, records)) = read_records(&source_path, total_page_size, page_index, limit)?;
+
+        let mut records_after = Vec::new();
+        for record in records {
+            let stamp = header.stamp(&record)?;
+            if stamp.seq > previous.manifest.seq {
+                first_seq = first_seq.or(Some(stamp.seq));
+                seq = seq.max(stamp.seq);
+                records_after.push(record);
+            }
+        }
+
+        if !records_after.is_empty() {
+            pages.insert(page_index, (header_record, records_after));
+        }
+    }
+
+    // checkpoint can release pages before incremental backup copy them
+    if let Some(first_seq) = first_seq {
+        if first_seq != previous.manifest.seq + 1 {
+            return Err(format!("records after seq {} are released from pages, take a full backup", previous.manifest.seq))
+        }
+    }
+
+    fs::create_dir_all(&backup_path).map_err(|e| e.to_string())?;
+    write_pages(&backup_path, total_page_size, pages)?;
+
+    let report = count(&backup_path, total_page_size)?;
+    let manifest = Manifest {
+        source: name.to_owned(),
+        created_at: dt.to_rfc3339(),
+        total_page_size,
+        pages: report.pages,
+        records: report.records,
+        seq,
+        base: Some(previous.name),
+    };
+    save_manifest(&backup_path, &manifest)?;
+
+    Ok(Backup { name: backup_name, manifest })
+}
+
+
+
+/// restore datastore target to state at instant, from latest full backup taken before it
+/// and incremental backups chained to that, records logged after instant are not restored
+pub fn restore_to(root: &str, name: &str, target: &str, at: DateTime<Utc>, mode: RestoreMode) -> Result<(), String> {
+    let backups = list(root, name)?;
+    let at_micros = at.timestamp_micros().max(0) as u64;
+
+    let created_at = |backup: &Backup| -> Result<DateTime<Utc>, String> {
+        DateTime::parse_from_rfc3339(&backup.manifest.created_at)
+            .map(|dt| dt.with_timezone(&Utc))
+            .map_err(|e| e.to_string())
+    };
+
+    // snapshot of full backup can not be cut, so it must be taken before instant
+    let mut full = None;
+    for (index, backup) in backups.iter().enumerate() {
+        if backup.manifest.base.is_none() && created_at(backup)? <= at {
+            full = Some(index);
+        }
+    }
+
+    let full = full.ok_or_else(|| format!("datastore {} has not any full backup before {}", name, at.to_rfc3339()))?;
+
+    let mut chain = vec![&backups[full]];
+    for backup in backups[full + 1..].iter() {
+        let last = chain[chain.len() - 1];
+        if backup.manifest.base.as_deref() != Some(last.name.as_str()) {
+            continue
+        }
+
+        chain.push(backup);
+
+        // records of later increments are all after instant
+        if created_at(backup)? > at {
+            break
+        }
+    }
+
+    for backup in chain.iter() {
+        let report = verify(root, &backup.name)?;
+        if !report.is_valid() {
+            return Err(format!("backup {} is corrupt: {}", backup.name, report.errors.join(", ")))
+        }
+    }
+
+    let total_page_size = chain[0].manifest.total_page_size;
+    let staging_path = format!("{}/{}.pitr", root, target);
+    if Path::new(&staging_path).is_dir() {
+        fs::remove_dir_all(&staging_path).map_err(|e| e.to_string())?;
+    }
+    fs::create_dir(&staging_path).map_err(|e| e.to_string())?;
+
+    let full_path = format!("{}/{}", root, chain[0].name);
+    if let Some((snapshot_index, filename)) = snapshot::latest(&full_path)? {
+        let dst = snapshot::filename_factory_snapshot(&staging_path, snapshot_index);
+        fs::copy(&filename, &dst).map_err(|e| e.to_string())?;
+    }
+
+    // records of same page can be in several backups of chain, they are merged in order of chain
+    let mut pages: BTreeMap<usize, RawPage> = BTreeMap::new();
+    for backup in chain.iter() {
+        let path = format!("{}/{}", root, backup.name);
+
+        for page_index in list_pages(&path, total_page_size).map_err(|e| e.to_string())? {
+            let (header, (header_record, records)) = read_records(&path, total_page_size, page_index, u64::MAX)?;
+
+            let page = pages.entry(page_index).or_insert_with(|| (header_record, Vec::new()));
+            for record in records {
+                // records of unstamped pages have not time and are restored
+                if header.stamp(&record)?.timestamp <= at_micros {
+                    page.1.push(record);
+                }
+            }
+        }
+    }
+
+    write_pages(&staging_path, total_page_size, pages)?;
+
+    let res = restore_dir(&staging_path, root, target, mode);
+    let _ = fs::remove_dir_all(&staging_path);
+    res
+}
+
+
+
+/// take backup set of sources to dest, sources are pinned together while their writers are blocked,
+/// so backup set restores all datastores to one point in time
+pub async fn backup_set(sources: &[Source], dest: &str) -> Result<SetManifest, String> {
+    if Path::new(dest).exists() {
+        return Err(format!("{} exist", dest))
+    }
+
+    hold_maintenance(sources).await;
+    let res = take_backup_set(sources, dest).await;
+    release_maintenance(sources);
+
+    res
+}
+
+async fn take_backup_set(sources: &[Source], dest: &str) -> Result<SetManifest, String> {
+    let created_at = Utc::now().to_rfc3339();
+    let pins = pin(sources).await?;
+
+    let sources = sources.to_vec();
+    let dest = dest.to_owned();
+
+    let task = tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(&dest).map_err(|e| e.to_string())?;
+
+        for (source, (page_index, len)) in sources.iter().zip(pins) {
+            copy_pinned(source, page_index, len, &format!("{}/{}", dest, source.name))?;
+            write_manifest(&dest, &source.name, &source.name, source.total_page_size, created_at.clone())?;
+        }
+
+        // set manifest is last file, backup set without it is incomplete
+        let manifest = SetManifest {
+            created_at,
+            datastores: sources.iter().map(|source| source.name.clone()).collect()
+        };
+
+        let bytes = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+        let filename = format!("{}/{}", dest, MANIFEST);
+        fs::write(&filename, bytes).map_err(|e| e.to_string())?;
+        File::open(&filename).and_then(|f| f.sync_all()).map_err(|e| e.to_string())?;
+        sync_dir(&dest)?;
+
+        Ok(manifest)
+    });
+
+    task.await.map_err(|e| e.to_string())?
+}
+
+
+// checkpoint and compaction must not release or rewrite pages of sources while copying
+async fn hold_maintenance(sources: &[Source]) {
+    for source in sources {
+        while source.maintenance.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+fn release_maintenance(sources: &[Source]) {
+    for source in sources {
+        source.maintenance.store(false, Ordering::Release);
+    }
+}
+
+
+// last position of every source, writers of all sources are blocked while pinning,
+// so no record is logged between pins. gates are locked in order of path same as
+// transactions, so they don't deadlock
+async fn pin(sources: &[Source]) -> Result<Vec<(usize, u64)>, String> {
+    let mut locking = sources.iter().collect::<Vec<_>>();
+    locking.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let mut gates = Vec::with_capacity(sources.len());
+    for source in locking {
+        gates.push(source.gate.write().await);
+    }
+
+    let mut pins = Vec::with_capacity(sources.len());
+    for source in sources {
+        pins.push(source.session.pin().await.map_err(|e| e.to_string())?);
+    }
+
+    Ok(pins)
+}
+
+
+/// verify every datastore of backup set and restore all of them under root
+pub fn restore_set(dest: &str, root: &str, mode: RestoreMode) -> Result<(), String> {
+    let bytes = fs::read(format!("{}/{}", dest, MANIFEST)).map_err(|e| e.to_string())?;
+    let manifest: SetManifest = serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+
+    // nothing restored if any datastore is corrupt
+    for name in manifest.datastores.iter() {
+        let report = verify(dest, name)?;
+        if !report.is_valid() {
+            return Err(format!("backup {} is corrupt: {}", name, report.errors.join(", ")))
+        }
+
+        if let RestoreMode::Clone = mode {
+            if Path::new(&format!("{}/{}", root, name)).exists() {
+                return Err(format!("datastore {} exist", name))
+            }
+        }
+    }
+
+    for name in manifest.datastores.iter() {
+        restore_dir(&format!("{}/{}", dest, name), root, name, mode)?;
+    }
+
+    Ok(())
+}
+
+
+
+// copy latest snapshot and pages of source up to pinned position
+fn copy_pinned(source: &Source, page_index: usize, len: u64, dst: &str) -> Result<(), String> {
+    fs::create_dir_all(dst).map_err(|e| e.to_string())?;
+
+    let first_page = match snapshot::latest(&source.path)? {
+        Some((snapshot_index, filename)) => {
+            let dst_filename = snapshot::filename_factory_snapshot(dst, snapshot_index);
+            fs::copy(&filename, &dst_filename).map_err(|e| e.to_string())?;
+            File::open(&dst_filename).and_then(|f| f.sync_all()).map_err(|e| e.to_string())?;
+            snapshot_index
+        }
+        None => 0
+    };
+
+    let pages = list_pages(&source.path, source.total_page_size).map_err(|e| e.to_string())?;
+    for index in pages.into_iter().filter(|index| *index >= first_page && *index <= page_index) {
+        let src_filename = filename_factory(&source.path, source.total_page_size * index);
+        let dst_filename = filename_factory(dst, source.total_page_size * index);
+
+        // pinned page is still appended, so just pinned len of it is copied
+        let limit = if index == page_index { len } else { u64::MAX };
+
+        let mut src = File::open(&src_filename).map_err(|e| e.to_string())?.take(limit);
+        let mut dst_file = File::create(&dst_filename).map_err(|e| e.to_string())?;
+        io::copy(&mut src, &mut dst_file).map_err(|e| e.to_string())?;
+        dst_file.sync_all().map_err(|e| e.to_string())?;
+    }
+
+    sync_dir(dst)
+}
+
+
+// copy backup directory to target datastore under root
+fn restore_dir(backup_path: &str, root: &str, target: &str, mode: RestoreMode) -> Result<(), String> {
+    let target_path = format!("{}/{}", root, target);
+    let staging_path = format!("{}.restore", target_path);
+
+    // previous restore stopped by a crash
+    recover(root, target)?;
+
+    if let RestoreMode::Clone = mode {
+        if Path::new(&target_path).exists() {
+            return Err(format!("datastore {} exist", target))
+        }
+    }
+
+    // backup is copied to staging directory first, so a crash never leaves half restored datastore
+    fs::create_dir(&staging_path).map_err(|e| e.to_string())?;
+
+    for entry in fs::read_dir(backup_path).map_err(|e| e.to_string())? {
+        let entry = entry.map_err(|e| e.to_string())?;
+        if !entry.path().is_file() || entry.file_name() == MANIFEST {
+            continue
+        }
+
+        let dst = Path::new(&staging_path).join(entry.file_name());
+        fs::copy(entry.path(), &dst).map_err(|e| e.to_string())?;
+        File::open(&dst).and_then(|f| f.sync_all()).map_err(|e| e.to_string())?;
+    }
+    sync_dir(&staging_path)?;
+
+    // after a crash between renames, recover put staging in place of target
+    if Path::new(&target_path).exists() {
+        let old_path = format!("{}.old", target_path);
+        fs::rename(&target_path, &old_path).map_err(|e| e.to_string())?;
+        fs::rename(&staging_path, &target_path).map_err(|e| e.to_string())?;
+        fs::remove_dir_all(&old_path).map_err(|e| e.to_string())?;
+    } else {
+        fs::rename(&staging_path, &target_path).map_err(|e| e.to_string())?;
+    }
+
+    sync_dir(root)
+}
+
+
+
+/// finish or undo restore of datastore that is stopped by a crash, staging is complete when target is
+/// moved to old, so it replace target. otherwise copying to staging is not finished and it is removed
+pub(crate) fn recover(root: &str, name: &str) -> Result<(), String> {
+    let target_path = format!("{}/{}", root, name);
+    let staging_path = format!("{}.restore", target_path);
+    let old_path = format!("{}.old", target_path);
+
+    if !Path::new(&staging_path).is_dir() {
+        return Ok(())
+    }
+
+    if !Path::new(&target_path).exists() && Path::new(&old_path).is_dir() {
+        fs::rename(&staging_path, &target_path).map_err(|e| e.to_string())?;
+        sync_dir(root)?;
+        fs::remove_dir_all(&old_path).map_err(|e| e.to_string())?;
+    } else {
+        fs::remove_dir_all(&staging_path).map_err(|e| e.to_string())?;
+    }
+
+    sync_dir(root)
+}
+
+
+
+/// write manifest of backup, it is last file of backup
+pub fn write_manifest(root: &str, source: &str, backup_name: &str, total_page_size: usize, created_at: String) -> Result<Manifest, String> {
+    let path = format!("{}/{}", root, backup_name);
+    let report = count(&path, total_page_size)?;
+
+    if !report.is_valid() {
+        return Err(report.errors.join(", "))
+    }
+
+    let manifest = Manifest {
+        source: source.to_owned(),
+        created_at,
+        total_page_size,
+        pages: report.pages,
+        records: report.records,
+        seq: codec::last_seq(&path, total_page_size)?,
+        base: None,
+    };
+
+    save_manifest(&path, &manifest)?;
+
+    Ok(manifest)
+}
+
+
+fn save_manifest(path: &str, manifest: &Manifest) -> Result<(), String> {
+    let bytes = serde_json::to_vec_pretty(manifest).map_err(|e| e.to_string())?;
+    let filename = format!("{}/{}", path, MANIFEST);
+    fs::write(&filename, bytes).map_err(|e| e.to_string())?;
+    File::open(&filename).and_then(|f| f.sync_all()).map_err(|e| e.to_string())?;
+    sync_dir(path)
+}
+
+
+pub fn read_manifest(root: &str, backup_name: &str) -> Result<Manifest, String> {
+    let bytes = fs::read(format!("{}/{}/{}", root, backup_name, MANIFEST)).map_err(|e| e.to_string())?;
+    serde_json::from_slice(&bytes).map_err(|e| e.to_string())
+}
+
+
+
+// count pages and records of directory and checksum every record,
+// header record of page is not counted
+fn count(path: &str, total_page_size: usize) -> Result<VerifyReport, String> {
+    let mut report = VerifyReport::default();
+
+    for page_index in list_pages(path, total_page_size).map_err(|e| e.to_string())? {
+        let bytes = fs::read(filename_factory(path, total_page_size * page_index)).map_err(|e| e.to_string())?;
+        let entries = recovery::scan(&bytes, &|_, _| true);
+
+        report.pages += 1;
+        report.records += entries.iter().filter(|e| matches!(e, Entry::Valid { .. })).count();
+
+        if let Some(Entry::Valid { start, end }) = entries.first() {
+            if PageHeader::parse(layout::data(&bytes[*start..*end])).is_some() {
+                report.records -= 1;
+            }
+        }
+
+        for reason in recovery::reasons(&entries) {
+            report.errors.push(format!("page {}: {}", page_index, reason));
+        }
+    }
+
+    Ok(report)
+}
+
+
+// header record of page if it has, and other records of it
+type RawPage = (Option<Vec<u8>>, Vec<Vec<u8>>);
+
+
+// return header, header record and other valid records of first limit bytes of page,
+// torn tail of page that is appending is not returned
+fn read_records(path: &str, total_page_size: usize, page_index: usize, limit: u64) -> Result<(PageHeader, RawPage), String> {
+    let mut bytes = Vec::new();
+    File::open(filename_factory(path, total_page_size * page_index))
+        .and_then(|file| file.take(limit).read_to_end(&mut bytes))
+        .map_err(|e| e.to_string())?;
+
+    let mut header = PageHeader::legacy();
+    let mut header_record = None;
+    let mut records = Vec::new();
+
+    for (index, entry) in recovery::scan(&bytes, &|_, _| true).into_iter().enumerate() {
+        if let Entry::Valid { start, end } = entry {
+            let data = layout::data(&bytes[start..end]);
+
+            match PageHeader::parse(data) {
+                Some(h) if index == 0 => {
+                    header = h;
+                    header_record = Some(data.to_vec());
+                }
+                _ => records.push(data.to_vec())
+            }
+        }
+    }
+
+    Ok((header, (header_record, records)))
+}
+
+
+// write raw records to pages of directory, every page start with its header record
+fn write_pages(path: &str, total_page_size: usize, pages: BTreeMap<usize, RawPage>) -> Result<(), String> {
+    for (page_index, (header_record, records)) in pages {
+        let filename = filename_factory(path, total_page_size * page_index);
+        let mut page = LogFile::open(&filename).map_err(|e| e.to_string())?;
+
+        if let Some(mut header_record) = header_record {
+            page.write(&mut header_record).map_err(|e| e.to_string())?;
+        }
+
+        for mut record in records {
+            page.write(&mut record).map_err(|e| e.to_string())?;
+        }
+
+        page.flush().map_err(|e| e.to_string())?;
+        File::open(&filename).and_then(|f| f.sync_all()).map_err(|e| e.to_string())?;
+    }
+
+    sync_dir(path)
+}
+
+
+const MANIFEST: &str = "MANIFEST";
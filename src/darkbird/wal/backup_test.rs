@@ -0,0 +1,211 @@
+use crate::{Options, Schema, Storage, StorageType, Durability, backup};
+use crate::darkbird::test_fixture::{Profile, TempDir};
+
+
+#[tokio::test]
+async fn backup_list_verify_and_restore() {
+    let path = TempDir::new();
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+        .with_durability(Durability::Flushed);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+    for i in 0..30 {
+        storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+    }
+    drop(storage);
+
+    backup::<String, Profile>(&path, "profiles", 1000, false).unwrap();
+
+    let backups = backup::list(&path, "profiles").unwrap();
+    assert_eq!(backups.len(), 1);
+    assert_eq!(backups[0].manifest.records, 30);
+    assert_eq!(backups[0].manifest.total_page_size, 5000);
+
+    let name = backups[0].name.clone();
+    assert!(backup::verify(&path, &name).unwrap().is_valid());
+
+    // clone to new datastore
+    backup::restore(&path, &name, "profiles_clone", backup::RestoreMode::Clone).unwrap();
+    assert!(backup::restore(&path, &name, "profiles_clone", backup::RestoreMode::Clone).is_err());
+
+    // replace datastore that changed after backup
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        storage.insert("pid-100".to_owned(), Profile::new("DanyalMh", 100)).await.unwrap();
+    }
+    backup::restore(&path, &name, "profiles", backup::RestoreMode::Replace).unwrap();
+
+    for datastore in ["profiles", "profiles_clone"] {
+        let ops = Options::new(&path, datastore, 1000, StorageType::DiskCopies, true);
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        assert_eq!(storage.collection_len(), 30);
+        assert!(storage.lookup(&"pid-100".to_owned()).is_none());
+    }
+
+    // corrupt record is detected
+    let page = format!("{}/{}/page-5000.LOG", path, name);
+    let mut bytes = std::fs::read(&page).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&page, bytes).unwrap();
+
+    assert!(!backup::verify(&path, &name).unwrap().is_valid());
+    assert!(backup::restore(&path, &name, "profiles", backup::RestoreMode::Replace).is_err());
+}
+
+
+#[tokio::test]
+async fn replace_restore_stopped_by_crash_is_finished_or_undone() {
+    let path = TempDir::new();
+    let open = |path: String| async move {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+        Storage::<String, Profile>::open(ops).await.unwrap().0
+    };
+
+    let storage = open(path.to_string()).await;
+    for i in 0..10 {
+        storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+    }
+    drop(storage);
+
+    backup::<String, Profile>(&path, "profiles", 1000, false).unwrap();
+    let name = backup::list(&path, "profiles").unwrap().pop().unwrap().name;
+
+    let storage = open(path.to_string()).await;
+    storage.insert("pid-100".to_owned(), Profile::new("DanyalMh", 100)).await.unwrap();
+    drop(storage);
+
+    // crash while copying to staging, datastore is kept
+    let staging = format!("{}/profiles.restore", path);
+    std::fs::create_dir(&staging).unwrap();
+    std::fs::write(format!("{}/page-5000.LOG", staging), b"torn").unwrap();
+
+    let storage = open(path.to_string()).await;
+    assert_eq!(storage.collection_len(), 11);
+    assert!(!std::path::Path::new(&staging).exists());
+    drop(storage);
+
+    // crash between renames, staging is complete and replace datastore
+    backup::restore(&path, &name, "profiles_copy", backup::RestoreMode::Clone).unwrap();
+    std::fs::rename(format!("{}/profiles", path), format!("{}/profiles.old", path)).unwrap();
+    std::fs::rename(format!("{}/profiles_copy", path), &staging).unwrap();
+
+    let storage = open(path.to_string()).await;
+    assert_eq!(storage.collection_len(), 10);
+    assert!(storage.lookup(&"pid-100".to_owned()).is_none());
+    assert!(!std::path::Path::new(&staging).exists());
+    assert!(!std::path::Path::new(&format!("{}/profiles.old", path)).exists());
+}
+
+
+#[tokio::test]
+async fn database_backup_restore_datastores_to_one_point() {
+    let path = TempDir::new();
+    let dest = format!("{}/set", path);
+
+    let db = Schema::new()
+        .with_datastore::<String, Profile>(Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)).await.unwrap()
+        .with_datastore::<u32, Profile>(Options::new(&path, "accounts", 1000, StorageType::DiskCopies, true)).await.unwrap()
+        .build();
+
+    for i in 0..20 {
+        db.insert::<String, Profile>(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+        db.insert::<u32, Profile>(i as u32, Profile::new("DanyalMh", i)).await.unwrap();
+    }
+
+    // backup copy snapshot and pages logged after it
+    db.checkpoint::<String, Profile>().await.unwrap();
+
+    // writers continue while backup is taken
+    let writer = async {
+        for i in 20..100 {
+            db.insert::<String, Profile>(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+            db.insert::<u32, Profile>(i as u32, Profile::new("DanyalMh", i)).await.unwrap();
+        }
+    };
+    let (manifest, _) = tokio::join!(db.backup(&dest), writer);
+    let manifest = manifest.unwrap();
+
+    assert_eq!(manifest.datastores, vec!["profiles".to_owned(), "accounts".to_owned()]);
+    assert!(db.backup(&dest).await.is_err());
+
+    let root = format!("{}/restored", path);
+    std::fs::create_dir_all(&root).unwrap();
+    backup::restore_set(&dest, &root, backup::RestoreMode::Clone).unwrap();
+    assert!(backup::restore_set(&dest, &root, backup::RestoreMode::Clone).is_err());
+
+    let profiles = Storage::<String, Profile>::open(Options::new(&root, "profiles", 1000, StorageType::DiskCopies, true)).await.unwrap().0;
+    let accounts = Storage::<u32, Profile>::open(Options::new(&root, "accounts", 1000, StorageType::DiskCopies, true)).await.unwrap().0;
+
+    // profile is written before account, so pin can just fall between them
+    let n = accounts.collection_len();
+    assert!(n >= 20);
+    assert!(profiles.collection_len() == n || profiles.collection_len() == n + 1);
+    for i in 0..n {
+        assert!(accounts.lookup(&(i as u32)).is_some());
+        assert!(profiles.lookup(&format!("pid-{}", i)).is_some());
+    }
+}
+
+
+#[tokio::test]
+async fn incremental_backups_restore_to_instant() {
+    let path = TempDir::new();
+
+    let open = |path: String| async move {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+        Storage::<String, Profile>::open(ops).await.unwrap().0
+    };
+
+    let storage = open(path.to_string()).await;
+    for i in 0..10 {
+        storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+    }
+    assert!(storage.backup_incremental().await.is_err());
+    drop(storage);
+
+    backup::<String, Profile>(&path, "profiles", 1000, false).unwrap();
+
+    // sequence continue after reopen
+    let storage = open(path.to_string()).await;
+    for i in 10..15 {
+        storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    let instant = chrono::Utc::now();
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    for i in 15..20 {
+        storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+    }
+
+    // taken from open storage, every acknowledged write is in it
+    let first = storage.backup_incremental().await.unwrap();
+    assert_eq!(first.manifest.records, 10);
+    assert_eq!(first.manifest.seq, 20);
+
+    storage.remove("pid-0".to_owned()).await.unwrap();
+    let second = storage.backup_incremental().await.unwrap();
+    assert_eq!(second.manifest.records, 1);
+    assert_eq!(second.manifest.base, Some(first.name.clone()));
+    drop(storage);
+
+    // increment alone is not a datastore
+    assert!(backup::restore(&path, &first.name, "profiles_clone", backup::RestoreMode::Clone).is_err());
+
+    backup::restore_to(&path, "profiles", "profiles_then", instant, backup::RestoreMode::Clone).unwrap();
+    backup::restore_to(&path, "profiles", "profiles_now", chrono::Utc::now(), backup::RestoreMode::Clone).unwrap();
+
+    let ops = Options::new(&path, "profiles_then", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+    assert_eq!(storage.collection_len(), 15);
+    assert!(storage.lookup(&"pid-0".to_owned()).is_some());
+
+    let ops = Options::new(&path, "profiles_now", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+    assert_eq!(storage.collection_len(), 19);
+    assert!(storage.lookup(&"pid-0".to_owned()).is_none());
+}
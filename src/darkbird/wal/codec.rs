@@ -0,0 +1,649 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use chacha20poly1305::{aead::{Aead, AeadCore, KeyInit, OsRng, Payload}, ChaCha20Poly1305, Key, Nonce};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use simple_wal::LogFile;
+
+use super::disk_log::{filename_factory, list_pages};
+use super::snapshot;
+
+
+
+// encode and decode records of pages and snapshots, every codec is a Format,
+// because id of format is recorded in header of page to decode it
+pub(crate) trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String>;
+}
+
+
+
+struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        bincode::serialize(value).map_err(|e| e.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        bincode::deserialize(bytes).map_err(|e| e.to_string())
+    }
+}
+
+
+struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(value).map_err(|e| e.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        serde_json::from_slice(bytes).map_err(|e| e.to_string())
+    }
+}
+
+
+struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        // named, so documents with skipped or optional fields are decodable
+        rmp_serde::to_vec_named(value).map_err(|e| e.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        rmp_serde::from_slice(bytes).map_err(|e| e.to_string())
+    }
+}
+
+
+struct BsonCodec;
+
+// bson top level must be a document, so every value is wrapped
+#[derive(Serialize)]
+struct BsonEncode<'a, T> {
+    v: &'a T
+}
+
+#[derive(Deserialize)]
+struct BsonDecode<T> {
+    v: T
+}
+
+impl Codec for BsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        bson::to_vec(&BsonEncode { v: value }).map_err(|e| e.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        bson::from_slice::<BsonDecode<T>>(bytes)
+            .map(|record| record.v)
+            .map_err(|e| e.to_string())
+    }
+}
+
+
+
+/// format of records, it is recorded in header of every page,
+/// so a datastore can contain pages with different formats.
+/// records are encoded just by these formats, custom codecs are not supported
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Format {
+    Bson,
+    Bincode,
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    #[inline]
+    fn id(&self) -> u8 {
+        match self {
+            Format::Bincode => 0,
+            Format::Json => 1,
+            Format::MessagePack => 2,
+            Format::Bson => 3,
+        }
+    }
+
+    #[inline]
+    fn from_id(id: u8) -> Option<Format> {
+        match id {
+            0 => Some(Format::Bincode),
+            1 => Some(Format::Json),
+            2 => Some(Format::MessagePack),
+            3 => Some(Format::Bson),
+            _ => None
+        }
+    }
+}
+
+impl Codec for Format {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Bincode => BincodeCodec.encode(value),
+            Format::Json => JsonCodec.encode(value),
+            Format::MessagePack => MessagePackCodec.encode(value),
+            Format::Bson => BsonCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Bincode => BincodeCodec.decode(bytes),
+            Format::Json => JsonCodec.decode(bytes),
+            Format::MessagePack => MessagePackCodec.decode(bytes),
+            Format::Bson => BsonCodec.decode(bytes),
+        }
+    }
+}
+
+
+
+/// compression of every record of page, it is recorded in header of page
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    #[inline]
+    fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    #[inline]
+    fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None
+        }
+    }
+}
+
+const ZSTD_LEVEL: i32 = 3;
+
+
+
+/// supply keys for encryption at rest, id of key is recorded in header of page,
+/// so after rotation pages encrypted with older keys are still readable
+pub trait KeyProvider: Send + Sync {
+    /// id of key that new pages encrypted with
+    fn current_key_id(&self) -> u32;
+
+    /// 32 bytes key of id, None if key is unknown
+    fn key(&self, key_id: u32) -> Option<[u8; 32]>;
+}
+
+
+/// AEAD that records of page encrypted with
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Cipher {
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    #[inline]
+    fn id(&self) -> u8 {
+        match self {
+            Cipher::ChaCha20Poly1305 => 1,
+        }
+    }
+
+    #[inline]
+    fn from_id(id: u8) -> Option<Cipher> {
+        match id {
+            1 => Some(Cipher::ChaCha20Poly1305),
+            _ => None
+        }
+    }
+}
+
+const NONCE_SIZE: usize = 12;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Encryption {
+    pub cipher: Cipher,
+    pub key_id: u32,
+}
+
+
+/// keys of datastore, it is empty when encryption is off
+#[derive(Clone, Default)]
+pub struct Keyring {
+    provider: Option<Arc<dyn KeyProvider>>
+}
+
+impl Keyring {
+    pub fn new(provider: Option<Arc<dyn KeyProvider>>) -> Self {
+        Keyring { provider }
+    }
+
+    /// encryption of new pages
+    pub fn encryption(&self) -> Option<Encryption> {
+        self.provider.as_ref().map(|provider| Encryption {
+            cipher: Cipher::ChaCha20Poly1305,
+            key_id: provider.current_key_id()
+        })
+    }
+
+    /// check key of encryption is available
+    pub fn check(&self, encryption: &Encryption) -> Result<(), String> {
+        self.cipher(encryption).map(|_| ())
+    }
+
+    fn cipher(&self, encryption: &Encryption) -> Result<ChaCha20Poly1305, String> {
+        let key = self.provider
+            .as_ref()
+            .and_then(|provider| provider.key(encryption.key_id))
+            .ok_or(format!("key {} is not available", encryption.key_id))?;
+
+        match encryption.cipher {
+            Cipher::ChaCha20Poly1305 => Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+        }
+    }
+}
+
+
+
+const PAGE_MAGIC: &[u8; 6] = b"DBPAGE";
+const PAGE_HEADER_VERSION: u8 = 1;
+
+// records of page are stamped and header record contain sequence of last record before page
+const STAMPED_PAGE_HEADER_VERSION: u8 = 2;
+
+const STAMP_SIZE: usize = 16;
+
+
+/// sequence number and time of a record, written by disk_log before every record of stamped page,
+/// records of unstamped pages have default stamp
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, PartialOrd, Ord)]
+pub struct Stamp {
+    pub seq: u64,
+
+    // microseconds since unix epoch
+    pub timestamp: u64,
+}
+
+impl Stamp {
+    pub fn now(seq: u64) -> Self {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        Stamp { seq, timestamp }
+    }
+
+    fn to_bytes(self) -> [u8; STAMP_SIZE] {
+        let mut bytes = [0; STAMP_SIZE];
+        bytes[..8].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Stamp {
+            seq: u64::from_le_bytes(bytes.get(..8)?.try_into().ok()?),
+            timestamp: u64::from_le_bytes(bytes.get(8..STAMP_SIZE)?.try_into().ok()?),
+        })
+    }
+}
+
+
+/// first record of every page, describe how records of page are encoded,
+/// pages written before header existed are bincode pages without header
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PageHeader {
+    pub format: Format,
+    pub compression: Compression,
+    pub encryption: Option<Encryption>,
+
+    // every record start with a stamp
+    pub stamped: bool,
+}
+
+impl PageHeader {
+    pub fn new(format: Format, compression: Compression, encryption: Option<Encryption>) -> Self {
+        PageHeader { format, compression, encryption, stamped: true }
+    }
+
+    /// header of pages without header record
+    pub fn legacy() -> Self {
+        PageHeader { format: Format::Bincode, compression: Compression::None, encryption: None, stamped: false }
+    }
+
+    /// same header, records written without stamp
+    pub fn unstamped(self) -> Self {
+        PageHeader { stamped: false, ..self }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        self.to_record(0)
+    }
+
+    /// header record of page, seq is sequence of last record logged before page
+    pub fn to_record(self, seq: u64) -> Vec<u8> {
+        let mut bytes = PAGE_MAGIC.to_vec();
+
+        if self.stamped {
+            bytes.push(STAMPED_PAGE_HEADER_VERSION);
+            bytes.push(self.format.id());
+            bytes.push(self.compression.id());
+            bytes.extend_from_slice(&seq.to_le_bytes());
+        } else {
+            bytes.push(PAGE_HEADER_VERSION);
+            bytes.push(self.format.id());
+            bytes.push(self.compression.id());
+        }
+
+        if let Some(encryption) = self.encryption {
+            bytes.push(encryption.cipher.id());
+            bytes.extend_from_slice(&encryption.key_id.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    /// return None if bytes is not a header record
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        Self::parse_record(bytes).map(|(header, _)| header)
+    }
+
+    /// parse header record, seq is 0 for unstamped pages
+    pub fn parse_record(bytes: &[u8]) -> Option<(Self, u64)> {
+        let rest = bytes.strip_prefix(PAGE_MAGIC)?;
+        match rest {
+            [PAGE_HEADER_VERSION, format, rest @ ..] => {
+                // header without compression is uncompressed
+                let (compression, rest) = match rest {
+                    [id, rest @ ..] => (Compression::from_id(*id)?, rest),
+                    [] => (Compression::None, rest)
+                };
+
+                let header = PageHeader {
+                    format: Format::from_id(*format)?,
+                    compression,
+                    encryption: parse_encryption(rest)?,
+                    stamped: false
+                };
+
+                Some((header, 0))
+            }
+            [STAMPED_PAGE_HEADER_VERSION, format, compression, rest @ ..] if rest.len() >= 8 => {
+                let (seq, rest) = rest.split_at(8);
+
+                let header = PageHeader {
+                    format: Format::from_id(*format)?,
+                    compression: Compression::from_id(*compression)?,
+                    encryption: parse_encryption(rest)?,
+                    stamped: true
+                };
+
+                Some((header, u64::from_le_bytes(seq.try_into().ok()?)))
+            }
+            _ => None
+        }
+    }
+
+    /// encode value by format, result is what Storage send to disk_log
+    #[inline]
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        self.format.encode(value)
+    }
+
+    /// encode and pack value, result is a record of page
+    #[inline]
+    pub fn seal<T: Serialize>(&self, value: &T, stamp: Stamp, keys: &Keyring) -> Result<Vec<u8>, String> {
+        self.encode(value)
+            .and_then(|bytes| self.pack(bytes, stamp, keys))
+            .map(|bytes| self.frame(stamp, bytes))
+    }
+
+    /// decode a record of page
+    #[inline]
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8], keys: &Keyring) -> Result<T, String> {
+        self.format.decode(&self.unpack(bytes, keys)?)
+    }
+
+    /// put stamp before packed record of stamped page
+    pub fn frame(&self, stamp: Stamp, bytes: Vec<u8>) -> Vec<u8> {
+        if !self.stamped {
+            return bytes
+        }
+
+        let mut record = Vec::with_capacity(STAMP_SIZE + bytes.len());
+        record.extend_from_slice(&stamp.to_bytes());
+        record.extend_from_slice(&bytes);
+        record
+    }
+
+    /// stamp of record, default for records of unstamped page
+    pub fn stamp(&self, record: &[u8]) -> Result<Stamp, String> {
+        match self.stamped {
+            true => Stamp::from_bytes(record).ok_or_else(|| "stamped record is too short".to_owned()),
+            false => Ok(Stamp::default())
+        }
+    }
+
+    /// compress and encrypt encoded record before written to page, stamp is
+    /// stamp that record is framed with
+    pub fn pack(&self, bytes: Vec<u8>, stamp: Stamp, keys: &Keyring) -> Result<Vec<u8>, String> {
+        let bytes = match self.compression {
+            Compression::None => bytes,
+            Compression::Zstd => zstd::bulk::compress(&bytes, ZSTD_LEVEL).map_err(|e| e.to_string())?,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(&bytes),
+        };
+
+        match &self.encryption {
+            None => Ok(bytes),
+            Some(encryption) => {
+                // every record has own random nonce and it is written before ciphertext
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let aad = self.aad(stamp);
+                let ciphertext = keys.cipher(encryption)?
+                    .encrypt(&nonce, Payload { msg: bytes.as_slice(), aad: &aad })
+                    .map_err(|e| e.to_string())?;
+
+                let mut record = nonce.to_vec();
+                record.extend_from_slice(&ciphertext);
+                Ok(record)
+            }
+        }
+    }
+
+    /// decrypt and decompress record read from page
+    pub fn unpack<'b>(&self, bytes: &'b [u8], keys: &Keyring) -> Result<Cow<'b, [u8]>, String> {
+        let stamp = self.stamp(bytes)?;
+        let bytes = match self.stamped {
+            true => bytes.get(STAMP_SIZE..).ok_or_else(|| "stamped record is too short".to_owned())?,
+            false => bytes
+        };
+
+        let bytes = match &self.encryption {
+            None => Cow::Borrowed(bytes),
+            Some(encryption) => {
+                if bytes.len() < NONCE_SIZE {
+                    return Err("encrypted record is too short".to_owned())
+                }
+
+                let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+                let aad = self.aad(stamp);
+                let plaintext = keys.cipher(encryption)?
+                    .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+                    .map_err(|_| format!("record can not decrypt with key {}", encryption.key_id))?;
+
+                Cow::Owned(plaintext)
+            }
+        };
+
+        match self.compression {
+            Compression::None => Ok(bytes),
+            Compression::Zstd => zstd::stream::decode_all(bytes.as_ref()).map(Cow::Owned).map_err(|e| e.to_string()),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&bytes).map(Cow::Owned).map_err(|e| e.to_string()),
+        }
+    }
+
+    // header and stamp are authenticated with record, so a record is not decrypted
+    // after it is moved to page of other header or its stamp is changed
+    fn aad(&self, stamp: Stamp) -> Vec<u8> {
+        let mut aad = self.to_bytes();
+        if self.stamped {
+            aad.extend_from_slice(&stamp.to_bytes());
+        }
+        aad
+    }
+}
+
+
+// header without cipher is not encrypted
+fn parse_encryption(rest: &[u8]) -> Option<Option<Encryption>> {
+    match rest {
+        [cipher, key_id @ ..] => Some(Some(Encryption {
+            cipher: Cipher::from_id(*cipher)?,
+            key_id: u32::from_le_bytes(key_id.try_into().ok()?)
+        })),
+        [] => Some(None)
+    }
+}
+
+
+
+/// decode every record of page according to its header
+pub fn read_page<T: DeserializeOwned>(log: &mut LogFile, keys: &Keyring) -> Result<Vec<T>, String> {
+    read_stamped_page(log, keys).map(|records| records.into_iter().map(|(_, record)| record).collect())
+}
+
+
+/// decode every record of page with its stamp
+pub fn read_stamped_page<T: DeserializeOwned>(log: &mut LogFile, keys: &Keyring) -> Result<Vec<(Stamp, T)>, String> {
+    let mut header = PageHeader::legacy();
+    let mut records = Vec::new();
+
+    let iter = log.iter(..).map_err(|e| e.to_string())?;
+    for (index, bytes) in iter.enumerate() {
+        let bytes = bytes.map_err(|e| e.to_string())?;
+
+        if index == 0 {
+            if let Some(h) = PageHeader::parse(&bytes) {
+                header = h;
+                continue
+            }
+        }
+
+        records.push((header.stamp(&bytes)?, header.decode(&bytes, keys)?));
+    }
+
+    Ok(records)
+}
+
+
+/// sequence of last record logged to datastore, 0 if there is not any stamped record,
+/// pages are read from newest until a stamped one found
+pub fn last_seq(path: &str, total_page_size: usize) -> Result<u64, String> {
+    if !std::path::Path::new(path).is_dir() {
+        return Ok(0)
+    }
+
+    let mut pages = list_pages(path, total_page_size).map_err(|e| e.to_string())?;
+    pages.reverse();
+
+    for page_index in pages {
+        let mut log = LogFile::open(filename_factory(path, total_page_size * page_index)).map_err(|e| e.to_string())?;
+        let mut header = PageHeader::legacy();
+        let mut seq = 0;
+
+        for (index, bytes) in log.iter(..).map_err(|e| e.to_string())?.enumerate() {
+            let bytes = bytes.map_err(|e| e.to_string())?;
+
+            if index == 0 {
+                if let Some((h, base)) = PageHeader::parse_record(&bytes) {
+                    header = h;
+                    seq = base;
+                    continue
+                }
+            }
+
+            seq = seq.max(header.stamp(&bytes)?.seq);
+        }
+
+        if seq > 0 {
+            return Ok(seq)
+        }
+    }
+
+    Ok(0)
+}
+
+
+/// return header of page, None if page is empty
+pub fn page_header(log: &mut LogFile) -> Result<Option<PageHeader>, String> {
+    let mut iter = log.iter(..).map_err(|e| e.to_string())?;
+    match iter.next() {
+        Some(bytes) => {
+            let bytes = bytes.map_err(|e| e.to_string())?;
+            Ok(Some(PageHeader::parse(&bytes).unwrap_or_else(PageHeader::legacy)))
+        }
+        None => Ok(None)
+    }
+}
+
+
+/// return (page_index, header) of every page of datastore
+pub fn page_headers(path: &str, total_page_size: usize) -> Result<Vec<(usize, PageHeader)>, String> {
+    let mut headers = Vec::new();
+
+    for page_index in list_pages(path, total_page_size).map_err(|e| e.to_string())? {
+        let mut log = LogFile::open(filename_factory(path, total_page_size * page_index)).map_err(|e| e.to_string())?;
+        if let Some(header) = page_header(&mut log)? {
+            headers.push((page_index, header));
+        }
+    }
+
+    Ok(headers)
+}
+
+
+/// return (page_index, format) of every page of datastore,
+/// used to detect datastore with mixed formats
+pub fn page_formats(path: &str, total_page_size: usize) -> Result<Vec<(usize, Format)>, String> {
+    page_headers(path, total_page_size).map(|headers| {
+        headers.into_iter().map(|(page_index, header)| (page_index, header.format)).collect()
+    })
+}
+
+
+/// check key of every encrypted page of datastore is available,
+/// so a missing key fail open instead of treating pages as corrupt
+pub fn check_keys(path: &str, total_page_size: usize, keys: &Keyring) -> Result<(), String> {
+    if !std::path::Path::new(path).is_dir() {
+        return Ok(())
+    }
+
+    for (page_index, header) in page_headers(path, total_page_size)? {
+        if let Some(encryption) = header.encryption {
+            keys.check(&encryption).map_err(|e| format!("page {}: {}", page_index, e))?;
+        }
+    }
+
+    if let Some((_, filename)) = snapshot::latest(path)? {
+        if let Some(encryption) = snapshot::header(&filename)?.encryption {
+            keys.check(&encryption).map_err(|e| format!("{}: {}", filename, e))?;
+        }
+    }
+
+    Ok(())
+}
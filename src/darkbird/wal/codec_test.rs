@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use crate::{Options, Storage, StorageType, Durability, Format, Compression, page_formats, reencode, rotate_keys};
+use crate::darkbird::test_fixture::{Profile, TestKeys, TempDir};
+
+
+#[tokio::test]
+async fn every_format_survive_reopen() {
+    for format in [Format::Json, Format::MessagePack, Format::Bson] {
+        let path = TempDir::new();
+
+        {
+            let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+                .with_durability(Durability::Flushed)
+                .with_format(format);
+
+            let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+            for i in 0..20 {
+                storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+            }
+            storage.remove("pid-3".to_owned()).await.unwrap();
+        }
+
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_format(format);
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+        assert_eq!(storage.collection_len(), 19);
+        assert_eq!(storage.lookup(&"pid-12".to_owned()).unwrap().value(), &Profile::new("DanyalMh", 12));
+    }
+}
+
+
+#[tokio::test]
+async fn mixed_formats_are_readable_and_reencoded() {
+    let path = TempDir::new();
+    let datastore = format!("{}/profiles", path);
+
+    for (format, from) in [(Format::Bincode, 0), (Format::Json, 10)] {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed)
+            .with_format(format);
+
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        for i in from..from + 10 {
+            storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+        }
+    }
+
+    let formats = page_formats(&datastore, 5000).unwrap();
+    assert_eq!(formats, vec![(1, Format::Bincode), (2, Format::Json)]);
+
+    reencode::<String, Profile>(&path, "profiles", 1000, Format::MessagePack, Compression::None, None).unwrap();
+
+    let formats = page_formats(&datastore, 5000).unwrap();
+    assert!(formats.iter().all(|(_, format)| *format == Format::MessagePack));
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+        .with_format(Format::MessagePack);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+    assert_eq!(storage.collection_len(), 20);
+    assert_eq!(storage.lookup(&"pid-15".to_owned()).unwrap().value(), &Profile::new("DanyalMh", 15));
+}
+
+
+#[tokio::test]
+async fn compressed_pages_are_smaller_and_mixed_pages_load() {
+    let path = TempDir::new();
+    let fullname = "DanyalMh ".repeat(50);
+
+    for (name, compression) in [("plain", Compression::None), ("zstd", Compression::Zstd), ("lz4", Compression::Lz4)] {
+        let ops = Options::new(&path, name, 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed)
+            .with_compression(compression);
+
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        for i in 0..50 {
+            storage.insert(format!("pid-{}", i), Profile::new(&fullname, i)).await.unwrap();
+        }
+    }
+
+    let size = |name: &str| std::fs::metadata(format!("{}/{}/page-5000.LOG", path, name)).unwrap().len();
+    assert!(size("zstd") * 4 < size("plain"));
+    assert!(size("lz4") * 4 < size("plain"));
+
+    // uncompressed page is still loaded when compression is turned on
+    {
+        let ops = Options::new(&path, "plain", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed)
+            .with_compression(Compression::Zstd);
+
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        storage.insert("pid-50".to_owned(), Profile::new(&fullname, 50)).await.unwrap();
+    }
+
+    let ops = Options::new(&path, "plain", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+    assert_eq!(storage.collection_len(), 51);
+    assert_eq!(storage.lookup(&"pid-50".to_owned()).unwrap().value(), &Profile::new(&fullname, 50));
+}
+
+
+#[tokio::test]
+async fn encrypted_pages_need_key_and_survive_rotation() {
+    let path = TempDir::new();
+    let old_keys = Arc::new(TestKeys { current: 1, keys: vec![(1, [7; 32])] });
+
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed)
+            .with_keys(old_keys.clone());
+
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        for i in 0..20 {
+            storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+        }
+    }
+
+    // documents are not plaintext in page
+    let page = std::fs::read(format!("{}/profiles/page-5000.LOG", path)).unwrap();
+    assert!(!page.windows(8).any(|w| w == b"DanyalMh"));
+
+    // open without key fail
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+    assert!(Storage::<String, Profile>::open(ops).await.is_err());
+
+    // rotate to key 2, after that key 1 is not needed
+    let keys = Arc::new(TestKeys { current: 2, keys: vec![(1, [7; 32]), (2, [9; 32])] });
+    rotate_keys::<String, Profile>(&path, "profiles", 1000, keys).unwrap();
+
+    let new_keys = Arc::new(TestKeys { current: 2, keys: vec![(2, [9; 32])] });
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+        .with_keys(new_keys);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+    assert_eq!(storage.collection_len(), 20);
+    assert_eq!(storage.lookup(&"pid-4".to_owned()).unwrap().value(), &Profile::new("DanyalMh", 4));
+}
+
+
+#[test]
+fn encrypted_record_is_bound_to_its_header_and_stamp() {
+    use super::codec::{Keyring, PageHeader, Stamp};
+
+    let keys = Keyring::new(Some(Arc::new(TestKeys { current: 1, keys: vec![(1, [7; 32])] })));
+    let header = PageHeader::new(Format::Bincode, Compression::None, keys.encryption());
+    let profile = Profile::new("DanyalMh", 1);
+
+    let record = header.seal(&profile, Stamp { seq: 5, timestamp: 1 }, &keys).unwrap();
+    assert_eq!(header.decode::<Profile>(&record, &keys).unwrap(), profile);
+
+    // record moved to another stamp or to page of another header is rejected
+    let mut restamped = record.clone();
+    restamped[0] = 6;
+    assert!(header.decode::<Profile>(&restamped, &keys).is_err());
+
+    let other = PageHeader::new(Format::Json, Compression::None, keys.encryption());
+    assert!(other.decode::<Profile>(&record, &keys).is_err());
+}
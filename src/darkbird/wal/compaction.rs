@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::Hash;
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Serialize};
+use simple_wal::LogFile;
+
+use crate::RQuery;
+
+use super::codec::{self, Keyring, PageHeader, Stamp};
+use super::disk_log::{filename_factory, list_pages};
+use super::patch;
+use super::snapshot::{self, sync_dir};
+
+
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CompactionStats {
+    pub pages_before: usize,
+    pub pages_after: usize,
+    pub records_before: usize,
+    pub records_after: usize,
+}
+
+
+
+/// rewrite all sealed pages (pages lower than until_page) and keep just latest RQuery per key,
+/// Remove is dropped when there is not any snapshot, because nothing older can contain that key.
+/// writes of Batch and Transaction that are kept stay in one record, so they are replayed
+/// all or nothing, and Transaction keep its id. Insert written before versions is kept
+/// as Versioned with version that loading give it
+///
+/// compacted pages first written to staging folder and swapped in after a commit marker,
+/// so it is safe to run while disk_log is appending to pages >= until_page,
+/// compacted pages are written with header
+pub fn compact<K, Doc>(path: &str, total_page_size: usize, until_page: usize, header: PageHeader, keys: &Keyring) -> Result<CompactionStats, String>
+where
+    K: Serialize + DeserializeOwned + Hash + Eq + Clone,
+    Doc: Serialize + DeserializeOwned,
+{
+    let staging_path = staging_path(path);
+
+    // remove leftover of interrupted compaction
+    recover(path, total_page_size)?;
+
+    let sealed = list_pages(path, total_page_size)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter(|index| *index < until_page)
+        .collect::<Vec<usize>>();
+
+    let mut stats = CompactionStats {
+        pages_before: sealed.len(),
+        ..Default::default()
+    };
+
+    if sealed.is_empty() {
+        return Ok(stats)
+    }
+
+    let snapshot = snapshot::latest(path)?;
+    let keep_remove = snapshot.is_some();
+
+
+    // records in order they are read, writes of Batch and Transaction are kept together,
+    // slot of a write is cleared when a later RQuery of its key is read
+    let mut records: Vec<Record<K, Doc>> = Vec::new();
+
+    // slots of latest RQuery per key, by (record, slot). Update is merged to document it patch,
+    // and kept after it when that document is older than sealed pages
+    let mut latest: HashMap<K, Vec<(usize, usize)>> = HashMap::new();
+
+    // version of latest write per key, Insert written before versions is next version of key
+    // like loading count it, so from page of snapshot versions start from versions of snapshot
+    let mut versions: HashMap<K, u64> = HashMap::new();
+    let mut seeded = false;
+
+    for index in sealed.iter() {
+        if let Some((snapshot_page, filename)) = &snapshot {
+            if !seeded && index >= snapshot_page {
+                versions = snapshot::read_versioned::<K, Doc>(filename, keys)?
+                    .into_iter()
+                    .map(|(key, _, version)| (key, version))
+                    .collect();
+                seeded = true;
+            }
+        }
+
+        let mut page = LogFile::open(filename_factory(path, total_page_size * index)).map_err(|e| e.to_string())?;
+
+        for (stamp, query) in codec::read_stamped_page::<RQuery<K, Doc>>(&mut page, keys)? {
+            stats.records_before += 1;
+
+            let (group, writes) = match query {
+                RQuery::Batch(writes) => (Group::Batch, writes),
+                RQuery::Transaction(txid, writes) => (Group::Transaction(txid), writes),
+                query => (Group::Single, vec![query]),
+            };
+
+            let at = records.len();
+            let mut slots = Vec::with_capacity(writes.len());
+
+            for query in writes.into_iter().flat_map(RQuery::flatten) {
+                let slot = (at, slots.len());
+                let (key, kept) = match query {
+                    RQuery::Insert(key, doc) => {
+                        let version = versions.get(&key).copied().unwrap_or(0) + 1;
+                        versions.insert(key.clone(), version);
+                        (key, Kept::Doc(doc, version))
+                    }
+                    RQuery::Versioned(key, doc, version) => {
+                        versions.insert(key.clone(), version);
+                        (key, Kept::Doc(doc, version))
+                    }
+                    RQuery::Remove(key) => {
+                        versions.remove(&key);
+                        (key, Kept::Removed)
+                    }
+                    RQuery::Update(key, patch, version) => {
+                        versions.insert(key.clone(), version);
+                        let positions = latest.entry(key.clone()).or_default();
+                        let previous = positions.last().and_then(|&position| slot_mut(&mut records, &mut slots, at, position).as_ref());
+
+                        // patch of document older than sealed pages is kept after patches before it
+                        let kept = match previous {
+                            Some((_, Kept::Doc(doc, _))) => {
+                                let doc = patch::apply(doc, &patch)?;
+                                for position in positions.drain(..) {
+                                    slot_mut(&mut records, &mut slots, at, position).take();
+                                }
+                                Kept::Doc(doc, version)
+                            }
+                            Some((_, Kept::Removed)) => return Err("update of missing document".to_owned()),
+                            _ => Kept::Patch(patch, version),
+                        };
+
+                        positions.push(slot);
+                        slots.push(Some((key, kept)));
+                        continue
+                    }
+                    _ => unreachable!(),
+                };
+
+                for position in latest.insert(key.clone(), vec![slot]).unwrap_or_default() {
+                    slot_mut(&mut records, &mut slots, at, position).take();
+                }
+                slots.push(Some((key, kept)));
+            }
+
+            records.push(Record { stamp, group, slots });
+        }
+    }
+
+    // Remove is dropped when nothing older can contain its key, record that every write of it
+    // is superseded is dropped, but Transaction is kept because its id tell it is logged
+    let records = records
+        .into_iter()
+        .filter_map(|mut record| {
+            record.slots.retain(|slot| match slot {
+                Some((_, Kept::Removed)) => keep_remove,
+                Some(_) => true,
+                None => false
+            });
+
+            match (&record.group, record.slots.is_empty()) {
+                (Group::Transaction(_), _) | (_, false) => Some(record),
+                _ => None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    stats.records_after = records.len();
+
+
+    // write compacted pages to staging folder, index of them start from first sealed page
+    if Path::new(&staging_path).is_dir() {
+        fs::remove_dir_all(&staging_path).map_err(|e| e.to_string())?;
+    }
+    fs::create_dir(&staging_path).map_err(|e| e.to_string())?;
+
+    let first = sealed[0];
+    let mut page_index = first;
+    let mut iter = records.into_iter().peekable();
+
+    // header record of page contain sequence of last record before it
+    let mut last_seq = 0;
+
+    while iter.peek().is_some() {
+        let filename = filename_factory(&staging_path, total_page_size * page_index);
+        let mut page = LogFile::open(&filename).map_err(|e| e.to_string())?;
+        page.write(&mut header.to_record(last_seq)).map_err(|e| e.to_string())?;
+
+        // header record is one of page records
+        for Record { stamp, group, slots } in iter.by_ref().take(total_page_size - 1) {
+            let mut writes = slots
+                .into_iter()
+                .flatten()
+                .map(|(key, kept)| match kept {
+                    Kept::Doc(doc, version) => RQuery::Versioned(key, doc, version),
+                    Kept::Patch(patch, version) => RQuery::Update(key, patch, version),
+                    Kept::Removed => RQuery::Remove(key),
+                })
+                .collect::<Vec<_>>();
+
+            let query = match group {
+                Group::Single => writes.remove(0),
+                Group::Batch => RQuery::Batch(writes),
+                Group::Transaction(txid) => RQuery::Transaction(txid, writes),
+            };
+            let mut bytes = header.seal(&query, stamp, keys)?;
+            last_seq = last_seq.max(stamp.seq);
+            page.write(&mut bytes).map_err(|e| e.to_string())?;
+        }
+
+        page.flush().map_err(|e| e.to_string())?;
+        File::open(&filename).and_then(|f| f.sync_data()).map_err(|e| e.to_string())?;
+
+        page_index += 1;
+    }
+
+    stats.pages_after = page_index - first;
+
+
+    // commit marker contain range of pages that replaced,
+    // it is written to temporary file and renamed so it is never half-written
+    let marker = format!("{} {} {}", first, page_index, until_page);
+    let tmp_marker = format!("{}/COMMIT.TMP", staging_path);
+    fs::write(&tmp_marker, marker).map_err(|e| e.to_string())?;
+    File::open(&tmp_marker).and_then(|f| f.sync_all()).map_err(|e| e.to_string())?;
+    fs::rename(&tmp_marker, commit_filename(&staging_path)).map_err(|e| e.to_string())?;
+    sync_dir(&staging_path)?;
+
+    recover(path, total_page_size)?;
+
+    Ok(stats)
+}
+
+
+
+/// complete committed compaction or discard uncommitted one,
+/// it is idempotent and must called before loading datastore
+pub fn recover(path: &str, total_page_size: usize) -> Result<(), String> {
+    let staging_path = staging_path(path);
+
+    if !Path::new(&staging_path).is_dir() {
+        return Ok(())
+    }
+
+    let marker = match fs::read_to_string(commit_filename(&staging_path)) {
+        Ok(marker) => marker,
+
+        // compaction not committed, old pages are still valid
+        Err(_) => return fs::remove_dir_all(&staging_path).map_err(|e| e.to_string())
+    };
+
+    let range = marker
+        .split_whitespace()
+        .map(|n| n.parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    let (first, end_compacted, until_page) = match range.as_slice() {
+        [first, end_compacted, until_page] => (*first, *end_compacted, *until_page),
+        _ => return Err(format!("bad compaction marker: {}", marker))
+    };
+
+    // move compacted pages over old pages with same index
+    for index in first..end_compacted {
+        let staged = filename_factory(&staging_path, total_page_size * index);
+        if Path::new(&staged).is_file() {
+            fs::rename(&staged, filename_factory(path, total_page_size * index)).map_err(|e| e.to_string())?;
+        }
+    }
+
+    // remove old pages that not replaced
+    for index in end_compacted..until_page {
+        let old = filename_factory(path, total_page_size * index);
+        if Path::new(&old).is_file() {
+            fs::remove_file(&old).map_err(|e| e.to_string())?;
+        }
+    }
+
+    sync_dir(path)?;
+    fs::remove_dir_all(&staging_path).map_err(|e| e.to_string())
+}
+
+
+
+// record of sealed pages, slot of a superseded write is None
+struct Record<K, Doc> {
+    stamp: Stamp,
+    group: Group,
+    slots: Vec<Option<(K, Kept<Doc>)>>,
+}
+
+enum Group {
+    Single,
+    Batch,
+    Transaction(u64),
+}
+
+enum Kept<Doc> {
+    Doc(Doc, u64),
+    Patch(Vec<u8>, u64),
+    Removed,
+}
+
+// slot of a write, slots of record that is being read are not in records yet
+fn slot_mut<'a, K, Doc>(records: &'a mut [Record<K, Doc>], slots: &'a mut [Option<(K, Kept<Doc>)>], at: usize, (record, slot): (usize, usize)) -> &'a mut Option<(K, Kept<Doc>)> {
+    match record == at {
+        true => &mut slots[slot],
+        false => &mut records[record].slots[slot]
+    }
+}
+
+#[inline]
+fn staging_path(path: &str) -> String {
+    format!("{}/compaction", path)
+}
+
+#[inline]
+fn commit_filename(staging_path: &str) -> String {
+    format!("{}/COMMIT", staging_path)
+}
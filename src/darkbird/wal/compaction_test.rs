@@ -0,0 +1,35 @@
+use crate::{Options, Storage, StorageType, Durability};
+use crate::darkbird::test_fixture::{Profile, TempDir};
+
+
+#[tokio::test]
+async fn online_compaction_keep_latest_per_key() {
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Flushed);
+
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        for round in 0..3 {
+            for i in 0..50 {
+                storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", round)).await.unwrap();
+            }
+        }
+        storage.remove("pid-0".to_owned()).await.unwrap();
+
+        let stats = storage.compact().await.unwrap();
+        assert_eq!(stats.records_before, 151);
+        assert_eq!(stats.records_after, 49);
+
+        // writes after compaction go to new page
+        storage.insert("pid-100".to_owned(), Profile::new("DanyalMh", 3)).await.unwrap();
+    }
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+    assert_eq!(storage.collection_len(), 50);
+    assert!(storage.lookup(&"pid-0".to_owned()).is_none());
+    assert_eq!(storage.lookup(&"pid-1".to_owned()).unwrap().value(), &Profile::new("DanyalMh", 2));
+}
@@ -5,6 +5,7 @@ pub const DISKLOG_BUFFER_SIZE: usize = 1000;
 
 struct TmpLogStruct {
     path: String,
+    filename: String,
     log: LogFile,
     current_page_index: usize
 }    
@@ -13,28 +14,60 @@ struct TmpLogStruct {
 
 enum Request {
 
-    Record(Vec<u8>),
+    Record {
+        bytes: Vec<u8>,
+        ack: Option<Ack>
+    },
 
     GetPage {
         page_index: usize, 
         dst: oneshot::Sender<Result<LogFile, StatusResult>>,
     },
+
+    Rotate {
+        dst: oneshot::Sender<Result<usize, StatusResult>>,
+    },
+
+    Pin {
+        dst: oneshot::Sender<Result<(usize, u64), StatusResult>>,
+    },
+
+    // answered by std channel, so it can be waited for out of runtime
+    Flush {
+        dst: std::sync::mpsc::SyncSender<Result<(), StatusResult>>,
+    },
+}
+
+
+// waiter for a record, it answered after batch containing record reached to durability
+struct Ack {
+    durability: Durability,
+    dst: oneshot::Sender<Result<(), StatusResult>>
 }
 
 
 pub struct DiskLog {
-    context: Context
+    context: Context,
+    durability: Durability,
+
+    // waiters of current batch (group commit)
+    pending: Vec<Ack>
 }
 
 impl DiskLog {
     pub fn open (path: &str, 
                  table_name: &str, 
-                 total_page_size: usize) -> Result<Self, LogError>  
+                 total_page_size: usize,
+                 durability: Durability,
+                 header: PageHeader,
+                 keys: Keyring) -> Result<Self, LogError>  
     {
-        match Context::open(path, table_name, total_page_size) {
+        match Context::open(path, table_name, total_page_size, header, keys) {
             Ok(context) => {
                 Ok(DiskLog {
-                    context
+                    context,
+                    durability,
+                    pending: Vec::new()
                 })        
             }
             Err(e) => {
@@ -46,6 +79,7 @@ impl DiskLog {
 
     pub fn run_service(mut self) -> Session {
         let (sx, mut rx) = mpsc::channel(DISKLOG_BUFFER_SIZE);
+        let durability = self.durability;
         std::thread::spawn(move || {
 
             let mut worker_state;
@@ -97,8 +131,9 @@ impl DiskLog {
                     
                 }
 
-                // Flush
-                let _= self.context.log.flush();
+                // Flush and answer waiters of batch, 
+                // all records of batch share one flush/fsync
+                self.commit();
 
 
                 // if worker_state was disconnect terminate
@@ -108,20 +143,65 @@ impl DiskLog {
             }
         });
 
-        Session::new(sx)
+        Session::new(sx, durability)
+    }
+
+
+    /// flush batch and if any waiter want Fsynced, fsync page
+    fn commit(&mut self) {
+        let mut res = self.context.log.flush().map_err(|e| e.to_string());
+
+        if res.is_ok() && self.pending.iter().any(|ack| ack.durability == Durability::Fsynced) {
+            res = self.context.sync().map_err(|e| e.to_string());
+        }
+
+        for ack in self.pending.drain(..) {
+            let _ = match &res {
+                Ok(_) => ack.dst.send(Ok(())),
+                Err(e) => ack.dst.send(Err(StatusResult::Err(e.clone()))),
+            };
+        }
     }
     
     fn handle_recv(&mut self, op: Option<Request>) -> Result<WorkerState, StatusResult> {
         match op {
             Some(req) => {
                 match req {
-                    Request::Record(mut bytes) => {
+                    Request::Record { mut bytes, ack } => {
                         // Log
                         match self.context.write_to_disk(&mut bytes) {
-                            Ok(_) => Ok(WorkerState::Continue),
-                            Err(e) => Err(e),
+                            Ok(_) => {
+                                if let Some(ack) = ack {
+                                    self.pending.push(ack);
+                                }
+                                Ok(WorkerState::Continue)
+                            }
+                            Err(e) => {
+                                match ack {
+                                    Some(ack) => {
+                                        let _ = ack.dst.send(Err(e));
+                                        Ok(WorkerState::Continue)
+                                    }
+                                    None => Err(e)
+                                }
+                            }
                         }
                     }
+                    Request::Rotate { dst } => {
+                        let _ = dst.send(self.context.rotate());
+                        Ok(WorkerState::Continue)
+                    }
+                    Request::Pin { dst } => {
+                        // records of batch before pin must be in pinned position
+                        self.commit();
+                        let _ = dst.send(self.context.pin());
+                        Ok(WorkerState::Continue)
+                    }
+                    Request::Flush { dst } => {
+                        self.commit();
+                        let _ = dst.send(self.context.log.flush().map_err(|e| StatusResult::Err(e.to_string())));
+                        Ok(WorkerState::Continue)
+                    }
                     Request::GetPage { page_index, dst } => {
                         
                         let filename = self.context.find_filename(page_index);
@@ -158,45 +238,7 @@ impl DiskLog {
 
     fn handle_try_recv(&mut self, res: Result<Request, TryRecvError>) -> Result<WorkerState, StatusResult> {
         match res {
-            Ok(req) => {
-                match req {
-                    Request::Record(mut bytes) => {
-                        // Log
-                        match self.context.write_to_disk(&mut bytes) {
-                            Ok(_) => Ok(WorkerState::Continue),
-                            Err(e) => Err(e),
-                        }
-                    }
-                    Request::GetPage { page_index, dst } => {
-                        
-                        let filename = self.context.find_filename(page_index);
-                        
-                        // Check file exist  
-                        if Path::new(&filename).is_file() {
-                            // open logFile
-                            match LogFile::open(filename) {
-                                Ok(log) => {
-                                    // send logfile
-                                    let _ = dst.send(Ok(log));
-                                    Ok(WorkerState::Continue)
-                                }
-                                Err(e) => {
-
-                                    // LogErr is => ("Bad checksum" || "Out of bounds" || "the log is locked"
-                                    let _ = dst.send(Err(StatusResult::LogErr(e)));
-                                    
-                                    return  Ok(WorkerState::Continue)
-                                }
-                            }
-                            
-                        // file not found
-                        } else {
-                            let _ = dst.send(Err(StatusResult::End));
-                            Ok(WorkerState::Continue)
-                        }
-                    }
-                }
-            }
+            Ok(req) => self.handle_recv(Some(req)),
             Err(e) => {
                 match e {
                     TryRecvError::Empty => Ok(WorkerState::Empty),
@@ -218,12 +260,27 @@ struct Context {
     log: LogFile,
     path: String,
 
+    // handle to current page, used for fsync
+    page: fs::File,
+
     // total_page_size is total len of query list per file.LOG  
     total_page_size: usize,
 
     // used_page is len of reocrd in current_file.LOG 
     used_page: usize,
 
+    // header written at start of every new page
+    header: PageHeader,
+
+    // keys that records encrypted with
+    keys: Keyring,
+
+    // 1 if current page start with header record, legacy pages have not header
+    header_records: usize,
+
+    // sequence of last record written, every record of stamped page get next one
+    seq: u64,
+
     // current_page is pointer to current_page
     current_page_index: usize
 
@@ -232,34 +289,78 @@ impl Context {
 
     pub fn open(path: &str, 
                 table_name: &str, 
-                mut total_page_size: usize) -> Result<Self, LogError> 
+                mut total_page_size: usize,
+                header: PageHeader,
+                keys: Keyring) -> Result<Self, LogError> 
     {
 
         // at-least DEFAULT_PAGE_SIZE Record
-        total_page_size =  if total_page_size < DEFAULT_PAGE_SIZE { DEFAULT_PAGE_SIZE } else { total_page_size };
+        total_page_size = page_size(total_page_size);
         
 
-        let mut slog = open_last_page(path, table_name, total_page_size);
+        let mut slog = open_last_page(path, table_name, total_page_size)?;
         
-        let used_page = used_page(&mut slog.log);
+        let used_page = used_page(&mut slog.log)?;
+
+        // first record of page, None if page is empty
+        let first_record = match slog.log.iter(..)?.next() {
+            Some(bytes) => Some(bytes?),
+            None => None
+        };
+
+        let page = fs::File::open(&slog.filename)?;
 
-        Ok(Context{
+        let seq = codec::last_seq(&slog.path, total_page_size).map_err(|e| into_log_error(StatusResult::Err(e)))?;
+
+        let mut context = Context{
             log: slog.log,
 
             path: slog.path,
+
+            page,
             
             total_page_size,
 
             used_page,
 
+            header,
+
+            keys,
+
+            header_records: 0,
+
+            seq,
+
             // current_page is pointer to current_page and when move to new page change
-            current_page_index: 1
-        })
+            current_page_index: slog.current_page_index
+        };
+
+        match first_record.map(|bytes| PageHeader::parse(&bytes)) {
+            // empty page
+            None => context.write_header().map_err(into_log_error)?,
+
+            Some(Some(page_header)) if page_header == header => context.header_records = 1,
+
+            // legacy page without header
+            Some(None) if PageHeader::legacy() == header => {}
+
+            // records of a page must have one format, so continue on a new page
+            Some(_) => context.move_to_next_page().map_err(into_log_error)?,
+        }
+
+        Ok(context)
     }
  
 
     #[inline]
     fn write_to_disk(&mut self, bytes: &mut Vec<u8>) -> Result<(), StatusResult> {
+        // sequence is taken just when record is written, so a failed write leave no gap
+        let stamp = Stamp::now(self.seq + 1);
+
+        // compress and encrypt record according to header of pages
+        let bytes = self.header.pack(std::mem::take(bytes), stamp, &self.keys).map_err(StatusResult::Err)?;
+        let bytes = &mut self.header.frame(stamp, bytes);
+
         let sum = self.used_page + 1;
 
         // if page have free space 
@@ -269,6 +370,7 @@ impl Context {
 
                 Ok(_) => {                    
                     self.used_page = sum;
+                    self.seq += 1;
                     Ok(())
                 }
                 Err(err) => {
@@ -286,67 +388,22 @@ impl Context {
             if let Err(e) = res {
                 return Err(StatusResult::IoError(e))
             }
+            self.seq += 1;
 
-            // flush to disk because move to next page
-            match self.log.flush() {
-                Ok(_) => {
-
-                    // ----- move to new page -----
-                    self.current_page_index += 1;
-
-                    // create filename for new page
-                    let curr_filename = filename_factory(&self.path, self.current_page_index * self.total_page_size);
-
-                    // create new page
-                    let log = match LogFile::open(&curr_filename) {
-                        Ok(lf) => lf,
-                        Err(err) => {
-                            return Err(StatusResult::LogErr(err))
-                        }
-                    };
-
-                    self.used_page = 0;
-                    self.log = log;
-
-                    Ok(())
-                }
-                Err(err) => {
-                    return Err(StatusResult::IoError(err))
-                }
-            }
+            self.move_to_next_page()
         }
         // if page is full
         else {
 
-            // flush to disk because move to next page
-            if let Err(e) = self.log.flush() {
-                // return error
-                return Err(StatusResult::IoError(e));
-            }
-
-            // ----- move to new page -----
-            self.current_page_index += 1;
-
-            // create filename for new page
-            let curr_filename = filename_factory(&self.path, self.current_page_index * self.total_page_size);
-            
-            
-            // create new page
-            let log = match LogFile::open(&curr_filename) {
-                Ok(lf) => lf,
-                Err(err) => {
-                    return Err(StatusResult::LogErr(err))
-                }
-            };
-        
-            self.log = log;       
+            self.move_to_next_page()?;
         
             // write buffer to page
             let res = self.log.write(bytes);
             
             match res {
                 Ok(_) => {
-                    self.used_page = 1;
+                    self.used_page += 1;
+                    self.seq += 1;
                     Ok(())
                 }
                 Err(err) => {
@@ -358,6 +415,87 @@ impl Context {
     }
 
 
+    /// flush and fsync current page then create next page and move to it
+    fn move_to_next_page(&mut self) -> Result<(), StatusResult> {
+        
+        // flush to disk because move to next page
+        if let Err(e) = self.log.flush().and_then(|_| self.sync()) {
+            // return error
+            return Err(StatusResult::IoError(e));
+        }
+
+        // ----- move to new page -----
+        let next_page_index = self.current_page_index + 1;
+
+        // create filename for new page
+        let curr_filename = filename_factory(&self.path, next_page_index * self.total_page_size);
+
+        // create new page
+        let log = match LogFile::open(&curr_filename) {
+            Ok(lf) => lf,
+            Err(err) => {
+                return Err(StatusResult::LogErr(err))
+            }
+        };
+
+        let page = match fs::File::open(&curr_filename) {
+            Ok(f) => f,
+            Err(err) => {
+                return Err(StatusResult::IoError(err))
+            }
+        };
+
+        self.current_page_index = next_page_index;
+        self.used_page = 0;
+        self.log = log;
+        self.page = page;
+
+        self.write_header()
+    }
+
+
+    /// write header record at start of current page
+    fn write_header(&mut self) -> Result<(), StatusResult> {
+        if let Err(e) = self.log.write(&mut self.header.to_record(self.seq)) {
+            return Err(StatusResult::IoError(e))
+        }
+
+        self.used_page += 1;
+        self.header_records = 1;
+
+        Ok(())
+    }
+
+
+    /// seal current page and return index of page that next record written to, 
+    /// all records logged before are in pages lower than returned index
+    fn rotate(&mut self) -> Result<usize, StatusResult> {
+        if self.used_page > self.header_records {
+            self.move_to_next_page()?;
+        }
+
+        Ok(self.current_page_index)
+    }
+
+
+    /// flush and fsync current page and return (page_index, len of page),
+    /// every record logged before is in pages lower than page_index or first len bytes of it
+    fn pin(&mut self) -> Result<(usize, u64), StatusResult> {
+        self.log.flush()
+            .and_then(|_| self.sync())
+            .and_then(|_| self.page.metadata())
+            .map(|metadata| (self.current_page_index, metadata.len()))
+            .map_err(StatusResult::IoError)
+    }
+
+
+    /// fsync current page
+    #[inline]
+    fn sync(&self) -> std::io::Result<()> {
+        self.page.sync_data()
+    }
+
+
     #[inline]
     fn find_filename(&self, page_index: usize) -> String {
         let s = filename_factory(&self.path, self.total_page_size * page_index);
@@ -371,13 +509,15 @@ impl Context {
 // -------------------------------------------------
 
 
-use crate::darkbird::{SessionResult, StatusResult};
+use crate::darkbird::{SessionResult, StatusResult, Durability};
+
+use super::codec::{self, Keyring, PageHeader, Stamp};
 
 use std::time::Duration;
-use std::{path::Path, fs};
+use std::{path::Path, fs, io};
 
 use simple_wal::{LogFile, LogError};
-use tokio::sync::mpsc::error::{TryRecvError, SendTimeoutError};
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError, SendTimeoutError};
 use tokio::sync::{oneshot, mpsc};
 
 
@@ -392,66 +532,89 @@ pub static TIMEOUT: Duration = Duration::from_secs(5);
 
 
 #[inline]
-fn filename_factory(path: &str, page_pointer: usize) -> String {
+pub fn filename_factory(path: &str, page_pointer: usize) -> String {
     format!("{}/page-{}.LOG", &path, page_pointer)
 }
 
+fn into_log_error(status: StatusResult) -> LogError {
+    match status {
+        StatusResult::LogErr(e) => e,
+        StatusResult::IoError(e) => e.into(),
+        e => io::Error::other(e.to_string()).into(),
+    }
+}
+
+// corrupt records are left by repair only with RecoveryPolicy::Strict, so open fail
+fn used_page(log: &mut LogFile) -> Result<usize, LogError> {
+
+    let mut counter = 0;
+    for record in log.iter(..)? {
+        record?;
+        counter += 1;
+    }
+
+    Ok(counter)
+}
+
 // if not exist directory then is first time run, create dir and a page-1 and open it
 // else open latest page exist
-fn used_page(log: &mut LogFile) -> usize {
+fn open_last_page(path: &str, table_name: &str, total_page_size: usize) -> Result<TmpLogStruct, LogError> {
+    let path = format!("{}/{}", path, table_name);
 
-    let mut counter = 0;
-    let iter = log.iter(..).unwrap();
-    iter.for_each(|_| counter += 1);
+    // if not exist, (First times is started_service)
+    if !Path::new(&path).is_dir() {
+        fs::create_dir_all(&path)?;
+    }
+
+    // pages lower than latest snapshot may be removed, so latest page is found by listing directory
+    let current_page_index = list_pages(&path, total_page_size)?
+        .last()
+        .copied()
+        .unwrap_or(1);
+
+    let filename = filename_factory(&path, total_page_size * current_page_index);
 
-    return counter
+    Ok(TmpLogStruct {
+        log: LogFile::open(&filename)?,
+        path,
+        filename,
+        current_page_index
+    })
 }
 
-fn open_last_page(path: &str, table_name: &str, total_page_size: usize) -> TmpLogStruct {
-     let path = format!("{}/{}", path, table_name);
-     // if not exist, (First times is started_service)
-     if !Path::new(&path).is_dir() {
-        let curr_filename = filename_factory(&path, total_page_size);
-        fs::create_dir(&path).unwrap();
-        return TmpLogStruct {
-            path: path,
-            log: LogFile::open(&curr_filename).unwrap(),
-            current_page_index: 1
-        }
-     }
-    else {
-         // -----------------------------------------
-         let mut page_index = 2;
-         let mut latest_page = filename_factory(&path, total_page_size); 
-         loop {        
-
-            let page_pointer = total_page_size * page_index;
-            let filename = filename_factory(&path, page_pointer);
-        
-            if Path::new(&filename).is_file() {
-                latest_page = filename;
-                page_index += 1; 
-            } 
-            else {
-                // if not exist page_2
-                if page_index == 2 {
-                    return TmpLogStruct {
-                        path,
-                        log: LogFile::open(latest_page).unwrap(),
-                        current_page_index: 1
-                    }
-                } 
-                else {
-                    return TmpLogStruct {
-                        path,
-                        log: LogFile::open(latest_page).unwrap(),
-                        current_page_index: (page_index - 1)
-                    }
-                }
-            }                
+
+/// return sorted index of all pages exist in datastore directory
+pub fn list_pages(path: &str, total_page_size: usize) -> Result<Vec<usize>, std::io::Error> {
+    let mut pages = Vec::new();
+
+    for entry in fs::read_dir(path)? {
+        let name = entry?.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue
+        };
+
+        let page_pointer = name
+            .strip_prefix("page-")
+            .and_then(|rest| rest.strip_suffix(".LOG"))
+            .and_then(|pointer| pointer.parse::<usize>().ok());
+
+        if let Some(page_pointer) = page_pointer {
+            if page_pointer % total_page_size == 0 {
+                pages.push(page_pointer / total_page_size);
+            }
         }
-     }
+    }
 
+    pages.sort();
+    Ok(pages)
+}
+
+
+/// at-least DEFAULT_PAGE_SIZE Record
+#[inline]
+pub fn page_size(total_page_size: usize) -> usize {
+    if total_page_size < DEFAULT_PAGE_SIZE { DEFAULT_PAGE_SIZE } else { total_page_size }
 }
 
 
@@ -460,29 +623,50 @@ fn open_last_page(path: &str, table_name: &str, total_page_size: usize) -> TmpLo
 // --------------------- Client Code --------------------------
 
 
+#[derive(Clone)]
 pub struct Session {
-    sender: mpsc::Sender<Request>
+    sender: mpsc::Sender<Request>,
+    durability: Durability
 }
 
 impl Session {
-    fn new(sender: mpsc::Sender<Request>) -> Self {
+    fn new(sender: mpsc::Sender<Request>, durability: Durability) -> Self {
         Session { 
-            sender 
+            sender,
+            durability
         }
     }
 
 
 
-    /// checkin a resource
+    /// checkin a resource, 
+    /// return according to durability (Enqueued, Flushed, Fsynced)
     pub async fn log(&self, record: Vec<u8>) -> Result<(), SessionResult> {
 
-        let res = self.sender.send_timeout(Request::Record(record), TIMEOUT).await;
-        match res {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                match e {
-                    SendTimeoutError::Timeout(_) => Err(SessionResult::Timeout),
-                    SendTimeoutError::Closed(_) => Err(SessionResult::Closed),
+        let (ack, resp) = match self.durability {
+            Durability::Enqueued => (None, None),
+            durability => {
+                let (dst, resp) = oneshot::channel();
+                (Some(Ack { durability, dst }), Some(resp))
+            }
+        };
+
+        let res = self.sender.send_timeout(Request::Record { bytes: record, ack }, TIMEOUT).await;
+        if let Err(e) = res {
+            return match e {
+                SendTimeoutError::Timeout(_) => Err(SessionResult::Timeout),
+                SendTimeoutError::Closed(_) => Err(SessionResult::Closed),
+            }
+        }
+
+        match resp {
+            None => Ok(()),
+            Some(resp) => {
+                // Await for batch commit
+                match resp.await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(SessionResult::Err(e)),
+                    Err(_) => Err(SessionResult::NoResponse),
                 }
             }
         }
@@ -533,4 +717,80 @@ impl Session {
 
 
 
+
+    /// return position that every record logged before it is durable in,
+    /// as (page_index, len of page)
+    pub async fn pin(&self) -> Result<(usize, u64), SessionResult> {
+        
+        // create oneshot channel
+        let (ask, resp) = oneshot::channel();
+
+        // send request with timeout (5 seconds)
+        let res = self.sender.send_timeout(Request::Pin { dst: ask }, TIMEOUT).await;
+
+        match res {
+            Err(SendTimeoutError::Closed(_req)) => Err(SessionResult::Closed),
+            Err(SendTimeoutError::Timeout(_req)) => Err(SessionResult::Timeout),
+            Ok(_) => {
+                match resp.await {
+                    Ok(Ok(position)) => Ok(position),
+                    Ok(Err(e)) => Err(SessionResult::Err(e)),
+                    Err(_) => Err(SessionResult::NoResponse),
+                }
+            }
+        }
+    }
+
+
+
+    /// block until every record logged before it is flushed, it does not need runtime,
+    /// so it can be called from drop
+    pub fn flush_blocking(&self) -> Result<(), SessionResult> {
+        let (ask, resp) = std::sync::mpsc::sync_channel(1);
+
+        let deadline = std::time::Instant::now() + TIMEOUT;
+        let mut req = Request::Flush { dst: ask };
+        loop {
+            match self.sender.try_send(req) {
+                Ok(_) => break,
+                Err(TrySendError::Closed(_)) => return Err(SessionResult::Closed),
+                Err(TrySendError::Full(_)) if std::time::Instant::now() >= deadline => return Err(SessionResult::Timeout),
+                Err(TrySendError::Full(r)) => {
+                    req = r;
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+
+        match resp.recv_timeout(TIMEOUT) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(SessionResult::Err(e)),
+            Err(_) => Err(SessionResult::NoResponse),
+        }
+    }
+
+
+
+    /// seal current page and return index of page that next record written to
+    pub async fn rotate(&self) -> Result<usize, SessionResult> {
+        
+        // create oneshot channel
+        let (ask, resp) = oneshot::channel();
+
+        // send request with timeout (5 seconds)
+        let res = self.sender.send_timeout(Request::Rotate { dst: ask }, TIMEOUT).await;
+
+        match res {
+            Err(SendTimeoutError::Closed(_req)) => Err(SessionResult::Closed),
+            Err(SendTimeoutError::Timeout(_req)) => Err(SessionResult::Timeout),
+            Ok(_) => {
+                match resp.await {
+                    Ok(Ok(page_index)) => Ok(page_index),
+                    Ok(Err(e)) => Err(SessionResult::Err(e)),
+                    Err(_) => Err(SessionResult::NoResponse),
+                }
+            }
+        }
+    }
+
 }
\ No newline at end of file
@@ -1,11 +1,15 @@
 
 use crate::RQuery;
 
-use super::page_processor::{Format, Sync, PageProcessor};
+use super::codec::{Compression, Format, KeyProvider};
+use super::backup;
+use super::disk_log::page_size;
+use super::page_processor::{Sync, PageProcessor};
 
 
 use chrono::Utc;
 use std::hash::Hash;
+use std::sync::Arc;
 use serde::{de::DeserializeOwned, Serialize};
 
 
@@ -40,13 +44,90 @@ where
 }
 
 
-// take a full copy from wal files with timestamp
+/// re-encode all pages and snapshot of datastore with format and compression,
+/// encrypted datastore need keys and result is encrypted with current key,
+/// storage must be closed while re-encoding
+pub fn reencode<'a, Key, Doc>(
+    root: &'a str, 
+    name: &'a str, 
+    total_page_size: usize, 
+    format: Format,
+    compression: Compression,
+    keys: Option<Arc<dyn KeyProvider>>) -> Result<(), String>
+
+where 
+    Key: Serialize + DeserializeOwned + Hash + Eq + PartialEq,
+    Doc: Serialize + DeserializeOwned,
+{
+
+    let mut pp = PageProcessor::<Key, Doc, Key, Doc>::new(
+        root, 
+        name, 
+        total_page_size, 
+        Sync::Overwrite, 
+        false, 
+        |rq| rq)
+        .with_format(format)
+        .with_compression(compression);
+
+    if let Some(keys) = keys {
+        pp = pp.with_keys(keys);
+    }
+
+    pp.start()
+}
+
+
+/// re-encrypt all pages and snapshot of datastore with current key of keys,
+/// after that older keys are not needed, storage must be closed while rotating
+pub fn rotate_keys<'a, Key, Doc>(
+    root: &'a str, 
+    name: &'a str, 
+    total_page_size: usize, 
+    keys: Arc<dyn KeyProvider>) -> Result<(), String>
+
+where 
+    Key: Serialize + DeserializeOwned + Hash + Eq + PartialEq,
+    Doc: Serialize + DeserializeOwned,
+{
+
+    let pp = PageProcessor::<Key, Doc, Key, Doc>::new(
+        root, 
+        name, 
+        total_page_size, 
+        Sync::Overwrite, 
+        false, 
+        |rq| rq)
+        .with_keys(keys);
+
+    pp.start()
+}
+
+
+// take a full copy from wal files with timestamp,
+// a manifest is written alongside it
 pub fn backup<'a, Key, Doc>(
     root: &'a str, 
     name: &'a str, 
     total_page_size: usize, 
     vacuum: bool)  -> Result<(), String> 
 
+where 
+    Key: Serialize + DeserializeOwned + Hash + Eq + PartialEq,
+    Doc: Serialize + DeserializeOwned,
+{
+    backup_with_keys::<Key, Doc>(root, name, total_page_size, vacuum, None)
+}
+
+
+// take a full copy from encrypted wal files with timestamp, copy is encrypted with current key
+pub fn backup_with_keys<'a, Key, Doc>(
+    root: &'a str, 
+    name: &'a str, 
+    total_page_size: usize, 
+    vacuum: bool,
+    keys: Option<Arc<dyn KeyProvider>>)  -> Result<(), String> 
+
 where 
     Key: Serialize + DeserializeOwned + Hash + Eq + PartialEq,
     Doc: Serialize + DeserializeOwned,
@@ -59,7 +140,7 @@ where
     let backup_name = format!("{}_backup_{}-{}", name, date, time);
 
 
-    let pp = PageProcessor::<Key, Doc, Key, Doc>::new(
+    let mut pp = PageProcessor::<Key, Doc, Key, Doc>::new(
         root, 
         name, 
         total_page_size, 
@@ -67,5 +148,11 @@ where
         vacuum, 
         |rq| rq);
 
-    pp.start()
+    if let Some(keys) = keys {
+        pp = pp.with_keys(keys);
+    }
+
+    pp.start()?;
+
+    backup::write_manifest(root, name, &backup_name, page_size(total_page_size), dt.to_rfc3339()).map(|_| ())
 }
@@ -0,0 +1,52 @@
+use std::convert::TryInto;
+
+
+// on-disk layout of simple_wal 0.3.0 page, Cargo.toml pins simple_wal to
+// this version because recovery and reader parse pages without it:
+// first 8 bytes is starting index of page, then every entry is
+// length of data (u64 le), data and crc32 of data (u32 le)
+
+
+/// offset of first entry in page
+pub const FIRST_ENTRY: usize = 8;
+
+/// bytes of length at start of entry
+pub const LEN_SIZE: usize = 8;
+const CHECKSUM_SIZE: usize = 4;
+
+
+/// entry at start of bytes
+pub enum Frame<'a> {
+    // whole entry, len is length of entry with its length and checksum
+    Whole { data: &'a [u8], len: usize, checksum_ok: bool },
+
+    // entry is not whole written or its length is not possible
+    Torn,
+}
+
+
+/// length of whole entry from its first bytes, None if they are not enough
+/// or length is not possible
+pub fn entry_len(bytes: &[u8]) -> Option<usize> {
+    let len = u64::from_le_bytes(bytes.get(..LEN_SIZE)?.try_into().ok()?);
+    usize::try_from(len).ok()?.checked_add(LEN_SIZE + CHECKSUM_SIZE)
+}
+
+
+pub fn frame(bytes: &[u8]) -> Frame<'_> {
+    let len = match entry_len(bytes).filter(|len| *len <= bytes.len()) {
+        Some(len) => len,
+        None => return Frame::Torn,
+    };
+
+    let data = &bytes[LEN_SIZE..len - CHECKSUM_SIZE];
+    let checksum = u32::from_le_bytes(bytes[len - CHECKSUM_SIZE..len].try_into().unwrap());
+
+    Frame::Whole { data, len, checksum_ok: crc32fast::hash(data) == checksum }
+}
+
+
+/// data of whole entry
+pub fn data(entry: &[u8]) -> &[u8] {
+    &entry[LEN_SIZE..entry.len() - CHECKSUM_SIZE]
+}
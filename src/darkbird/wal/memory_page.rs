@@ -1,38 +1,58 @@
 use std::collections::HashMap;
 use std::hash::Hash;
 
-use tokio::time::Instant;
-
 use crate::RQuery;
 
 
 pub struct MemoryPage<K: Eq + PartialEq + Hash, Doc> {
-    mapper: HashMap<(&'static str, K), (Instant, Option<Doc>)>
+    mapper: HashMap<(&'static str, K), Stashed<Doc>>,
+
+    // Batch and Transaction are kept whole, so they are replayed all or nothing
+    batches: Vec<(usize, RQuery<K, Doc>)>,
+
+    // order records are stashed
+    seq: usize,
 }
 
-impl<K, Doc> MemoryPage<K, Doc>  
-where 
+// version of Versioned is kept beside its doc
+type Stashed<Doc> = (usize, Option<Doc>, Option<u64>);
+
+impl<K, Doc> MemoryPage<K, Doc>
+where
     K: Eq + PartialEq + Hash
 {
-    
+
     pub fn new() -> Self {
-        MemoryPage { mapper: HashMap::new() }
+        MemoryPage { mapper: HashMap::new(), batches: Vec::new(), seq: 0 }
     }
 
     pub fn stash(&mut self, rquery: RQuery<K, Doc>)  {
+        self.seq += 1;
+
+        let version = match rquery {
+            RQuery::Batch(_) | RQuery::Transaction(_, _) => return self.batches.push((self.seq, rquery)),
+            RQuery::Versioned(_, _, version) => Some(version),
+            _ => None
+        };
+
         let (type_id, key, doc) = rquery.into_raw();
-        let time = Instant::now();
-        self.mapper.insert((type_id, key), (time, doc));
+        self.mapper.insert((type_id, key), (self.seq, doc, version));
     }
 
 
-    pub fn get_page(self) -> Vec<(Instant, RQuery<K, Doc>)> {
-        let mut result = Vec::with_capacity(self.mapper.len());
-        for ((type_id, key), (instant, doc)) in self.mapper {
-            result.push((instant, RQuery::from_raw(type_id, key, doc)));
+    pub fn get_page(self) -> Vec<(usize, RQuery<K, Doc>)> {
+        let mut result = self.batches;
+        result.reserve(self.mapper.len());
+
+        for ((type_id, key), (seq, doc, version)) in self.mapper {
+            let rquery = match (RQuery::from_raw(type_id, key, doc), version) {
+                (RQuery::Insert(key, doc), Some(version)) => RQuery::Versioned(key, doc, version),
+                (rquery, _) => rquery
+            };
+            result.push((seq, rquery));
         }
 
-        result.sort_by(|(a, _), (b, _)| a.cmp(b));
+        result.sort_by_key(|(seq, _)| *seq);
         result
-    } 
-}
\ No newline at end of file
+    }
+}
@@ -2,3 +2,23 @@ pub mod disk_log;
 pub mod page_processor;
 pub mod memory_page;
 pub mod helper;
+pub mod snapshot;
+pub mod compaction;
+pub mod recovery;
+pub mod codec;
+pub mod backup;
+pub mod reader;
+pub mod patch;
+mod layout;
+#[cfg(test)]
+mod codec_test;
+#[cfg(test)]
+mod reader_test;
+#[cfg(test)]
+mod recovery_test;
+#[cfg(test)]
+mod compaction_test;
+#[cfg(test)]
+mod backup_test;
+#[cfg(test)]
+mod patch_test;
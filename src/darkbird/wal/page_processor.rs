@@ -1,4 +1,4 @@
-use std::{path::Path, fs, marker::PhantomData};
+use std::{path::Path, fs, marker::PhantomData, cell::RefCell};
 use std::hash::Hash;
 
 use serde::{Serialize, de::DeserializeOwned};
@@ -6,8 +6,13 @@ use simple_wal::LogFile;
 
 use crate::RQuery;
 
-use super::disk_log::DEFAULT_PAGE_SIZE;
+use std::sync::Arc;
+
+use super::codec::{Compression, Format, KeyProvider, Keyring, PageHeader, Stamp};
+use super::disk_log::{list_pages, page_size};
+use super::snapshot;
 use super::memory_page::MemoryPage;
+use super::patch::Resolver;
 
 
 
@@ -21,13 +26,6 @@ pub enum Sync<'a> {
 
 
 
-#[derive(Clone)]
-pub enum Format {
-    Bson, 
-    Bincode
-}
-
-
 enum Recovery {
     Recoverable(Metadata),
     UnRecoverable(String)
@@ -40,13 +38,28 @@ struct Metadata {
 }
 
 
+type Handler<'a, OldKey, OldDoc, NewKey, NewDoc> = dyn FnMut(RQuery<OldKey, OldDoc>) -> Result<Option<RQuery<NewKey, NewDoc>>, String> + 'a;
+
+
 pub struct PageProcessor<'a, OldKey, OldDoc, NewKey, NewDoc> {
     root: &'a str,
     source_name: &'a str,
     source_total_page_size: usize,
     sync_name: Sync<'a>,
     vacuum: bool,
-    handler: fn(RQuery<OldKey, OldDoc>) -> RQuery<NewKey, NewDoc>,
+
+    // None drop RQuery from result, error stop processing
+    handler: RefCell<Box<Handler<'a, OldKey, OldDoc, NewKey, NewDoc>>>,
+
+    // format and compression of result, None keep them from every source page
+    format: Option<Format>,
+    compression: Option<Compression>,
+
+    // keys of encrypted source, result is encrypted with current key of it
+    keys: Keyring,
+
+    // Update of source is given to handler as document it made
+    resolver: RefCell<Resolver<OldKey, OldDoc>>,
 
     phan_old_key: PhantomData<OldKey>,
     phan_old_doc: PhantomData<OldDoc>,
@@ -56,15 +69,25 @@ pub struct PageProcessor<'a, OldKey, OldDoc, NewKey, NewDoc> {
 
 impl<'a, OldKey, OldDoc, NewKey, NewDoc> PageProcessor<'a, OldKey, OldDoc, NewKey, NewDoc> 
 where
-    OldKey: Serialize + DeserializeOwned + Hash + Eq + PartialEq,
-    OldDoc: Serialize + DeserializeOwned,
-    NewKey: Serialize + DeserializeOwned + Hash + Eq +,
-    NewDoc: Serialize + DeserializeOwned
+    OldKey: Serialize + DeserializeOwned + Hash + Eq + PartialEq + 'a,
+    OldDoc: Serialize + DeserializeOwned + 'a,
+    NewKey: Serialize + DeserializeOwned + Hash + Eq + 'a,
+    NewDoc: Serialize + DeserializeOwned + 'a
 {
     
     pub fn new(root: &'a str, source_name: &'a str, source_total_page_size: usize,
                sync_name: Sync<'a>, vacuum: bool, 
                handler: fn(RQuery<OldKey, OldDoc>) -> RQuery<NewKey, NewDoc>) -> Self 
+    {
+        Self::with_handler(root, source_name, source_total_page_size, sync_name, vacuum, move |rq| Ok(Some(handler(rq))))
+    }
+
+
+    /// handler can capture state, drop RQuery by returning None or fail processing by error
+    pub fn with_handler<F>(root: &'a str, source_name: &'a str, source_total_page_size: usize,
+                           sync_name: Sync<'a>, vacuum: bool, handler: F) -> Self 
+    where
+        F: FnMut(RQuery<OldKey, OldDoc>) -> Result<Option<RQuery<NewKey, NewDoc>>, String> + 'a
     {
         PageProcessor {
             root,
@@ -72,7 +95,11 @@ where
             source_total_page_size,
             sync_name,
             vacuum,
-            handler,
+            handler: RefCell::new(Box::new(handler)),
+            format: None,
+            compression: None,
+            keys: Keyring::default(),
+            resolver: RefCell::new(Resolver::new()),
             phan_old_key: PhantomData,
             phan_old_doc: PhantomData,
             phan_new_key: PhantomData,
@@ -81,6 +108,26 @@ where
     }
 
 
+    /// re-encode result with format
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// re-compress result with compression
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// decrypt source pages by keys and encrypt result with current key,
+    /// without keys result is not encrypted
+    pub fn with_keys(mut self, keys: Arc<dyn KeyProvider>) -> Self {
+        self.keys = Keyring::new(Some(keys));
+        self
+    }
+
+
     pub fn start(self) -> Result<(), String> {
         match self.internal_start() {
             Ok(_) => Ok(()),
@@ -105,7 +152,7 @@ where
 
     fn internal_start(&self) -> Result<(), Recovery> {
         
-        let total_page_size =  if self.source_total_page_size < DEFAULT_PAGE_SIZE { DEFAULT_PAGE_SIZE } else { self.source_total_page_size };
+        let total_page_size = page_size(self.source_total_page_size);
         let source_path = format!("{}/{}", self.root, self.source_name);
         let sync_path   = format!("{}/{}", self.root, self.sync_name());
 
@@ -121,21 +168,27 @@ where
             }
         }
 
-        // page_index
-        let mut page_index = 1;
+        // documents are kept for Update just if some record update their key
+        if let Err(e) = self.resolver.borrow_mut().watch_pages(&source_path, total_page_size, &self.keys) {
+            return Err(Recovery::UnRecoverable(e))
+        }
 
-        loop {
+        // snapshot is transformed as list of Insert
+        if let Err(e) = self.process_snapshot(&source_path, &sync_path) {
+            return Err(Recovery::UnRecoverable(e))
+        }
 
-            let page_pointer = total_page_size * page_index;
-            let source_name = filename_factory(&source_path, page_pointer);
 
-            page_index += 1;
+        // pages older than snapshot may be removed, so pages are found by listing directory
+        let pages = match list_pages(&source_path, total_page_size) {
+            Ok(pages) => pages,
+            Err(e) => return Err(Recovery::UnRecoverable(e.to_string()))
+        };
 
+        for page_index in pages {
 
-            // filename not exist return Ok
-            if !Path::new(&source_name).is_file() {
-                return Ok(())
-            }
+            let page_pointer = total_page_size * page_index;
+            let source_name = filename_factory(&source_path, page_pointer);
 
 
             // prepare source_page_name
@@ -199,6 +252,14 @@ where
 
             let mut memory_page = MemoryPage::new();
 
+            // header of source page select decoder, pages without header are legacy bincode,
+            // sync page start with header of target format
+            let mut source_header = PageHeader::legacy();
+            let mut sync_header = None;
+
+            // stamps of records are kept, vacuumed records get stamp of last record of page
+            let mut last_stamp = Stamp::default();
+
             for bytes in source_pager_iter {
                 match bytes {
                     Err(e) => {
@@ -211,8 +272,41 @@ where
                     }
                     Ok(raw_qline) => {
 
+                        let header = match sync_header {
+                            Some(header) => header,
+                            None => {
+                                let parsed = PageHeader::parse_record(&raw_qline);
+                                let mut seq = 0;
+                                if let Some((parsed, base)) = parsed {
+                                    source_header = parsed;
+                                    seq = base;
+                                }
+
+                                let header = self.target_header(source_header);
+                                sync_header = Some(header);
+
+                                if let Err(e) = sync_page.write(&mut header.to_record(seq)) {
+                                    let meta = Metadata {
+                                        original_filename: source_page_name.to_owned(),
+                                        currepted_filename: source_name.to_owned(),
+                                        err: e.to_string(),
+                                    };
+                                    return Err(Recovery::Recoverable(meta))
+                                }
+
+                                if parsed.is_some() {
+                                    continue
+                                }
+
+                                header
+                            }
+                        };
+
+                        let stamp = source_header.stamp(&raw_qline).unwrap_or_default();
+                        last_stamp = stamp;
+
                         // Deserialize rquery
-                        let old_query: RQuery<OldKey, OldDoc> = match bincode::deserialize(&raw_qline) {
+                        let old_query: RQuery<OldKey, OldDoc> = match source_header.decode(&raw_qline, &self.keys) {
                             Ok(res) => res,
                             Err(e) => {
                                 let meta = Metadata {
@@ -224,8 +318,32 @@ where
                             }
                         };
 
+                        let old_query = match self.resolver.borrow_mut().resolve(old_query) {
+                            Ok(res) => res,
+                            Err(err) => {
+                                let meta = Metadata {
+                                    original_filename: source_page_name.to_owned(),
+                                    currepted_filename: source_name.to_owned(),
+                                    err,
+                                };
+                                return Err(Recovery::Recoverable(meta))
+                            }
+                        };
+
                         // transform
-                        let new_query = (&self.handler)(old_query);
+                        // handler see Insert and Remove, version and batch of them are kept
+                        let new_query = match old_query.map_writes(&mut *self.handler.borrow_mut()) {
+                            Ok(Some(new_query)) => new_query,
+                            Ok(None) => continue,
+                            Err(err) => {
+                                let meta = Metadata {
+                                    original_filename: source_page_name.to_owned(),
+                                    currepted_filename: source_name.to_owned(),
+                                    err,
+                                };
+                                return Err(Recovery::Recoverable(meta))
+                            }
+                        };
 
 
                         if self.vacuum {
@@ -235,7 +353,17 @@ where
                         } else {
 
                             // serialize
-                            let mut bytes = bincode::serialize(&new_query).unwrap();
+                            let mut bytes = match header.seal(&new_query, stamp, &self.keys) {
+                                Ok(bytes) => bytes,
+                                Err(err) => {
+                                    let meta = Metadata {
+                                        original_filename: source_page_name.to_owned(),
+                                        currepted_filename: source_name.to_owned(),
+                                        err,
+                                    };
+                                    return Err(Recovery::Recoverable(meta))
+                                }
+                            };
 
 
                             // write to sync
@@ -253,10 +381,22 @@ where
             }
 
             if self.vacuum {
+                let header = sync_header.unwrap_or(source_header);
+
                 for (_, rquery) in memory_page.get_page().into_iter() {
                     
                     // serialize
-                    let mut bytes = bincode::serialize(&rquery).unwrap();
+                    let mut bytes = match header.seal(&rquery, last_stamp, &self.keys) {
+                        Ok(bytes) => bytes,
+                        Err(err) => {
+                            let meta = Metadata {
+                                original_filename: source_page_name.to_owned(),
+                                currepted_filename: source_name.to_owned(),
+                                err,
+                            };
+                            return Err(Recovery::Recoverable(meta))
+                        }
+                    };
 
                     // write to sync
                     if let Err(e) = sync_page.write(&mut bytes) {
@@ -286,10 +426,46 @@ where
 
         }
 
+        Ok(())
+    }
+
+
+    fn process_snapshot(&self, source_path: &str, sync_path: &str) -> Result<(), String> {
+        let (page_index, filename) = match snapshot::latest(source_path)? {
+            Some(latest) => latest,
+            None => return Ok(())
+        };
+
+        let entries = snapshot::read_versioned::<OldKey, OldDoc>(&filename, &self.keys)?;
+        let header = self.target_header(snapshot::header(&filename)?);
+
+        let mut writer = snapshot::Writer::create(sync_path, page_index, header, self.keys.clone())?;
+        for (key, doc, version) in entries {
+            self.resolver.borrow_mut().insert(&key, &doc)?;
+            if let Some(RQuery::Insert(key, doc)) = (self.handler.borrow_mut())(RQuery::Insert(key, doc))? {
+                writer.append(&key, &doc, version)?;
+            }
+        }
+
+        writer.commit()
     }
 
 
 
+    // header of result for a source with header
+    #[inline]
+    fn target_header(&self, source: PageHeader) -> PageHeader {
+        let header = PageHeader::new(
+            self.format.unwrap_or(source.format),
+            self.compression.unwrap_or(source.compression),
+            self.keys.encryption()
+        );
+
+        // unstamped source has not any stamp to keep
+        PageHeader { stamped: source.stamped, ..header }
+    }
+
+
     fn sync_name(&self) -> &'a str {
         match self.sync_name {
             Sync::Overwrite => self.source_name,
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use simple_wal::LogFile;
+
+use crate::RQuery;
+
+use super::{codec::{self, Keyring}, disk_log::{filename_factory, list_pages}};
+
+
+
+/// patch of RQuery::Update is json merge patch (rfc 7386) of serialized document,
+/// it keep just fields that changed, so it is usually much smaller than document.
+///
+/// None if document can not be patched, (e.g. it is not serializable to json
+/// or a null field of it is lost by merge), full document must be logged instead
+pub fn diff<Doc>(old: &Doc, new: &Doc) -> Option<Vec<u8>>
+where
+    Doc: Serialize,
+{
+    let old = serde_json::to_value(old).ok()?;
+    let new = serde_json::to_value(new).ok()?;
+
+    let patch = diff_value(&old, &new);
+
+    // merge patch can not set a field to null, it must give back new document as it is
+    let mut patched = old;
+    merge(&mut patched, patch.clone());
+    if patched != new {
+        return None
+    }
+
+    serde_json::to_vec(&patch).ok()
+}
+
+
+/// apply patch of diff to document it is made from
+pub fn apply<Doc>(doc: &Doc, patch: &[u8]) -> Result<Doc, String>
+where
+    Doc: Serialize + DeserializeOwned,
+{
+    let mut value = serde_json::to_value(doc).map_err(|e| e.to_string())?;
+    let patch = serde_json::from_slice(patch).map_err(|e| e.to_string())?;
+
+    merge(&mut value, patch);
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+
+fn diff_value(old: &Value, new: &Value) -> Value {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut patch = Map::new();
+
+            for key in old.keys().filter(|key| !new.contains_key(*key)) {
+                patch.insert(key.clone(), Value::Null);
+            }
+
+            for (key, value) in new {
+                match old.get(key) {
+                    Some(old_value) if old_value == value => {}
+                    Some(old_value) => { patch.insert(key.clone(), diff_value(old_value, value)); }
+                    None => { patch.insert(key.clone(), value.clone()); }
+                }
+            }
+
+            Value::Object(patch)
+        }
+        (_, new) => new.clone()
+    }
+}
+
+
+fn merge(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+
+            if let Value::Object(target) = target {
+                for (key, value) in patch {
+                    match value {
+                        Value::Null => { target.remove(&key); }
+                        value => merge(target.entry(key).or_insert(Value::Null), value),
+                    }
+                }
+            }
+        }
+        patch => *target = patch,
+    }
+}
+
+
+
+/// keep latest document of updated keys while records are read in order they written,
+/// so Update is resolved to Versioned record of document it made. keys that are updated
+/// are found by watch before, documents of other keys are not kept, so documents of
+/// datastore without Update need not be serializable to json.
+/// documents are kept as json value by encoded key, so K and Doc need not be Clone
+pub struct Resolver<K, Doc> {
+    docs: HashMap<Vec<u8>, Value>,
+    updated: HashSet<Vec<u8>>,
+    phantom: PhantomData<(K, Doc)>,
+}
+
+impl<K, Doc> Resolver<K, Doc>
+where
+    K: Serialize,
+    Doc: Serialize + DeserializeOwned,
+{
+    pub fn new() -> Self {
+        Resolver { docs: HashMap::new(), updated: HashSet::new(), phantom: PhantomData }
+    }
+
+    /// keys of Update in record, it is called for every record before they are resolved
+    pub fn watch(&mut self, rq: &RQuery<K, Doc>) -> Result<(), String> {
+        match rq {
+            RQuery::Update(key, _, _) => {
+                self.updated.insert(bincode::serialize(key).map_err(|e| e.to_string())?);
+            }
+            RQuery::Batch(writes) | RQuery::Transaction(_, writes) => {
+                for rq in writes {
+                    self.watch(rq)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// watch records of every page in path
+    pub fn watch_pages(&mut self, path: &str, total_page_size: usize, keys: &Keyring) -> Result<(), String>
+    where
+        K: DeserializeOwned,
+    {
+        for page_index in list_pages(path, total_page_size).map_err(|e| e.to_string())? {
+            let mut page = LogFile::open(filename_factory(path, total_page_size * page_index)).map_err(|e| e.to_string())?;
+            for rq in codec::read_page::<RQuery<K, Doc>>(&mut page, keys)? {
+                self.watch(&rq)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// document of key before records, (e.g. entry of snapshot)
+    pub fn insert(&mut self, key: &K, doc: &Doc) -> Result<(), String> {
+        let key = bincode::serialize(key).map_err(|e| e.to_string())?;
+        if self.updated.contains(&key) {
+            let doc = serde_json::to_value(doc).map_err(|e| e.to_string())?;
+            self.docs.insert(key, doc);
+        }
+        Ok(())
+    }
+
+    pub fn resolve(&mut self, rq: RQuery<K, Doc>) -> Result<RQuery<K, Doc>, String> {
+        match rq {
+            RQuery::Insert(key, doc) => {
+                self.insert(&key, &doc)?;
+                Ok(RQuery::Insert(key, doc))
+            }
+            RQuery::Versioned(key, doc, version) => {
+                self.insert(&key, &doc)?;
+                Ok(RQuery::Versioned(key, doc, version))
+            }
+            RQuery::Update(key, patch, version) => {
+                let encoded = bincode::serialize(&key).map_err(|e| e.to_string())?;
+                let value = match self.docs.get_mut(&encoded) {
+                    Some(value) => value,
+                    None => return Err("update of missing document".to_owned())
+                };
+
+                merge(value, serde_json::from_slice(&patch).map_err(|e| e.to_string())?);
+                let doc = serde_json::from_value(value.clone()).map_err(|e| e.to_string())?;
+                Ok(RQuery::Versioned(key, doc, version))
+            }
+            RQuery::Remove(key) => {
+                self.docs.remove(&bincode::serialize(&key).map_err(|e| e.to_string())?);
+                Ok(RQuery::Remove(key))
+            }
+            RQuery::Removed(key, version) => {
+                self.docs.remove(&bincode::serialize(&key).map_err(|e| e.to_string())?);
+                Ok(RQuery::Removed(key, version))
+            }
+            RQuery::Batch(writes) => Ok(RQuery::Batch(self.resolve_all(writes)?)),
+            RQuery::Transaction(txid, writes) => Ok(RQuery::Transaction(txid, self.resolve_all(writes)?)),
+        }
+    }
+
+    fn resolve_all(&mut self, writes: Vec<RQuery<K, Doc>>) -> Result<Vec<RQuery<K, Doc>>, String> {
+        writes.into_iter().map(|rq| self.resolve(rq)).collect()
+    }
+}
+
+impl<K, Doc> Default for Resolver<K, Doc>
+where
+    K: Serialize,
+    Doc: Serialize + DeserializeOwned,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
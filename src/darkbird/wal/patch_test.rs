@@ -0,0 +1,23 @@
+use std::collections::HashMap;
+
+use crate::RQuery;
+use crate::darkbird::test_fixture::Profile;
+use super::patch::{self, Resolver};
+
+
+#[test]
+fn resolver_keep_just_documents_of_updated_keys() {
+    // document that is not serializable to json is passed through
+    let mut resolver = Resolver::<u32, HashMap<(u32, u32), u32>>::new();
+    let doc = HashMap::from([((1, 2), 3)]);
+    assert_eq!(resolver.resolve(RQuery::Insert(1, doc.clone())).unwrap(), RQuery::Insert(1, doc));
+
+    let old = Profile::new("DanyalMh", 1);
+    let new = Profile::new("DanyalMh", 2);
+    let update = RQuery::Update("pid-1".to_owned(), patch::diff(&old, &new).unwrap(), 2);
+
+    let mut resolver = Resolver::<String, Profile>::new();
+    resolver.watch(&RQuery::Batch(vec![update.clone()])).unwrap();
+    resolver.resolve(RQuery::Insert("pid-1".to_owned(), old)).unwrap();
+    assert_eq!(resolver.resolve(update).unwrap(), RQuery::Versioned("pid-1".to_owned(), new, 2));
+}
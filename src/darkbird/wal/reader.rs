@@ -0,0 +1,325 @@
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::RQuery;
+
+use super::codec::{KeyProvider, Keyring, PageHeader, Stamp};
+use super::disk_log::{filename_factory, list_pages, page_size};
+use super::layout::{entry_len, frame, Frame, FIRST_ENTRY, LEN_SIZE};
+
+
+
+/// place of a record in wal, offset is byte offset of record in its page file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Position {
+    pub page_index: usize,
+    pub offset: u64,
+}
+
+
+/// record of wal with its stamp
+pub type Record<K, Doc> = (Stamp, RQuery<K, Doc>);
+
+
+/// where WalReader start reading
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StartAt {
+    /// first record of oldest page exist, records released by checkpoint are only in snapshot
+    Beginning,
+
+    /// record at position, usually a position returned by WalReader::position
+    Position(Position),
+
+    /// first record with sequence equal or greater than seq
+    Seq(u64),
+}
+
+
+/// follow changes of datastore from its wal files, files are opened read-only
+/// so it can read datastore of another process, reader wait for records
+/// appended by disk_log, so no event is missed after restart
+pub struct WalReader<K, Doc> {
+    tail: Tail,
+    keys: Keyring,
+    poll_interval: Duration,
+
+    _marker: PhantomData<(K, Doc)>,
+}
+
+
+impl<K, Doc> WalReader<K, Doc>
+where
+    K: Serialize + DeserializeOwned + Hash + Eq,
+    Doc: Serialize + DeserializeOwned,
+{
+    pub fn open(root: &str, name: &str, total_page_size: usize, start: StartAt) -> Result<Self, String> {
+        Ok(WalReader {
+            tail: Tail::open(format!("{}/{}", root, name), page_size(total_page_size), start)?,
+            keys: Keyring::new(None),
+            poll_interval: Duration::from_millis(50),
+            _marker: PhantomData,
+        })
+    }
+
+    /// keys of encrypted datastore
+    pub fn with_keys(mut self, keys: Arc<dyn KeyProvider>) -> Self {
+        self.keys = Keyring::new(Some(keys));
+        self
+    }
+
+    /// how often wal is checked for new records while waiting
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// position of next record, reader can be opened from it later
+    pub fn position(&self) -> Position {
+        self.tail.position
+    }
+
+
+    /// next record if exist, None when reader reached end of wal
+    pub fn try_next(&mut self) -> Result<Option<Record<K, Doc>>, String> {
+        match self.tail.try_next()? {
+            Some((header, data)) => Ok(Some((header.stamp(&data)?, header.decode(&data, &self.keys)?))),
+            None => Ok(None)
+        }
+    }
+
+
+    /// wait until next record is appended
+    pub async fn next(&mut self) -> Result<Record<K, Doc>, String> {
+        loop {
+            if let Some(record) = self.try_next()? {
+                return Ok(record)
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+
+    /// block thread until next record is appended
+    pub fn next_blocking(&mut self) -> Result<Record<K, Doc>, String> {
+        loop {
+            if let Some(record) = self.try_next()? {
+                return Ok(record)
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+
+
+// records of wal as they are on page, not decoded
+pub(crate) struct Tail {
+    path: String,
+    total_page_size: usize,
+
+    // current page and header of it
+    file: Option<File>,
+    header: PageHeader,
+    position: Position,
+
+    // records lower than it are skipped
+    min_seq: u64,
+}
+
+
+impl Tail {
+    pub(crate) fn open(path: String, total_page_size: usize, start: StartAt) -> Result<Self, String> {
+        if !Path::new(&path).is_dir() {
+            return Err(format!("datastore {} not exist", path))
+        }
+
+        let pages = list_pages(&path, total_page_size).map_err(|e| e.to_string())?;
+        let first_page = pages.first().copied().unwrap_or(1);
+
+        let (position, min_seq) = match start {
+            StartAt::Beginning => (Position { page_index: first_page, offset: FIRST_RECORD }, 0),
+            StartAt::Position(position) => (position, 0),
+            StartAt::Seq(seq) => {
+                // last page that its base is lower than seq contain it
+                let mut page_index = first_page;
+                for index in pages {
+                    let filename = filename_factory(&path, total_page_size * index);
+                    match read_header(&filename)? {
+                        Some((_, base)) if base < seq => page_index = index,
+                        Some(_) => break,
+                        None => {}
+                    }
+                }
+                (Position { page_index, offset: FIRST_RECORD }, seq)
+            }
+        };
+
+        Ok(Tail {
+            path,
+            total_page_size,
+            file: None,
+            header: PageHeader::legacy(),
+            position,
+            min_seq,
+        })
+    }
+
+
+    // next record with header of its page, None when end of wal is reached
+    pub(crate) fn try_next(&mut self) -> Result<Option<(PageHeader, Vec<u8>)>, String> {
+        loop {
+            if self.file.is_none() && !self.open_page()? {
+                return Ok(None)
+            }
+
+            let first = self.position.offset == FIRST_RECORD;
+            let data = match self.read_record()? {
+                Some(data) => data,
+                None => {
+                    // disk_log go to next page after current page is full
+                    let page_index = match self.next_page()? {
+                        Some(page_index) => page_index,
+                        None => return Ok(None)
+                    };
+
+                    // records written to current page before next page is created
+                    // are read to the end before moving on
+                    match self.read_record()? {
+                        Some(data) => data,
+                        None => {
+                            self.file = None;
+                            self.position = Position { page_index, offset: FIRST_RECORD };
+                            continue
+                        }
+                    }
+                }
+            };
+
+            // header record is not yielded
+            if first {
+                if let Some((header, _)) = PageHeader::parse_record(&data) {
+                    self.header = header;
+                    continue
+                }
+            }
+
+            if self.header.stamped && self.header.stamp(&data)?.seq < self.min_seq {
+                continue
+            }
+
+            return Ok(Some((self.header, data)))
+        }
+    }
+
+
+    // open page of position and read its header, false if page is not created yet
+    fn open_page(&mut self) -> Result<bool, String> {
+        let filename = filename_factory(&self.path, self.total_page_size * self.position.page_index);
+
+        let file = match File::open(&filename) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // page is released by checkpoint
+                return match self.next_page()? {
+                    Some(_) => Err(format!("page {} is released", self.position.page_index)),
+                    None => Ok(false)
+                }
+            }
+            Err(e) => return Err(e.to_string())
+        };
+
+        // from first record, header is read as first record
+        self.header = match self.position.offset == FIRST_RECORD {
+            true => PageHeader::legacy(),
+            false => read_header(&filename)?.map(|(header, _)| header).unwrap_or_else(PageHeader::legacy)
+        };
+        self.file = Some(file);
+
+        Ok(true)
+    }
+
+
+    // data of record at position and move position after it, None if record is not complete yet
+    fn read_record(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(None)
+        };
+
+        let Position { page_index, offset } = self.position;
+        match read_entry(file, offset) {
+            Ok(Some((data, end))) => {
+                self.position.offset = end;
+                Ok(Some(data))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(format!("page {} at {}: {}", page_index, offset, e))
+        }
+    }
+
+
+    // first page after current page
+    fn next_page(&self) -> Result<Option<usize>, String> {
+        let pages = list_pages(&self.path, self.total_page_size).map_err(|e| e.to_string())?;
+        Ok(pages.into_iter().find(|index| *index > self.position.page_index))
+    }
+}
+
+
+
+// header of page and its base sequence, None if page has not header
+fn read_header(filename: &str) -> Result<Option<(PageHeader, u64)>, String> {
+    let mut file = match File::open(filename) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.to_string())
+    };
+
+    match read_entry(&mut file, FIRST_RECORD) {
+        Ok(Some((data, _))) => Ok(PageHeader::parse_record(&data)),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+
+// data of entry and offset after it
+type Entry = (Vec<u8>, u64);
+
+// entry of simple_wal at offset and offset after it, None if entry is not whole written
+fn read_entry(file: &mut File, offset: u64) -> io::Result<Option<Entry>> {
+    let file_len = file.metadata()?.len();
+    let available = file_len.saturating_sub(offset);
+
+    let mut len = [0; LEN_SIZE];
+    if available < LEN_SIZE as u64 {
+        return Ok(None)
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut len)?;
+
+    let len = match entry_len(&len).filter(|len| *len as u64 <= available) {
+        Some(len) => len,
+        None => return Ok(None)
+    };
+
+    let mut bytes = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+
+    match frame(&bytes) {
+        Frame::Whole { data, checksum_ok: true, .. } => Ok(Some((data.to_vec(), offset + len as u64))),
+        Frame::Whole { .. } => Err(io::Error::new(io::ErrorKind::InvalidData, "bad checksum")),
+        Frame::Torn => Ok(None)
+    }
+}
+
+
+const FIRST_RECORD: u64 = FIRST_ENTRY as u64;
@@ -0,0 +1,52 @@
+use crate::{Options, Storage, StorageType, RQuery, WalReader, StartAt, Durability};
+use crate::darkbird::test_fixture::{Profile, TempDir};
+
+
+#[tokio::test]
+async fn wal_reader_follow_appends_across_pages_and_resume() {
+    let path = TempDir::new();
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+        .with_durability(Durability::Flushed);
+    let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+
+    for i in 0..1500 {
+        storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+    }
+    storage.remove("pid-0".to_owned()).await.unwrap();
+
+    // first 1000 records are in first page
+    let mut reader = WalReader::<String, Profile>::open(&path, "profiles", 1000, StartAt::Beginning).unwrap()
+        .with_poll_interval(std::time::Duration::from_millis(5));
+
+    let mut seqs = Vec::new();
+    for i in 0..1500 {
+        let (stamp, rq) = reader.next().await.unwrap();
+        assert_eq!(rq, RQuery::Versioned(format!("pid-{}", i), Profile::new("DanyalMh", i), 1));
+        seqs.push(stamp.seq);
+    }
+    assert_eq!(reader.next().await.unwrap().1, RQuery::Remove("pid-0".to_owned()));
+    assert!(seqs.windows(2).all(|w| w[1] == w[0] + 1));
+    assert!(reader.try_next().unwrap().is_none());
+
+    // reader wait for record appended later
+    let waiting = tokio::spawn(async move {
+        let record = reader.next().await.unwrap();
+        (record, reader.position())
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+    storage.insert("pid-new".to_owned(), Profile::new("Jack", 1)).await.unwrap();
+
+    let ((_, rq), position) = tokio::time::timeout(std::time::Duration::from_secs(5), waiting).await.unwrap().unwrap();
+    assert_eq!(rq, RQuery::Versioned("pid-new".to_owned(), Profile::new("Jack", 1), 1));
+
+    // resume from position and from sequence
+    storage.insert("pid-last".to_owned(), Profile::new("Jack", 2)).await.unwrap();
+    let mut reader = WalReader::<String, Profile>::open(&path, "profiles", 1000, StartAt::Position(position)).unwrap();
+    assert_eq!(reader.next().await.unwrap().1, RQuery::Versioned("pid-last".to_owned(), Profile::new("Jack", 2), 1));
+
+    let mut reader = WalReader::<String, Profile>::open(&path, "profiles", 1000, StartAt::Seq(seqs[1200])).unwrap();
+    let (stamp, rq) = reader.next().await.unwrap();
+    assert_eq!(stamp.seq, seqs[1200]);
+    assert_eq!(rq, RQuery::Versioned("pid-1200".to_owned(), Profile::new("DanyalMh", 1200), 1));
+}
@@ -0,0 +1,272 @@
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use simple_wal::LogFile;
+
+use crate::darkbird::RecoveryPolicy;
+
+use super::codec::PageHeader;
+use super::disk_log::{filename_factory, list_pages};
+use super::layout::{self, frame, Frame, FIRST_ENTRY};
+use super::snapshot::sync_dir;
+
+
+
+/// what recovery dropped from datastore pages on open
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RecoveryReport {
+    pub pages: Vec<PageReport>,
+}
+
+impl RecoveryReport {
+    /// true if nothing dropped
+    pub fn is_clean(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// total records dropped from all pages
+    pub fn dropped_records(&self) -> usize {
+        self.pages.iter().map(|p| p.dropped_records).sum()
+    }
+}
+
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PageReport {
+    pub page_index: usize,
+
+    // records removed from page and written to quarantine file
+    pub dropped_records: usize,
+
+    // bytes of tail that cut from page
+    pub truncated_bytes: u64,
+
+    pub errors: Vec<String>,
+}
+
+
+/// every dropped record is kept in quarantine file for inspection
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuarantineRecord {
+    pub page_index: usize,
+    pub offset: u64,
+    pub reason: String,
+    pub bytes: Vec<u8>,
+}
+
+
+
+const TORN_RECORD: &str = "torn record";
+
+
+// entry of a page, start..end is range of whole entry in file
+pub(super) enum Entry {
+    Valid { start: usize, end: usize },
+    Corrupt { start: usize, end: usize, reason: String },
+}
+
+
+
+/// check every page of datastore according to policy, repair them and
+/// move unreadable records to quarantine file,
+/// `valid` is called with header of page for every record with good checksum to check it is decodable
+pub fn repair<F>(path: &str, total_page_size: usize, policy: RecoveryPolicy, valid: F) -> Result<RecoveryReport, String>
+where
+    F: Fn(&PageHeader, &[u8]) -> bool
+{
+    let mut report = RecoveryReport::default();
+
+    if let RecoveryPolicy::Strict = policy {
+        return Ok(report)
+    }
+
+    if !Path::new(path).is_dir() {
+        return Ok(report)
+    }
+
+    let pages = list_pages(path, total_page_size).map_err(|e| e.to_string())?;
+    let tail = pages.last().copied();
+
+    for page_index in pages {
+        let filename = filename_factory(path, total_page_size * page_index);
+        let bytes = fs::read(&filename).map_err(|e| e.to_string())?;
+
+        let entries = scan(&bytes, &valid);
+        let first_corrupt = match entries.iter().position(|e| matches!(e, Entry::Corrupt { .. })) {
+            Some(position) => position,
+            None => continue,
+        };
+
+        let mut page_report = PageReport { page_index, ..Default::default() };
+
+        match policy {
+            RecoveryPolicy::TruncateTail => {
+                if Some(page_index) != tail {
+                    return Err(corrupt_error(page_index, &entries))
+                }
+
+                // cut page from first corrupt record, everything after it is quarantined
+                let cut = match &entries[first_corrupt] {
+                    Entry::Valid { start, .. } | Entry::Corrupt { start, .. } => *start,
+                };
+
+                page_report.dropped_records = entries.len() - first_corrupt;
+                page_report.truncated_bytes = (bytes.len() - cut) as u64;
+                page_report.errors = reasons(&entries[first_corrupt..]);
+
+                quarantine(path, &[QuarantineRecord {
+                    page_index,
+                    offset: cut as u64,
+                    reason: page_report.errors.join(", "),
+                    bytes: bytes[cut..].to_vec(),
+                }])?;
+
+                let file = OpenOptions::new().write(true).open(&filename).map_err(|e| e.to_string())?;
+                file.set_len(cut as u64).map_err(|e| e.to_string())?;
+                file.sync_all().map_err(|e| e.to_string())?;
+            }
+
+            RecoveryPolicy::SkipCorruptRecords => {
+                let mut dropped = Vec::new();
+                let tmp_filename = format!("{}.TMP", filename);
+                let _ = fs::remove_file(&tmp_filename);
+
+                {
+                    let mut page = LogFile::open(&tmp_filename).map_err(|e| e.to_string())?;
+                    for entry in entries.iter() {
+                        match entry {
+                            Entry::Valid { start, end } => {
+                                let mut data = layout::data(&bytes[*start..*end]).to_vec();
+                                page.write(&mut data).map_err(|e| e.to_string())?;
+                            }
+                            Entry::Corrupt { start, end, reason } => {
+                                dropped.push(QuarantineRecord {
+                                    page_index,
+                                    offset: *start as u64,
+                                    reason: reason.clone(),
+                                    bytes: bytes[*start..*end].to_vec(),
+                                });
+                            }
+                        }
+                    }
+                    page.flush().map_err(|e| e.to_string())?;
+                }
+
+                File::open(&tmp_filename).and_then(|f| f.sync_all()).map_err(|e| e.to_string())?;
+
+                page_report.dropped_records = dropped.len();
+                page_report.errors = reasons(&entries);
+
+                // tail bytes that are not a whole record
+                if let Some(Entry::Corrupt { start, end, reason }) = entries.last() {
+                    if reason == TORN_RECORD {
+                        page_report.truncated_bytes = (end - start) as u64;
+                    }
+                }
+
+                quarantine(path, &dropped)?;
+                fs::rename(&tmp_filename, &filename).map_err(|e| e.to_string())?;
+            }
+
+            RecoveryPolicy::Strict => {}
+        }
+
+        report.pages.push(page_report);
+    }
+
+    if !report.is_clean() {
+        sync_dir(path)?;
+    }
+
+    Ok(report)
+}
+
+
+
+// split page to entries, a record with bad checksum is skipped by its length,
+// but if length is not possible rest of page is one corrupt entry
+pub(super) fn scan<F>(bytes: &[u8], valid: &F) -> Vec<Entry>
+where
+    F: Fn(&PageHeader, &[u8]) -> bool
+{
+    let mut entries = Vec::new();
+
+    if bytes.len() < FIRST_ENTRY {
+        return entries
+    }
+
+    let mut header = PageHeader::legacy();
+
+    let mut pos = FIRST_ENTRY;
+    while pos < bytes.len() {
+        let (data, end, checksum_ok) = match frame(&bytes[pos..]) {
+            Frame::Whole { data, len, checksum_ok } => (data, pos + len, checksum_ok),
+            Frame::Torn => {
+                entries.push(Entry::Corrupt { start: pos, end: bytes.len(), reason: TORN_RECORD.to_owned() });
+                break
+            }
+        };
+
+        let page_header = match entries.is_empty() {
+            true => PageHeader::parse(data),
+            false => None
+        };
+
+        if !checksum_ok {
+            entries.push(Entry::Corrupt { start: pos, end, reason: "bad checksum".to_owned() });
+        } else if let Some(page_header) = page_header {
+            header = page_header;
+            entries.push(Entry::Valid { start: pos, end });
+        } else if !valid(&header, data) {
+            entries.push(Entry::Corrupt { start: pos, end, reason: "undecodable record".to_owned() });
+        } else {
+            entries.push(Entry::Valid { start: pos, end });
+        }
+
+        pos = end;
+    }
+
+    entries
+}
+
+
+pub(super) fn reasons(entries: &[Entry]) -> Vec<String> {
+    entries
+        .iter()
+        .filter_map(|e| match e {
+            Entry::Corrupt { start, reason, .. } => Some(format!("{} at {}", reason, start)),
+            Entry::Valid { .. } => None,
+        })
+        .collect()
+}
+
+
+fn corrupt_error(page_index: usize, entries: &[Entry]) -> String {
+    format!("page {} is corrupt: {}", page_index, reasons(entries).join(", "))
+}
+
+
+/// append dropped records to quarantine file of datastore
+fn quarantine(path: &str, records: &[QuarantineRecord]) -> Result<(), String> {
+    if records.is_empty() {
+        return Ok(())
+    }
+
+    let filename = quarantine_filename(path);
+    let mut log = LogFile::open(&filename).map_err(|e| e.to_string())?;
+
+    for record in records {
+        let mut bytes = bincode::serialize(record).map_err(|e| e.to_string())?;
+        log.write(&mut bytes).map_err(|e| e.to_string())?;
+    }
+
+    log.flush().map_err(|e| e.to_string())?;
+    File::open(&filename).and_then(|f| f.sync_all()).map_err(|e| e.to_string())
+}
+
+
+#[inline]
+pub fn quarantine_filename(path: &str) -> String {
+    format!("{}/quarantine.LOG", path)
+}
@@ -0,0 +1,37 @@
+use crate::{Options, Storage, StorageType, Durability, RecoveryPolicy};
+use crate::darkbird::test_fixture::{Profile, TempDir};
+
+
+#[tokio::test]
+async fn corrupt_tail_record_is_quarantined() {
+    let path = TempDir::new();
+
+    {
+        let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+            .with_durability(Durability::Fsynced);
+
+        let storage = Storage::<String, Profile>::open(ops).await.unwrap().0;
+        for i in 0..10 {
+            storage.insert(format!("pid-{}", i), Profile::new("DanyalMh", i)).await.unwrap();
+        }
+    }
+
+    // flip checksum of last record
+    let page = format!("{}/profiles/page-5000.LOG", path);
+    let mut bytes = std::fs::read(&page).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&page, bytes).unwrap();
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true);
+    assert!(Storage::<String, Profile>::open(ops).await.is_err());
+
+    let ops = Options::new(&path, "profiles", 1000, StorageType::DiskCopies, true)
+        .with_recovery(RecoveryPolicy::TruncateTail);
+    let (storage, report) = Storage::<String, Profile>::open(ops).await.unwrap();
+
+    assert_eq!(storage.collection_len(), 9);
+    assert_eq!(report.dropped_records(), 1);
+    assert_eq!(&report, storage.recovery_report());
+    assert!(std::path::Path::new(&format!("{}/profiles/quarantine.LOG", path)).is_file());
+}
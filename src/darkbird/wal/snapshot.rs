@@ -0,0 +1,240 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::codec::{Stamp, Keyring, PageHeader};
+use super::disk_log::{filename_factory, list_pages};
+
+
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"DBSNAP02";
+
+// entries of snapshot written before versions have not version
+const SNAPSHOT_MAGIC_V1: &[u8; 8] = b"DBSNAP01";
+
+// length of frame that close snapshot
+const END_FRAME: u64 = u64::MAX;
+
+
+
+/// snapshot file tagged with index of first page must replayed after it
+#[inline]
+pub fn filename_factory_snapshot(path: &str, page_index: usize) -> String {
+    format!("{}/snapshot-{}.SNAP", path, page_index)
+}
+
+
+/// return (page_index, filename) of latest snapshot exist in datastore directory
+pub fn latest(path: &str) -> Result<Option<(usize, String)>, String> {
+    let mut latest = None;
+
+    for page_index in list_snapshots(path)? {
+        latest = Some((page_index, filename_factory_snapshot(path, page_index)));
+    }
+
+    Ok(latest)
+}
+
+
+/// return sorted page_index of all snapshots exist in datastore directory
+pub fn list_snapshots(path: &str) -> Result<Vec<usize>, String> {
+    let mut snapshots = Vec::new();
+
+    let dir = fs::read_dir(path).map_err(|e| e.to_string())?;
+    for entry in dir {
+        let name = entry.map_err(|e| e.to_string())?.file_name();
+        let page_index = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("snapshot-"))
+            .and_then(|rest| rest.strip_suffix(".SNAP"))
+            .and_then(|index| index.parse::<usize>().ok());
+
+        if let Some(page_index) = page_index {
+            snapshots.push(page_index);
+        }
+    }
+
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+
+/// snapshot is written to temporary file and on commit fsynced and renamed to snapshot file,
+/// so a crash never leaves half-written snapshot
+///
+/// after magic, snapshot is frames of [len][bytes], first frame is a page header
+/// that entries encoded by it, entry is (key, doc, version)
+pub struct Writer {
+    writer: BufWriter<File>,
+    header: PageHeader,
+    keys: Keyring,
+    path: String,
+    tmp_filename: String,
+    page_index: usize
+}
+
+impl Writer {
+    pub fn create(path: &str, page_index: usize, header: PageHeader, keys: Keyring) -> Result<Self, String> {
+        let tmp_filename = format!("{}/snapshot-{}.TMP", path, page_index);
+
+        // snapshot is state at page_index, so entries have not stamp
+        let header = header.unstamped();
+
+        let file = File::create(&tmp_filename).map_err(|e| e.to_string())?;
+        let mut writer = BufWriter::new(file);
+
+        writer.write_all(SNAPSHOT_MAGIC).map_err(|e| e.to_string())?;
+        write_frame(&mut writer, &header.to_bytes())?;
+
+        Ok(Writer {
+            writer,
+            header,
+            keys,
+            path: path.to_owned(),
+            tmp_filename,
+            page_index
+        })
+    }
+
+    /// snapshot is closed by end frame,
+    /// because collection can change while iterating and len is not known up front
+    #[inline]
+    pub fn append<K: Serialize, Doc: Serialize>(&mut self, key: &K, doc: &Doc, version: u64) -> Result<(), String> {
+        let bytes = self.header.seal(&(key, doc, version), Stamp::default(), &self.keys)?;
+        write_frame(&mut self.writer, &bytes)
+    }
+
+    pub fn commit(mut self) -> Result<(), String> {
+        self.writer.write_all(&END_FRAME.to_le_bytes()).map_err(|e| e.to_string())?;
+
+        let file = self.writer.into_inner().map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+
+        fs::rename(&self.tmp_filename, filename_factory_snapshot(&self.path, self.page_index)).map_err(|e| e.to_string())?;
+        sync_dir(&self.path)
+    }
+}
+
+
+/// read all entries of snapshot file
+pub fn read<K, Doc>(filename: &str, keys: &Keyring) -> Result<Vec<(K, Doc)>, String>
+where
+    K: DeserializeOwned,
+    Doc: DeserializeOwned,
+{
+    let entries = read_versioned(filename, keys)?;
+    Ok(entries.into_iter().map(|(key, doc, _)| (key, doc)).collect())
+}
+
+/// read all entries of snapshot file with version of them,
+/// version of entries written before versions is 1
+pub fn read_versioned<K, Doc>(filename: &str, keys: &Keyring) -> Result<Vec<(K, Doc, u64)>, String>
+where
+    K: DeserializeOwned,
+    Doc: DeserializeOwned,
+{
+    let (mut reader, header, versioned) = open(filename)?;
+
+    let mut entries = Vec::new();
+    while let Some(bytes) = read_frame(&mut reader)? {
+        match versioned {
+            true => entries.push(header.decode(&bytes, keys)?),
+            false => {
+                let (key, doc) = header.decode(&bytes, keys)?;
+                entries.push((key, doc, 1));
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+
+/// return header that entries of snapshot encoded by it
+pub fn header(filename: &str) -> Result<PageHeader, String> {
+    open(filename).map(|(_, header, _)| header)
+}
+
+
+// open snapshot and read magic and header, true if entries have version
+fn open(filename: &str) -> Result<(BufReader<File>, PageHeader, bool), String> {
+    let file = File::open(filename).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(|e| e.to_string())?;
+    let versioned = match &magic {
+        SNAPSHOT_MAGIC => true,
+        SNAPSHOT_MAGIC_V1 => false,
+        _ => return Err(format!("{} is not a snapshot", filename))
+    };
+
+    match read_frame(&mut reader)?.and_then(|bytes| PageHeader::parse(&bytes)) {
+        Some(header) => Ok((reader, header, versioned)),
+        None => Err(format!("{} has not header", filename))
+    }
+}
+
+
+#[inline]
+fn write_frame<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<(), String> {
+    writer.write_all(&(bytes.len() as u64).to_le_bytes()).map_err(|e| e.to_string())?;
+    writer.write_all(bytes).map_err(|e| e.to_string())
+}
+
+// None when reached to end frame
+#[inline]
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, String> {
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len).map_err(|e| e.to_string())?;
+
+    let len = u64::from_le_bytes(len);
+    if len == END_FRAME {
+        return Ok(None)
+    }
+
+    // corrupt len must not allocate whole memory
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+    if bytes.len() as u64 != len {
+        return Err("snapshot is truncated".to_owned())
+    }
+
+    Ok(Some(bytes))
+}
+
+
+/// remove (or move to archive folder) all pages and snapshots older than snapshot with page_index
+pub fn release_older(path: &str, total_page_size: usize, page_index: usize, archive: bool) -> Result<(), String> {
+    let archive_path = format!("{}/archive", path);
+    if archive && !Path::new(&archive_path).is_dir() {
+        fs::create_dir(&archive_path).map_err(|e| e.to_string())?;
+    }
+
+    let pages = list_pages(path, total_page_size).map_err(|e| e.to_string())?;
+    for index in pages.into_iter().filter(|index| *index < page_index) {
+        let filename = filename_factory(path, total_page_size * index);
+        let res = if archive {
+            fs::rename(&filename, filename_factory(&archive_path, total_page_size * index))
+        } else {
+            fs::remove_file(&filename)
+        };
+        res.map_err(|e| e.to_string())?;
+    }
+
+    for index in list_snapshots(path)?.into_iter().filter(|index| *index < page_index) {
+        fs::remove_file(filename_factory_snapshot(path, index)).map_err(|e| e.to_string())?;
+    }
+
+    sync_dir(path)
+}
+
+
+// fsync directory, so renames are durable
+pub fn sync_dir(path: &str) -> Result<(), String> {
+    File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| e.to_string())
+}
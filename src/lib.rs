@@ -8,16 +8,25 @@ pub use serde;
 pub use darkbird::{
     SessionResult,
     StatusResult,
-    storage::Storage,
+    storage::{Storage, Transaction},
     storage_vector::VecStorage,
     vector::{VectorId, Vector},
     storage_redis,
-    wal::{helper::{backup, migration}, page_processor::{Format, Sync, PageProcessor}}, 
+    wal::{backup, helper::{backup, backup_with_keys, migration, reencode, rotate_keys}, page_processor::{Sync, PageProcessor}, codec::{Format, Compression, KeyProvider, Stamp, page_formats}, compaction::CompactionStats, recovery::{RecoveryReport, PageReport, QuarantineRecord}, reader::{WalReader, Position, StartAt}}, 
     persistent_worker::{Persistent, DatabaseName, DatabaseSession, Stop},
+    migration::{Migrations, MigrationReport, StepReport, schema_version},
+    replica::Node,
+    cluster::{ClusterDatabase, RebalanceReport},
+    replication::{Leader, Follower, ReplicationStatus},
+    transaction::{DatabaseTransaction, TransactionLog},
     document,
     RQuery, 
     Event,
     Options,
+    Durability,
+    Checkpoint,
+    LoadProgress,
+    RecoveryPolicy,
     Config,
     StorageType,
     schema::Schema,